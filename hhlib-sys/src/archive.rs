@@ -0,0 +1,598 @@
+//! A compact archive of decoded T2 time tags. The tags are split into blocks, and within each block
+//! the times of each channel are stored as varint deltas from the one before, then compressed with
+//! zstd. An index of the time range and position of each block at the end of the file lets any
+//! range of time be read without decompressing the rest.
+//!
+//! The layout is the magic, a `u32` length and the JSON `ArchiveMetadata`, the blocks, then the
+//! index of `(first time, last time, offset, length, events)` of each block as `u64`s, followed by
+//! the offset of the index, the number of blocks and the magic again. All integers are little endian.
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::DeviceConfig;
+use crate::measurement::T2Encoder;
+use crate::ptu::{file_error, PtuHeader, PtuWriter, RecordType, StopReason};
+use crate::raw::{RawFile, RawMeasurement, RawMetadata, RawRecorder};
+use crate::reader::TtrFile;
+use crate::types::HydraHarpError;
+
+const MAGIC: &[u8; 8] = b"HHTAGS\x00\x01";
+/// The default number of events in each block, about a second of data at a few MHz
+pub const BLOCK_EVENTS: usize = 1 << 22;
+const RECORDS_LENGTH: usize = 131072;
+
+/// What the time tags were measured with, kept so that they can be written back out in full
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveMetadata {
+    pub serial: String,
+    /// Resolution of the original records in ps
+    pub resolution: f64,
+    /// The settings the device was measuring with, if known
+    pub config: Option<DeviceConfig>,
+    /// Length of the recording in ps, if known
+    pub duration: Option<u64>,
+}
+
+/// The position of one block in the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockIndex {
+    pub first_time: u64,
+    pub last_time: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub events: u64,
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u64, HydraHarpError> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        let b = *bytes.get(*position).ok_or(HydraHarpError::FileError)?;
+        *position += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b < 0x80 {
+            return Ok(v);
+        }
+    }
+    Err(HydraHarpError::FileError)
+}
+
+/// Encode the events of a block, which are sorted by time, before compression
+fn encode_block(events: &[(u8, u64)], first_time: u64) -> Vec<u8> {
+    let mut channels: Vec<(u8, Vec<u64>)> = Vec::new();
+    for &(c, t) in events.iter() {
+        match channels.iter_mut().find(|(channel, _)| *channel == c) {
+            Some((_, times)) => times.push(t),
+            None => channels.push((c, vec![t])),
+        }
+    }
+    let mut out = Vec::with_capacity(events.len() * 2);
+    write_varint(&mut out, channels.len() as u64);
+    for (c, times) in channels.iter() {
+        out.push(*c);
+        write_varint(&mut out, times.len() as u64);
+        let mut last = first_time;
+        for &t in times.iter() {
+            write_varint(&mut out, t - last);
+            last = t;
+        }
+    }
+    out
+}
+
+/// Decode the events of a block, in order of time and then channel
+fn decode_block(bytes: &[u8], first_time: u64) -> Result<Vec<(u8, u64)>, HydraHarpError> {
+    let mut position = 0;
+    let mut events = Vec::new();
+    for _ in 0..read_varint(bytes, &mut position)? {
+        let c = *bytes.get(position).ok_or(HydraHarpError::FileError)?;
+        position += 1;
+        let mut t = first_time;
+        for _ in 0..read_varint(bytes, &mut position)? {
+            t = t
+                .checked_add(read_varint(bytes, &mut position)?)
+                .ok_or(HydraHarpError::FileError)?;
+            events.push((c, t));
+        }
+    }
+    events.sort_unstable_by_key(|&(c, t)| (t, c));
+    Ok(events)
+}
+
+/// Writes time tags to an archive in blocks
+pub struct ArchiveWriter<W: Write + Seek> {
+    writer: W,
+    pending: Vec<(u8, u64)>,
+    index: Vec<BlockIndex>,
+    /// The number of events in each block
+    pub block_events: usize,
+    /// The zstd compression level
+    pub level: i32,
+    finished: bool,
+}
+
+impl ArchiveWriter<BufWriter<File>> {
+    /// Start an archive at `path`, replacing any file there.
+    /// Returns `FileError` if it can't be written
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        metadata: &ArchiveMetadata,
+    ) -> Result<ArchiveWriter<BufWriter<File>>, HydraHarpError> {
+        let file = File::create(path).map_err(file_error)?;
+        ArchiveWriter::new(BufWriter::new(file), metadata)
+    }
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    /// Start an archive, writing the header
+    pub fn new(
+        mut writer: W,
+        metadata: &ArchiveMetadata,
+    ) -> Result<ArchiveWriter<W>, HydraHarpError> {
+        let json = serde_json::to_vec(metadata).map_err(|_| HydraHarpError::FileError)?;
+        writer.write_all(MAGIC).map_err(file_error)?;
+        writer
+            .write_all(&(json.len() as u32).to_le_bytes())
+            .map_err(file_error)?;
+        writer.write_all(&json).map_err(file_error)?;
+        Ok(ArchiveWriter {
+            writer,
+            pending: Vec::new(),
+            index: Vec::new(),
+            block_events: BLOCK_EVENTS,
+            level: 3,
+            finished: false,
+        })
+    }
+
+    /// Add sorted `(channel, time)` values following on from those already written.
+    /// Returns `InvalidArgument` if a time is earlier than the one before or the archive is finished
+    pub fn write(&mut self, events: &[(u8, u64)]) -> Result<(), HydraHarpError> {
+        if self.finished {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let mut last = self.pending.last().map_or(0, |&(_, t)| t);
+        if let Some(block) = self.index.last() {
+            last = last.max(block.last_time);
+        }
+        for &(c, t) in events.iter() {
+            if t < last {
+                return Err(HydraHarpError::InvalidArgument);
+            }
+            last = t;
+            self.pending.push((c, t));
+            if self.pending.len() >= self.block_events {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<(), HydraHarpError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let first_time = self.pending[0].1;
+        let bytes = encode_block(&self.pending, first_time);
+        let compressed = zstd::encode_all(&bytes[..], self.level).map_err(file_error)?;
+        let offset = self.writer.seek(SeekFrom::Current(0)).map_err(file_error)?;
+        self.writer.write_all(&compressed).map_err(file_error)?;
+        self.index.push(BlockIndex {
+            first_time,
+            last_time: self.pending[self.pending.len() - 1].1,
+            offset,
+            length: compressed.len() as u64,
+            events: self.pending.len() as u64,
+        });
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// The number of events written so far
+    pub fn events(&self) -> u64 {
+        self.index.iter().map(|b| b.events).sum::<u64>() + self.pending.len() as u64
+    }
+
+    /// Write the last block and the index. Finishing again does nothing
+    pub fn finish(&mut self) -> Result<(), HydraHarpError> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_block()?;
+        let index_offset = self.writer.seek(SeekFrom::Current(0)).map_err(file_error)?;
+        let mut bytes = Vec::with_capacity(self.index.len() * 40 + 24);
+        for b in self.index.iter() {
+            for v in [b.first_time, b.last_time, b.offset, b.length, b.events].iter() {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&index_offset.to_le_bytes());
+        bytes.extend_from_slice(&(self.index.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(MAGIC);
+        self.writer.write_all(&bytes).map_err(file_error)?;
+        self.writer.flush().map_err(file_error)?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for ArchiveWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(b)
+}
+
+/// An archive opened for reading, with only its header and index loaded
+pub struct ArchiveFile {
+    pub path: PathBuf,
+    pub metadata: ArchiveMetadata,
+    pub blocks: Vec<BlockIndex>,
+}
+
+impl ArchiveFile {
+    /// Open an archive, reading its header and index.
+    /// Returns `FileError` if it can't be read or isn't a finished archive
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ArchiveFile, HydraHarpError> {
+        let mut f = File::open(path.as_ref()).map_err(file_error)?;
+        let length = f.metadata().map_err(file_error)?.len();
+        let mut header = [0; 12];
+        f.read_exact(&mut header).map_err(file_error)?;
+        if &header[..8] != MAGIC || length < 12 + 24 {
+            return Err(HydraHarpError::FileError);
+        }
+        // Check the lengths read from the file fit in it before allocating for them
+        let json_length = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as u64;
+        if json_length > length - 12 - 24 {
+            return Err(HydraHarpError::FileError);
+        }
+        let mut json = vec![0; json_length as usize];
+        f.read_exact(&mut json).map_err(file_error)?;
+        let metadata = serde_json::from_slice(&json).map_err(|_| HydraHarpError::FileError)?;
+
+        let mut footer = [0; 24];
+        f.seek(SeekFrom::End(-24)).map_err(file_error)?;
+        f.read_exact(&mut footer).map_err(file_error)?;
+        if &footer[16..] != MAGIC {
+            return Err(HydraHarpError::FileError);
+        }
+        let index_offset = read_u64(&footer);
+        let index_length = read_u64(&footer[8..])
+            .checked_mul(40)
+            .filter(|&l| {
+                index_offset
+                    .checked_add(l)
+                    .map_or(false, |end| end <= length - 24)
+            })
+            .ok_or(HydraHarpError::FileError)?;
+        let mut index = vec![0; index_length as usize];
+        f.seek(SeekFrom::Start(index_offset)).map_err(file_error)?;
+        f.read_exact(&mut index).map_err(file_error)?;
+        // Each block has to lie between the header and the index, so that reading it can't
+        // allocate more than the file holds
+        let blocks = index
+            .chunks(40)
+            .map(|b| BlockIndex {
+                first_time: read_u64(b),
+                last_time: read_u64(&b[8..]),
+                offset: read_u64(&b[16..]),
+                length: read_u64(&b[24..]),
+                events: read_u64(&b[32..]),
+            })
+            .map(|b| {
+                let end = b.offset.checked_add(b.length);
+                if b.offset >= 12 + json_length && end.map_or(false, |end| end <= index_offset) {
+                    Ok(b)
+                } else {
+                    Err(HydraHarpError::FileError)
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ArchiveFile {
+            path: path.as_ref().to_path_buf(),
+            metadata,
+            blocks,
+        })
+    }
+
+    /// The number of events in the archive
+    pub fn events(&self) -> u64 {
+        self.blocks.iter().map(|b| b.events).sum()
+    }
+
+    /// Pass the sorted `(channel, time)` values with times in `range` to `process`, a block at a
+    /// time, decompressing only the blocks which overlap it
+    pub fn read_range<F>(&self, range: Range<u64>, mut process: F) -> Result<(), HydraHarpError>
+    where
+        F: FnMut(&[(u8, u64)]),
+    {
+        let mut f = File::open(&self.path).map_err(file_error)?;
+        let mut compressed = Vec::new();
+        for b in self
+            .blocks
+            .iter()
+            .filter(|b| b.last_time >= range.start && b.first_time < range.end)
+        {
+            compressed.resize(b.length as usize, 0);
+            f.seek(SeekFrom::Start(b.offset)).map_err(file_error)?;
+            f.read_exact(&mut compressed).map_err(file_error)?;
+            let bytes = zstd::decode_all(&compressed[..]).map_err(file_error)?;
+            let events = decode_block(&bytes, b.first_time)?;
+            let start = events
+                .iter()
+                .position(|&(_, t)| t >= range.start)
+                .unwrap_or_else(|| events.len());
+            let end = events
+                .iter()
+                .position(|&(_, t)| t >= range.end)
+                .unwrap_or_else(|| events.len());
+            process(&events[start..end]);
+        }
+        Ok(())
+    }
+
+    /// Pass all of the sorted `(channel, time)` values to `process`, a block at a time
+    pub fn read<F>(&self, process: F) -> Result<(), HydraHarpError>
+    where
+        F: FnMut(&[(u8, u64)]),
+    {
+        self.read_range(0..u64::max_value(), process)
+    }
+
+    /// The settings to write with the records, or the defaults for all 8 inputs if they aren't known
+    fn config(&self) -> DeviceConfig {
+        self.metadata
+            .config
+            .clone()
+            .unwrap_or_else(|| DeviceConfig::new(8))
+    }
+
+    /// Pass the events re-encoded as version 2 T2 fifo records to `process`, in chunks
+    fn read_records<F>(&self, mut process: F) -> Result<(), HydraHarpError>
+    where
+        F: FnMut(&[u32]) -> Result<(), HydraHarpError>,
+    {
+        let mut encoder = T2Encoder::new();
+        let mut records = Vec::with_capacity(RECORDS_LENGTH + 64);
+        let mut result = Ok(());
+        self.read(|events| {
+            for &(c, t) in events.iter() {
+                encoder.encode(c, t, |r| records.push(r));
+                if records.len() >= RECORDS_LENGTH && result.is_ok() {
+                    result = process(&records);
+                    records.clear();
+                }
+            }
+        })?;
+        result?;
+        process(&records)
+    }
+
+    /// Write the events to a PTU file as version 2 T2 records.
+    /// Returns `FileError` if it can't be written
+    pub fn to_ptu<P: AsRef<Path>>(&self, path: P) -> Result<(), HydraHarpError> {
+        let header = PtuHeader {
+            record_type: RecordType::HydraHarp2T2,
+            serial: self.metadata.serial.clone(),
+            model: String::new(),
+            part_number: String::new(),
+            hardware_version: String::new(),
+            resolution: self.metadata.resolution,
+            sync_period: None,
+            config: self.config(),
+            acquisition_time: (self.metadata.duration.unwrap_or(0) / 1_000_000_000) as i32,
+            start_time: SystemTime::now(),
+            extra_tags: Vec::new(),
+        };
+        let mut writer = PtuWriter::create(path, &header)?;
+        self.read_records(|records| writer.write_records(records))?;
+        writer.finish(StopReason::TimeOver)
+    }
+
+    /// Write the events to a raw recording of one measurement as version 2 T2 records.
+    /// Returns `FileError` if it can't be written
+    pub fn to_raw<P: AsRef<Path>>(&self, path: P) -> Result<(), HydraHarpError> {
+        let metadata = RawMetadata {
+            record_type: RecordType::HydraHarp2T2,
+            serial: self.metadata.serial.clone(),
+            model: String::new(),
+            part_number: String::new(),
+            hardware_version: String::new(),
+            resolution: self.metadata.resolution,
+            sync_period: None,
+            config: self.config(),
+            measurements: Vec::new(),
+        };
+        let mut recorder = RawRecorder::create(path, metadata)?;
+        let mut records = 0;
+        self.read_records(|r| {
+            records += r.len() as u64;
+            recorder.write_records(r)
+        })?;
+        recorder.metadata.measurements.push(RawMeasurement {
+            first_record: 0,
+            records,
+            acquisition_time: (self.metadata.duration.unwrap_or(0) / 1_000_000_000) as i32,
+            start_time: 0.0,
+            stop_time: 0.0,
+            flags: 0,
+            warnings: 0,
+            error: None,
+        });
+        recorder.save()
+    }
+}
+
+/// Archive the T2 records of a PTU or HT2 file at `path` to an archive at `destination`, keeping the markers.
+/// Returns the number of events archived, `InvalidMode` if the file isn't T2 and `FileError` if
+/// either file can't be read or written
+pub fn archive_ttr_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    destination: Q,
+) -> Result<u64, HydraHarpError> {
+    let file = TtrFile::open(path)?;
+    let metadata = ArchiveMetadata {
+        serial: file
+            .tag("HW_SerialNo")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string(),
+        resolution: file.resolution().unwrap_or(1.0),
+        config: None,
+        duration: file.duration(),
+    };
+    let mut writer = ArchiveWriter::create(destination, &metadata)?;
+    let mut measurement = file.measurement(&[]);
+    measurement.keep_markers = true;
+    let mut result = Ok(());
+    file.read_T2(measurement, |t| {
+        if result.is_ok() {
+            result = writer.write(t);
+        }
+    })?;
+    result?;
+    writer.finish()?;
+    Ok(writer.events())
+}
+
+/// Archive the records of a T2 raw recording at `path` to an archive at `destination`, keeping the markers.
+/// Returns the number of events archived, `InvalidMode` if the recording isn't T2 and `FileError` if
+/// either file can't be read or written
+pub fn archive_raw_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    destination: Q,
+) -> Result<u64, HydraHarpError> {
+    let file = RawFile::open(path)?;
+    let metadata = ArchiveMetadata {
+        serial: file.metadata.serial.clone(),
+        resolution: file.metadata.resolution,
+        config: Some(file.metadata.config.clone()),
+        duration: Some(file.duration()),
+    };
+    let mut writer = ArchiveWriter::create(destination, &metadata)?;
+    let mut measurement = file.measurement(&[]);
+    measurement.keep_markers = true;
+    let mut result = Ok(());
+    file.read_T2(measurement, |t| {
+        if result.is_ok() {
+            result = writer.write(t);
+        }
+    })?;
+    result?;
+    writer.finish()?;
+    Ok(writer.events())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_block, read_u64, write_varint, ArchiveFile, ArchiveMetadata, ArchiveWriter,
+    };
+    use crate::measurement::MARKER_CHANNEL;
+    use crate::reader::TtrFile;
+
+    #[test]
+    fn round_trips_through_ptu() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!(
+            "hhlib_sys_archive_test_{}.tags",
+            std::process::id()
+        ));
+        let ptu = dir.join(format!("hhlib_sys_archive_test_{}.ptu", std::process::id()));
+        let mut events = Vec::new();
+        for i in 0..10_000u64 {
+            events.push(((i % 3) as u8, i * i * 1000));
+        }
+        events.push((MARKER_CHANNEL + 2, 99_990_000 * 1000));
+        events.sort_by_key(|&(c, t)| (t, c));
+        let metadata = ArchiveMetadata {
+            serial: "1234567".to_string(),
+            resolution: 1.0,
+            config: None,
+            duration: Some(100_000_000_000),
+        };
+        let mut w = ArchiveWriter::create(&path, &metadata).unwrap();
+        w.block_events = 1000;
+        w.write(&events[..5000]).unwrap();
+        w.write(&events[5000..]).unwrap();
+        assert!(w.write(&[(1, 0)]).is_err());
+        w.finish().unwrap();
+        drop(w);
+
+        let archive = ArchiveFile::open(&path).unwrap();
+        assert_eq!(archive.metadata, metadata);
+        assert_eq!(archive.events(), events.len() as u64);
+        let mut read = Vec::new();
+        archive.read(|e| read.extend_from_slice(e)).unwrap();
+        assert_eq!(read, events);
+        let range = 1_000_000_000..50_000_000_000;
+        let mut read = Vec::new();
+        archive
+            .read_range(range.clone(), |e| read.extend_from_slice(e))
+            .unwrap();
+        let expected = events
+            .iter()
+            .cloned()
+            .filter(|&(_, t)| range.start <= t && t < range.end)
+            .collect::<Vec<_>>();
+        assert_eq!(read, expected);
+
+        // A block count in the footer which doesn't fit in the file
+        let mut bytes = std::fs::read(&path).unwrap();
+        let count = bytes.len() - 16;
+        bytes[count..count + 8].copy_from_slice(&(1u64 << 60).to_le_bytes());
+        std::fs::write(&ptu, &bytes).unwrap();
+        assert!(ArchiveFile::open(&ptu).is_err());
+        // A block which runs into the index
+        let mut bytes = std::fs::read(&path).unwrap();
+        let length = read_u64(&bytes[bytes.len() - 24..]) as usize + 24;
+        bytes[length..length + 8].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        std::fs::write(&ptu, &bytes).unwrap();
+        assert!(ArchiveFile::open(&ptu).is_err());
+
+        archive.to_ptu(&ptu).unwrap();
+        let file = TtrFile::open(&ptu).unwrap();
+        let mut measurement = file.measurement(&[]);
+        measurement.keep_markers = true;
+        let mut read = Vec::new();
+        file.read_T2(measurement, |t| read.extend_from_slice(t))
+            .unwrap();
+        read.sort_by_key(|&(c, t)| (t, c));
+        assert_eq!(read, events);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&ptu).unwrap();
+    }
+
+    #[test]
+    fn rejects_times_past_the_end_of_time() {
+        // One channel with two events, the second of which is after the last time there can be
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 1);
+        bytes.push(1);
+        write_varint(&mut bytes, 2);
+        write_varint(&mut bytes, 10);
+        write_varint(&mut bytes, u64::max_value() - 10);
+        assert!(decode_block(&bytes, 5).is_err());
+        assert_eq!(
+            decode_block(&bytes, 0).unwrap(),
+            vec![(1, 10), (1, u64::max_value())]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Automatic calibration of the channel delays.
+//! Records a short acquisition, finds the correlation peak of each channel against a reference channel,
+//! and works out the offsets which line all of the peaks up at zero delay.
+use crate::bindings::{CHANOFFSMAX, CHANOFFSMIN};
+use crate::config::DeviceConfig;
+use crate::correlation::CrossCorrelator;
+use crate::device::Device;
+use crate::measurement::{run_measurement_T2, Measureable};
+use crate::types::HydraHarpError;
+
+/// The conversion from a gaussian standard deviation to its full width at half maximum
+const SIGMA_TO_FWHM: f64 = 2.354_820_045;
+
+/// The measured delay of a channel relative to the reference channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelDelay {
+    pub channel: u8,
+    /// Position of the correlation peak, `t_channel - t_reference`, in ps
+    pub delay: f64,
+    /// FWHM of the correlation peak in ps. This is the combined timing jitter of the two channels
+    pub jitter: f64,
+    /// The number of pairs in the peak bin, to judge whether the peak is real
+    pub peak_counts: u64,
+}
+
+/// Where to look for the delays: the channels to line up with a reference channel, and the range
+/// and bin width of the correlations which find their peaks.
+/// Channels use the numbering from `Measurement::convert_values_T2`, so the sync is channel 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelaySearch {
+    pub reference: u8,
+    pub channels: Vec<u8>,
+    /// Width of the correlation bins in ps
+    pub bin_width: u64,
+    /// The peaks are searched for between `-tau` and `tau` ps
+    pub tau: i64,
+}
+
+/// Find the peak of a correlation histogram with sub-bin precision.
+/// Fits a gaussian through the highest bin and its neighbours, after subtracting the median as background.
+/// Returns `(position, fwhm)` in ps, or `None` if the histogram is empty
+pub fn find_peak(correlator: &CrossCorrelator) -> Option<(f64, f64)> {
+    let h = &correlator.histogram;
+    let (peak, &max) = h.iter().enumerate().max_by_key(|&(_, c)| *c)?;
+    if max == 0 {
+        return None;
+    }
+    let mut sorted = h.clone();
+    sorted.sort();
+    let background = sorted[sorted.len() / 2] as f64;
+    let width = correlator.bin_width as f64;
+    let centre = correlator.tau()[peak];
+
+    if peak == 0 || peak + 1 == h.len() {
+        return Some((centre, width));
+    }
+    let (y0, y1, y2) = (
+        h[peak - 1] as f64 - background,
+        h[peak] as f64 - background,
+        h[peak + 1] as f64 - background,
+    );
+    if y0 <= 0.0 || y2 <= 0.0 {
+        // The peak is narrower than a bin, so the best we can do is the bin it's in
+        return Some((centre, width));
+    }
+    let (l0, l1, l2) = (y0.ln(), y1.ln(), y2.ln());
+    let curvature = l0 - 2.0 * l1 + l2;
+    if curvature >= 0.0 {
+        return Some((centre, width));
+    }
+    let shift = 0.5 * (l0 - l2) / curvature;
+    let sigma = width / (-curvature).sqrt();
+    Some((centre + shift * width, sigma * SIGMA_TO_FWHM))
+}
+
+/// Measure for `acquisition_time` ms and find the delay of each of the channels of `search`
+/// relative to its reference.
+/// Returns `InvalidArgument` if there is no peak for a channel
+pub fn measure_delays<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    search: &DelaySearch,
+) -> Result<Vec<ChannelDelay>, HydraHarpError> {
+    let mut correlators = search
+        .channels
+        .iter()
+        .map(|&c| CrossCorrelator::symmetric(search.reference, c, search.bin_width, search.tau))
+        .collect::<Result<Vec<_>, _>>()?;
+    run_measurement_T2(d, acquisition_time, |times| {
+        for c in correlators.iter_mut() {
+            c.add_times(times);
+        }
+    })?;
+    correlators
+        .iter()
+        .map(|c| match find_peak(c) {
+            Some((delay, jitter)) => Ok(ChannelDelay {
+                channel: c.channel_b,
+                delay,
+                jitter,
+                peak_counts: *c.histogram.iter().max().unwrap_or(&0),
+            }),
+            None => Err(HydraHarpError::InvalidArgument),
+        })
+        .collect()
+}
+
+/// Change the offsets in `config` so that each of the measured delays becomes zero.
+/// Returns `InvalidArgument` if a new offset is outside the range the device can apply, in which
+/// case none of the offsets are changed
+pub fn correct_offsets(
+    config: &mut DeviceConfig,
+    delays: &[ChannelDelay],
+) -> Result<(), HydraHarpError> {
+    let offsets = delays
+        .iter()
+        .map(|d| {
+            let current = config
+                .channel_offset(d.channel)
+                .ok_or(HydraHarpError::InvalidArgument)?;
+            let offset = current - d.delay.round() as i32;
+            if offset < CHANOFFSMIN as i32 || offset > CHANOFFSMAX as i32 {
+                return Err(HydraHarpError::InvalidArgument);
+            }
+            Ok((d.channel, offset))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (channel, offset) in offsets {
+        config.set_channel_offset(channel, offset)?;
+    }
+    Ok(())
+}
+
+/// Measure the delays of the channels of `search` and return them.
+/// If `write_offsets` is true, also correct the offsets in `config` and apply them to the device
+pub fn calibrate_delays(
+    d: &mut Device,
+    config: &mut DeviceConfig,
+    acquisition_time: i32,
+    search: &DelaySearch,
+    write_offsets: bool,
+) -> Result<Vec<ChannelDelay>, HydraHarpError> {
+    let delays = measure_delays(d, acquisition_time, search)?;
+    if write_offsets {
+        correct_offsets(config, &delays)?;
+        config.apply_offsets(d)?;
+    }
+    Ok(delays)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{correct_offsets, find_peak, ChannelDelay};
+    use crate::config::DeviceConfig;
+    use crate::correlation::CrossCorrelator;
+
+    #[test]
+    fn out_of_range_offset_changes_nothing() {
+        let mut config = DeviceConfig::new(2);
+        let delay = |channel, delay| ChannelDelay {
+            channel,
+            delay,
+            jitter: 0.0,
+            peak_counts: 1,
+        };
+        let delays = [delay(1, 1000.0), delay(2, 1e9)];
+        assert!(correct_offsets(&mut config, &delays).is_err());
+        assert_eq!(config, DeviceConfig::new(2));
+        correct_offsets(&mut config, &delays[..1]).unwrap();
+        assert_eq!(config.channel_offset(1), Some(-1000));
+    }
+
+    #[test]
+    fn peak_between_bins_is_interpolated() {
+        let mut c = CrossCorrelator::new(0, 1, 10, 0, 100).unwrap();
+        c.histogram = vec![0, 0, 0, 0, 2, 8, 4, 0, 0, 0];
+        let (position, _) = find_peak(&c).unwrap();
+        assert!(position > 55.0 && position < 60.0);
+    }
+}
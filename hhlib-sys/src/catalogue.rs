@@ -0,0 +1,606 @@
+//! A catalogue of measurement runs in a SQLite database, so that results outlive the program which
+//! measured them. Each run keeps the device and its settings, the singles and coincidences, any
+//! warnings and the path of its raw recording, which can be read again to redo an analysis.
+//! Runs can be searched by time, tag, note, device and any of the settings, eg.
+//!
+//! ```ignore
+//! let catalogue = Catalogue::open("runs.sqlite")?;
+//! let mut run = Run::from_recording("scan.bin", "HOM scan, 2nm filters")?;
+//! run.tags.push("hom".to_string());
+//! catalogue.add(&run)?;
+//!
+//! let mut query = RunQuery::default();
+//! query.tag = Some("hom".to_string());
+//! query.parameters.push(("sync_divider".to_string(), 2.into()));
+//! let runs = catalogue.search(&query)?;
+//! ```
+use std::path::Path;
+
+use pyo3::prelude::*;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, Row};
+
+use crate::coincidence::CoincidenceCounter;
+use crate::config::DeviceConfig;
+use crate::raw::{unix_time, RawFile};
+use crate::replay::{FileReplay, Pacing};
+use crate::types::{HydraHarpError, MeasurementMode};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        time REAL NOT NULL,
+        note TEXT NOT NULL,
+        serial TEXT NOT NULL,
+        model TEXT NOT NULL DEFAULT '',
+        part_number TEXT NOT NULL DEFAULT '',
+        hardware_version TEXT NOT NULL DEFAULT '',
+        resolution REAL NOT NULL,
+        config TEXT,
+        duration INTEGER NOT NULL,
+        singles TEXT NOT NULL,
+        coincidence_window INTEGER,
+        coincidences TEXT NOT NULL,
+        flags INTEGER NOT NULL,
+        warnings INTEGER NOT NULL,
+        raw_file TEXT
+    );
+    CREATE INDEX IF NOT EXISTS runs_time ON runs (time);
+    CREATE TABLE IF NOT EXISTS tags (
+        run INTEGER NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (run, tag)
+    );
+    CREATE INDEX IF NOT EXISTS tags_tag ON tags (tag);
+";
+
+const COLUMNS: &str = "id, time, note, serial, model, part_number, hardware_version, resolution, \
+                       config, duration, singles, coincidence_window, coincidences, flags, \
+                       warnings, raw_file";
+
+/// The columns added to `runs` since it was first made, which older catalogues won't have yet
+const ADDED_COLUMNS: [&str; 3] = ["model", "part_number", "hardware_version"];
+
+fn database_error(_: rusqlite::Error) -> HydraHarpError {
+    HydraHarpError::FileError
+}
+
+/// One acquisition in the catalogue
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    /// The id of the run in the catalogue, `None` until it's added
+    #[prop(get)]
+    pub id: Option<i64>,
+    /// Wall clock time the run started at, in seconds since the unix epoch
+    #[prop(get)]
+    pub time: f64,
+    #[prop(get, set)]
+    pub note: String,
+    #[prop(get, set)]
+    pub tags: Vec<String>,
+    #[prop(get)]
+    pub serial: String,
+    /// The model, part number and version of the hardware, empty if unknown
+    #[prop(get)]
+    pub model: String,
+    #[prop(get)]
+    pub part_number: String,
+    #[prop(get)]
+    pub hardware_version: String,
+    /// Resolution of the times in ps
+    #[prop(get)]
+    pub resolution: f64,
+    /// The settings the device was measuring with, if known
+    pub config: Option<DeviceConfig>,
+    /// Length of the run in ps
+    #[prop(get)]
+    pub duration: u64,
+    /// The counts of each channel, where the sync is channel 0
+    #[prop(get)]
+    pub singles: Vec<u64>,
+    /// Coincidence window in ps, `None` if no coincidences were counted
+    #[prop(get)]
+    pub coincidence_window: Option<u64>,
+    /// The coincidences between each pair of channels
+    #[prop(get)]
+    pub coincidences: Vec<Vec<u64>>,
+    /// The `FLAG_*` flags seen during the run
+    #[prop(get)]
+    pub flags: i32,
+    /// The warnings seen during the run
+    #[prop(get)]
+    pub warnings: i32,
+    /// The path of the raw recording of the run, if it was recorded
+    #[prop(get)]
+    pub raw_file: Option<String>,
+}
+
+impl Run {
+    /// A run starting now with nothing measured yet
+    pub fn new(note: &str) -> Run {
+        Run {
+            id: None,
+            time: unix_time(),
+            note: note.to_string(),
+            tags: Vec::new(),
+            serial: String::new(),
+            model: String::new(),
+            part_number: String::new(),
+            hardware_version: String::new(),
+            resolution: 1.0,
+            config: None,
+            duration: 0,
+            singles: Vec::new(),
+            coincidence_window: None,
+            coincidences: Vec::new(),
+            flags: 0,
+            warnings: 0,
+            raw_file: None,
+        }
+    }
+
+    /// A run describing the raw recording at `path`, with the device, settings, flags and warnings
+    /// from its sidecar and the singles counted from its records.
+    /// Returns `FileError` if it can't be read and `InvalidArgument` if the sidecar isn't valid
+    pub fn from_recording<P: AsRef<Path>>(path: P, note: &str) -> Result<Run, HydraHarpError> {
+        let file = RawFile::open(path.as_ref())?;
+        let metadata = &file.metadata;
+        let mut run = Run::new(note);
+        if let Some(first) = metadata.measurements.first() {
+            run.time = first.start_time;
+        }
+        run.serial = metadata.serial.clone();
+        run.model = metadata.model.clone();
+        run.part_number = metadata.part_number.clone();
+        run.hardware_version = metadata.hardware_version.clone();
+        run.resolution = metadata.resolution;
+        run.config = Some(metadata.config.clone());
+        run.duration = file.duration();
+        run.flags = metadata.measurements.iter().fold(0, |f, m| f | m.flags);
+        run.warnings = metadata.measurements.iter().fold(0, |w, m| w | m.warnings);
+        run.raw_file = Some(path.as_ref().to_string_lossy().into_owned());
+
+        let singles = &mut run.singles;
+        let mut count = |channel: u8| {
+            if singles.len() <= channel as usize {
+                singles.resize(channel as usize + 1, 0);
+            }
+            singles[channel as usize] += 1;
+        };
+        match metadata.record_type.mode() {
+            MeasurementMode::T2 => file.read_T2(file.measurement(&[]), |times| {
+                times.iter().for_each(|&(c, _)| count(c))
+            })?,
+            _ => {
+                for i in 0..metadata.measurements.len() {
+                    file.read_T3(i, |times| times.iter().for_each(|&(c, _, _)| count(c)))?;
+                }
+            }
+        }
+        Ok(run)
+    }
+
+    /// Take the singles, coincidences and duration from a coincidence counter
+    pub fn set_coincidences(&mut self, counter: &CoincidenceCounter) {
+        self.singles = counter.singles.clone();
+        self.coincidence_window = Some(counter.window);
+        self.coincidences = counter.coincidences.clone();
+        self.duration = counter.duration;
+    }
+
+    /// Open the raw recording of the run, to analyse it again.
+    /// Returns `InvalidArgument` if the run wasn't recorded
+    pub fn open_recording(&self) -> Result<RawFile, HydraHarpError> {
+        RawFile::open(self.recording()?)
+    }
+
+    /// Replay measurement `index` of the raw recording of the run through `Measureable`, so that
+    /// any live analysis can be run on it again.
+    /// Returns `InvalidArgument` if the run wasn't recorded
+    pub fn replay(&self, index: usize, pacing: Pacing) -> Result<FileReplay, HydraHarpError> {
+        FileReplay::open_recording(self.recording()?, index, pacing)
+    }
+
+    fn recording(&self) -> Result<&str, HydraHarpError> {
+        self.raw_file
+            .as_ref()
+            .map(|p| p.as_str())
+            .ok_or(HydraHarpError::InvalidArgument)
+    }
+}
+
+/// The runs to find in a search. Every condition which is set has to match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunQuery {
+    /// Runs which started at or after this time, in seconds since the unix epoch
+    pub since: Option<f64>,
+    /// Runs which started before this time, in seconds since the unix epoch
+    pub until: Option<f64>,
+    /// Runs with this tag
+    pub tag: Option<String>,
+    /// Runs with this text in their note
+    pub text: Option<String>,
+    /// Runs measured with the device with this serial number
+    pub serial: Option<String>,
+    /// Runs whose settings have these values, where each setting is a path into the JSON of
+    /// `DeviceConfig` such as `sync_divider` or `inputs[0].offset`. Numbers, strings and booleans
+    /// can be matched
+    pub parameters: Vec<(String, serde_json::Value)>,
+}
+
+/// A catalogue of runs, kept in a SQLite database
+pub struct Catalogue {
+    connection: Connection,
+}
+
+impl Catalogue {
+    /// Open the catalogue at `path`, creating it if it doesn't exist.
+    /// Returns `FileError` if it can't be opened
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Catalogue, HydraHarpError> {
+        Catalogue::new(Connection::open(path).map_err(database_error)?)
+    }
+
+    /// A catalogue kept in memory, which is lost when it's dropped
+    pub fn in_memory() -> Result<Catalogue, HydraHarpError> {
+        Catalogue::new(Connection::open_in_memory().map_err(database_error)?)
+    }
+
+    fn new(connection: Connection) -> Result<Catalogue, HydraHarpError> {
+        connection.execute_batch(SCHEMA).map_err(database_error)?;
+        let columns = connection
+            .prepare("SELECT name FROM pragma_table_info('runs')")
+            .map_err(database_error)?
+            .query_map(params![], |r| r.get(0))
+            .map_err(database_error)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(database_error)?;
+        for column in ADDED_COLUMNS
+            .iter()
+            .filter(|c| !columns.iter().any(|n| n == *c))
+        {
+            connection
+                .execute_batch(&format!(
+                    "ALTER TABLE runs ADD COLUMN {} TEXT NOT NULL DEFAULT ''",
+                    column
+                ))
+                .map_err(database_error)?;
+        }
+        Ok(Catalogue { connection })
+    }
+
+    /// Add a run to the catalogue with its tags, returning its id. The id of `run` itself is ignored.
+    /// Returns `FileError` if it can't be written, in which case nothing is added
+    pub fn add(&mut self, run: &Run) -> Result<i64, HydraHarpError> {
+        let config = match run.config.as_ref() {
+            Some(c) => Some(serde_json::to_string(c).map_err(|_| HydraHarpError::FileError)?),
+            None => None,
+        };
+        let singles = serde_json::to_string(&run.singles).map_err(|_| HydraHarpError::FileError)?;
+        let coincidences =
+            serde_json::to_string(&run.coincidences).map_err(|_| HydraHarpError::FileError)?;
+        let transaction = self.connection.transaction().map_err(database_error)?;
+        transaction
+            .execute(
+                &format!(
+                    "INSERT INTO runs ({}) VALUES \
+                     (NULL, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+                    COLUMNS
+                ),
+                params![
+                    run.time,
+                    run.note,
+                    run.serial,
+                    run.model,
+                    run.part_number,
+                    run.hardware_version,
+                    run.resolution,
+                    config,
+                    run.duration as i64,
+                    singles,
+                    run.coincidence_window.map(|w| w as i64),
+                    coincidences,
+                    run.flags,
+                    run.warnings,
+                    run.raw_file,
+                ],
+            )
+            .map_err(database_error)?;
+        let id = transaction.last_insert_rowid();
+        for tag in run.tags.iter() {
+            transaction
+                .execute(
+                    "INSERT OR IGNORE INTO tags (run, tag) VALUES (?1, ?2)",
+                    params![id, tag],
+                )
+                .map_err(database_error)?;
+        }
+        transaction.commit().map_err(database_error)?;
+        Ok(id)
+    }
+
+    /// The run with id `id`.
+    /// Returns `InvalidArgument` if there's no such run
+    pub fn get(&self, id: i64) -> Result<Run, HydraHarpError> {
+        let mut query = self
+            .connection
+            .prepare(&format!("SELECT {} FROM runs WHERE id = ?1", COLUMNS))
+            .map_err(database_error)?;
+        let mut rows = query.query(params![id]).map_err(database_error)?;
+        match rows.next().map_err(database_error)? {
+            Some(row) => self.run_from_row(row),
+            None => Err(HydraHarpError::InvalidArgument),
+        }
+    }
+
+    /// The runs matching `query`, oldest first
+    pub fn search(&self, query: &RunQuery) -> Result<Vec<Run>, HydraHarpError> {
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        if let Some(since) = query.since {
+            conditions.push("time >= ?");
+            values.push(Value::Real(since));
+        }
+        if let Some(until) = query.until {
+            conditions.push("time < ?");
+            values.push(Value::Real(until));
+        }
+        if let Some(tag) = query.tag.as_ref() {
+            conditions.push("id IN (SELECT run FROM tags WHERE tag = ?)");
+            values.push(Value::Text(tag.clone()));
+        }
+        if let Some(text) = query.text.as_ref() {
+            conditions.push("instr(note, ?) > 0");
+            values.push(Value::Text(text.clone()));
+        }
+        if let Some(serial) = query.serial.as_ref() {
+            conditions.push("serial = ?");
+            values.push(Value::Text(serial.clone()));
+        }
+        for (path, value) in query.parameters.iter() {
+            conditions.push("json_extract(config, ?) = ?");
+            values.push(Value::Text(format!("$.{}", path)));
+            values.push(match value {
+                serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+                serde_json::Value::Number(n) => Value::Real(n.as_f64().unwrap_or(std::f64::NAN)),
+                serde_json::Value::String(s) => Value::Text(s.clone()),
+                _ => return Err(HydraHarpError::InvalidArgument),
+            });
+        }
+        let mut sql = format!("SELECT {} FROM runs", COLUMNS);
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY time, id");
+        let mut statement = self.connection.prepare(&sql).map_err(database_error)?;
+        let mut rows = statement
+            .query(params_from_iter(values.iter()))
+            .map_err(database_error)?;
+        let mut runs = Vec::new();
+        while let Some(row) = rows.next().map_err(database_error)? {
+            runs.push(self.run_from_row(row)?);
+        }
+        Ok(runs)
+    }
+
+    /// Tag a run. Tagging a run twice with the same tag does nothing
+    pub fn tag(&self, id: i64, tag: &str) -> Result<(), HydraHarpError> {
+        self.connection
+            .execute(
+                "INSERT OR IGNORE INTO tags (run, tag) VALUES (?1, ?2)",
+                params![id, tag],
+            )
+            .map(|_| ())
+            .map_err(database_error)
+    }
+
+    /// Remove a tag from a run
+    pub fn untag(&self, id: i64, tag: &str) -> Result<(), HydraHarpError> {
+        self.connection
+            .execute(
+                "DELETE FROM tags WHERE run = ?1 AND tag = ?2",
+                params![id, tag],
+            )
+            .map(|_| ())
+            .map_err(database_error)
+    }
+
+    /// Replace the note of a run.
+    /// Returns `InvalidArgument` if there's no such run
+    pub fn set_note(&self, id: i64, note: &str) -> Result<(), HydraHarpError> {
+        match self
+            .connection
+            .execute("UPDATE runs SET note = ?1 WHERE id = ?2", params![note, id])
+            .map_err(database_error)?
+        {
+            0 => Err(HydraHarpError::InvalidArgument),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove a run and its tags from the catalogue. Its raw recording is left alone
+    pub fn remove(&mut self, id: i64) -> Result<(), HydraHarpError> {
+        let transaction = self.connection.transaction().map_err(database_error)?;
+        transaction
+            .execute("DELETE FROM tags WHERE run = ?1", params![id])
+            .map_err(database_error)?;
+        transaction
+            .execute("DELETE FROM runs WHERE id = ?1", params![id])
+            .map_err(database_error)?;
+        transaction.commit().map_err(database_error)
+    }
+
+    fn run_from_row(&self, row: &Row) -> Result<Run, HydraHarpError> {
+        let id: i64 = row.get(0).map_err(database_error)?;
+        let config: Option<String> = row.get(8).map_err(database_error)?;
+        let singles: String = row.get(10).map_err(database_error)?;
+        let coincidences: String = row.get(12).map_err(database_error)?;
+        let duration: i64 = row.get(9).map_err(database_error)?;
+        let window: Option<i64> = row.get(11).map_err(database_error)?;
+        let mut tags = self
+            .connection
+            .prepare("SELECT tag FROM tags WHERE run = ?1 ORDER BY tag")
+            .map_err(database_error)?;
+        let tags = tags
+            .query_map(params![id], |r| r.get(0))
+            .map_err(database_error)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(database_error)?;
+        let invalid = |_| HydraHarpError::InvalidArgument;
+        Ok(Run {
+            id: Some(id),
+            time: row.get(1).map_err(database_error)?,
+            note: row.get(2).map_err(database_error)?,
+            tags,
+            serial: row.get(3).map_err(database_error)?,
+            model: row.get(4).map_err(database_error)?,
+            part_number: row.get(5).map_err(database_error)?,
+            hardware_version: row.get(6).map_err(database_error)?,
+            resolution: row.get(7).map_err(database_error)?,
+            config: match config {
+                Some(c) => Some(serde_json::from_str(&c).map_err(invalid)?),
+                None => None,
+            },
+            duration: duration as u64,
+            singles: serde_json::from_str(&singles).map_err(invalid)?,
+            coincidence_window: window.map(|w| w as u64),
+            coincidences: serde_json::from_str(&coincidences).map_err(invalid)?,
+            flags: row.get(13).map_err(database_error)?,
+            warnings: row.get(14).map_err(database_error)?,
+            raw_file: row.get(15).map_err(database_error)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Catalogue, Run, RunQuery};
+    use crate::config::DeviceConfig;
+    use crate::ptu::RecordType;
+    use crate::raw::{record_raw, sidecar_path, RawMetadata, RawRecorder};
+    use crate::simulator::PhotonSimulator;
+    use crate::types::HydraHarpError;
+    use rusqlite::Connection;
+
+    #[test]
+    fn runs_can_be_found_again() {
+        let mut catalogue = Catalogue::in_memory().unwrap();
+        let mut first = Run::new("alignment");
+        first.time = 1000.0;
+        first.tags = vec!["hom".to_string(), "pump 10mW".to_string()];
+        first.config = Some(DeviceConfig::new(4));
+        first.singles = vec![10, 20];
+        first.coincidence_window = Some(1000);
+        first.coincidences = vec![vec![0, 5], vec![5, 0]];
+        let mut second = Run::new("overnight scan");
+        second.time = 2000.0;
+        second.tags = vec!["hom".to_string()];
+        let mut config = DeviceConfig::new(4);
+        config.sync_divider = 2;
+        config.inputs[1].offset = -300;
+        second.config = Some(config);
+
+        let first_id = catalogue.add(&first).unwrap();
+        let second_id = catalogue.add(&second).unwrap();
+        first.id = Some(first_id);
+        first.tags.sort();
+        assert_eq!(catalogue.get(first_id).unwrap(), first);
+        assert_eq!(catalogue.get(100), Err(HydraHarpError::InvalidArgument));
+
+        let ids = |catalogue: &Catalogue, query: &RunQuery| {
+            catalogue
+                .search(query)
+                .unwrap()
+                .iter()
+                .map(|r| r.id.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let mut query = RunQuery::default();
+        query.tag = Some("hom".to_string());
+        assert_eq!(ids(&catalogue, &query), vec![first_id, second_id]);
+        query.since = Some(1500.0);
+        assert_eq!(ids(&catalogue, &query), vec![second_id]);
+
+        let mut query = RunQuery::default();
+        query
+            .parameters
+            .push(("sync_divider".to_string(), 2.into()));
+        query
+            .parameters
+            .push(("inputs[1].offset".to_string(), (-300).into()));
+        query.parameters.push(("mode".to_string(), "T2".into()));
+        assert_eq!(ids(&catalogue, &query), vec![second_id]);
+
+        catalogue.untag(first_id, "hom").unwrap();
+        catalogue
+            .set_note(second_id, "overnight scan, 2nm filters")
+            .unwrap();
+        let mut query = RunQuery::default();
+        query.tag = Some("hom".to_string());
+        query.text = Some("2nm".to_string());
+        assert_eq!(ids(&catalogue, &query), vec![second_id]);
+        catalogue.remove(second_id).unwrap();
+        assert_eq!(ids(&catalogue, &query), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn runs_keep_the_hardware_of_their_recording() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!(
+            "hhlib_sys_catalogue_test_{}.bin",
+            std::process::id()
+        ));
+        let database = dir.join(format!(
+            "hhlib_sys_catalogue_test_{}.sqlite",
+            std::process::id()
+        ));
+        let metadata = RawMetadata {
+            record_type: RecordType::HydraHarp2T2,
+            serial: "1234567".to_string(),
+            model: "HydraHarp 400".to_string(),
+            part_number: "930002".to_string(),
+            hardware_version: "2.0".to_string(),
+            resolution: 1.0,
+            sync_period: None,
+            config: DeviceConfig::new(2),
+            measurements: Vec::new(),
+        };
+        let mut simulator = PhotonSimulator::new(3);
+        simulator.singles.push((1, 1e5));
+        let mut recorder = RawRecorder::create(&path, metadata).unwrap();
+        record_raw(&mut simulator, 10, &mut recorder).unwrap();
+        drop(recorder);
+        let run = Run::from_recording(&path, "").unwrap();
+        assert_eq!(run.model, "HydraHarp 400");
+        assert_eq!(run.part_number, "930002");
+        assert_eq!(run.hardware_version, "2.0");
+
+        // A catalogue made before the hardware was kept gets the new columns when it's opened
+        let _ = std::fs::remove_file(&database);
+        Connection::open(&database)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE runs (
+                    id INTEGER PRIMARY KEY, time REAL NOT NULL, note TEXT NOT NULL,
+                    serial TEXT NOT NULL, resolution REAL NOT NULL, config TEXT,
+                    duration INTEGER NOT NULL, singles TEXT NOT NULL, coincidence_window INTEGER,
+                    coincidences TEXT NOT NULL, flags INTEGER NOT NULL, warnings INTEGER NOT NULL,
+                    raw_file TEXT
+                );
+                INSERT INTO runs VALUES (1, 1000.0, 'old', '1234567', 1.0, NULL, 0, '[]', NULL,
+                    '[]', 0, 0, NULL);",
+            )
+            .unwrap();
+        let mut catalogue = Catalogue::open(&database).unwrap();
+        assert_eq!(catalogue.get(1).unwrap().model, "");
+        let id = catalogue.add(&run).unwrap();
+        let mut expected = run.clone();
+        expected.id = Some(id);
+        assert_eq!(catalogue.get(id).unwrap(), expected);
+        drop(catalogue);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(sidecar_path(&path)).unwrap();
+        std::fs::remove_file(&database).unwrap();
+    }
+}
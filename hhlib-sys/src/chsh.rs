@@ -0,0 +1,176 @@
+//! CHSH Bell inequality evaluation from the coincidences of the four combinations of settings.
+//! Each side has a `+` and `-` outcome, eg. the two outputs of a polarising beamsplitter.
+use pyo3::prelude::*;
+
+use crate::coincidence::CoincidenceCounter;
+use crate::types::HydraHarpError;
+
+/// The channels of the detectors on the two outputs of each side
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChshChannels {
+    pub a_plus: u8,
+    pub a_minus: u8,
+    pub b_plus: u8,
+    pub b_minus: u8,
+}
+
+/// The coincidences between the outcomes at one combination of settings, in the order
+/// `++`, `+-`, `-+`, `--`, with their variances
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SettingCounts {
+    pub counts: [f64; 4],
+    pub variances: [f64; 4],
+}
+
+impl SettingCounts {
+    /// Poissonian counts, for when the outcomes were counted some other way, eg. by measuring the
+    /// orthogonal settings separately with one detector on each side
+    pub fn new(
+        plus_plus: u64,
+        plus_minus: u64,
+        minus_plus: u64,
+        minus_minus: u64,
+    ) -> SettingCounts {
+        let counts = [
+            plus_plus as f64,
+            plus_minus as f64,
+            minus_plus as f64,
+            minus_minus as f64,
+        ];
+        SettingCounts {
+            counts,
+            variances: counts,
+        }
+    }
+
+    /// The counts from a coincidence counter, optionally with the accidentals subtracted.
+    /// Returns `InvalidArgument` if the counter doesn't have the channels
+    pub fn from_counter(
+        counter: &CoincidenceCounter,
+        channels: ChshChannels,
+        subtract_accidentals: bool,
+    ) -> Result<SettingCounts, HydraHarpError> {
+        let pair = |a: u8, b: u8| {
+            let p = counter.pair(a, b)?;
+            Ok(if subtract_accidentals {
+                (p.subtracted, p.subtracted_error.powi(2))
+            } else {
+                (p.coincidences as f64, p.coincidences as f64)
+            })
+        };
+        let outcomes = [
+            pair(channels.a_plus, channels.b_plus)?,
+            pair(channels.a_plus, channels.b_minus)?,
+            pair(channels.a_minus, channels.b_plus)?,
+            pair(channels.a_minus, channels.b_minus)?,
+        ];
+        Ok(SettingCounts {
+            counts: [outcomes[0].0, outcomes[1].0, outcomes[2].0, outcomes[3].0],
+            variances: [outcomes[0].1, outcomes[1].1, outcomes[2].1, outcomes[3].1],
+        })
+    }
+
+    /// Add the counts of another measurement at the same settings
+    pub fn add(&mut self, other: &SettingCounts) {
+        for i in 0..4 {
+            self.counts[i] += other.counts[i];
+            self.variances[i] += other.variances[i];
+        }
+    }
+
+    /// The correlation `E = (N++ + N-- - N+- - N-+) / N` and its error, or `None` if there are no counts
+    pub fn correlation(&self) -> Option<(f64, f64)> {
+        let [pp, pm, mp, mm] = self.counts;
+        let same = pp + mm;
+        let different = pm + mp;
+        let total = same + different;
+        if total <= 0.0 {
+            return None;
+        }
+        let e = (same - different) / total;
+        let d_same = 2.0 * different / (total * total);
+        let d_different = 2.0 * same / (total * total);
+        let [vpp, vpm, vmp, vmm] = self.variances;
+        let variance = d_same * d_same * (vpp + vmm) + d_different * d_different * (vpm + vmp);
+        Some((e, variance.sqrt()))
+    }
+}
+
+/// The result of a CHSH measurement
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChshResult {
+    /// `E` of the settings `(a, b)`, `(a, b')`, `(a', b)`, `(a', b')`
+    #[prop(get)]
+    pub correlations: Vec<f64>,
+    #[prop(get)]
+    pub correlation_errors: Vec<f64>,
+    /// `S = E(a, b) - E(a, b') + E(a', b) + E(a', b')`
+    #[prop(get)]
+    pub s: f64,
+    #[prop(get)]
+    pub s_error: f64,
+    /// `(|S| - 2) / s_error`, the number of standard deviations by which the inequality is violated
+    #[prop(get)]
+    pub violation: f64,
+}
+
+/// Evaluate the CHSH inequality from the counts of the settings `(a, b)`, `(a, b')`, `(a', b)`, `(a', b')`.
+/// Returns `InvalidArgument` if any setting has no counts
+pub fn chsh(settings: &[SettingCounts; 4]) -> Result<ChshResult, HydraHarpError> {
+    let (correlations, correlation_errors): (Vec<f64>, Vec<f64>) = settings
+        .iter()
+        .map(|s| s.correlation().ok_or(HydraHarpError::InvalidArgument))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .unzip();
+    let s = correlations[0] - correlations[1] + correlations[2] + correlations[3];
+    let s_error = correlation_errors.iter().map(|e| e * e).sum::<f64>().sqrt();
+    Ok(ChshResult {
+        violation: (s.abs() - 2.0) / s_error,
+        correlations,
+        correlation_errors,
+        s,
+        s_error,
+    })
+}
+
+/// Evaluate the CHSH inequality from the segments of a single run, where `markers` are the markers
+/// which started the segments of the settings `(a, b)`, `(a, b')`, `(a', b)`, `(a', b')`.
+/// The segments of each setting are added together
+pub fn chsh_from_segments(
+    segments: &[(u8, CoincidenceCounter)],
+    markers: [u8; 4],
+    channels: ChshChannels,
+    subtract_accidentals: bool,
+) -> Result<ChshResult, HydraHarpError> {
+    let mut settings = [SettingCounts::new(0, 0, 0, 0); 4];
+    for (marker, counter) in segments.iter() {
+        if let Some(i) = markers.iter().position(|m| m == marker) {
+            settings[i].add(&SettingCounts::from_counter(
+                counter,
+                channels,
+                subtract_accidentals,
+            )?);
+        }
+    }
+    chsh(&settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chsh, SettingCounts};
+
+    #[test]
+    fn maximal_violation() {
+        let setting = |e: f64| {
+            let same = (250.0 * (1.0 + e)).round() as u64;
+            let different = (250.0 * (1.0 - e)).round() as u64;
+            SettingCounts::new(same, different, different, same)
+        };
+        let r = std::f64::consts::FRAC_1_SQRT_2;
+        let result = chsh(&[setting(r), setting(-r), setting(r), setting(r)]).unwrap();
+        assert!((result.s - 2.0 * 2f64.sqrt()).abs() < 0.01);
+        assert!(result.violation > 10.0);
+    }
+}
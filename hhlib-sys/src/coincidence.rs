@@ -0,0 +1,335 @@
+//! Counting two-way coincidences between every pair of channels, along with the accidental
+//! coincidences expected from the singles and (optionally) measured in side windows.
+use pyo3::prelude::*;
+use std::collections::VecDeque;
+
+use crate::measurement::{run_measurement_with_delays_T2, Measureable, Measurement};
+use crate::types::HydraHarpError;
+
+/// The coincidences between a pair of channels, and the figures derived from them.
+/// All the errors are one standard deviation, assuming Poissonian counts.
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairStatistics {
+    #[prop(get)]
+    pub channel_a: u8,
+    #[prop(get)]
+    pub channel_b: u8,
+    #[prop(get)]
+    pub coincidences: u64,
+    #[prop(get)]
+    pub coincidences_error: f64,
+    /// Accidentals expected from the singles rates, `N_a * N_b * window_width / duration`
+    #[prop(get)]
+    pub accidentals: f64,
+    #[prop(get)]
+    pub accidentals_error: f64,
+    /// Accidentals counted in the side windows, averaged over the windows. `None` if not measured
+    #[prop(get)]
+    pub side_window_accidentals: Option<f64>,
+    #[prop(get)]
+    pub side_window_accidentals_error: Option<f64>,
+    /// Coincidences with the accidentals subtracted. Uses the side windows if they were measured
+    #[prop(get)]
+    pub subtracted: f64,
+    #[prop(get)]
+    pub subtracted_error: f64,
+    /// Coincidence to accidental ratio. Uses the side windows if they were measured
+    #[prop(get)]
+    pub car: f64,
+    #[prop(get)]
+    pub car_error: f64,
+}
+
+impl PairStatistics {
+    /// Work out the statistics for a pair of channels.
+    /// `window_width` is the full width of the coincidence window and `duration` the acquisition time, both in ps.
+    /// `side_windows` is the total count in the side windows and the number of side windows, if they were measured
+    pub fn new(
+        channels: (u8, u8),
+        coincidences: u64,
+        singles: (u64, u64),
+        window_width: u64,
+        duration: u64,
+        side_windows: Option<(u64, u64)>,
+    ) -> PairStatistics {
+        let n = coincidences as f64;
+        let (n_a, n_b) = (singles.0 as f64, singles.1 as f64);
+        let accidentals = if duration > 0 {
+            n_a * n_b * window_width as f64 / duration as f64
+        } else {
+            0.0
+        };
+        let accidentals_error = if n_a > 0.0 && n_b > 0.0 {
+            accidentals * (1.0 / n_a + 1.0 / n_b).sqrt()
+        } else {
+            0.0
+        };
+        let side = side_windows
+            .filter(|&(_, windows)| windows > 0)
+            .map(|(count, windows)| {
+                (
+                    count as f64 / windows as f64,
+                    (count as f64).sqrt() / windows as f64,
+                )
+            });
+        let (background, background_error) = side.unwrap_or((accidentals, accidentals_error));
+        let (car, car_error) = if background > 0.0 && n > 0.0 {
+            let car = n / background;
+            (
+                car,
+                car * (1.0 / n + (background_error / background).powi(2)).sqrt(),
+            )
+        } else {
+            (0.0, 0.0)
+        };
+        PairStatistics {
+            channel_a: channels.0,
+            channel_b: channels.1,
+            coincidences,
+            coincidences_error: n.sqrt(),
+            accidentals,
+            accidentals_error,
+            side_window_accidentals: side.map(|(s, _)| s),
+            side_window_accidentals_error: side.map(|(_, e)| e),
+            subtracted: n - background,
+            subtracted_error: (n + background_error * background_error).sqrt(),
+            car,
+            car_error,
+        }
+    }
+}
+
+/// Counts singles and two-way coincidences between every pair of channels in a stream of T2 times,
+/// keeping track of events across chunks and measurements.
+/// Two events on channels `a < b` are coincident if `early < t_b - t_a < late`, where the
+/// window `(early, late)` can be set for each pair and defaults to `(-window, window)`.
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoincidenceCounter {
+    /// The number of channels, including the sync at channel 0
+    pub channels: usize,
+    /// Half width of the default coincidence window in ps
+    pub window: u64,
+    /// `windows[a][b]` is the `(early, late)` limits of the window between channels `a < b` in ps
+    pub windows: Vec<Vec<(i64, i64)>>,
+    /// Software delays added to each channel in ps when measuring, see `Measurement::with_delays`
+    pub delays: Vec<i64>,
+    /// If set, also count coincidences in windows at this delay either side of zero in ps,
+    /// to measure the accidentals directly
+    pub side_window_offset: Option<u64>,
+    pub singles: Vec<u64>,
+    /// `coincidences[a][b]` is the number of coincidences between channels `a < b`
+    pub coincidences: Vec<Vec<u64>>,
+    /// `side_coincidences[a][b]` is the total in both side windows between channels `a < b`
+    pub side_coincidences: Vec<Vec<u64>>,
+    /// Total acquisition time in ps
+    pub duration: u64,
+    buffer: VecDeque<(u8, u64)>,
+}
+
+impl CoincidenceCounter {
+    pub fn new(
+        channels: usize,
+        window: u64,
+        side_window_offset: Option<u64>,
+    ) -> CoincidenceCounter {
+        CoincidenceCounter {
+            channels,
+            window,
+            windows: vec![vec![(-(window as i64), window as i64); channels]; channels],
+            delays: Vec::new(),
+            side_window_offset,
+            singles: vec![0; channels],
+            coincidences: vec![vec![0; channels]; channels],
+            side_coincidences: vec![vec![0; channels]; channels],
+            duration: 0,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Set the `(early, late)` limits of the window for the pair of channels `a < b` in ps.
+    /// Returns `InvalidArgument` if there's no such pair or the window is empty
+    pub fn set_pair_window(
+        &mut self,
+        a: u8,
+        b: u8,
+        early: i64,
+        late: i64,
+    ) -> Result<(), HydraHarpError> {
+        let (a, b) = (a as usize, b as usize);
+        if a >= b || b >= self.channels || late <= early + 1 {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        self.windows[a][b] = (early, late);
+        Ok(())
+    }
+
+    /// The furthest apart two events can be and still land in a window
+    fn reach(&self) -> u64 {
+        let widest = self
+            .windows
+            .iter()
+            .flat_map(|w| w.iter())
+            .map(|&(early, late)| std::cmp::max(early.abs(), late.abs()))
+            .max()
+            .unwrap_or(0);
+        widest as u64 + self.side_window_offset.unwrap_or(0)
+    }
+
+    /// Add a chunk of channels and times.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last.
+    /// Times before the latest buffered one, eg. from corrupted records, are dropped
+    pub fn add_times(&mut self, times: &[(u8, u64)]) {
+        let offset = self.side_window_offset.map(|o| o as i64);
+        let reach = self.reach();
+        for &(channel, time) in times.iter() {
+            if channel as usize >= self.channels
+                || self.buffer.back().map_or(false, |&(_, t)| time < t)
+            {
+                continue;
+            }
+            self.singles[channel as usize] += 1;
+            while self
+                .buffer
+                .front()
+                .map_or(false, |&(_, t)| t + reach <= time)
+            {
+                self.buffer.pop_front();
+            }
+            for &(other, t) in self.buffer.iter() {
+                if other == channel {
+                    continue;
+                }
+                // The delay of the higher channel relative to the lower one
+                let (a, b, delay) = if other < channel {
+                    (other as usize, channel as usize, (time - t) as i64)
+                } else {
+                    (channel as usize, other as usize, -((time - t) as i64))
+                };
+                let (early, late) = self.windows[a][b];
+                let inside = |d: i64| d > early && d < late;
+                if inside(delay) {
+                    self.coincidences[a][b] += 1;
+                }
+                if let Some(o) = offset {
+                    if inside(delay - o) || inside(delay + o) {
+                        self.side_coincidences[a][b] += 1;
+                    }
+                }
+            }
+            self.buffer.push_back((channel, time));
+        }
+    }
+
+    /// Add `duration` ps of acquisition time and forget the buffered events.
+    /// Call this at the end of each measurement, as the times restart from zero in the next one
+    pub fn end_measurement(&mut self, duration: u64) {
+        self.duration += duration;
+        self.buffer.clear();
+    }
+
+    /// The statistics for the pair of channels `a` and `b`, in either order.
+    /// Returns `InvalidArgument` if they're the same channel or either is out of range
+    pub fn pair(&self, a: u8, b: u8) -> Result<PairStatistics, HydraHarpError> {
+        let (a, b) = (a.min(b) as usize, a.max(b) as usize);
+        if a == b || b >= self.channels {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        Ok(PairStatistics::new(
+            (a as u8, b as u8),
+            self.coincidences[a][b],
+            (self.singles[a], self.singles[b]),
+            (self.windows[a][b].1 - self.windows[a][b].0) as u64,
+            self.duration,
+            self.side_window_offset
+                .map(|_| (self.side_coincidences[a][b], 2)),
+        ))
+    }
+
+    /// The statistics for every pair of channels, in the order (0, 1), (0, 2), ... (1, 2), ...
+    pub fn results(&self) -> Vec<PairStatistics> {
+        let mut results = Vec::new();
+        for a in 0..self.channels {
+            for b in (a + 1)..self.channels {
+                results.extend(self.pair(a as u8, b as u8));
+            }
+        }
+        results
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, adding the counts to `counter`
+pub fn measure_coincidences<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    counter: &mut CoincidenceCounter,
+) -> Result<(), HydraHarpError> {
+    let delays = counter.delays.clone();
+    run_measurement_with_delays_T2(d, acquisition_time, &delays, |times| {
+        counter.add_times(times)
+    })?;
+    counter.end_measurement(acquisition_time as u64 * 1_000_000_000);
+    Ok(())
+}
+
+/// Add recorded T2 fifo records to `counter`, applying its software delays.
+/// `duration` is the length of the recording in ps
+pub fn add_coincidence_records(records: &[u32], duration: u64, counter: &mut CoincidenceCounter) {
+    let mut measurement = Measurement::with_delays(&counter.delays);
+    let channel_times = measurement.convert_sorted_T2(records);
+    counter.add_times(&channel_times);
+    counter.add_times(&measurement.flush_T2());
+    counter.end_measurement(duration);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoincidenceCounter, PairStatistics};
+    use crate::types::HydraHarpError;
+
+    #[test]
+    fn side_windows_replace_singles_estimate() {
+        let p = PairStatistics::new(
+            (0, 1),
+            100,
+            (10_000, 10_000),
+            2000,
+            1_000_000_000,
+            Some((8, 2)),
+        );
+        assert_eq!(p.accidentals, 200.0);
+        assert_eq!(p.side_window_accidentals, Some(4.0));
+        assert_eq!(p.subtracted, 96.0);
+        assert_eq!(p.car, 25.0);
+    }
+
+    #[test]
+    fn counts_pairs_either_way_round() {
+        let mut c = CoincidenceCounter::new(3, 100, Some(1000));
+        c.add_times(&[(2, 0), (1, 50), (1, 1020), (0, 5000), (2, 5010)]);
+        assert_eq!(c.coincidences[1][2], 1);
+        assert_eq!(c.coincidences[0][2], 1);
+        assert_eq!(c.side_coincidences[1][2], 1);
+        assert_eq!(c.singles, vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn asymmetric_pair_window() {
+        let mut c = CoincidenceCounter::new(3, 100, None);
+        c.set_pair_window(1, 2, 0, 500).unwrap();
+        c.add_times(&[(1, 0), (2, 300), (2, 1000), (1, 1050)]);
+        assert_eq!(c.coincidences[1][2], 1);
+        assert!(c.set_pair_window(2, 1, 0, 500).is_err());
+    }
+
+    #[test]
+    fn pairs_in_either_order() {
+        let mut c = CoincidenceCounter::new(3, 100, None);
+        c.add_times(&[(1, 0), (2, 50)]);
+        assert_eq!(c.pair(2, 1), c.pair(1, 2));
+        assert_eq!(c.pair(2, 1).unwrap().coincidences, 1);
+        assert_eq!(c.pair(1, 1), Err(HydraHarpError::InvalidArgument));
+        assert_eq!(c.pair(1, 3), Err(HydraHarpError::InvalidArgument));
+    }
+}
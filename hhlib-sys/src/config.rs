@@ -0,0 +1,133 @@
+//! The settings of a device, kept together so they can be applied in one go and saved alongside data
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::device::Device;
+use crate::ptu::file_error;
+use crate::types::{HydraHarpError, MeasurementMode, ReferenceSource};
+
+/// The settings of one input channel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputConfig {
+    pub enabled: bool,
+    /// CFD discriminator level in mV
+    pub cfd_level: i32,
+    /// CFD zero cross level in mV
+    pub cfd_zero_cross: i32,
+    /// Timing offset in ps
+    pub offset: i32,
+}
+
+/// The settings applied to a device before a measurement
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub mode: MeasurementMode,
+    pub reference_source: ReferenceSource,
+    pub sync_divider: i32,
+    /// Sync CFD discriminator level in mV
+    pub sync_cfd_level: i32,
+    /// Sync CFD zero cross level in mV
+    pub sync_cfd_zero_cross: i32,
+    /// Sync timing offset in ps
+    pub sync_offset: i32,
+    pub inputs: Vec<InputConfig>,
+}
+
+impl DeviceConfig {
+    /// A T2 configuration with the internal clock, no sync division, 50mV/10mV CFDs and no offsets
+    pub fn new(number_of_inputs: usize) -> DeviceConfig {
+        DeviceConfig {
+            mode: MeasurementMode::T2,
+            reference_source: ReferenceSource::Internal,
+            sync_divider: 1,
+            sync_cfd_level: 50,
+            sync_cfd_zero_cross: 10,
+            sync_offset: 0,
+            inputs: vec![
+                InputConfig {
+                    enabled: true,
+                    cfd_level: 50,
+                    cfd_zero_cross: 10,
+                    offset: 0,
+                };
+                number_of_inputs
+            ],
+        }
+    }
+
+    /// Read a configuration saved with `save`.
+    /// Returns `FileError` if it can't be read and `InvalidArgument` if it isn't valid
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DeviceConfig, HydraHarpError> {
+        let json = std::fs::read_to_string(path).map_err(file_error)?;
+        serde_json::from_str(&json).map_err(|_| HydraHarpError::InvalidArgument)
+    }
+
+    /// Write the configuration to `path` as JSON, replacing it in one step so that a crash
+    /// can't leave it half written
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), HydraHarpError> {
+        let path = path.as_ref();
+        let partial = path.with_extension("partial");
+        let json = serde_json::to_string_pretty(self).map_err(|_| HydraHarpError::FileError)?;
+        std::fs::write(&partial, json).map_err(file_error)?;
+        std::fs::rename(&partial, path).map_err(file_error)
+    }
+
+    /// Initialise and calibrate the device, then apply all of the settings
+    pub fn apply(&self, d: &mut Device) -> Result<(), HydraHarpError> {
+        d.initialise(self.mode, self.reference_source)?;
+        d.calibrate()?;
+        d.set_sync_divider(self.sync_divider)?;
+        d.set_sync_CFD(self.sync_cfd_level, self.sync_cfd_zero_cross)?;
+        for (i, input) in self.inputs.iter().enumerate() {
+            d.set_input_CFD(i as i32, input.cfd_level, input.cfd_zero_cross)?;
+            d.set_input_channel_enabled(i as i32, input.enabled)?;
+        }
+        self.apply_offsets(d)
+    }
+
+    /// Apply only the sync and input offsets, which can be changed without reinitialising
+    pub fn apply_offsets(&self, d: &mut Device) -> Result<(), HydraHarpError> {
+        d.set_sync_channel_offset(self.sync_offset)?;
+        for (i, input) in self.inputs.iter().enumerate() {
+            d.set_input_channel_offset(i as i32, input.offset)?;
+        }
+        Ok(())
+    }
+
+    /// The offset of a T2 channel, where the sync is channel 0 and input `i` is channel `i + 1`
+    pub fn channel_offset(&self, channel: u8) -> Option<i32> {
+        match channel {
+            0 => Some(self.sync_offset),
+            c => self.inputs.get(c as usize - 1).map(|i| i.offset),
+        }
+    }
+
+    /// Set the offset of a T2 channel, where the sync is channel 0 and input `i` is channel `i + 1`.
+    /// Returns `InvalidArgument` if there's no such channel
+    pub fn set_channel_offset(&mut self, channel: u8, offset: i32) -> Result<(), HydraHarpError> {
+        match channel {
+            0 => self.sync_offset = offset,
+            c => match self.inputs.get_mut(c as usize - 1) {
+                Some(input) => input.offset = offset,
+                None => return Err(HydraHarpError::InvalidArgument),
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceConfig;
+
+    #[test]
+    fn saved_config_loads() {
+        let path =
+            std::env::temp_dir().join(format!("hhlib_sys_config_{}.json", std::process::id()));
+        let mut config = DeviceConfig::new(4);
+        config.set_channel_offset(3, -1234).unwrap();
+        config.save(&path).unwrap();
+        assert_eq!(DeviceConfig::load(&path).unwrap(), config);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
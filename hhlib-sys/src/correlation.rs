@@ -0,0 +1,226 @@
+//! Cross-correlation histograms g2(τ) between two channels.
+//! Delays are measured as `t_b - t_a` in picoseconds and can be negative.
+use pyo3::prelude::*;
+use std::collections::VecDeque;
+
+use crate::measurement::{run_measurement_T2, Measureable};
+use crate::types::HydraHarpError;
+
+/// Accumulates a histogram of the delays `t_b - t_a` between events on `channel_a` and
+/// `channel_b` for delays in `tau_min..tau_max`.
+/// The channels use the same numbering as `Measurement::convert_values_T2`, so the sync is channel 0.
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossCorrelator {
+    pub channel_a: u8,
+    pub channel_b: u8,
+    /// Width of a histogram bin in ps
+    pub bin_width: u64,
+    /// Start of the delay range in ps (inclusive)
+    pub tau_min: i64,
+    /// End of the delay range in ps (exclusive)
+    pub tau_max: i64,
+    /// The number of pairs in each bin
+    pub histogram: Vec<u64>,
+    pub singles_a: u64,
+    pub singles_b: u64,
+    /// Total acquisition time in ps, used to normalise the histogram
+    pub duration: u64,
+    buffer_a: VecDeque<u64>,
+    buffer_b: VecDeque<u64>,
+}
+
+impl CrossCorrelator {
+    /// Create a new correlator. `tau_max - tau_min` is rounded up to a whole number of bins.
+    /// Returns `InvalidArgument` if the bin width is zero or the range is empty.
+    pub fn new(
+        channel_a: u8,
+        channel_b: u8,
+        bin_width: u64,
+        tau_min: i64,
+        tau_max: i64,
+    ) -> Result<CrossCorrelator, HydraHarpError> {
+        if bin_width == 0 || tau_max <= tau_min {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let span = (tau_max - tau_min) as u64;
+        let bins = ((span + bin_width - 1) / bin_width) as usize;
+        Ok(CrossCorrelator {
+            channel_a,
+            channel_b,
+            bin_width,
+            tau_min,
+            tau_max: tau_min + (bins as u64 * bin_width) as i64,
+            histogram: vec![0; bins],
+            singles_a: 0,
+            singles_b: 0,
+            duration: 0,
+            buffer_a: VecDeque::new(),
+            buffer_b: VecDeque::new(),
+        })
+    }
+
+    /// Create a correlator covering delays in `-tau..tau`
+    pub fn symmetric(
+        channel_a: u8,
+        channel_b: u8,
+        bin_width: u64,
+        tau: i64,
+    ) -> Result<CrossCorrelator, HydraHarpError> {
+        CrossCorrelator::new(channel_a, channel_b, bin_width, -tau, tau)
+    }
+
+    fn bin_index(&self, delay: i64) -> Option<usize> {
+        if delay < self.tau_min || delay >= self.tau_max {
+            None
+        } else {
+            Some(((delay - self.tau_min) as u64 / self.bin_width) as usize)
+        }
+    }
+
+    /// The longest delay, either side of zero, that can land in the histogram
+    fn reach(&self) -> u64 {
+        std::cmp::max(self.tau_min.abs(), self.tau_max.abs()) as u64
+    }
+
+    /// Add a chunk of channels and times to the histogram.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last
+    pub fn add_times(&mut self, times: &[(u8, u64)]) {
+        let reach = self.reach();
+        for &(channel, time) in times.iter() {
+            if channel != self.channel_a && channel != self.channel_b {
+                continue;
+            }
+            let oldest = time.saturating_sub(reach);
+            while self.buffer_a.front().map_or(false, |&t| t < oldest) {
+                self.buffer_a.pop_front();
+            }
+            while self.buffer_b.front().map_or(false, |&t| t < oldest) {
+                self.buffer_b.pop_front();
+            }
+
+            if channel == self.channel_b {
+                // Pair with earlier events on a, giving delays >= 0
+                for &t_a in self.buffer_a.iter() {
+                    if let Some(i) = self.bin_index(time as i64 - t_a as i64) {
+                        self.histogram[i] += 1;
+                    }
+                }
+            }
+            if channel == self.channel_a {
+                // Pair with earlier events on b, giving delays <= 0.
+                // In an autocorrelation the zero delay pair has already been counted above
+                for &t_b in self.buffer_b.iter() {
+                    if self.channel_a == self.channel_b && t_b == time {
+                        continue;
+                    }
+                    if let Some(i) = self.bin_index(t_b as i64 - time as i64) {
+                        self.histogram[i] += 1;
+                    }
+                }
+            }
+
+            if channel == self.channel_a {
+                self.singles_a += 1;
+                self.buffer_a.push_back(time);
+            }
+            if channel == self.channel_b {
+                self.singles_b += 1;
+                self.buffer_b.push_back(time);
+            }
+        }
+    }
+
+    /// Add `duration` ps of acquisition time and forget the buffered events.
+    /// Call this at the end of each measurement, as the times restart from zero in the next one
+    pub fn end_measurement(&mut self, duration: u64) {
+        self.duration += duration;
+        self.buffer_a.clear();
+        self.buffer_b.clear();
+    }
+
+    /// Add the counts from another correlator, eg. one run in parallel on another part of the data.
+    /// Returns `InvalidArgument` if the channels or binning don't match
+    pub fn merge(&mut self, other: &CrossCorrelator) -> Result<(), HydraHarpError> {
+        if (
+            self.channel_a,
+            self.channel_b,
+            self.bin_width,
+            self.tau_min,
+            self.tau_max,
+        ) != (
+            other.channel_a,
+            other.channel_b,
+            other.bin_width,
+            other.tau_min,
+            other.tau_max,
+        ) {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        for (h, o) in self.histogram.iter_mut().zip(other.histogram.iter()) {
+            *h += o;
+        }
+        self.singles_a += other.singles_a;
+        self.singles_b += other.singles_b;
+        self.duration += other.duration;
+        Ok(())
+    }
+
+    /// The delay at the centre of each bin in ps
+    pub fn tau(&self) -> Vec<f64> {
+        (0..self.histogram.len())
+            .map(|i| self.tau_min as f64 + (i as f64 + 0.5) * self.bin_width as f64)
+            .collect()
+    }
+
+    /// The histogram normalised by the rate of uncorrelated pairs, `N_a * N_b * bin_width / duration`,
+    /// so that uncorrelated sources give g2 = 1. Returns zeros if there's nothing to normalise by
+    pub fn g2(&self) -> Vec<f64> {
+        let expected = self.singles_a as f64 * self.singles_b as f64 * self.bin_width as f64
+            / self.duration as f64;
+        self.histogram
+            .iter()
+            .map(|&c| {
+                if expected > 0.0 {
+                    c as f64 / expected
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, adding the results to `correlator`
+pub fn measure_correlation<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    correlator: &mut CrossCorrelator,
+) -> Result<(), HydraHarpError> {
+    run_measurement_T2(d, acquisition_time, |times| correlator.add_times(times))?;
+    correlator.end_measurement(acquisition_time as u64 * 1_000_000_000);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CrossCorrelator;
+
+    #[test]
+    fn negative_and_positive_delays_are_binned() {
+        let mut c = CrossCorrelator::symmetric(1, 2, 100, 1000).unwrap();
+        c.add_times(&[(1, 5000), (2, 5250), (2, 9000), (1, 9420)]);
+        assert_eq!(c.histogram.len(), 20);
+        assert_eq!(c.histogram[12], 1);
+        assert_eq!(c.histogram[5], 1);
+        assert_eq!(c.histogram.iter().sum::<u64>(), 2);
+    }
+
+    #[test]
+    fn merge_needs_matching_binning() {
+        let mut a = CrossCorrelator::new(1, 2, 100, -500, 500).unwrap();
+        let b = CrossCorrelator::new(1, 2, 50, -500, 500).unwrap();
+        assert!(a.merge(&b).is_err());
+        assert!(a.merge(&a.clone()).is_ok());
+    }
+}
@@ -10,6 +10,15 @@ use crate::types::{
 };
 use crate::measurement::Measureable;
 
+/// A null terminated string written by the library
+fn c_string(chars: &[i8]) -> String {
+    chars
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8 as char)
+        .collect()
+}
+
 /// Contains the information of the device - the number it is (0 -> 7) and the serial of it.
 #[pyclass]
 #[derive(Debug, PartialEq)]
@@ -18,6 +27,8 @@ pub struct Device {
     pub serial: [i8; 8],
     /// the length of the histograms returned by get_histogram in u32
     pub histogram_length: Option<usize>,
+    /// Whether the device is still open. Once it's closed the id may belong to another device
+    open: bool,
 }
 
 impl Device {
@@ -26,12 +37,53 @@ impl Device {
         let mut serial = [0i8; 8];
         error_enum_or_value! {
             unsafe {HH_OpenDevice(id, serial.as_mut_ptr())},
-            Device {id, serial, histogram_length: None}
+            Device {id, serial, histogram_length: None, open: true}
+        }
+    }
+
+    /// Whether the device is still open
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// The id to pass to the library, or `DeviceNotOpen` if the device has been closed
+    fn open_id(&self) -> Result<i32, HydraHarpError> {
+        if self.open {
+            Ok(self.id)
+        } else {
+            Err(DeviceNotOpen)
         }
     }
 
-    /// Try to close this device
+    /// The serial number of the device as a string
+    pub fn serial_number(&self) -> String {
+        c_string(&self.serial)
+    }
+
+    /// Get the model, part number and version of the hardware
+    pub fn get_hardware_info(&self) -> Result<(String, String, String), HydraHarpError> {
+        let mut model = [0i8; 16];
+        let mut part_number = [0i8; 8];
+        let mut version = [0i8; 8];
+        error_enum_or_value! {
+            unsafe {
+                HH_GetHardwareInfo(
+                    self.open_id()?,
+                    model.as_mut_ptr(),
+                    part_number.as_mut_ptr(),
+                    version.as_mut_ptr()
+                )
+            },
+            (c_string(&model), c_string(&part_number), c_string(&version))
+        }
+    }
+
+    /// Try to close this device. Does nothing if it's already closed
     pub fn close_device(&mut self) -> Result<(), HydraHarpError> {
+        if !self.open {
+            return Ok(());
+        }
+        self.open = false;
         error_enum_or_value! {
             unsafe {
                 HH_CloseDevice(self.id)
@@ -48,7 +100,7 @@ impl Device {
     ) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_Initialize(self.id,
+                HH_Initialize(self.open_id()?,
                               num::ToPrimitive::to_i32(&mode).unwrap(),
                               num::ToPrimitive::to_i32(&ref_source).unwrap())
             },
@@ -64,7 +116,7 @@ impl Device {
         error_enum_or_value! {{
             unsafe {
                 HH_GetBaseResolution(
-                    self.id,
+                    self.open_id()?,
                     &mut res as *mut f64,
                     &mut bin as *mut i32
                 )
@@ -78,7 +130,7 @@ impl Device {
         let mut inputs = 0i32;
         error_enum_or_value! {
             unsafe {
-                HH_GetNumOfInputChannels(self.id, &mut inputs as *mut i32)
+                HH_GetNumOfInputChannels(self.open_id()?, &mut inputs as *mut i32)
             },
             inputs
         }
@@ -88,7 +140,7 @@ impl Device {
     pub fn calibrate(&mut self) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_Calibrate(self.id)
+                HH_Calibrate(self.open_id()?)
             },
             ()
         }
@@ -99,7 +151,7 @@ impl Device {
         error_enum_or_value! {
             unsafe {
                 HH_SetSyncDiv(
-                    self.id,
+                    self.open_id()?,
                     divisions
                 )
             },
@@ -113,7 +165,7 @@ impl Device {
     pub fn set_sync_CFD(&mut self, level: i32, zerox: i32) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetSyncCFD(self.id, level, zerox)
+                HH_SetSyncCFD(self.open_id()?, level, zerox)
             },
             ()
         }
@@ -124,7 +176,7 @@ impl Device {
     pub fn set_sync_channel_offset(&mut self, offset: i32) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetSyncChannelOffset(self.id, offset)
+                HH_SetSyncChannelOffset(self.open_id()?, offset)
             },
             ()
         }
@@ -139,7 +191,7 @@ impl Device {
     ) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetInputCFD(self.id, channel, level, zerox)
+                HH_SetInputCFD(self.open_id()?, channel, level, zerox)
             },
             ()
         }
@@ -153,7 +205,7 @@ impl Device {
     ) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetInputChannelOffset(self.id, channel, offset)
+                HH_SetInputChannelOffset(self.open_id()?, channel, offset)
             },
             ()
         }
@@ -167,7 +219,7 @@ impl Device {
     ) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetInputChannelEnable(self.id, channel, enabled as i32)
+                HH_SetInputChannelEnable(self.open_id()?, channel, enabled as i32)
             },
             ()
         }
@@ -182,7 +234,7 @@ impl Device {
     ) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetStopOverflow(self.id, stop_ofl as i32, stopcount)
+                HH_SetStopOverflow(self.open_id()?, stop_ofl as i32, stopcount)
             },
             ()
         }
@@ -195,7 +247,7 @@ impl Device {
     pub fn set_binning(&mut self, binning: i32) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetBinning(self.id, binning)
+                HH_SetBinning(self.open_id()?, binning)
             },
             ()
         }
@@ -205,7 +257,7 @@ impl Device {
     pub fn set_offset(&mut self, offset: i32) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetOffset(self.id, offset)
+                HH_SetOffset(self.open_id()?, offset)
             },
             ()
         }
@@ -216,7 +268,7 @@ impl Device {
         let mut actual_length: i32 = 0;
         let return_val = error_enum_or_value! {
             unsafe {
-                HH_SetHistoLen(self.id, length, &mut actual_length as *mut i32)
+                HH_SetHistoLen(self.open_id()?, length, &mut actual_length as *mut i32)
             },
             actual_length
         };
@@ -230,7 +282,7 @@ impl Device {
     pub fn clear_histogram_memory(&mut self) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_ClearHistMem(self.id)
+                HH_ClearHistMem(self.open_id()?)
             },
             ()
         }
@@ -245,7 +297,7 @@ impl Device {
     ) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetMeasControl(self.id, num::ToPrimitive::to_i32(&control).unwrap(),
+                HH_SetMeasControl(self.open_id()?, num::ToPrimitive::to_i32(&control).unwrap(),
                                   num::ToPrimitive::to_i32(&start_edge).unwrap(),
                                   num::ToPrimitive::to_i32(&stop_edge).unwrap())
             },
@@ -258,7 +310,7 @@ impl Device {
     pub fn stop_measurement(&mut self) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_StopMeas(self.id)
+                HH_StopMeas(self.open_id()?)
             },
             ()
         }
@@ -274,7 +326,7 @@ impl Device {
             let mut histogram_data: Vec<u32> = vec![0; histogram_length];
             error_enum_or_value! {
                 unsafe {
-                    HH_GetHistogram(self.id, histogram_data.as_mut_ptr(), channel, clear as i32)
+                    HH_GetHistogram(self.open_id()?, histogram_data.as_mut_ptr(), channel, clear as i32)
                 },
                 histogram_data
             }
@@ -288,7 +340,7 @@ impl Device {
         let mut resolution: f64 = 0.0;
         error_enum_or_value! {
             unsafe {
-                HH_GetResolution(self.id, &mut resolution as *mut f64)
+                HH_GetResolution(self.open_id()?, &mut resolution as *mut f64)
             },
             resolution
         }
@@ -299,7 +351,7 @@ impl Device {
         let mut sync_rate: i32 = 0;
         error_enum_or_value! {
             unsafe {
-                HH_GetSyncRate(self.id, &mut sync_rate as *mut i32)
+                HH_GetSyncRate(self.open_id()?, &mut sync_rate as *mut i32)
             },
             sync_rate
         }
@@ -312,7 +364,7 @@ impl Device {
         let mut count_rate: i32 = 0;
         error_enum_or_value! {
             unsafe {
-                HH_GetCountRate(self.id, channel, &mut count_rate as *mut i32)
+                HH_GetCountRate(self.open_id()?, channel, &mut count_rate as *mut i32)
             },
             count_rate
         }
@@ -323,7 +375,7 @@ impl Device {
         let mut flags: i32 = 0;
         error_enum_or_value! {
             unsafe {
-                HH_GetFlags(self.id, &mut flags as *mut i32)
+                HH_GetFlags(self.open_id()?, &mut flags as *mut i32)
             },
             flags
         }
@@ -334,7 +386,7 @@ impl Device {
         let mut time: f64 = 0.0;
         error_enum_or_value! {
             unsafe {
-                HH_GetElapsedMeasTime(self.id, &mut time as *mut f64)
+                HH_GetElapsedMeasTime(self.open_id()?, &mut time as *mut f64)
             },
             time
         }
@@ -345,7 +397,7 @@ impl Device {
         let mut warnings: i32 = 0;
         error_enum_or_value! {
             unsafe {
-                HH_GetWarnings(self.id, &mut warnings as *mut i32)
+                HH_GetWarnings(self.open_id()?, &mut warnings as *mut i32)
             },
             warnings
         }
@@ -363,7 +415,7 @@ impl Device {
     ) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetMarkerEdges(self.id,
+                HH_SetMarkerEdges(self.open_id()?,
                                   num::ToPrimitive::to_i32(&me1).unwrap(),
                                   num::ToPrimitive::to_i32(&me2).unwrap(),
                                   num::ToPrimitive::to_i32(&me3).unwrap(),
@@ -384,7 +436,7 @@ impl Device {
     ) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetMarkerEnable(self.id, en1 as i32, en2 as i32, en3 as i32, en4 as i32)},
+                HH_SetMarkerEnable(self.open_id()?, en1 as i32, en2 as i32, en3 as i32, en4 as i32)},
             ()
         }
     }
@@ -394,7 +446,7 @@ impl Device {
     pub fn set_marker_holdoff_time(&mut self, holdoff_time: i32) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_SetMarkerHoldoffTime(self.id, holdoff_time)
+                HH_SetMarkerHoldoffTime(self.open_id()?, holdoff_time)
             },
             ()
         }
@@ -403,7 +455,9 @@ impl Device {
 
 impl Drop for Device {
     fn drop(&mut self) {
-        self.close_device();
+        if self.open {
+            let _ = self.close_device();
+        }
     }
 }
 
@@ -412,7 +466,7 @@ impl Measureable for Device {
     fn start_measurement(&mut self, acquisition_time: i32) -> Result<(), HydraHarpError> {
         error_enum_or_value! {
             unsafe {
-                HH_StartMeas(self.id, acquisition_time)
+                HH_StartMeas(self.open_id()?, acquisition_time)
             },
             ()
         }
@@ -431,7 +485,7 @@ impl Measureable for Device {
         error_enum_or_value! {
             unsafe {
                 HH_ReadFiFo(
-                    self.id, buffer.as_mut_ptr(), records_to_fetch,
+                    self.open_id()?, buffer.as_mut_ptr(), records_to_fetch,
                     &mut records_written as *mut i32
                     )
             },
@@ -444,9 +498,17 @@ impl Measureable for Device {
         let mut status: i32 = 0;
         error_enum_or_value! {
             unsafe {
-                HH_CTCStatus(self.id, &mut status as *mut i32)
+                HH_CTCStatus(self.open_id()?, &mut status as *mut i32)
             },
             num::FromPrimitive::from_i32(status).unwrap()
         }
     }
+
+    fn get_flags(&self) -> Result<i32, HydraHarpError> {
+        Device::get_flags(self)
+    }
+
+    fn get_warnings(&self) -> Result<i32, HydraHarpError> {
+        Device::get_warnings(self)
+    }
 }
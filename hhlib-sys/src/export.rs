@@ -0,0 +1,558 @@
+//! Exporting time tags and results as tables for numpy, pandas and Polars. Tables are written as
+//! `.npy` structured arrays or `.npz` archives of columns, and with the `arrow` feature as Arrow IPC
+//! (`.arrow`, `.feather`) or Parquet files. Each column carries its unit, and each table the
+//! resolution, device settings and analysis parameters needed to interpret it. A `.npy` file has
+//! no room for metadata, so the metadata is only kept by the other formats; in a `.npz` archive
+//! it's the JSON string in `metadata.npy`.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::path::Path;
+
+use crate::coincidence::CoincidenceCounter;
+use crate::config::DeviceConfig;
+use crate::correlation::CrossCorrelator;
+use crate::lifetime::LifetimeHistogram;
+use crate::mub::CoincidenceMatrix;
+use crate::ptu::file_error;
+use crate::raw::{sidecar_path, RawFile};
+use crate::reader::TtrFile;
+use crate::trace::TraceBinner;
+use crate::types::HydraHarpError;
+
+/// The values of a column
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnData {
+    U8(Vec<u8>),
+    U64(Vec<u64>),
+    I64(Vec<i64>),
+    F64(Vec<f64>),
+}
+
+impl ColumnData {
+    pub fn len(&self) -> usize {
+        match self {
+            ColumnData::U8(v) => v.len(),
+            ColumnData::U64(v) => v.len(),
+            ColumnData::I64(v) => v.len(),
+            ColumnData::F64(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The numpy type of the values
+    fn descr(&self) -> &'static str {
+        match self {
+            ColumnData::U8(_) => "|u1",
+            ColumnData::U64(_) => "<u8",
+            ColumnData::I64(_) => "<i8",
+            ColumnData::F64(_) => "<f8",
+        }
+    }
+
+    fn write_value(&self, i: usize, out: &mut Vec<u8>) {
+        match self {
+            ColumnData::U8(v) => out.push(v[i]),
+            ColumnData::U64(v) => out.extend_from_slice(&v[i].to_le_bytes()),
+            ColumnData::I64(v) => out.extend_from_slice(&v[i].to_le_bytes()),
+            ColumnData::F64(v) => out.extend_from_slice(&v[i].to_le_bytes()),
+        }
+    }
+}
+
+/// A named column of a table
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    /// The unit of the values, eg. `"ps"` or `"counts"`
+    pub unit: Option<String>,
+    pub data: ColumnData,
+}
+
+/// Columns of equal length, with metadata about the whole table
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Table {
+    pub columns: Vec<Column>,
+    /// Values describing the table, eg. `"resolution_ps"`, with the device settings as JSON in `"config"`
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// The formats a table can be written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Npy,
+    Npz,
+    Arrow,
+    Parquet,
+}
+
+impl ExportFormat {
+    /// The format for the extension of `path`: `.npy`, `.npz`, `.arrow`, `.feather`, `.ipc` or `.parquet`
+    pub fn from_path(path: &Path) -> Option<ExportFormat> {
+        match path.extension()?.to_str()? {
+            "npy" => Some(ExportFormat::Npy),
+            "npz" => Some(ExportFormat::Npz),
+            "arrow" | "feather" | "ipc" => Some(ExportFormat::Arrow),
+            "parquet" => Some(ExportFormat::Parquet),
+            _ => None,
+        }
+    }
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table::default()
+    }
+
+    /// Add a column.
+    /// Returns `InvalidArgument` if its length doesn't match the columns already added
+    pub fn add_column(
+        &mut self,
+        name: &str,
+        unit: Option<&str>,
+        data: ColumnData,
+    ) -> Result<(), HydraHarpError> {
+        if self
+            .columns
+            .first()
+            .map_or(false, |c| c.data.len() != data.len())
+        {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        self.columns.push(Column {
+            name: name.to_string(),
+            unit: unit.map(|u| u.to_string()),
+            data,
+        });
+        Ok(())
+    }
+
+    pub fn set_metadata<T: ToString>(&mut self, key: &str, value: T) {
+        self.metadata.insert(key.to_string(), value.to_string());
+    }
+
+    /// Add the resolution of the times in ps and the device settings to the metadata
+    pub fn set_measurement(&mut self, resolution: f64, config: Option<&DeviceConfig>) {
+        self.set_metadata("resolution_ps", resolution);
+        if let Some(json) = config.and_then(|c| serde_json::to_string(c).ok()) {
+            self.set_metadata("config", json);
+        }
+    }
+
+    /// The number of rows
+    pub fn rows(&self) -> usize {
+        self.columns.first().map_or(0, |c| c.data.len())
+    }
+
+    /// `(channel, time)` values, with the times in ps
+    pub fn time_tags(times: &[(u8, u64)]) -> Table {
+        let mut table = Table::new();
+        table.columns = vec![
+            Column {
+                name: "channel".to_string(),
+                unit: None,
+                data: ColumnData::U8(times.iter().map(|&(c, _)| c).collect()),
+            },
+            Column {
+                name: "time".to_string(),
+                unit: Some("ps".to_string()),
+                data: ColumnData::U64(times.iter().map(|&(_, t)| t).collect()),
+            },
+        ];
+        table
+    }
+
+    /// The `(channel, time)` values of a T2 PTU, HT2 or raw recording, with the markers kept and the
+    /// resolution and device settings of raw recordings in the metadata. The whole file is decoded into memory.
+    /// Returns `InvalidMode` if it isn't T2 and `FileError` if it can't be read
+    pub fn time_tags_of_file<P: AsRef<Path>>(path: P) -> Result<Table, HydraHarpError> {
+        let mut times = Vec::new();
+        if sidecar_path(path.as_ref()).exists() {
+            let file = RawFile::open(path)?;
+            let mut measurement = file.measurement(&[]);
+            measurement.keep_markers = true;
+            file.read_T2(measurement, |t| times.extend_from_slice(t))?;
+            let mut table = Table::time_tags(&times);
+            table.set_measurement(file.metadata.resolution, Some(&file.metadata.config));
+            Ok(table)
+        } else {
+            let file = TtrFile::open(path)?;
+            let mut measurement = file.measurement(&[]);
+            measurement.keep_markers = true;
+            file.read_T2(measurement, |t| times.extend_from_slice(t))?;
+            let mut table = Table::time_tags(&times);
+            table.set_measurement(file.resolution().unwrap_or(1.0), None);
+            Ok(table)
+        }
+    }
+
+    /// The start of each bin of a count rate trace in ps, the singles of each channel as
+    /// `singles_<channel>` and the coincidences of each pair as `coincidences_<a>_<b>`
+    pub fn trace(trace: &TraceBinner) -> Table {
+        let mut table = Table::new();
+        let bins = trace.singles.len() as u64;
+        let starts = (trace.first_bin..trace.first_bin + bins)
+            .map(|b| b * trace.bin_width)
+            .collect();
+        let _ = table.add_column("bin_start", Some("ps"), ColumnData::U64(starts));
+        for c in 0..trace.channels {
+            let singles = trace.singles.iter().map(|b| b[c]).collect();
+            let _ = table.add_column(
+                &format!("singles_{}", c),
+                Some("counts"),
+                ColumnData::U64(singles),
+            );
+        }
+        for (i, (a, b)) in trace.pairs.iter().enumerate() {
+            let coincidences = trace.coincidences.iter().map(|bin| bin[i]).collect();
+            let _ = table.add_column(
+                &format!("coincidences_{}_{}", a, b),
+                Some("counts"),
+                ColumnData::U64(coincidences),
+            );
+        }
+        table.set_metadata("bin_width_ps", trace.bin_width);
+        table.set_metadata("window_ps", trace.window);
+        table
+    }
+
+    /// The singles and coincidences of each pair of channels `a < b`, one pair to a row
+    pub fn coincidences(counter: &CoincidenceCounter) -> Table {
+        let pairs = (0..counter.channels)
+            .flat_map(|a| (a + 1..counter.channels).map(move |b| (a, b)))
+            .collect::<Vec<_>>();
+        let mut table = Table::new();
+        let column = |f: fn(&CoincidenceCounter, usize, usize) -> u64| {
+            ColumnData::U64(pairs.iter().map(|&(a, b)| f(counter, a, b)).collect())
+        };
+        let _ = table.add_column("channel_a", None, column(|_, a, _| a as u64));
+        let _ = table.add_column("channel_b", None, column(|_, _, b| b as u64));
+        let _ = table.add_column("singles_a", Some("counts"), column(|c, a, _| c.singles[a]));
+        let _ = table.add_column("singles_b", Some("counts"), column(|c, _, b| c.singles[b]));
+        let _ = table.add_column(
+            "coincidences",
+            Some("counts"),
+            column(|c, a, b| c.coincidences[a][b]),
+        );
+        if let Some(offset) = counter.side_window_offset {
+            let _ = table.add_column(
+                "side_coincidences",
+                Some("counts"),
+                column(|c, a, b| c.side_coincidences[a][b]),
+            );
+            table.set_metadata("side_window_offset_ps", offset);
+        }
+        table.set_metadata("window_ps", counter.window);
+        table.set_metadata("duration_ps", counter.duration);
+        table
+    }
+
+    /// The delay at the start of each bin of a cross-correlation in ps, and the pairs in it
+    pub fn correlation(correlator: &CrossCorrelator) -> Table {
+        let mut table = Table::new();
+        let taus = (0..correlator.histogram.len())
+            .map(|i| correlator.tau_min + i as i64 * correlator.bin_width as i64)
+            .collect();
+        let _ = table.add_column("tau", Some("ps"), ColumnData::I64(taus));
+        let _ = table.add_column(
+            "counts",
+            Some("counts"),
+            ColumnData::U64(correlator.histogram.clone()),
+        );
+        table.set_metadata("channel_a", correlator.channel_a);
+        table.set_metadata("channel_b", correlator.channel_b);
+        table.set_metadata("bin_width_ps", correlator.bin_width);
+        table.set_metadata("singles_a", correlator.singles_a);
+        table.set_metadata("singles_b", correlator.singles_b);
+        table.set_metadata("duration_ps", correlator.duration);
+        table
+    }
+
+    /// The delay after the sync at the start of each bin of a lifetime histogram in ps, and the
+    /// counts of each channel as `counts_<channel>`
+    pub fn lifetime(histogram: &LifetimeHistogram) -> Table {
+        let mut table = Table::new();
+        let bins = histogram.histograms.first().map_or(0, |h| h.len()) as u64;
+        let delays = (0..bins).map(|b| b * histogram.bin_width).collect();
+        let _ = table.add_column("delay", Some("ps"), ColumnData::U64(delays));
+        for (c, h) in histogram.histograms.iter().enumerate().skip(1) {
+            let _ = table.add_column(
+                &format!("counts_{}", c),
+                Some("counts"),
+                ColumnData::U64(h.clone()),
+            );
+        }
+        table.set_metadata("bin_width_ps", histogram.bin_width);
+        table.set_metadata(
+            "overflows",
+            serde_json::to_string(&histogram.overflows).unwrap_or_default(),
+        );
+        table
+    }
+
+    /// The counts and variances of each `(a, b)` element of a coincidence matrix, one element to a row
+    pub fn coincidence_matrix(matrix: &CoincidenceMatrix) -> Table {
+        let d = matrix.dimension();
+        let elements = (0..d)
+            .flat_map(|a| (0..d).map(move |b| (a, b)))
+            .collect::<Vec<_>>();
+        let mut table = Table::new();
+        let _ = table.add_column(
+            "a",
+            None,
+            ColumnData::U64(elements.iter().map(|&(a, _)| a as u64).collect()),
+        );
+        let _ = table.add_column(
+            "b",
+            None,
+            ColumnData::U64(elements.iter().map(|&(_, b)| b as u64).collect()),
+        );
+        let _ = table.add_column(
+            "counts",
+            Some("counts"),
+            ColumnData::F64(elements.iter().map(|&(a, b)| matrix.counts[a][b]).collect()),
+        );
+        let _ = table.add_column(
+            "variance",
+            Some("counts^2"),
+            ColumnData::F64(
+                elements
+                    .iter()
+                    .map(|&(a, b)| matrix.variances[a][b])
+                    .collect(),
+            ),
+        );
+        table.set_metadata("dimension", d);
+        table
+    }
+
+    /// The metadata, with the unit of each column as `unit_<column>`, as a JSON object
+    fn metadata_json(&self) -> String {
+        let mut metadata = self.metadata.clone();
+        for c in self.columns.iter() {
+            if let Some(unit) = &c.unit {
+                metadata.insert(format!("unit_{}", c.name), unit.clone());
+            }
+        }
+        serde_json::to_string(&metadata).unwrap_or_default()
+    }
+
+    /// Write the rows as a numpy structured array, with a field for each column
+    pub fn write_npy<W: Write>(&self, w: &mut W) -> Result<(), HydraHarpError> {
+        let fields = self
+            .columns
+            .iter()
+            .map(|c| format!("('{}', '{}')", c.name, c.data.descr()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write_npy_header(w, &format!("[{}]", fields), &format!("({},)", self.rows()))?;
+        let mut row = Vec::new();
+        for i in 0..self.rows() {
+            row.clear();
+            for c in self.columns.iter() {
+                c.data.write_value(i, &mut row);
+            }
+            w.write_all(&row).map_err(file_error)?;
+        }
+        Ok(())
+    }
+
+    /// Write each column as an array in a `.npz` archive, along with `metadata`
+    pub fn write_npz<W: Write + Seek>(&self, w: W) -> Result<(), HydraHarpError> {
+        let mut zip = zip::ZipWriter::new(w);
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let zip_error = |_| HydraHarpError::FileError;
+        for c in self.columns.iter() {
+            zip.start_file(format!("{}.npy", c.name), options)
+                .map_err(zip_error)?;
+            let mut bytes = Vec::with_capacity(c.data.len() * 8);
+            for i in 0..c.data.len() {
+                c.data.write_value(i, &mut bytes);
+            }
+            write_npy_header(
+                &mut zip,
+                &format!("'{}'", c.data.descr()),
+                &format!("({},)", c.data.len()),
+            )?;
+            zip.write_all(&bytes).map_err(file_error)?;
+        }
+        // A 0-d array of UTF-32 characters, which numpy loads without needing pickle
+        let json = self.metadata_json();
+        let characters = json.chars().count().max(1);
+        zip.start_file("metadata.npy", options).map_err(zip_error)?;
+        write_npy_header(&mut zip, &format!("'<U{}'", characters), "()")?;
+        let mut bytes = Vec::with_capacity(characters * 4);
+        for c in json.chars() {
+            bytes.extend_from_slice(&(c as u32).to_le_bytes());
+        }
+        bytes.resize(characters * 4, 0);
+        zip.write_all(&bytes).map_err(file_error)?;
+        zip.finish().map_err(zip_error)?;
+        Ok(())
+    }
+
+    /// Write the table to `path` in the format of its extension, see `ExportFormat::from_path`.
+    /// Returns `InvalidArgument` for an unknown extension, `InvalidOption` for Arrow and Parquet
+    /// without the `arrow` feature, and `FileError` if it can't be written
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), HydraHarpError> {
+        let format =
+            ExportFormat::from_path(path.as_ref()).ok_or(HydraHarpError::InvalidArgument)?;
+        let file = File::create(path.as_ref()).map_err(file_error)?;
+        match format {
+            ExportFormat::Npy => {
+                let mut w = BufWriter::new(file);
+                self.write_npy(&mut w)?;
+                w.flush().map_err(file_error)
+            }
+            ExportFormat::Npz => self.write_npz(BufWriter::new(file)),
+            #[cfg(feature = "arrow")]
+            ExportFormat::Arrow => arrow_export::write_ipc(self, file),
+            #[cfg(feature = "arrow")]
+            ExportFormat::Parquet => arrow_export::write_parquet(self, file),
+            #[cfg(not(feature = "arrow"))]
+            ExportFormat::Arrow | ExportFormat::Parquet => Err(HydraHarpError::InvalidOption),
+        }
+    }
+}
+
+/// Write the header of a version 1 `.npy` file, padded so that the data is aligned to 64 bytes
+fn write_npy_header<W: Write>(w: &mut W, descr: &str, shape: &str) -> Result<(), HydraHarpError> {
+    let mut header = format!(
+        "{{'descr': {}, 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+    if header.len() > u16::max_value() as usize {
+        return Err(HydraHarpError::InvalidArgument);
+    }
+    w.write_all(b"\x93NUMPY\x01\x00").map_err(file_error)?;
+    w.write_all(&(header.len() as u16).to_le_bytes())
+        .map_err(file_error)?;
+    w.write_all(header.as_bytes()).map_err(file_error)
+}
+
+#[cfg(feature = "arrow")]
+mod arrow_export {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::sync::Arc;
+
+    use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, UInt64Array, UInt8Array};
+    use arrow_ipc::writer::FileWriter;
+    use arrow_schema::{DataType, Field, Schema};
+    use parquet::arrow::ArrowWriter;
+    use parquet::basic::Compression;
+    use parquet::file::properties::WriterProperties;
+
+    use super::{ColumnData, Table};
+    use crate::types::HydraHarpError;
+
+    /// The table as one record batch, with the unit of each column in the field's metadata
+    pub fn record_batch(table: &Table) -> Result<RecordBatch, HydraHarpError> {
+        let mut fields = Vec::new();
+        let mut arrays: Vec<ArrayRef> = Vec::new();
+        for c in table.columns.iter() {
+            let (data_type, array): (DataType, ArrayRef) = match &c.data {
+                ColumnData::U8(v) => (DataType::UInt8, Arc::new(UInt8Array::from(v.clone()))),
+                ColumnData::U64(v) => (DataType::UInt64, Arc::new(UInt64Array::from(v.clone()))),
+                ColumnData::I64(v) => (DataType::Int64, Arc::new(Int64Array::from(v.clone()))),
+                ColumnData::F64(v) => (DataType::Float64, Arc::new(Float64Array::from(v.clone()))),
+            };
+            let mut metadata = HashMap::new();
+            if let Some(unit) = &c.unit {
+                metadata.insert("unit".to_string(), unit.clone());
+            }
+            fields.push(Field::new(c.name.as_str(), data_type, false).with_metadata(metadata));
+            arrays.push(array);
+        }
+        let metadata = table
+            .metadata
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let schema = Arc::new(Schema::new_with_metadata(fields, metadata));
+        RecordBatch::try_new(schema, arrays).map_err(|_| HydraHarpError::InvalidArgument)
+    }
+
+    pub fn write_ipc(table: &Table, file: File) -> Result<(), HydraHarpError> {
+        let batch = record_batch(table)?;
+        let arrow_error = |_| HydraHarpError::FileError;
+        let mut w = FileWriter::try_new(file, &batch.schema()).map_err(arrow_error)?;
+        w.write(&batch).map_err(arrow_error)?;
+        w.finish().map_err(arrow_error)
+    }
+
+    pub fn write_parquet(table: &Table, file: File) -> Result<(), HydraHarpError> {
+        let batch = record_batch(table)?;
+        let parquet_error = |_| HydraHarpError::FileError;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut w =
+            ArrowWriter::try_new(file, batch.schema(), Some(properties)).map_err(parquet_error)?;
+        w.write(&batch).map_err(parquet_error)?;
+        w.close().map(|_| ()).map_err(parquet_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Table;
+
+    #[test]
+    fn npy_is_a_structured_array() {
+        let table = Table::time_tags(&[(1, 10), (2, 300)]);
+        let mut bytes = Vec::new();
+        table.write_npy(&mut bytes).unwrap();
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_length = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_length) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + header_length]).unwrap();
+        assert!(header.starts_with(
+            "{'descr': [('channel', '|u1'), ('time', '<u8')], 'fortran_order': False, 'shape': (2,), }"
+        ));
+        assert_eq!(
+            &bytes[10 + header_length..],
+            &[1, 10, 0, 0, 0, 0, 0, 0, 0, 2, 44, 1, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn arrow_keeps_units_and_metadata() {
+        use arrow_ipc::reader::FileReader;
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let mut table = Table::time_tags(&[(1, 10), (2, 300)]);
+        table.set_measurement(1.0, None);
+        let dir = std::env::temp_dir();
+        for extension in ["arrow", "parquet"].iter() {
+            let path = dir.join(format!(
+                "hhlib_sys_export_test_{}.{}",
+                std::process::id(),
+                extension
+            ));
+            table.write(&path).unwrap();
+            let file = std::fs::File::open(&path).unwrap();
+            let (schema, batch) = if *extension == "arrow" {
+                let mut reader = FileReader::try_new(file, None).unwrap();
+                (reader.schema(), reader.next().unwrap().unwrap())
+            } else {
+                let builder = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+                let schema = builder.schema().clone();
+                (schema, builder.build().unwrap().next().unwrap().unwrap())
+            };
+            assert_eq!(batch.num_rows(), 2);
+            assert_eq!(schema.field(1).metadata()["unit"], "ps");
+            assert_eq!(schema.metadata()["resolution_ps"], "1");
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Injecting faults into a measurement source, to test how errors are recovered from and shown.
+//! The faults are scripted in a JSON file, eg.
+//!
+//! ```json
+//! {
+//!     "seed": 1,
+//!     "faults": [
+//!         { "fault": "error", "call": "read_fifo", "nth": 3, "error": "USBBulkRDFail" },
+//!         { "fault": "fifo_full", "after_reads": 10 },
+//!         { "fault": "stall", "after_reads": 5, "reads": 100 },
+//!         { "fault": "corrupt", "probability": 0.001 },
+//!         { "fault": "reorder", "probability": 0.01 },
+//!         { "fault": "warning", "after_reads": 0, "warning": "SyncRateTooLow" }
+//!     ]
+//! }
+//! ```
+//!
+//! Times within a measurement are counted in calls to `read_fifo` rather than wall clock time,
+//! so that a script replays an incident the same way every time.
+use std::cell::Cell;
+use std::path::Path;
+
+use pyo3::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::bindings::FLAG_FIFOFULL;
+use crate::measurement::Measureable;
+use crate::ptu::file_error;
+use crate::simulator::PhotonSimulator;
+use crate::types::{CTCStatus, HydraHarpError, Warning};
+
+/// The functions of `Measureable`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Call {
+    StartMeasurement,
+    ReadFifo,
+    GetCtcStatus,
+    GetFlags,
+    GetWarnings,
+}
+
+fn one() -> u64 {
+    1
+}
+
+/// A fault to inject
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "fault", rename_all = "snake_case")]
+pub enum Fault {
+    /// Return `error` from the `nth` call of `call` and the `count - 1` calls after it, counting
+    /// from 1 over every measurement
+    Error {
+        call: Call,
+        nth: u64,
+        error: HydraHarpError,
+        #[serde(default = "one")]
+        count: u64,
+    },
+    /// Set `FLAG_FIFOFULL` from `after_reads` reads into each measurement
+    FifoFull { after_reads: u64 },
+    /// Return no records for `reads` reads from `after_reads` reads into each measurement
+    Stall { after_reads: u64, reads: u64 },
+    /// Replace each record with random bits with `probability`
+    Corrupt { probability: f64 },
+    /// Swap each record with the one after it with `probability`
+    Reorder { probability: f64 },
+    /// Report `warning` from `after_reads` reads into each measurement
+    Warning { after_reads: u64, warning: Warning },
+}
+
+/// A list of faults to inject, with the seed for the random ones
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FaultScript {
+    #[serde(default)]
+    pub seed: u64,
+    pub faults: Vec<Fault>,
+}
+
+impl FaultScript {
+    /// Parse a script from JSON.
+    /// Returns `InvalidArgument` if it isn't a valid script
+    pub fn from_json(json: &str) -> Result<FaultScript, HydraHarpError> {
+        serde_json::from_str(json).map_err(|_| HydraHarpError::InvalidArgument)
+    }
+
+    /// Read a script from a JSON file.
+    /// Returns `FileError` if it can't be read and `InvalidArgument` if it isn't a valid script
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<FaultScript, HydraHarpError> {
+        FaultScript::from_json(&std::fs::read_to_string(path).map_err(file_error)?)
+    }
+}
+
+/// Wraps a measurement source, injecting the faults of a script into it
+pub struct FaultInjector<M: Measureable> {
+    pub device: M,
+    pub script: FaultScript,
+    rng: StdRng,
+    /// The number of calls of each function so far, in the order of `Call`
+    calls: [Cell<u64>; 5],
+    /// The number of reads in the current measurement
+    reads: u64,
+}
+
+impl<M: Measureable> FaultInjector<M> {
+    pub fn new(device: M, script: FaultScript) -> FaultInjector<M> {
+        FaultInjector {
+            device,
+            rng: StdRng::seed_from_u64(script.seed),
+            script,
+            calls: Default::default(),
+            reads: 0,
+        }
+    }
+
+    /// Count a call, returning the error scripted for it if there is one
+    fn call(&self, call: Call) -> Result<(), HydraHarpError> {
+        let calls = &self.calls[call as usize];
+        calls.set(calls.get() + 1);
+        let n = calls.get();
+        for fault in self.script.faults.iter() {
+            if let Fault::Error {
+                call: c,
+                nth,
+                error,
+                count,
+            } = *fault
+            {
+                if c == call && n >= nth && n < nth + count {
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stalled(&self) -> bool {
+        self.script.faults.iter().any(|f| match *f {
+            Fault::Stall { after_reads, reads } => {
+                self.reads > after_reads && self.reads <= after_reads + reads
+            }
+            _ => false,
+        })
+    }
+}
+
+impl<M: Measureable> Measureable for FaultInjector<M> {
+    fn start_measurement(&mut self, acquisition_time: i32) -> Result<(), HydraHarpError> {
+        self.call(Call::StartMeasurement)?;
+        self.reads = 0;
+        self.device.start_measurement(acquisition_time)
+    }
+
+    fn read_fifo(
+        &mut self,
+        buffer: &mut [u32],
+        records_to_fetch: i32,
+    ) -> Result<i32, HydraHarpError> {
+        self.call(Call::ReadFifo)?;
+        self.reads += 1;
+        if self.stalled() {
+            return Ok(0);
+        }
+        let n = self.device.read_fifo(buffer, records_to_fetch)?;
+        let records = &mut buffer[..n.max(0) as usize];
+        for fault in self.script.faults.iter() {
+            match *fault {
+                Fault::Corrupt { probability } => {
+                    for r in records.iter_mut() {
+                        if self.rng.gen::<f64>() < probability {
+                            *r = self.rng.gen();
+                        }
+                    }
+                }
+                Fault::Reorder { probability } => {
+                    for i in 1..records.len() {
+                        if self.rng.gen::<f64>() < probability {
+                            records.swap(i - 1, i);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(n)
+    }
+
+    /// Running while the fifo is stalled, so that the stall isn't taken as the end of the measurement
+    fn get_CTC_status(&self) -> Result<CTCStatus, HydraHarpError> {
+        self.call(Call::GetCtcStatus)?;
+        if self.stalled() {
+            return Ok(CTCStatus::Running);
+        }
+        self.device.get_CTC_status()
+    }
+
+    fn get_flags(&self) -> Result<i32, HydraHarpError> {
+        self.call(Call::GetFlags)?;
+        let full = self.script.faults.iter().any(|f| match *f {
+            Fault::FifoFull { after_reads } => self.reads >= after_reads,
+            _ => false,
+        });
+        let flags = self.device.get_flags()?;
+        Ok(if full {
+            flags | FLAG_FIFOFULL as i32
+        } else {
+            flags
+        })
+    }
+
+    fn get_warnings(&self) -> Result<i32, HydraHarpError> {
+        self.call(Call::GetWarnings)?;
+        let warnings = self.script.faults.iter().fold(0, |w, f| match *f {
+            Fault::Warning {
+                after_reads,
+                warning,
+            } if self.reads >= after_reads => w | warning as i32,
+            _ => w,
+        });
+        Ok(self.device.get_warnings()? | warnings)
+    }
+}
+
+/// A simulated device with faults injected into it
+#[pyclass]
+pub struct FaultySimulator {
+    pub injector: FaultInjector<PhotonSimulator>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaultInjector, FaultScript};
+    use crate::bindings::FLAG_FIFOFULL;
+    use crate::coincidence::{measure_coincidences, CoincidenceCounter};
+    use crate::measurement::{run_measurement_T2, Measureable};
+    use crate::simulator::PhotonSimulator;
+    use crate::types::HydraHarpError;
+
+    #[test]
+    fn scripted_faults() {
+        let script = FaultScript::from_json(
+            r#"{ "faults": [
+                { "fault": "error", "call": "read_fifo", "nth": 3, "error": "USBBulkRDFail" },
+                { "fault": "fifo_full", "after_reads": 2 },
+                { "fault": "stall", "after_reads": 0, "reads": 5 },
+                { "fault": "reorder", "probability": 0.5 }
+            ] }"#,
+        )
+        .unwrap();
+        let mut simulator = PhotonSimulator::new(1);
+        simulator.singles.push((1, 1e6));
+        let mut d = FaultInjector::new(simulator, script);
+        let result = run_measurement_T2(&mut d, 10, |_| ());
+        assert_eq!(result, Err(HydraHarpError::USBBulkRDFail));
+        assert_eq!(
+            d.get_flags().unwrap() & FLAG_FIFOFULL as i32,
+            FLAG_FIFOFULL as i32
+        );
+
+        // The stall lasts past the error, then the reordered records come out unsorted
+        let mut sorted = true;
+        let mut latest = 0;
+        d.start_measurement(10).unwrap();
+        let mut buffer = vec![0; 1024];
+        while d.read_fifo(&mut buffer, 1024).unwrap() == 0 {}
+        for &r in buffer.iter().filter(|&&r| r >> 31 == 0) {
+            let time = r & ((1 << 25) - 1);
+            sorted &= time >= latest;
+            latest = time;
+        }
+        assert!(!sorted);
+
+        // Corrupted records decode to times out of order, which the counter has to put up with.
+        // All 64 inputs are counted so that every corrupted time gets to the counter
+        let script =
+            FaultScript::from_json(r#"{ "faults": [{ "fault": "corrupt", "probability": 0.1 }] }"#)
+                .unwrap();
+        let mut simulator = PhotonSimulator::new(1);
+        simulator.singles.push((1, 1e6));
+        simulator.singles.push((2, 1e6));
+        let mut d = FaultInjector::new(simulator, script);
+        let mut counter = CoincidenceCounter::new(65, 1000, None);
+        measure_coincidences(&mut d, 200, &mut counter).unwrap();
+        assert!(counter.singles[1] > 0);
+    }
+}
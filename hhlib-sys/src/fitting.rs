@@ -0,0 +1,339 @@
+//! Least squares fitting of measured curves, and fitting of multi-exponential decays to lifetime
+//! histograms, with a background and optionally reconvolution with a measured instrument response.
+use pyo3::prelude::*;
+use std::cmp::Ordering;
+
+use crate::types::HydraHarpError;
+
+/// The result of a least squares fit
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeastSquares {
+    pub params: Vec<f64>,
+    /// One standard deviation errors of the parameters, from the covariance matrix
+    pub errors: Vec<f64>,
+    pub chi_squared: f64,
+    /// Chi squared divided by the degrees of freedom, which should be about 1 for a good fit
+    pub reduced_chi_squared: f64,
+    pub iterations: usize,
+}
+
+/// Solve `a x = b` by Gauss-Jordan elimination, returning `None` if `a` is singular
+pub(crate) fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| {
+            a[i][col]
+                .abs()
+                .partial_cmp(&a[j][col].abs())
+                .unwrap_or(Ordering::Equal)
+        })?;
+        // Also catches a NaN pivot
+        if !(a[pivot][col].abs() >= 1e-300) {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in 0..n {
+            if row != col {
+                let factor = a[row][col] / a[col][col];
+                for k in col..n {
+                    a[row][k] -= factor * a[col][k];
+                }
+                b[row] -= factor * b[col];
+            }
+        }
+    }
+    Some((0..n).map(|i| b[i] / a[i][i]).collect())
+}
+
+/// Invert a square matrix, returning `None` if it's singular
+fn invert(a: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let n = a.len();
+    let columns = (0..n)
+        .map(|i| {
+            let mut e = vec![0.0; n];
+            e[i] = 1.0;
+            solve(a.to_vec(), e)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(
+        (0..n)
+            .map(|i| (0..n).map(|j| columns[j][i]).collect())
+            .collect(),
+    )
+}
+
+fn chi_squared(model: &[f64], y: &[f64], sigma: &[f64]) -> f64 {
+    model
+        .iter()
+        .zip(y.iter().zip(sigma.iter()))
+        .map(|(m, (y, s))| ((y - m) / s).powi(2))
+        .sum()
+}
+
+/// The weighted jacobian `d(model_i)/d(p_j) / sigma_i` and the normal equations `(J^T J, J^T r)`
+fn normal_equations<F: Fn(&[f64]) -> Vec<f64>>(
+    model: &F,
+    params: &[f64],
+    current: &[f64],
+    y: &[f64],
+    sigma: &[f64],
+) -> (Vec<Vec<f64>>, Vec<f64>) {
+    let k = params.len();
+    let jacobian = (0..k)
+        .map(|j| {
+            let step = 1e-6 * params[j].abs().max(1e-3);
+            let mut shifted = params.to_vec();
+            shifted[j] += step;
+            model(&shifted)
+                .iter()
+                .zip(current.iter().zip(sigma.iter()))
+                .map(|(m, (c, s))| (m - c) / step / s)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let residuals = y
+        .iter()
+        .zip(current.iter().zip(sigma.iter()))
+        .map(|(y, (c, s))| (y - c) / s)
+        .collect::<Vec<_>>();
+    let dot = |a: &[f64], b: &[f64]| a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f64>();
+    let jtj = (0..k)
+        .map(|i| (0..k).map(|j| dot(&jacobian[i], &jacobian[j])).collect())
+        .collect();
+    let jtr = (0..k).map(|i| dot(&jacobian[i], &residuals)).collect();
+    (jtj, jtr)
+}
+
+/// Fit `model(params)` to the data `y` with errors `sigma` by Levenberg-Marquardt, starting from `initial`.
+/// The model returns its value at each of the data points.
+/// Returns `InvalidArgument` if there are no more data points than parameters, the data or the
+/// model at `initial` aren't finite, or the fit is degenerate
+pub fn levenberg_marquardt<F: Fn(&[f64]) -> Vec<f64>>(
+    model: F,
+    y: &[f64],
+    sigma: &[f64],
+    initial: &[f64],
+) -> Result<LeastSquares, HydraHarpError> {
+    const MAX_ITERATIONS: usize = 1000;
+    if y.len() <= initial.len() || y.len() != sigma.len() {
+        return Err(HydraHarpError::InvalidArgument);
+    }
+    let mut params = initial.to_vec();
+    let mut current = model(&params);
+    let mut chi2 = chi_squared(&current, y, sigma);
+    if !chi2.is_finite() {
+        return Err(HydraHarpError::InvalidArgument);
+    }
+    let mut lambda = 1e-3;
+    let mut iterations = 0;
+    while iterations < MAX_ITERATIONS {
+        iterations += 1;
+        let (jtj, jtr) = normal_equations(&model, &params, &current, y, sigma);
+        let mut improved = false;
+        while lambda < 1e12 {
+            let mut damped = jtj.clone();
+            for (i, row) in damped.iter_mut().enumerate() {
+                row[i] += lambda * jtj[i][i].max(1e-12);
+            }
+            if let Some(step) = solve(damped, jtr.clone()) {
+                let trial = params
+                    .iter()
+                    .zip(step.iter())
+                    .map(|(p, s)| p + s)
+                    .collect::<Vec<_>>();
+                let trial_model = model(&trial);
+                let trial_chi2 = chi_squared(&trial_model, y, sigma);
+                if trial_chi2 <= chi2 {
+                    let change = (chi2 - trial_chi2) / trial_chi2.max(1e-300);
+                    params = trial;
+                    current = trial_model;
+                    chi2 = trial_chi2;
+                    lambda = (lambda * 0.1).max(1e-12);
+                    improved = change > 1e-10;
+                    break;
+                }
+            }
+            lambda *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+    let (jtj, _) = normal_equations(&model, &params, &current, y, sigma);
+    let covariance = invert(&jtj).ok_or(HydraHarpError::InvalidArgument)?;
+    let errors = (0..params.len())
+        .map(|i| covariance[i][i].max(0.0).sqrt())
+        .collect();
+    Ok(LeastSquares {
+        params,
+        errors,
+        chi_squared: chi2,
+        reduced_chi_squared: chi2 / (y.len() - initial.len()) as f64,
+        iterations,
+    })
+}
+
+/// The result of fitting a multi-exponential decay to a lifetime histogram.
+/// All of the errors are one standard deviation
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecayFit {
+    /// Lifetime of each component in ps
+    #[prop(get)]
+    pub lifetimes: Vec<f64>,
+    #[prop(get)]
+    pub lifetime_errors: Vec<f64>,
+    /// Amplitude of each component. Without an IRF, this is the counts per bin at the start of the fit range
+    #[prop(get)]
+    pub amplitudes: Vec<f64>,
+    #[prop(get)]
+    pub amplitude_errors: Vec<f64>,
+    /// Background counts per bin
+    #[prop(get)]
+    pub background: f64,
+    #[prop(get)]
+    pub background_error: f64,
+    #[prop(get)]
+    pub chi_squared: f64,
+    #[prop(get)]
+    pub reduced_chi_squared: f64,
+}
+
+/// The decay of each component, reconvolved with `irf` if given, over the whole histogram
+fn decays(
+    bins: usize,
+    bin_width: f64,
+    start: usize,
+    lifetimes: &[f64],
+    irf: Option<&[f64]>,
+) -> Vec<Vec<f64>> {
+    lifetimes
+        .iter()
+        .map(|tau| {
+            let q = (-bin_width / tau.abs().max(1e-3)).exp();
+            match irf {
+                Some(irf) => irf
+                    .iter()
+                    .scan(0.0, |c, i| {
+                        *c = *c * q + i;
+                        Some(*c)
+                    })
+                    .collect(),
+                None => (0..bins)
+                    .map(|i| {
+                        if i < start {
+                            0.0
+                        } else {
+                            q.powi((i - start) as i32)
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Fit a sum of exponential decays and a constant background to the bins `range.0..range.1` of a
+/// lifetime histogram with bins of `bin_width` ps, starting from the guesses of `lifetimes` in ps.
+/// Without an `irf` the decays start at the beginning of the range (a tail fit). With a measured
+/// `irf`, which must have the same number of bins as the histogram, the decays are reconvolved with it.
+/// Returns `InvalidArgument` if the inputs don't fit together or the fit fails
+pub fn fit_decay(
+    histogram: &[u64],
+    bin_width: f64,
+    lifetimes: &[f64],
+    irf: Option<&[f64]>,
+    range: (usize, usize),
+) -> Result<DecayFit, HydraHarpError> {
+    let (start, end) = range;
+    let bins = histogram.len();
+    if lifetimes.is_empty() || start >= end || end > bins || irf.map_or(false, |i| i.len() != bins)
+    {
+        return Err(HydraHarpError::InvalidArgument);
+    }
+    let irf_total: f64 = irf.map_or(1.0, |i| i.iter().sum());
+    if irf_total <= 0.0 {
+        return Err(HydraHarpError::InvalidArgument);
+    }
+    let irf = irf.map(|i| i.iter().map(|x| x / irf_total).collect::<Vec<_>>());
+    let irf = irf.as_ref().map(|i| i.as_slice());
+
+    let y = histogram[start..end]
+        .iter()
+        .map(|&c| c as f64)
+        .collect::<Vec<_>>();
+    let sigma = y.iter().map(|&c| c.max(1.0).sqrt()).collect::<Vec<_>>();
+    let n = lifetimes.len();
+    // The parameters are the background, then the amplitude and lifetime of each component
+    let model = |p: &[f64]| {
+        let taus = (0..n).map(|k| p[2 + 2 * k]).collect::<Vec<_>>();
+        let shapes = decays(bins, bin_width, start, &taus, irf);
+        (start..end)
+            .map(|i| p[0] + (0..n).map(|k| p[1 + 2 * k] * shapes[k][i]).sum::<f64>())
+            .collect::<Vec<_>>()
+    };
+
+    let tail = std::cmp::max((end - start) / 20, 1);
+    let background = y[y.len() - tail..].iter().sum::<f64>() / tail as f64;
+    let peak = y.iter().cloned().fold(0.0, f64::max) - background;
+    let shapes = decays(bins, bin_width, start, lifetimes, irf);
+    let mut initial = vec![background];
+    for (k, &tau) in lifetimes.iter().enumerate() {
+        let height = shapes[k][start..end]
+            .iter()
+            .cloned()
+            .fold(0.0, f64::max)
+            .max(1e-12);
+        initial.push(peak / (n as f64 * height));
+        initial.push(tau);
+    }
+
+    let fit = levenberg_marquardt(model, &y, &sigma, &initial)?;
+    let p = &fit.params;
+    let e = &fit.errors;
+    Ok(DecayFit {
+        lifetimes: (0..n).map(|k| p[2 + 2 * k].abs()).collect(),
+        lifetime_errors: (0..n).map(|k| e[2 + 2 * k]).collect(),
+        amplitudes: (0..n).map(|k| p[1 + 2 * k]).collect(),
+        amplitude_errors: (0..n).map(|k| e[1 + 2 * k]).collect(),
+        background: p[0],
+        background_error: e[0],
+        chi_squared: fit.chi_squared,
+        reduced_chi_squared: fit.reduced_chi_squared,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit_decay, levenberg_marquardt};
+
+    #[test]
+    fn recovers_lifetime_with_irf() {
+        let bins = 200;
+        let irf = (0..bins)
+            .map(|i| (-((i as f64 - 20.0) / 2.0).powi(2) / 2.0).exp())
+            .collect::<Vec<_>>();
+        let total: f64 = irf.iter().sum();
+        let q = (-50.0f64 / 500.0).exp();
+        let mut c = 0.0;
+        let histogram = irf
+            .iter()
+            .map(|i| {
+                c = c * q + i / total;
+                (5.0 + 10_000.0 * c).round() as u64
+            })
+            .collect::<Vec<_>>();
+        let fit = fit_decay(&histogram, 50.0, &[300.0], Some(&irf), (0, bins)).unwrap();
+        assert!((fit.lifetimes[0] - 500.0).abs() < 5.0);
+        assert!((fit.background - 5.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn nan_data_is_an_error() {
+        let y = [1.0, 2.0, std::f64::NAN, 4.0];
+        let sigma = [1.0; 4];
+        let line = |p: &[f64]| (0..4).map(|x| p[0] + p[1] * x as f64).collect::<Vec<_>>();
+        assert!(levenberg_marquardt(line, &y, &sigma, &[0.0, 1.0]).is_err());
+    }
+}
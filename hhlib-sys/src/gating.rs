@@ -0,0 +1,252 @@
+//! Gating of events by their delay after the most recent sync, for pulsed experiments.
+//! Sits between the T2 or T3 decoding and the counters, keeping only the events of each channel which
+//! arrive within one of its gates after a laser pulse, to reject afterglow and dark counts.
+use pyo3::prelude::*;
+
+use crate::coincidence::CoincidenceCounter;
+use crate::measurement::{run_measurement_T3, run_measurement_with_delays_T2, Measureable};
+use crate::types::HydraHarpError;
+
+/// A range of delays after the sync, `start..=end` in ps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gate {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Gate {
+    fn contains(&self, delay: u64) -> bool {
+        delay >= self.start && delay <= self.end
+    }
+}
+
+/// Keeps only the events which arrive within one of the gates of their channel after the most
+/// recent sync. The sync (channel 0) and channels without gates are passed through untouched.
+/// Events before the first sync of a measurement can't be gated, so are dropped from gated channels
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GateFilter {
+    /// `gates[channel]` are the gates of that channel. An empty list lets every event through
+    pub gates: Vec<Vec<Gate>>,
+    /// `kept[channel]` is the number of events of a gated channel which were inside a gate
+    pub kept: Vec<u64>,
+    /// `rejected[channel][gate]` is the number of events of the channel outside that gate.
+    /// An event is only dropped if every gate of its channel rejected it
+    pub rejected: Vec<Vec<u64>>,
+    /// `dropped[channel]` is the number of events of the channel which were outside all of its gates
+    pub dropped: Vec<u64>,
+    last_sync: Option<u64>,
+}
+
+impl GateFilter {
+    /// A filter for the first `channels` channels, including the sync, with no gates set
+    pub fn new(channels: usize) -> GateFilter {
+        GateFilter {
+            gates: vec![Vec::new(); channels],
+            kept: vec![0; channels],
+            rejected: vec![Vec::new(); channels],
+            dropped: vec![0; channels],
+            last_sync: None,
+        }
+    }
+
+    /// Set the gates of `channel`, resetting its counts.
+    /// Returns `InvalidArgument` for the sync channel, a channel the filter doesn't have or an empty gate
+    pub fn set_gates(&mut self, channel: u8, gates: &[Gate]) -> Result<(), HydraHarpError> {
+        let channel = channel as usize;
+        if channel == 0 || channel >= self.gates.len() || gates.iter().any(|g| g.end < g.start) {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        self.gates[channel] = gates.to_vec();
+        self.kept[channel] = 0;
+        self.rejected[channel] = vec![0; gates.len()];
+        self.dropped[channel] = 0;
+        Ok(())
+    }
+
+    /// Filter a chunk of channels and times, returning the events which pass.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last
+    pub fn filter(&mut self, times: &[(u8, u64)]) -> Vec<(u8, u64)> {
+        let mut passed = Vec::with_capacity(times.len());
+        for &(channel, time) in times.iter() {
+            if channel == 0 {
+                self.last_sync = Some(time);
+                passed.push((channel, time));
+                continue;
+            }
+            // An event before the sync, which can only come from corrupted records, has no delay
+            let delay = self.last_sync.and_then(|s| time.checked_sub(s));
+            if self.passes(channel, delay) {
+                passed.push((channel, time));
+            }
+        }
+        passed
+    }
+
+    /// Filter a chunk of T3 `(channel, sync number, start-stop time)` events, returning the events
+    /// which pass. The start-stop time, in units of `resolution` ps, is already the delay after the sync
+    pub fn filter_T3(&mut self, events: &[(u8, u64, u32)], resolution: u64) -> Vec<(u8, u64, u32)> {
+        events
+            .iter()
+            .cloned()
+            .filter(|&(channel, _, dtime)| self.passes(channel, Some(dtime as u64 * resolution)))
+            .collect()
+    }
+
+    /// Whether an event of `channel` with `delay` after the sync passes, counting it
+    fn passes(&mut self, channel: u8, delay: Option<u64>) -> bool {
+        let c = channel as usize;
+        let gates = match self.gates.get(c) {
+            Some(gates) if !gates.is_empty() => gates,
+            _ => return true,
+        };
+        let mut inside = false;
+        for (i, gate) in gates.iter().enumerate() {
+            if delay.map_or(false, |d| gate.contains(d)) {
+                inside = true;
+            } else {
+                self.rejected[c][i] += 1;
+            }
+        }
+        if inside {
+            self.kept[c] += 1;
+        } else {
+            self.dropped[c] += 1;
+        }
+        inside
+    }
+
+    /// Forget the last sync. Call this at the end of each measurement, as the times restart from
+    /// zero in the next one
+    pub fn end_measurement(&mut self) {
+        self.last_sync = None;
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, gating the events with `gate` before adding them to `counter`
+pub fn measure_gated_coincidences<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    gate: &mut GateFilter,
+    counter: &mut CoincidenceCounter,
+) -> Result<(), HydraHarpError> {
+    let delays = counter.delays.clone();
+    run_measurement_with_delays_T2(d, acquisition_time, &delays, |times| {
+        counter.add_times(&gate.filter(times))
+    })?;
+    gate.end_measurement();
+    counter.end_measurement(acquisition_time as u64 * 1_000_000_000);
+    Ok(())
+}
+
+/// Run a T3 measurement for `acquisition_time` ms, gating the events with `gate` on their start-stop
+/// time before adding them to `counter`. Each event is counted at `sync number * sync_period +
+/// start-stop time * resolution` ps, plus the delay of its channel in `counter`.
+/// There are no sync events in T3 mode, so the counter only sees the detector channels
+pub fn measure_gated_coincidences_T3<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    resolution: u64,
+    sync_period: u64,
+    gate: &mut GateFilter,
+    counter: &mut CoincidenceCounter,
+) -> Result<(), HydraHarpError> {
+    let smallest = counter
+        .delays
+        .iter()
+        .cloned()
+        .chain(Some(0))
+        .min()
+        .unwrap_or(0);
+    let delays = counter
+        .delays
+        .iter()
+        .map(|&delay| (delay - smallest) as u64)
+        .collect::<Vec<_>>();
+    let default_delay = -smallest as u64;
+    let mut held_back = Vec::new();
+    run_measurement_T3(d, acquisition_time, |events| {
+        let mut times = gate
+            .filter_T3(events, resolution)
+            .into_iter()
+            .map(|(channel, nsync, dtime)| {
+                let delay = delays
+                    .get(channel as usize)
+                    .cloned()
+                    .unwrap_or(default_delay);
+                (
+                    channel,
+                    nsync * sync_period + dtime as u64 * resolution + delay,
+                )
+            })
+            .collect::<Vec<_>>();
+        times.append(&mut held_back);
+        times.sort_by_key(|&(_, t)| t);
+        // The events of the next chunk are no earlier than the start of the last sync period of
+        // this one, so only the times after that can still be overtaken
+        if let Some(&(_, nsync, _)) = events.last() {
+            let latest = nsync * sync_period;
+            let split = times
+                .iter()
+                .position(|&(_, t)| t >= latest)
+                .unwrap_or_else(|| times.len());
+            held_back = times.split_off(split);
+        }
+        counter.add_times(&times);
+    })?;
+    counter.add_times(&held_back);
+    counter.end_measurement(acquisition_time as u64 * 1_000_000_000);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gate, GateFilter};
+
+    #[test]
+    fn keeps_events_inside_any_gate() {
+        let mut f = GateFilter::new(3);
+        let gates = [
+            Gate {
+                start: 100,
+                end: 200,
+            },
+            Gate {
+                start: 500,
+                end: 600,
+            },
+        ];
+        f.set_gates(1, &gates).unwrap();
+        let passed = f.filter(&[
+            (1, 50),
+            (0, 1000),
+            (1, 1150),
+            (2, 1300),
+            (1, 1400),
+            (1, 1550),
+        ]);
+        assert_eq!(passed, vec![(0, 1000), (1, 1150), (2, 1300), (1, 1550)]);
+        assert_eq!(f.kept[1], 2);
+        assert_eq!(f.dropped[1], 2);
+        assert_eq!(f.rejected[1], vec![3, 3]);
+        assert!(f.set_gates(0, &gates).is_err());
+    }
+
+    #[test]
+    fn gates_T3_events_on_start_stop_time() {
+        let mut f = GateFilter::new(3);
+        f.set_gates(
+            1,
+            &[Gate {
+                start: 100,
+                end: 200,
+            }],
+        )
+        .unwrap();
+        let passed = f.filter_T3(&[(1, 0, 1), (1, 0, 3), (2, 1, 9), (1, 2, 4), (1, 3, 5)], 50);
+        assert_eq!(passed, vec![(1, 0, 3), (2, 1, 9), (1, 2, 4)]);
+        assert_eq!(f.kept[1], 2);
+        assert_eq!(f.dropped[1], 2);
+        assert_eq!(f.rejected[1], vec![2]);
+    }
+}
@@ -0,0 +1,218 @@
+//! Heralded second order autocorrelation, g2(0), of a heralded single photon source.
+//! The signal photon goes through a beamsplitter onto detectors `a` and `b`, and each herald
+//! opens a window on both detectors.
+use pyo3::prelude::*;
+use std::collections::VecDeque;
+
+use crate::measurement::{run_measurement_T2, Measureable, Measurement};
+use crate::types::HydraHarpError;
+
+/// A window of delays relative to the herald, `start..=end` in ps. Either end can be negative
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeraldWindow {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl HeraldWindow {
+    fn contains(&self, delay: i64) -> bool {
+        delay >= self.start && delay <= self.end
+    }
+}
+
+/// Counts the three-fold coincidence events needed for the heralded g2(0)
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeraldedCounter {
+    pub herald: u8,
+    pub channel_a: u8,
+    pub channel_b: u8,
+    pub window_a: HeraldWindow,
+    pub window_b: HeraldWindow,
+    /// The number of heralds
+    pub heralds: u64,
+    /// Heralds with a detection on `a`
+    pub herald_a: u64,
+    /// Heralds with a detection on `b`
+    pub herald_b: u64,
+    /// Heralds with detections on both `a` and `b`
+    pub herald_ab: u64,
+    open_heralds: VecDeque<u64>,
+    buffer_a: VecDeque<u64>,
+    buffer_b: VecDeque<u64>,
+}
+
+impl HeraldedCounter {
+    /// Returns `InvalidArgument` if either window is empty
+    pub fn new(
+        herald: u8,
+        channel_a: u8,
+        channel_b: u8,
+        window_a: HeraldWindow,
+        window_b: HeraldWindow,
+    ) -> Result<HeraldedCounter, HydraHarpError> {
+        if window_a.end < window_a.start || window_b.end < window_b.start {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        Ok(HeraldedCounter {
+            herald,
+            channel_a,
+            channel_b,
+            window_a,
+            window_b,
+            heralds: 0,
+            herald_a: 0,
+            herald_b: 0,
+            herald_ab: 0,
+            open_heralds: VecDeque::new(),
+            buffer_a: VecDeque::new(),
+            buffer_b: VecDeque::new(),
+        })
+    }
+
+    /// Count the detections in the windows of the oldest open herald and close it
+    fn close_herald(&mut self) {
+        if let Some(h) = self.open_heralds.pop_front() {
+            let h = h as i64;
+            let window_a = self.window_a;
+            let window_b = self.window_b;
+            let fired_a = self
+                .buffer_a
+                .iter()
+                .any(|&t| window_a.contains(t as i64 - h));
+            let fired_b = self
+                .buffer_b
+                .iter()
+                .any(|&t| window_b.contains(t as i64 - h));
+            self.heralds += 1;
+            self.herald_a += fired_a as u64;
+            self.herald_b += fired_b as u64;
+            self.herald_ab += (fired_a && fired_b) as u64;
+        }
+    }
+
+    /// Add a chunk of channels and times.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last
+    pub fn add_times(&mut self, times: &[(u8, u64)]) {
+        let end = std::cmp::max(self.window_a.end, self.window_b.end);
+        for &(channel, time) in times.iter() {
+            if channel != self.herald && channel != self.channel_a && channel != self.channel_b {
+                continue;
+            }
+            // A herald can be closed once nothing later can land in its windows
+            while self
+                .open_heralds
+                .front()
+                .map_or(false, |&h| time as i64 > h as i64 + end)
+            {
+                self.close_herald();
+            }
+            if channel == self.herald {
+                self.open_heralds.push_back(time);
+            }
+            if channel == self.channel_a {
+                self.buffer_a.push_back(time);
+            }
+            if channel == self.channel_b {
+                self.buffer_b.push_back(time);
+            }
+            // Detections before the start of the window of the oldest herald we could still see are no use
+            let oldest = self.open_heralds.front().map_or(time, |&h| h) as i64;
+            while self
+                .buffer_a
+                .front()
+                .map_or(false, |&t| (t as i64) < oldest + self.window_a.start)
+            {
+                self.buffer_a.pop_front();
+            }
+            while self
+                .buffer_b
+                .front()
+                .map_or(false, |&t| (t as i64) < oldest + self.window_b.start)
+            {
+                self.buffer_b.pop_front();
+            }
+        }
+    }
+
+    /// Count the heralds still waiting for the end of their windows and forget the buffered events.
+    /// Call this at the end of each measurement, as the times restart from zero in the next one
+    pub fn end_measurement(&mut self) {
+        while !self.open_heralds.is_empty() {
+            self.close_herald();
+        }
+        self.buffer_a.clear();
+        self.buffer_b.clear();
+    }
+
+    /// The heralded g2(0) = `N_h * N_hab / (N_ha * N_hb)` and its Poissonian error, as `(g2, error)`.
+    /// Returns `None` until there has been a herald with each of `a` and `b`
+    pub fn g2(&self) -> Option<(f64, f64)> {
+        if self.herald_a == 0 || self.herald_b == 0 {
+            return None;
+        }
+        let (h, a, b, ab) = (
+            self.heralds as f64,
+            self.herald_a as f64,
+            self.herald_b as f64,
+            self.herald_ab as f64,
+        );
+        let g2 = h * ab / (a * b);
+        let error = if ab > 0.0 {
+            g2 * (1.0 / h + 1.0 / a + 1.0 / b + 1.0 / ab).sqrt()
+        } else {
+            // With no triples, use the error from a single count
+            h / (a * b)
+        };
+        Some((g2, error))
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, adding the counts to `counter`
+pub fn measure_heralded<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    counter: &mut HeraldedCounter,
+) -> Result<(), HydraHarpError> {
+    run_measurement_T2(d, acquisition_time, |times| counter.add_times(times))?;
+    counter.end_measurement();
+    Ok(())
+}
+
+/// Add recorded T2 fifo records to `counter`, eg. from a file saved from an earlier measurement
+pub fn add_heralded_records(records: &[u32], counter: &mut HeraldedCounter) {
+    let mut channel_times = Measurement::new(0).convert_values_T2(records);
+    channel_times.sort_by_key(|(_, t)| *t);
+    counter.add_times(&channel_times);
+    counter.end_measurement();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeraldWindow, HeraldedCounter};
+
+    #[test]
+    fn counts_herald_combinations() {
+        let w = HeraldWindow {
+            start: -100,
+            end: 100,
+        };
+        let mut c = HeraldedCounter::new(0, 1, 2, w, w).unwrap();
+        c.add_times(&[
+            (0, 1000),
+            (1, 1050),
+            (1, 1950),
+            (0, 2000),
+            (2, 2010),
+            (0, 3000),
+            (0, 4000),
+            (2, 4100),
+        ]);
+        c.end_measurement();
+        assert_eq!(
+            (c.heralds, c.herald_a, c.herald_b, c.herald_ab),
+            (4, 2, 2, 1)
+        );
+        assert_eq!(c.g2().unwrap().0, 1.0);
+    }
+}
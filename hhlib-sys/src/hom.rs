@@ -0,0 +1,199 @@
+//! Hong-Ou-Mandel dip analysis. A delay is scanned between the two photons and the coincidences
+//! between the two outputs of the beamsplitter are measured at each position. The dip is fitted
+//! to find the visibility, width and centre.
+use pyo3::prelude::*;
+use std::cmp::Ordering;
+
+use crate::coincidence::{CoincidenceCounter, PairStatistics};
+use crate::fitting::levenberg_marquardt;
+use crate::types::HydraHarpError;
+
+/// The shape of the dip
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DipShape {
+    /// `exp(-4 ln2 x^2 / width^2)`, where the width is the FWHM
+    Gaussian,
+    /// `sin(pi x / width) / (pi x / width)`, where the width is the distance from the centre to the
+    /// first zero. This is the dip from photons with rectangular spectra
+    Sinc,
+}
+
+impl DipShape {
+    fn profile(self, x: f64) -> f64 {
+        match self {
+            DipShape::Gaussian => (-4.0 * std::f64::consts::LN_2 * x * x).exp(),
+            DipShape::Sinc => {
+                let u = std::f64::consts::PI * x;
+                if u.abs() < 1e-9 {
+                    1.0
+                } else {
+                    u.sin() / u
+                }
+            }
+        }
+    }
+}
+
+/// The result of fitting `background * (1 - visibility * shape((x - centre) / width))` to a scan.
+/// All of the errors are one standard deviation
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DipFit {
+    #[prop(get)]
+    pub visibility: f64,
+    #[prop(get)]
+    pub visibility_error: f64,
+    /// Width of the dip in the units of the positions, see `DipShape`
+    #[prop(get)]
+    pub width: f64,
+    #[prop(get)]
+    pub width_error: f64,
+    #[prop(get)]
+    pub centre: f64,
+    #[prop(get)]
+    pub centre_error: f64,
+    /// The coincidences far from the dip
+    #[prop(get)]
+    pub background: f64,
+    #[prop(get)]
+    pub background_error: f64,
+    #[prop(get)]
+    pub reduced_chi_squared: f64,
+}
+
+/// The points of a HOM scan, the coincidences between two channels at each position of the delay.
+/// A position can be measured several times, each measurement is a separate point in the fit
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomScan {
+    /// The lower of the two channels
+    pub channel_a: u8,
+    pub channel_b: u8,
+    /// `(position, statistics)` of each measurement
+    pub points: Vec<(f64, PairStatistics)>,
+}
+
+impl HomScan {
+    /// The channels can be given in either order
+    pub fn new(channel_a: u8, channel_b: u8) -> HomScan {
+        HomScan {
+            channel_a: channel_a.min(channel_b),
+            channel_b: channel_a.max(channel_b),
+            points: Vec::new(),
+        }
+    }
+
+    /// Add the result of a measurement at `position` from a coincidence counter.
+    /// Returns `InvalidArgument` if the counter doesn't have the channels
+    pub fn add_counter(
+        &mut self,
+        position: f64,
+        counter: &CoincidenceCounter,
+    ) -> Result<(), HydraHarpError> {
+        let statistics = counter.pair(self.channel_a, self.channel_b)?;
+        self.points.push((position, statistics));
+        Ok(())
+    }
+
+    /// Fit a dip of `shape` to the scan. If `subtract_accidentals` is true, the coincidences have
+    /// the accidentals subtracted first.
+    /// Returns `InvalidArgument` if there aren't enough points or the fit fails
+    pub fn fit(
+        &self,
+        shape: DipShape,
+        subtract_accidentals: bool,
+    ) -> Result<DipFit, HydraHarpError> {
+        let x = self.points.iter().map(|(p, _)| *p).collect::<Vec<_>>();
+        let (y, sigma): (Vec<f64>, Vec<f64>) = self
+            .points
+            .iter()
+            .map(|(_, s)| {
+                if subtract_accidentals {
+                    (s.subtracted, s.subtracted_error.max(1.0))
+                } else {
+                    (s.coincidences as f64, s.coincidences_error.max(1.0))
+                }
+            })
+            .unzip();
+        fit_dip(&x, &y, &sigma, shape)
+    }
+}
+
+/// Fit a dip of `shape` to the coincidences `y` with errors `sigma` at the positions `x`.
+/// Returns `InvalidArgument` if there are fewer than 5 points, `y` isn't finite or the fit fails
+pub fn fit_dip(
+    x: &[f64],
+    y: &[f64],
+    sigma: &[f64],
+    shape: DipShape,
+) -> Result<DipFit, HydraHarpError> {
+    if x.len() < 5 || x.len() != y.len() || y.iter().any(|y| !y.is_finite()) {
+        return Err(HydraHarpError::InvalidArgument);
+    }
+    // The parameters are the background, visibility, centre and width
+    let model = |p: &[f64]| {
+        x.iter()
+            .map(|&x| p[0] * (1.0 - p[1] * shape.profile((x - p[2]) / p[3])))
+            .collect::<Vec<_>>()
+    };
+
+    let (lowest, _) = x
+        .iter()
+        .zip(y.iter())
+        .min_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(Ordering::Equal))
+        .unwrap();
+    let (first, last) = x.iter().fold(
+        (std::f64::INFINITY, std::f64::NEG_INFINITY),
+        |(lo, hi), &x| (lo.min(x), hi.max(x)),
+    );
+    let background = y.iter().cloned().fold(0.0, f64::max);
+    let minimum = y.iter().cloned().fold(std::f64::INFINITY, f64::min);
+    let initial = [
+        background,
+        1.0 - minimum / background.max(1e-12),
+        *lowest,
+        (last - first) / 5.0,
+    ];
+
+    let fit = levenberg_marquardt(model, y, sigma, &initial)?;
+    let (p, e) = (&fit.params, &fit.errors);
+    Ok(DipFit {
+        visibility: p[1],
+        visibility_error: e[1],
+        width: p[3].abs(),
+        width_error: e[3],
+        centre: p[2],
+        centre_error: e[2],
+        background: p[0],
+        background_error: e[0],
+        reduced_chi_squared: fit.reduced_chi_squared,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fit_dip, DipShape, HomScan};
+
+    #[test]
+    fn fits_gaussian_dip() {
+        let x = (0..41).map(|i| i as f64 * 0.5).collect::<Vec<_>>();
+        let y = x
+            .iter()
+            .map(|x| 1000.0 * (1.0 - 0.9 * DipShape::Gaussian.profile((x - 11.0) / 3.0)))
+            .collect::<Vec<_>>();
+        let sigma = y.iter().map(|y: &f64| y.sqrt()).collect::<Vec<_>>();
+        let fit = fit_dip(&x, &y, &sigma, DipShape::Gaussian).unwrap();
+        assert!((fit.visibility - 0.9).abs() < 1e-3);
+        assert!((fit.centre - 11.0).abs() < 1e-3);
+        assert!((fit.width - 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn channels_are_ordered_and_nan_is_an_error() {
+        let scan = HomScan::new(3, 1);
+        assert_eq!((scan.channel_a, scan.channel_b), (1, 3));
+        let x = (0..5).map(|i| i as f64).collect::<Vec<_>>();
+        let y = [1.0, 0.5, std::f64::NAN, 0.5, 1.0];
+        assert!(fit_dip(&x, &y, &[1.0; 5], DipShape::Sinc).is_err());
+    }
+}
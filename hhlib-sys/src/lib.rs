@@ -10,8 +10,35 @@ pub mod bindings {
     include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 }
 
+pub mod archive;
+pub mod calibration;
+#[cfg(feature = "catalogue")]
+pub mod catalogue;
+pub mod chsh;
+pub mod coincidence;
+pub mod config;
+pub mod correlation;
 pub mod device;
+pub mod export;
+pub mod faults;
+pub mod fitting;
+pub mod gating;
+pub mod heralded;
+pub mod hom;
+pub mod lifetime;
+pub mod logic;
 pub mod measurement;
+pub mod mub;
+pub mod multi_tau;
+pub mod pipeline;
+pub mod ptu;
+pub mod raw;
+pub mod reader;
+pub mod replay;
+pub mod segment;
+pub mod simulator;
+pub mod tomography;
+pub mod trace;
 pub mod types;
 #[cfg(feature = "pyo3")]
 pub mod python_wrapper;
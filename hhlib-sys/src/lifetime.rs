@@ -0,0 +1,132 @@
+//! TCSPC start-stop histograms of each channel, for measuring fluorescence lifetimes.
+//! Filled either from T3 records, or from T2 times using the delay after the most recent sync.
+use pyo3::prelude::*;
+
+use crate::measurement::{run_measurement_T2, run_measurement_T3, Measureable};
+use crate::types::HydraHarpError;
+
+/// A histogram of the delay after the sync for each channel
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifetimeHistogram {
+    /// Width of each bin in ps
+    pub bin_width: u64,
+    /// `histograms[channel][bin]`. The sync, channel 0, is never counted
+    pub histograms: Vec<Vec<u64>>,
+    /// Events later than the last bin
+    pub overflows: Vec<u64>,
+    last_sync: Option<u64>,
+}
+
+impl LifetimeHistogram {
+    /// Histograms for the first `channels` channels, including the sync, with `bins` bins of `bin_width` ps.
+    /// Returns `InvalidArgument` if the bin width is zero
+    pub fn new(
+        channels: usize,
+        bin_width: u64,
+        bins: usize,
+    ) -> Result<LifetimeHistogram, HydraHarpError> {
+        if bin_width == 0 {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        Ok(LifetimeHistogram {
+            bin_width,
+            histograms: vec![vec![0; bins]; channels],
+            overflows: vec![0; channels],
+            last_sync: None,
+        })
+    }
+
+    /// The start of each bin in ps
+    pub fn tau(&self) -> Vec<f64> {
+        let bins = self.histograms.first().map_or(0, |h| h.len());
+        (0..bins)
+            .map(|i| (i as u64 * self.bin_width) as f64)
+            .collect()
+    }
+
+    fn add_delay(&mut self, channel: u8, delay: u64) {
+        let c = channel as usize;
+        if c == 0 || c >= self.histograms.len() {
+            return;
+        }
+        let bin = (delay / self.bin_width) as usize;
+        match self.histograms[c].get_mut(bin) {
+            Some(count) => *count += 1,
+            None => self.overflows[c] += 1,
+        }
+    }
+
+    /// Add a chunk of T2 channels and times, using the delay of each event after the most recent sync.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last
+    pub fn add_times(&mut self, times: &[(u8, u64)]) {
+        for &(channel, time) in times.iter() {
+            if channel == 0 {
+                self.last_sync = Some(time);
+            } else if let Some(delay) = self.last_sync.and_then(|s| time.checked_sub(s)) {
+                self.add_delay(channel, delay);
+            }
+        }
+    }
+
+    /// Add T3 `(channel, sync number, start-stop time)` events, where the start-stop time is in
+    /// units of `resolution` ps
+    pub fn add_T3(&mut self, events: &[(u8, u64, u32)], resolution: u64) {
+        for &(channel, _, dtime) in events.iter() {
+            self.add_delay(channel, dtime as u64 * resolution);
+        }
+    }
+
+    /// Forget the last sync. Call this at the end of each measurement, as the times restart from
+    /// zero in the next one
+    pub fn end_measurement(&mut self) {
+        self.last_sync = None;
+    }
+}
+
+/// Run a T2 measurement for `acquisition_time` ms, adding the delays after the sync to `histogram`
+pub fn measure_lifetime<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    histogram: &mut LifetimeHistogram,
+) -> Result<(), HydraHarpError> {
+    run_measurement_T2(d, acquisition_time, |times| histogram.add_times(times))?;
+    histogram.end_measurement();
+    Ok(())
+}
+
+/// Run a T3 measurement for `acquisition_time` ms, adding the start-stop times to `histogram`.
+/// `resolution` is the T3 resolution of the device in ps
+pub fn measure_lifetime_T3<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    resolution: u64,
+    histogram: &mut LifetimeHistogram,
+) -> Result<(), HydraHarpError> {
+    run_measurement_T3(d, acquisition_time, |events| {
+        histogram.add_T3(events, resolution)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LifetimeHistogram;
+
+    #[test]
+    fn delays_are_from_last_sync() {
+        let mut h = LifetimeHistogram::new(3, 100, 5).unwrap();
+        h.add_times(&[
+            (1, 50),
+            (0, 1000),
+            (1, 1050),
+            (2, 1250),
+            (0, 2000),
+            (2, 2820),
+        ]);
+        assert_eq!(h.histograms[1], vec![1, 0, 0, 0, 0]);
+        assert_eq!(h.histograms[2], vec![0, 0, 1, 0, 0]);
+        assert_eq!(h.overflows, vec![0, 0, 1]);
+        h.add_T3(&[(1, 7, 3)], 64);
+        assert_eq!(h.histograms[1][1], 1);
+    }
+}
@@ -0,0 +1,347 @@
+//! Boolean coincidence logic, for veto and anti-coincidence counting.
+//! Each event on the trigger channel is counted if a condition built from AND, OR and NOT of
+//! windows on other channels holds. For example, counting events on channel 1 when channel 2
+//! did not fire within 5ns is the condition `!2[-5000,5000]` with trigger 1.
+use pyo3::prelude::*;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use crate::measurement::{run_measurement_T2, Measureable};
+use crate::types::HydraHarpError;
+
+/// A condition on the events around a trigger
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// There is an event on `channel` with a delay from the trigger in `start..=end` ps
+    Fired {
+        channel: u8,
+        start: i64,
+        end: i64,
+    },
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    /// An event on `channel` within `width` ps either side of `delay` ps after the trigger
+    pub fn within(channel: u8, delay: i64, width: i64) -> Condition {
+        Condition::Fired {
+            channel,
+            start: delay - width,
+            end: delay + width,
+        }
+    }
+
+    /// Every channel used in the condition
+    fn channels(&self, out: &mut Vec<u8>) {
+        match self {
+            Condition::Fired { channel, .. } => {
+                if !out.contains(channel) {
+                    out.push(*channel)
+                }
+            }
+            Condition::And(cs) | Condition::Or(cs) => cs.iter().for_each(|c| c.channels(out)),
+            Condition::Not(c) => c.channels(out),
+        }
+    }
+
+    /// The earliest and latest delays the condition looks at
+    fn extent(&self) -> (i64, i64) {
+        match self {
+            Condition::Fired { start, end, .. } => (*start, *end),
+            Condition::And(cs) | Condition::Or(cs) => cs
+                .iter()
+                .map(|c| c.extent())
+                .fold((0, 0), |(s, e), (cs, ce)| (s.min(cs), e.max(ce))),
+            Condition::Not(c) => c.extent(),
+        }
+    }
+
+    /// Check the condition for a trigger at `trigger`, given the buffered `(channel, time)` events
+    fn evaluate(&self, trigger: (u8, u64), events: &VecDeque<(u8, u64)>) -> bool {
+        match self {
+            Condition::Fired {
+                channel,
+                start,
+                end,
+            } => events.iter().any(|&(c, t)| {
+                let delay = t as i64 - trigger.1 as i64;
+                c == *channel && delay >= *start && delay <= *end && (c, t) != trigger
+            }),
+            Condition::And(cs) => cs.iter().all(|c| c.evaluate(trigger, events)),
+            Condition::Or(cs) => cs.iter().any(|c| c.evaluate(trigger, events)),
+            Condition::Not(c) => !c.evaluate(trigger, events),
+        }
+    }
+}
+
+/// Parses conditions written like `(1[-500,500] | 3[0,1000]) & !2[-5000,5000]`, where
+/// `c[start,end]` is an event on channel `c` between `start` and `end` ps after the trigger.
+/// `!` binds tightest, then `&`, then `|`. Returns `InvalidArgument` if the text can't be parsed
+impl FromStr for Condition {
+    type Err = HydraHarpError;
+
+    fn from_str(s: &str) -> Result<Condition, HydraHarpError> {
+        let tokens = tokenise(s)?;
+        let mut position = 0;
+        let condition = parse_or(&tokens, &mut position)?;
+        if position == tokens.len() {
+            Ok(condition)
+        } else {
+            Err(HydraHarpError::InvalidArgument)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(char),
+}
+
+fn tokenise(s: &str) -> Result<Vec<Token>, HydraHarpError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '-' {
+            let mut number = String::new();
+            number.push(c);
+            chars.next();
+            while let Some(&d) = chars.peek() {
+                if d.is_ascii_digit() {
+                    number.push(d);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let n = number
+                .parse()
+                .map_err(|_| HydraHarpError::InvalidArgument)?;
+            tokens.push(Token::Number(n));
+        } else if "()[],&|!".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+    }
+    Ok(tokens)
+}
+
+fn expect(tokens: &[Token], position: &mut usize, symbol: char) -> Result<(), HydraHarpError> {
+    if tokens.get(*position) == Some(&Token::Symbol(symbol)) {
+        *position += 1;
+        Ok(())
+    } else {
+        Err(HydraHarpError::InvalidArgument)
+    }
+}
+
+fn number(tokens: &[Token], position: &mut usize) -> Result<i64, HydraHarpError> {
+    match tokens.get(*position) {
+        Some(&Token::Number(n)) => {
+            *position += 1;
+            Ok(n)
+        }
+        _ => Err(HydraHarpError::InvalidArgument),
+    }
+}
+
+fn parse_or(tokens: &[Token], position: &mut usize) -> Result<Condition, HydraHarpError> {
+    let mut terms = vec![parse_and(tokens, position)?];
+    while tokens.get(*position) == Some(&Token::Symbol('|')) {
+        *position += 1;
+        terms.push(parse_and(tokens, position)?);
+    }
+    Ok(if terms.len() == 1 {
+        terms.remove(0)
+    } else {
+        Condition::Or(terms)
+    })
+}
+
+fn parse_and(tokens: &[Token], position: &mut usize) -> Result<Condition, HydraHarpError> {
+    let mut terms = vec![parse_factor(tokens, position)?];
+    while tokens.get(*position) == Some(&Token::Symbol('&')) {
+        *position += 1;
+        terms.push(parse_factor(tokens, position)?);
+    }
+    Ok(if terms.len() == 1 {
+        terms.remove(0)
+    } else {
+        Condition::And(terms)
+    })
+}
+
+fn parse_factor(tokens: &[Token], position: &mut usize) -> Result<Condition, HydraHarpError> {
+    match tokens.get(*position) {
+        Some(Token::Symbol('!')) => {
+            *position += 1;
+            Ok(Condition::Not(Box::new(parse_factor(tokens, position)?)))
+        }
+        Some(Token::Symbol('(')) => {
+            *position += 1;
+            let condition = parse_or(tokens, position)?;
+            expect(tokens, position, ')')?;
+            Ok(condition)
+        }
+        Some(&Token::Number(channel)) if channel >= 0 && channel < 256 => {
+            *position += 1;
+            expect(tokens, position, '[')?;
+            let start = number(tokens, position)?;
+            expect(tokens, position, ',')?;
+            let end = number(tokens, position)?;
+            expect(tokens, position, ']')?;
+            if end < start {
+                return Err(HydraHarpError::InvalidArgument);
+            }
+            Ok(Condition::Fired {
+                channel: channel as u8,
+                start,
+                end,
+            })
+        }
+        _ => Err(HydraHarpError::InvalidArgument),
+    }
+}
+
+/// Counts the events on `trigger` for which `condition` holds
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConditionalCounter {
+    pub trigger: u8,
+    pub condition: Condition,
+    /// The number of events on the trigger channel
+    pub triggers: u64,
+    /// The number of those events for which the condition held
+    pub counts: u64,
+    channels: Vec<u8>,
+    extent: (i64, i64),
+    open_triggers: VecDeque<u64>,
+    buffer: VecDeque<(u8, u64)>,
+}
+
+impl ConditionalCounter {
+    pub fn new(trigger: u8, condition: Condition) -> ConditionalCounter {
+        let mut channels = Vec::new();
+        condition.channels(&mut channels);
+        let extent = condition.extent();
+        ConditionalCounter {
+            trigger,
+            condition,
+            triggers: 0,
+            counts: 0,
+            channels,
+            extent,
+            open_triggers: VecDeque::new(),
+            buffer: VecDeque::new(),
+        }
+    }
+
+    fn close_trigger(&mut self) {
+        if let Some(t) = self.open_triggers.pop_front() {
+            self.triggers += 1;
+            if self.condition.evaluate((self.trigger, t), &self.buffer) {
+                self.counts += 1;
+            }
+        }
+    }
+
+    /// Add a chunk of channels and times.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last
+    pub fn add_times(&mut self, times: &[(u8, u64)]) {
+        let (start, end) = self.extent;
+        for &(channel, time) in times.iter() {
+            let used = self.channels.contains(&channel);
+            if channel != self.trigger && !used {
+                continue;
+            }
+            while self
+                .open_triggers
+                .front()
+                .map_or(false, |&t| time as i64 > t as i64 + end)
+            {
+                self.close_trigger();
+            }
+            if channel == self.trigger {
+                self.open_triggers.push_back(time);
+            }
+            if used {
+                self.buffer.push_back((channel, time));
+            }
+            let oldest = self.open_triggers.front().map_or(time, |&t| t) as i64;
+            while self
+                .buffer
+                .front()
+                .map_or(false, |&(_, t)| (t as i64) < oldest + start)
+            {
+                self.buffer.pop_front();
+            }
+        }
+    }
+
+    /// Count the triggers still waiting for the end of their windows and forget the buffered events.
+    /// Call this at the end of each measurement, as the times restart from zero in the next one
+    pub fn end_measurement(&mut self) {
+        while !self.open_triggers.is_empty() {
+            self.close_trigger();
+        }
+        self.buffer.clear();
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, adding the counts to `counter`
+pub fn measure_conditional<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    counter: &mut ConditionalCounter,
+) -> Result<(), HydraHarpError> {
+    run_measurement_T2(d, acquisition_time, |times| counter.add_times(times))?;
+    counter.end_measurement();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Condition, ConditionalCounter};
+
+    #[test]
+    fn parses_precedence() {
+        let c: Condition = "1[0,10] | 2[-5,5] & !3[0,0]".parse().unwrap();
+        assert_eq!(
+            c,
+            Condition::Or(vec![
+                Condition::Fired {
+                    channel: 1,
+                    start: 0,
+                    end: 10
+                },
+                Condition::And(vec![
+                    Condition::Fired {
+                        channel: 2,
+                        start: -5,
+                        end: 5
+                    },
+                    Condition::Not(Box::new(Condition::Fired {
+                        channel: 3,
+                        start: 0,
+                        end: 0
+                    })),
+                ]),
+            ])
+        );
+        assert!("1[0,10] &".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn veto_removes_coincident_events() {
+        let mut c = ConditionalCounter::new(1, "!2[-100,100]".parse().unwrap());
+        c.add_times(&[(2, 950), (1, 1000), (1, 2000), (1, 3000), (2, 3080)]);
+        c.end_measurement();
+        assert_eq!((c.triggers, c.counts), (3, 1));
+    }
+}
@@ -6,8 +6,14 @@ use std::time::Duration;
 use crate::types::{HydraHarpError, CTCStatus};
 
 const OVERFLOW_PERIOD: u64 = 33554432;
+/// Each T2 overflow record of firmware version 1 is a single overflow of this period
+const OVERFLOW_PERIOD_V1: u64 = 33552000;
 const OVERFLOW_MASK: u32 = (63 << 25);
-const TIME_MASK: u32 = (1 << 24) - 1;
+const TIME_MASK: u32 = (1 << 25) - 1;
+
+/// Markers are put in the stream of times as channel `MARKER_CHANNEL + marker` when kept,
+/// above any input channel
+pub const MARKER_CHANNEL: u8 = 100;
 
 /// Describes the different types a T2 value can have
 pub enum T2Value {
@@ -18,6 +24,8 @@ pub enum T2Value {
     Overflow(u32),
     InternalSync(u8),
     ExternalSync(u8),
+    /// The u8 is the marker number, the u32 is the time
+    Marker(u8, u32),
 }
 
 /// Converts a single 4 byte phrase into a T2Value
@@ -26,16 +34,56 @@ pub fn convert_T2_value(v: &u32) -> T2Value {
     match (v & (1 << 31)) {
         0 => Time((v >> 25) as u8 & 63u8, v & TIME_MASK),
         _ => match (v & OVERFLOW_MASK) {
-            OVERFLOW_MASK => Overflow(v & ((1 << 24) - 1)),
+            OVERFLOW_MASK => Overflow(v & TIME_MASK),
             0 => Sync(v & TIME_MASK),
+            m if m >> 25 <= 15 => Marker((m >> 25) as u8, v & TIME_MASK),
             _ => InternalSync(0),
         },
     }
 }
 
+/// Describes the different types a T3 value can have
+pub enum T3Value {
+    /// The channel number, the sync count since the last overflow and the start-stop time
+    Time(u8, u32, u32),
+    /// The number of sync overflows
+    Overflow(u32),
+    Marker(u8),
+}
+
+const T3_SYNC_PERIOD: u64 = 1024;
+
+/// Converts a single 4 byte phrase into a T3Value
+pub fn convert_T3_value(v: &u32) -> T3Value {
+    use crate::measurement::T3Value::*;
+    let channel = (v >> 25) as u8 & 63u8;
+    let dtime = (v >> 10) & ((1 << 15) - 1);
+    let nsync = v & (T3_SYNC_PERIOD as u32 - 1);
+    match (v & (1 << 31), channel) {
+        (0, c) => Time(c, nsync, dtime),
+        (_, 63) => Overflow(nsync),
+        (_, c) => Marker(c),
+    }
+}
+
 /// The measurement struct which keeps track of timining overflows
 pub struct Measurement {
     pub time_overflow: u64,
+    /// The number of syncs before the current T3 overflow period
+    pub sync_overflow: u64,
+    /// Software delay added to each channel in ps, shifted so that the smallest is zero
+    pub delays: Vec<u64>,
+    /// The delay of the channels (and markers) without one in `delays`, which is the shift taking
+    /// a zero delay to where it is in `delays`
+    pub default_delay: u64,
+    /// The latest time read from the fifo, before the delays are added
+    latest_time: u64,
+    /// Delayed times which could still have earlier times from the next chunk go in front of them
+    held_back: Vec<(u8, u64)>,
+    /// If true, markers are kept in the converted times as channel `MARKER_CHANNEL + marker`
+    pub keep_markers: bool,
+    /// If true, the records are from firmware version 1, where each overflow record is a single overflow
+    pub version_1_records: bool,
 }
 
 impl Measurement {
@@ -43,30 +91,249 @@ impl Measurement {
     pub fn new(overflow: u64) -> Measurement {
         Measurement {
             time_overflow: overflow,
+            sync_overflow: 0,
+            delays: Vec::new(),
+            default_delay: 0,
+            latest_time: 0,
+            held_back: Vec::new(),
+            keep_markers: false,
+            version_1_records: false,
+        }
+    }
+
+    /// Define a new measurement which delays each channel by `delays[channel]` ps in software.
+    /// Only the differences between the delays matter, so they can be negative.
+    /// Channels without a delay given, and markers, have a delay of zero
+    pub fn with_delays(delays: &[i64]) -> Measurement {
+        let smallest = delays.iter().cloned().chain(Some(0)).min().unwrap_or(0);
+        let mut measurement = Measurement::new(0);
+        measurement.delays = delays.iter().map(|&d| (d - smallest) as u64).collect();
+        measurement.default_delay = -smallest as u64;
+        measurement
+    }
+
+    fn delay(&self, channel: u8) -> u64 {
+        self.delays
+            .get(channel as usize)
+            .cloned()
+            .unwrap_or(self.default_delay)
+    }
+
+    /// Convert a single fifo output in T2 mode into a channel and time, if it is one,
+    /// keeping track of the overflows
+    pub fn convert_value_T2(&mut self, v: &u32) -> Option<(u8, u64)> {
+        use crate::measurement::T2Value::*;
+        match convert_T2_value(v) {
+            Time(c, t) => {
+                self.latest_time = t as u64 + self.time_overflow;
+                Some((c + 1, self.latest_time + self.delay(c + 1)))
+            }
+            Sync(t) => {
+                self.latest_time = t as u64 + self.time_overflow;
+                Some((0, self.latest_time + self.delay(0)))
+            }
+            Overflow(_) if self.version_1_records => {
+                self.time_overflow += OVERFLOW_PERIOD_V1;
+                None
+            }
+            Overflow(t) => {
+                self.time_overflow += (t as u64) * OVERFLOW_PERIOD;
+                None
+            }
+            Marker(m, t) if self.keep_markers => {
+                self.latest_time = t as u64 + self.time_overflow;
+                Some((MARKER_CHANNEL + m, self.latest_time + self.default_delay))
+            }
+            _ => None,
         }
     }
 
     /// Convert a set of fifo outputs in T2 mode into a vector of channels and times
     /// Sets the sync channel to index zero and the rest higher
     pub fn convert_values_T2(&mut self, input: &[u32]) -> Vec<(u8, u64)> {
-        use crate::measurement::T2Value::*;
         let mut times = Vec::with_capacity(input.len());
         for i in input {
-            match convert_T2_value(i) {
-                Time(c, t) => times.push((c+1, t as u64 + self.time_overflow)),
-                Sync(t) => times.push((0, t as u64 + self.time_overflow)),
-                Overflow(t) => self.time_overflow += (t as u64) * OVERFLOW_PERIOD,
-                _ => (),
+            times.extend(self.convert_value_T2(i));
+        }
+        times
+    }
+
+    /// Convert a single fifo output in T3 mode into a `(channel, sync number, start-stop time)`,
+    /// if it is one, keeping track of the overflows
+    pub fn convert_value_T3(&mut self, v: &u32) -> Option<(u8, u64, u32)> {
+        use crate::measurement::T3Value::*;
+        match convert_T3_value(v) {
+            Time(c, nsync, dtime) => {
+                return Some((c + 1, self.sync_overflow + nsync as u64, dtime))
             }
+            // Old firmware writes a single overflow as zero
+            Overflow(0) => self.sync_overflow += T3_SYNC_PERIOD,
+            Overflow(_) if self.version_1_records => self.sync_overflow += T3_SYNC_PERIOD,
+            Overflow(n) => self.sync_overflow += n as u64 * T3_SYNC_PERIOD,
+            Marker(_) => (),
+        }
+        None
+    }
+
+    /// Convert a set of fifo outputs in T3 mode into a vector of `(channel, sync number, start-stop time)`.
+    /// The channels are numbered as in `convert_values_T2`, and the start-stop time is in units of the resolution
+    pub fn convert_values_T3(&mut self, input: &[u32]) -> Vec<(u8, u64, u32)> {
+        let mut events = Vec::with_capacity(input.len());
+        for i in input {
+            events.extend(self.convert_value_T3(i));
+        }
+        events
+    }
+
+    /// Convert a chunk of fifo outputs in T2 mode into a sorted vector of channels and times.
+    /// When there are software delays, the times which could still be overtaken by the next chunk
+    /// are held back until then, so that the chunks follow on from each other in order
+    pub fn convert_sorted_T2(&mut self, input: &[u32]) -> Vec<(u8, u64)> {
+        let mut times = self.convert_values_T2(input);
+        times.append(&mut self.held_back);
+        times.sort_by_key(|(_, t)| *t);
+        if self.default_delay > 0 || self.delays.iter().any(|&d| d > 0) {
+            let latest = self.latest_time;
+            let split = times
+                .iter()
+                .position(|&(_, t)| t >= latest)
+                .unwrap_or_else(|| times.len());
+            self.held_back = times.split_off(split);
         }
         times
     }
+
+    /// Get the times held back by `convert_sorted_T2`. Use at the end of a measurement
+    pub fn flush_T2(&mut self) -> Vec<(u8, u64)> {
+        std::mem::replace(&mut self.held_back, Vec::new())
+    }
+}
+
+/// Encodes `(channel, time)` values as version 2 T2 fifo records, the reverse of
+/// `Measurement::convert_values_T2` with the markers kept
+#[derive(Debug, Clone, Default)]
+pub struct T2Encoder {
+    /// The number of overflows encoded so far
+    pub overflows: u64,
+}
+
+impl T2Encoder {
+    pub fn new() -> T2Encoder {
+        T2Encoder { overflows: 0 }
+    }
+
+    /// Encode one value, passing the overflow records needed to reach it and then its record to
+    /// `push`. The times mustn't decrease
+    pub fn encode<F: FnMut(u32)>(&mut self, channel: u8, time: u64, mut push: F) {
+        let overflows = time / OVERFLOW_PERIOD;
+        while self.overflows < overflows {
+            let n = (overflows - self.overflows).min(TIME_MASK as u64);
+            push(OVERFLOW_MASK | (1 << 31) | n as u32);
+            self.overflows += n;
+        }
+        let time = time as u32 & TIME_MASK;
+        push(match channel {
+            0 => (1 << 31) | time,
+            c if c >= MARKER_CHANNEL => (1 << 31) | (((c - MARKER_CHANNEL) as u32) << 25) | time,
+            c => ((c as u32 - 1) << 25) | time,
+        });
+    }
+}
+
+/// Run a T2 measurement for `acquisition_time` ms, passing each chunk of sorted (channel, time)
+/// values to `process` as it's read from the fifo
+pub fn run_measurement_T2<M, F>(
+    d: &mut M,
+    acquisition_time: i32,
+    process: F,
+) -> Result<(), HydraHarpError>
+where
+    M: Measureable,
+    F: FnMut(&[(u8, u64)]),
+{
+    run_measurement_with_delays_T2(d, acquisition_time, &[], process)
+}
+
+/// Like `run_measurement_T2`, but delaying each channel by `delays[channel]` ps in software
+pub fn run_measurement_with_delays_T2<M, F>(
+    d: &mut M,
+    acquisition_time: i32,
+    delays: &[i64],
+    process: F,
+) -> Result<(), HydraHarpError>
+where
+    M: Measureable,
+    F: FnMut(&[(u8, u64)]),
+{
+    run_measurement_with_T2(d, acquisition_time, Measurement::with_delays(delays), process)
+}
+
+/// Like `run_measurement_T2`, but converting the fifo records with `measurement`, eg. to keep the markers
+pub fn run_measurement_with_T2<M, F>(
+    d: &mut M,
+    acquisition_time: i32,
+    mut measurement: Measurement,
+    mut process: F,
+) -> Result<(), HydraHarpError>
+where
+    M: Measureable,
+    F: FnMut(&[(u8, u64)]),
+{
+    const BUFFER_LENGTH: usize = 131072;
+    let mut buffer = vec![0u32; BUFFER_LENGTH];
+
+    d.start_measurement(acquisition_time)?;
+    loop {
+        let num_read = d.read_fifo(&mut buffer, BUFFER_LENGTH as i32)? as usize;
+        if num_read > 0 {
+            process(&measurement.convert_sorted_T2(&buffer[..num_read]));
+        } else if d.get_CTC_status()? == CTCStatus::Ended {
+            break;
+        }
+    }
+    process(&measurement.flush_T2());
+    Ok(())
+}
+
+/// Run a T3 measurement for `acquisition_time` ms, passing each chunk of
+/// `(channel, sync number, start-stop time)` values to `process` as it's read from the fifo
+pub fn run_measurement_T3<M, F>(
+    d: &mut M,
+    acquisition_time: i32,
+    mut process: F,
+) -> Result<(), HydraHarpError>
+where
+    M: Measureable,
+    F: FnMut(&[(u8, u64, u32)]),
+{
+    const BUFFER_LENGTH: usize = 131072;
+    let mut buffer = vec![0u32; BUFFER_LENGTH];
+    let mut measurement = Measurement::new(0);
+
+    d.start_measurement(acquisition_time)?;
+    loop {
+        let num_read = d.read_fifo(&mut buffer, BUFFER_LENGTH as i32)? as usize;
+        if num_read > 0 {
+            process(&measurement.convert_values_T3(&buffer[..num_read]));
+        } else if d.get_CTC_status()? == CTCStatus::Ended {
+            break;
+        }
+    }
+    Ok(())
 }
 
 pub trait Measureable {
     fn start_measurement(&mut self, acquisition_time: i32) -> Result<(), HydraHarpError>;
     fn read_fifo(&mut self, buffer: &mut [u32], records_to_fetch: i32) -> Result<i32, HydraHarpError>;
     fn get_CTC_status(&self) -> Result<CTCStatus, HydraHarpError>;
+    /// The flags of the device, eg. `FLAG_FIFOFULL`. Sources without flags never set any
+    fn get_flags(&self) -> Result<i32, HydraHarpError> {
+        Ok(0)
+    }
+    /// The warnings of the device, as a bitmask of `types::Warning`
+    fn get_warnings(&self) -> Result<i32, HydraHarpError> {
+        Ok(0)
+    }
 }
 
 pub struct TestMeasureable {
@@ -74,7 +341,7 @@ pub struct TestMeasureable {
 }
 
 impl TestMeasureable {
-    fn new() -> TestMeasureable {
+    pub fn new() -> TestMeasureable {
         TestMeasureable {
             time: 0
         }
@@ -92,3 +359,21 @@ impl Measureable for TestMeasureable {
         Ok(CTCStatus::Ended)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Measurement, T2Encoder};
+
+    #[test]
+    fn negative_delays_shift_the_unlisted_channels_too() {
+        let mut records = Vec::new();
+        let mut encoder = T2Encoder::new();
+        for &channel in [0, 1, 2, 3].iter() {
+            encoder.encode(channel, 1000, |r| records.push(r));
+        }
+        let mut measurement = Measurement::with_delays(&[0, -100]);
+        let mut times = measurement.convert_sorted_T2(&records);
+        times.append(&mut measurement.flush_T2());
+        assert_eq!(times, vec![(1, 1000), (0, 1100), (2, 1100), (3, 1100)]);
+    }
+}
@@ -0,0 +1,248 @@
+//! Entanglement witnesses for `d`-dimensional entanglement from coincidence matrices measured in
+//! two or more mutually unbiased bases (MUBs). In each basis, outcome `i` of one side is correlated
+//! with outcome `i` of the other, so the other side should measure in the complex conjugate bases.
+//!
+//! With `m` bases, the sum `C` of the fraction of coincidences on the diagonal of each basis bounds
+//! the fidelity to the maximally entangled state, `F >= (C - 1) / (m - 1)`, which is exact for a
+//! complete set of `d + 1` bases. A state with Schmidt number `r` has `C <= 1 + (m - 1) r / d`.
+use pyo3::prelude::*;
+
+use crate::coincidence::{CoincidenceCounter, PairStatistics};
+use crate::types::HydraHarpError;
+
+/// The coincidences between each outcome of one side and each outcome of the other in one basis,
+/// with their variances
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoincidenceMatrix {
+    /// `counts[a][b]`
+    pub counts: Vec<Vec<f64>>,
+    pub variances: Vec<Vec<f64>>,
+}
+
+impl CoincidenceMatrix {
+    /// An empty `dimension` x `dimension` matrix, to be filled with `set_pair`
+    pub fn new(dimension: usize) -> CoincidenceMatrix {
+        CoincidenceMatrix {
+            counts: vec![vec![0.0; dimension]; dimension],
+            variances: vec![vec![0.0; dimension]; dimension],
+        }
+    }
+
+    /// Poissonian counts, `counts[a][b]`.
+    /// Returns `InvalidArgument` if the matrix isn't square
+    pub fn from_counts(counts: &[Vec<u64>]) -> Result<CoincidenceMatrix, HydraHarpError> {
+        if counts.iter().any(|row| row.len() != counts.len()) {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let counts = counts
+            .iter()
+            .map(|row| row.iter().map(|&c| c as f64).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        Ok(CoincidenceMatrix {
+            variances: counts.clone(),
+            counts,
+        })
+    }
+
+    /// Set the coincidences of outcomes `a` and `b`, eg. from a measurement with the SLMs showing
+    /// those basis states, optionally with the accidentals subtracted.
+    /// Returns `InvalidArgument` if either outcome is outside the matrix
+    pub fn set_pair(
+        &mut self,
+        a: usize,
+        b: usize,
+        statistics: &PairStatistics,
+        subtract_accidentals: bool,
+    ) -> Result<(), HydraHarpError> {
+        if a >= self.dimension() || b >= self.dimension() {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let (count, error) = if subtract_accidentals {
+            (statistics.subtracted, statistics.subtracted_error)
+        } else {
+            (
+                statistics.coincidences as f64,
+                statistics.coincidences_error,
+            )
+        };
+        self.counts[a][b] = count;
+        self.variances[a][b] = error * error;
+        Ok(())
+    }
+
+    /// The matrix from a coincidence counter with a detector on each outcome, where `a_channels[i]`
+    /// and `b_channels[i]` are the channels of outcome `i` of each side.
+    /// Returns `InvalidArgument` if there are different numbers of channels on each side
+    pub fn from_counter(
+        counter: &CoincidenceCounter,
+        a_channels: &[u8],
+        b_channels: &[u8],
+        subtract_accidentals: bool,
+    ) -> Result<CoincidenceMatrix, HydraHarpError> {
+        if a_channels.len() != b_channels.len() {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let mut matrix = CoincidenceMatrix::new(a_channels.len());
+        for (i, &a) in a_channels.iter().enumerate() {
+            for (j, &b) in b_channels.iter().enumerate() {
+                let statistics = counter.pair(a, b)?;
+                matrix.set_pair(i, j, &statistics, subtract_accidentals)?;
+            }
+        }
+        Ok(matrix)
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// The fraction of the coincidences on the diagonal and its error, or `None` if there are no
+    /// coincidences
+    pub fn correlation(&self) -> Option<(f64, f64)> {
+        let total = self.counts.iter().flatten().sum::<f64>();
+        if total <= 0.0 {
+            return None;
+        }
+        let diagonal = (0..self.dimension())
+            .map(|i| self.counts[i][i])
+            .sum::<f64>();
+        let p = diagonal / total;
+        let mut variance = 0.0;
+        for (i, row) in self.variances.iter().enumerate() {
+            for (j, v) in row.iter().enumerate() {
+                let derivative = if i == j {
+                    (total - diagonal) / (total * total)
+                } else {
+                    -diagonal / (total * total)
+                };
+                variance += derivative * derivative * v;
+            }
+        }
+        Some((p, variance.sqrt()))
+    }
+
+    /// The visibility `(d p - 1) / (d - 1)` and its error, where `p` is the fraction on the diagonal.
+    /// This is the weight of the maximally entangled state in a mixture with white noise that would
+    /// give the same correlation, 1 for perfect correlations and 0 for none
+    pub fn visibility(&self) -> Option<(f64, f64)> {
+        let d = self.dimension() as f64;
+        if d < 2.0 {
+            return None;
+        }
+        self.correlation()
+            .map(|(p, error)| ((d * p - 1.0) / (d - 1.0), d * error / (d - 1.0)))
+    }
+}
+
+/// The result of the MUB entanglement witness
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MubWitness {
+    /// The visibility of each basis, see `CoincidenceMatrix::visibility`
+    #[prop(get)]
+    pub visibilities: Vec<f64>,
+    #[prop(get)]
+    pub visibility_errors: Vec<f64>,
+    /// `C`, the sum of the fraction of coincidences on the diagonal of each basis
+    #[prop(get)]
+    pub correlation_sum: f64,
+    #[prop(get)]
+    pub correlation_sum_error: f64,
+    /// Lower bound on the fidelity to the maximally entangled state
+    #[prop(get)]
+    pub fidelity_bound: f64,
+    #[prop(get)]
+    pub fidelity_bound_error: f64,
+    /// The Schmidt number certified by `C`, 1 if the state could be separable
+    #[prop(get)]
+    pub schmidt_number: usize,
+    /// The number of standard deviations by which `C` exceeds the bound for one less Schmidt number,
+    /// `None` if the state could be separable
+    #[prop(get)]
+    pub significance: Option<f64>,
+}
+
+/// Evaluate the witness from the coincidence matrices of two or more mutually unbiased bases.
+/// Returns `InvalidArgument` if there are fewer than two bases, they have different dimensions,
+/// or any basis has no coincidences
+pub fn mub_witness(bases: &[CoincidenceMatrix]) -> Result<MubWitness, HydraHarpError> {
+    let dimension = bases.first().map_or(0, |b| b.dimension());
+    if bases.len() < 2 || dimension < 2 || bases.iter().any(|b| b.dimension() != dimension) {
+        return Err(HydraHarpError::InvalidArgument);
+    }
+    let correlations = bases
+        .iter()
+        .map(|b| b.correlation().ok_or(HydraHarpError::InvalidArgument))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (visibilities, visibility_errors): (Vec<f64>, Vec<f64>) =
+        bases.iter().filter_map(|b| b.visibility()).unzip();
+
+    let m = bases.len() as f64;
+    let d = dimension as f64;
+    let correlation_sum = correlations.iter().map(|(p, _)| p).sum::<f64>();
+    let correlation_sum_error = correlations.iter().map(|(_, e)| e * e).sum::<f64>().sqrt();
+
+    // A Schmidt number r state has C <= 1 + (m - 1) r / d, so C certifies the smallest r it exceeds
+    let excess = d * (correlation_sum - 1.0) / (m - 1.0);
+    let schmidt_number = if excess <= 1.0 {
+        1
+    } else {
+        (excess.ceil() as usize).min(dimension)
+    };
+    let significance = if schmidt_number > 1 {
+        let bound = 1.0 + (m - 1.0) * (schmidt_number - 1) as f64 / d;
+        Some((correlation_sum - bound) / correlation_sum_error)
+    } else {
+        None
+    };
+
+    Ok(MubWitness {
+        visibilities,
+        visibility_errors,
+        correlation_sum,
+        correlation_sum_error,
+        fidelity_bound: (correlation_sum - 1.0) / (m - 1.0),
+        fidelity_bound_error: correlation_sum_error / (m - 1.0),
+        schmidt_number,
+        significance,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mub_witness, CoincidenceMatrix};
+
+    fn isotropic(d: usize, visibility: f64) -> CoincidenceMatrix {
+        let counts = (0..d)
+            .map(|i| {
+                (0..d)
+                    .map(|j| {
+                        let white = (1.0 - visibility) * 1000.0 / d as f64;
+                        let correlated = if i == j { visibility * 1000.0 } else { 0.0 };
+                        (white + correlated).round() as u64
+                    })
+                    .collect()
+            })
+            .collect::<Vec<_>>();
+        CoincidenceMatrix::from_counts(&counts).unwrap()
+    }
+
+    #[test]
+    fn certifies_schmidt_number() {
+        let perfect = mub_witness(&[isotropic(4, 1.0), isotropic(4, 1.0)]).unwrap();
+        assert!((perfect.fidelity_bound - 1.0).abs() < 1e-9);
+        assert_eq!(perfect.schmidt_number, 4);
+        assert!((perfect.visibilities[0] - 1.0).abs() < 1e-9);
+
+        let noisy =
+            mub_witness(&[isotropic(4, 0.6), isotropic(4, 0.6), isotropic(4, 0.6)]).unwrap();
+        assert!((noisy.visibilities[1] - 0.6).abs() < 1e-3);
+        // C = 3 * (0.6 + 0.1) = 2.1, so d (C - 1) / (m - 1) = 2.2
+        assert_eq!(noisy.schmidt_number, 3);
+
+        let separable = mub_witness(&[isotropic(4, 0.2), isotropic(4, 0.2)]).unwrap();
+        assert_eq!(separable.schmidt_number, 1);
+        assert_eq!(separable.significance, None);
+    }
+}
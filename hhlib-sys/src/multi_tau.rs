@@ -0,0 +1,328 @@
+//! Multi-tau correlator for fluorescence correlation spectroscopy.
+//! Works directly on the photon arrival times, so the lag can go from the base lag up to seconds
+//! without binning the whole acquisition.
+//! The acquisition is split into segments, each of which gives its own G(τ). The curve is the mean
+//! of the segments weighted by their length, and the spread of the segments gives the error on each point.
+use pyo3::prelude::*;
+
+use crate::measurement::{run_measurement_T2, Measureable};
+use crate::types::HydraHarpError;
+
+/// Online multi-tau correlator between `channel_a` and `channel_b`.
+/// Setting both channels to the same value gives the autocorrelation.
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultiTauCorrelator {
+    pub channel_a: u8,
+    pub channel_b: u8,
+    /// The shortest lag in ps. All other lags are multiples of this
+    pub base_lag: u64,
+    /// The number of lags in the first level. Each later level adds half this many, at twice the spacing
+    pub points_per_level: usize,
+    pub levels: usize,
+    /// The length of each segment in units of `base_lag`
+    pub segment_length: u64,
+    /// G(τ) for each completed segment, NaN for the lags which don't fit in a short final segment
+    pub segments: Vec<Vec<f64>>,
+    /// The length of each segment in `segments` in units of `base_lag`. Only the final segment of
+    /// a measurement can be shorter than `segment_length`
+    pub segment_lengths: Vec<u64>,
+    /// The number of segments without any photons on one of the channels, which have no G(τ)
+    pub empty_segments: u64,
+    segment_start: u64,
+    times_a: Vec<u64>,
+    times_b: Vec<u64>,
+}
+
+/// Turn a sorted list of times into (time, number of photons at that time)
+fn weighted(times: &[u64]) -> Vec<(u64, u64)> {
+    let mut out: Vec<(u64, u64)> = Vec::with_capacity(times.len());
+    for &t in times.iter() {
+        match out.last_mut() {
+            Some((last, w)) if *last == t => *w += 1,
+            _ => out.push((t, 1)),
+        }
+    }
+    out
+}
+
+/// Halve the time resolution, merging the photons which now fall in the same bin
+fn coarsen(times: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut out: Vec<(u64, u64)> = Vec::with_capacity(times.len());
+    for &(t, w) in times.iter() {
+        match out.last_mut() {
+            Some((last, lw)) if *last == t >> 1 => *lw += w,
+            _ => out.push((t >> 1, w)),
+        }
+    }
+    out
+}
+
+/// Sum of `w_a * w_b` over all pairs where `t_b - t_a == lag`
+fn lagged_product(a: &[(u64, u64)], b: &[(u64, u64)], lag: u64) -> u64 {
+    let mut sum = 0;
+    let mut j = 0;
+    for &(t_a, w_a) in a.iter() {
+        let target = t_a + lag;
+        while j < b.len() && b[j].0 < target {
+            j += 1;
+        }
+        if j == b.len() {
+            break;
+        }
+        if b[j].0 == target {
+            sum += w_a * b[j].1;
+        }
+    }
+    sum
+}
+
+impl MultiTauCorrelator {
+    /// Create a new correlator. `points_per_level` should be even, and the segments should be
+    /// longer than the longest lag. Returns `InvalidArgument` otherwise.
+    pub fn new(
+        channel_a: u8,
+        channel_b: u8,
+        base_lag: u64,
+        points_per_level: usize,
+        levels: usize,
+        segment_length: u64,
+    ) -> Result<MultiTauCorrelator, HydraHarpError> {
+        if base_lag == 0 || points_per_level < 2 || points_per_level % 2 != 0 || levels == 0 {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let mut correlator = MultiTauCorrelator {
+            channel_a,
+            channel_b,
+            base_lag,
+            points_per_level,
+            levels,
+            segment_length: segment_length / base_lag,
+            segments: Vec::new(),
+            segment_lengths: Vec::new(),
+            empty_segments: 0,
+            segment_start: 0,
+            times_a: Vec::new(),
+            times_b: Vec::new(),
+        };
+        match correlator.lags().last() {
+            Some(&l) if l < correlator.segment_length => Ok(correlator),
+            _ => Err(HydraHarpError::InvalidArgument),
+        }
+    }
+
+    /// The lags in units of `base_lag`, along with the level each is calculated at
+    fn level_lags(&self) -> Vec<(usize, u64)> {
+        let m = self.points_per_level as u64;
+        let mut lags = Vec::new();
+        for level in 0..self.levels {
+            let first = if level == 0 { 1 } else { m / 2 + 1 };
+            for j in first..=m {
+                lags.push((level, j << level));
+            }
+        }
+        lags
+    }
+
+    /// The lags in units of `base_lag`
+    pub fn lags(&self) -> Vec<u64> {
+        self.level_lags().into_iter().map(|(_, l)| l).collect()
+    }
+
+    /// The lags in ps
+    pub fn tau(&self) -> Vec<f64> {
+        self.lags()
+            .into_iter()
+            .map(|l| (l * self.base_lag) as f64)
+            .collect()
+    }
+
+    /// Add a chunk of channels and times.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last
+    pub fn add_times(&mut self, times: &[(u8, u64)]) {
+        for &(channel, time) in times.iter() {
+            if channel != self.channel_a && channel != self.channel_b {
+                continue;
+            }
+            let t = time / self.base_lag;
+            // Times from a segment which has already been finished are dropped
+            if t < self.segment_start {
+                continue;
+            }
+            while t >= self.segment_start + self.segment_length {
+                self.finish_segment(self.segment_length);
+            }
+            if channel == self.channel_a {
+                self.times_a.push(t - self.segment_start);
+            }
+            if channel == self.channel_b {
+                self.times_b.push(t - self.segment_start);
+            }
+        }
+    }
+
+    /// Calculate G(τ) for the current segment, which is `length` long, and start the next one.
+    /// Segments without any photons on one of the channels are counted in `empty_segments`
+    fn finish_segment(&mut self, length: u64) {
+        let n_a = self.times_a.len() as f64;
+        let n_b = self.times_b.len() as f64;
+        if n_a == 0.0 || n_b == 0.0 {
+            self.empty_segments += 1;
+        } else {
+            let mut a = weighted(&self.times_a);
+            let mut b = weighted(&self.times_b);
+            let mut current_level = 0;
+            let mut g = Vec::new();
+            for (level, lag) in self.level_lags() {
+                while current_level < level {
+                    a = coarsen(&a);
+                    b = coarsen(&b);
+                    current_level += 1;
+                }
+                let bins = (length >> level) as f64;
+                let lag = lag >> level;
+                if lag as f64 >= bins {
+                    g.push(std::f64::NAN);
+                    continue;
+                }
+                let sum = lagged_product(&a, &b, lag) as f64;
+                g.push(sum * bins * bins / ((bins - lag as f64) * n_a * n_b));
+            }
+            self.segments.push(g);
+            self.segment_lengths.push(length);
+        }
+        self.segment_start += self.segment_length;
+        self.times_a.clear();
+        self.times_b.clear();
+    }
+
+    /// Finish the segments covered by the `duration` ps of the measurement, with a shorter final
+    /// segment for any time left over.
+    /// Call this at the end of each measurement, as the times restart from zero in the next one
+    pub fn end_measurement(&mut self, duration: u64) {
+        let end = duration / self.base_lag;
+        while self.segment_start + self.segment_length <= end {
+            self.finish_segment(self.segment_length);
+        }
+        if end > self.segment_start {
+            self.finish_segment(end - self.segment_start);
+        }
+        self.segment_start = 0;
+        self.times_a.clear();
+        self.times_b.clear();
+    }
+
+    /// The mean G(τ) over the segments, weighted by their length, and its standard error, as
+    /// `(g, error)`. The error of a lag is zero until at least two segments are long enough for it
+    pub fn curve(&self) -> (Vec<f64>, Vec<f64>) {
+        let lags = self.lags().len();
+        let mut mean = vec![0.0; lags];
+        let mut error = vec![0.0; lags];
+        for j in 0..lags {
+            let values = self
+                .segments
+                .iter()
+                .zip(self.segment_lengths.iter())
+                .filter(|(s, _)| !s[j].is_nan())
+                .map(|(s, &l)| (s[j], l as f64))
+                .collect::<Vec<_>>();
+            let n = values.len() as f64;
+            let total: f64 = values.iter().map(|&(_, w)| w).sum();
+            if values.is_empty() {
+                continue;
+            }
+            mean[j] = values.iter().map(|&(g, w)| g * w).sum::<f64>() / total;
+            if values.len() > 1 {
+                let variance: f64 = values
+                    .iter()
+                    .map(|&(g, w)| w * w * (g - mean[j]) * (g - mean[j]))
+                    .sum();
+                error[j] = (variance / (total * total) * n / (n - 1.0)).sqrt();
+            }
+        }
+        (mean, error)
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, adding its segments to `correlator`
+pub fn measure_multi_tau<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    correlator: &mut MultiTauCorrelator,
+) -> Result<(), HydraHarpError> {
+    run_measurement_T2(d, acquisition_time, |times| correlator.add_times(times))?;
+    correlator.end_measurement(acquisition_time as u64 * 1_000_000_000);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MultiTauCorrelator;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use rand_distr::Exp1;
+
+    /// Poissonian times at `rate` per ps for `duration` ps
+    fn poissonian(rng: &mut StdRng, rate: f64, duration: u64) -> Vec<u64> {
+        let mut times = Vec::new();
+        let mut t = 0.0;
+        loop {
+            t += rng.sample::<f64, _>(Exp1) / rate;
+            if t >= duration as f64 {
+                return times;
+            }
+            times.push(t as u64);
+        }
+    }
+
+    fn merge(a: &[u64], b: &[u64]) -> Vec<(u8, u64)> {
+        let mut times = a
+            .iter()
+            .map(|&t| (1, t))
+            .chain(b.iter().map(|&t| (2, t)))
+            .collect::<Vec<_>>();
+        times.sort_by_key(|&(_, t)| t);
+        times
+    }
+
+    #[test]
+    fn uncorrelated_light_gives_one() {
+        let mut rng = StdRng::seed_from_u64(1);
+        // 20.5 segments of 1ms at 10MHz on each channel, so the last segment is half as long
+        let duration = 20_500_000_000;
+        let a = poissonian(&mut rng, 1e-5, duration);
+        let b = poissonian(&mut rng, 1e-5, duration);
+        let mut c = MultiTauCorrelator::new(1, 2, 1000, 4, 3, 1_000_000_000).unwrap();
+        c.add_times(&merge(&a, &b));
+        c.end_measurement(duration);
+        assert_eq!(c.segments.len(), 21);
+        assert_eq!(c.segment_lengths[20], 500_000);
+        let (g, error) = c.curve();
+        for (g, e) in g.iter().zip(error.iter()) {
+            assert!((g - 1.0).abs() < 0.05, "G = {}", g);
+            assert!(*e > 0.0 && *e < 0.05);
+        }
+    }
+
+    #[test]
+    fn delayed_copy_peaks_at_the_delay() {
+        let mut rng = StdRng::seed_from_u64(2);
+        // Shorter than one segment, so there's only the final segment
+        let duration = 500_000_000;
+        let a = poissonian(&mut rng, 1e-6, duration);
+        let b = a.iter().map(|t| t + 8000).collect::<Vec<_>>();
+        let mut c = MultiTauCorrelator::new(1, 2, 1000, 4, 3, 1_000_000_000).unwrap();
+        c.add_times(&merge(&a, &b));
+        c.end_measurement(duration);
+        let (g, _) = c.curve();
+        let peak = (0..g.len()).max_by(|&i, &j| g[i].partial_cmp(&g[j]).unwrap()).unwrap();
+        assert_eq!(c.tau()[peak], 8000.0);
+        assert!(g[peak] > 100.0);
+    }
+
+    #[test]
+    fn lags_double_in_spacing_each_level() {
+        let c = MultiTauCorrelator::new(1, 1, 1000, 4, 3, 1_000_000).unwrap();
+        assert_eq!(c.lags(), vec![1, 2, 3, 4, 6, 8, 12, 16]);
+    }
+}
@@ -0,0 +1,521 @@
+//! A pipeline of time tag processing, so that one acquisition can feed several analyses.
+//! The fifo records from a source (anything `Measureable`, or recorded records) are decoded with
+//! software delays, passed through a chain of stages which filter or change the events, and then
+//! handed to every sink. Recorders are sinks too, and are given the records before they're decoded.
+//! Only T2 records are decoded.
+use pyo3::prelude::*;
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::coincidence::CoincidenceCounter;
+use crate::correlation::CrossCorrelator;
+use crate::gating::GateFilter;
+use crate::heralded::HeraldedCounter;
+use crate::lifetime::LifetimeHistogram;
+use crate::logic::ConditionalCounter;
+use crate::measurement::{Measureable, Measurement};
+use crate::multi_tau::MultiTauCorrelator;
+use crate::ptu::{PtuWriter, RecordType, StopReason};
+use crate::raw::{unix_time, RawMeasurement, RawRecorder};
+use crate::trace::TraceBinner;
+use crate::types::{CTCStatus, HydraHarpError, MeasurementMode};
+
+/// A step in a pipeline which takes sorted `(channel, time)` events and returns sorted events
+pub trait Stage {
+    fn process(&mut self, times: Vec<(u8, u64)>) -> Vec<(u8, u64)>;
+    /// Called at the end of each measurement, as the times restart from zero in the next one
+    fn end_measurement(&mut self) {}
+}
+
+/// The end of a pipeline, which takes sorted `(channel, time)` events
+pub trait Sink {
+    fn add_times(&mut self, times: &[(u8, u64)]);
+    /// Called at the end of each measurement with its length in ps
+    fn end_measurement(&mut self, duration: u64);
+}
+
+impl Stage for GateFilter {
+    fn process(&mut self, times: Vec<(u8, u64)>) -> Vec<(u8, u64)> {
+        self.filter(&times)
+    }
+
+    fn end_measurement(&mut self) {
+        GateFilter::end_measurement(self)
+    }
+}
+
+impl Sink for CrossCorrelator {
+    fn add_times(&mut self, times: &[(u8, u64)]) {
+        CrossCorrelator::add_times(self, times)
+    }
+
+    fn end_measurement(&mut self, duration: u64) {
+        CrossCorrelator::end_measurement(self, duration)
+    }
+}
+
+impl Sink for MultiTauCorrelator {
+    fn add_times(&mut self, times: &[(u8, u64)]) {
+        MultiTauCorrelator::add_times(self, times)
+    }
+
+    fn end_measurement(&mut self, duration: u64) {
+        MultiTauCorrelator::end_measurement(self, duration)
+    }
+}
+
+impl Sink for CoincidenceCounter {
+    fn add_times(&mut self, times: &[(u8, u64)]) {
+        CoincidenceCounter::add_times(self, times)
+    }
+
+    fn end_measurement(&mut self, duration: u64) {
+        CoincidenceCounter::end_measurement(self, duration)
+    }
+}
+
+impl Sink for HeraldedCounter {
+    fn add_times(&mut self, times: &[(u8, u64)]) {
+        HeraldedCounter::add_times(self, times)
+    }
+
+    fn end_measurement(&mut self, _duration: u64) {
+        HeraldedCounter::end_measurement(self)
+    }
+}
+
+impl Sink for ConditionalCounter {
+    fn add_times(&mut self, times: &[(u8, u64)]) {
+        ConditionalCounter::add_times(self, times)
+    }
+
+    fn end_measurement(&mut self, _duration: u64) {
+        ConditionalCounter::end_measurement(self)
+    }
+}
+
+impl Sink for TraceBinner {
+    fn add_times(&mut self, times: &[(u8, u64)]) {
+        TraceBinner::add_times(self, times)
+    }
+
+    fn end_measurement(&mut self, duration: u64) {
+        TraceBinner::end_measurement(self, duration)
+    }
+}
+
+impl Sink for LifetimeHistogram {
+    fn add_times(&mut self, times: &[(u8, u64)]) {
+        LifetimeHistogram::add_times(self, times)
+    }
+
+    fn end_measurement(&mut self, _duration: u64) {
+        LifetimeHistogram::end_measurement(self)
+    }
+}
+
+/// The stages a pipeline can have
+pub enum PipelineStage {
+    /// Keep only the events inside the gates after the sync
+    Gate(GateFilter),
+    /// Keep only the events on these channels
+    Channels(Vec<u8>),
+    /// Move the events on each of `from` onto channel `into`, eg. to treat several detectors as one
+    Merge {
+        from: Vec<u8>,
+        into: u8,
+    },
+    Custom(Box<dyn Stage + Send>),
+}
+
+impl Stage for PipelineStage {
+    fn process(&mut self, mut times: Vec<(u8, u64)>) -> Vec<(u8, u64)> {
+        match self {
+            PipelineStage::Gate(g) => g.process(times),
+            PipelineStage::Channels(channels) => {
+                times.retain(|(c, _)| channels.contains(c));
+                times
+            }
+            PipelineStage::Merge { from, into } => {
+                for (c, _) in times.iter_mut() {
+                    if from.contains(c) {
+                        *c = *into;
+                    }
+                }
+                times
+            }
+            PipelineStage::Custom(s) => s.process(times),
+        }
+    }
+
+    fn end_measurement(&mut self) {
+        match self {
+            PipelineStage::Gate(g) => Stage::end_measurement(g),
+            PipelineStage::Custom(s) => s.end_measurement(),
+            _ => (),
+        }
+    }
+}
+
+/// The sinks a pipeline can have. Match on them to get the results out after a measurement
+pub enum PipelineSink {
+    Correlator(CrossCorrelator),
+    MultiTau(MultiTauCorrelator),
+    Coincidences(CoincidenceCounter),
+    Heralded(HeraldedCounter),
+    Conditional(ConditionalCounter),
+    Trace(TraceBinner),
+    Lifetime(LifetimeHistogram),
+    /// Writes the records to a PTU file, which is finished by `Pipeline::finish` or when it's dropped
+    Ptu(PtuWriter<BufWriter<File>>),
+    /// Appends the records to a raw recording, adding each measurement to its sidecar
+    Raw(RawRecorder),
+    Custom(Box<dyn Sink + Send>),
+}
+
+impl PipelineSink {
+    /// The type of the records written by a recorder, or `None` for the other sinks
+    pub fn record_type(&self) -> Option<RecordType> {
+        match self {
+            PipelineSink::Ptu(w) => Some(w.record_type),
+            PipelineSink::Raw(r) => Some(r.metadata.record_type),
+            _ => None,
+        }
+    }
+
+    /// Start a measurement of `acquisition_time` ms in a raw recording
+    fn start_recording(&mut self, acquisition_time: i32) {
+        if let PipelineSink::Raw(r) = self {
+            let first_record = r.metadata.records();
+            r.metadata.measurements.push(RawMeasurement {
+                first_record,
+                records: 0,
+                acquisition_time,
+                start_time: unix_time(),
+                stop_time: 0.0,
+                flags: 0,
+                warnings: 0,
+                error: None,
+            });
+        }
+    }
+
+    /// Write fifo records to a recorder. Does nothing for the other sinks
+    fn add_records(&mut self, records: &[u32]) -> Result<(), HydraHarpError> {
+        match self {
+            PipelineSink::Ptu(w) => w.write_records(records),
+            PipelineSink::Raw(r) => {
+                r.write_records(records)?;
+                if let Some(m) = r.metadata.measurements.last_mut() {
+                    m.records += records.len() as u64;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// End the measurement of a recorder with the flags, warnings and error it stopped with.
+    /// A PTU file is finished if there was an error, and a raw recording's sidecar is saved
+    fn end_recording(
+        &mut self,
+        flags: i32,
+        warnings: i32,
+        error: Option<HydraHarpError>,
+    ) -> Result<(), HydraHarpError> {
+        match self {
+            PipelineSink::Ptu(w) if error.is_some() => w.finish(StopReason::Error),
+            PipelineSink::Raw(r) => {
+                if let Some(m) = r.metadata.measurements.last_mut() {
+                    m.stop_time = unix_time();
+                    m.flags = flags;
+                    m.warnings = warnings;
+                    m.error = error;
+                }
+                r.save()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Sink for PipelineSink {
+    fn add_times(&mut self, times: &[(u8, u64)]) {
+        match self {
+            PipelineSink::Correlator(s) => Sink::add_times(s, times),
+            PipelineSink::MultiTau(s) => Sink::add_times(s, times),
+            PipelineSink::Coincidences(s) => Sink::add_times(s, times),
+            PipelineSink::Heralded(s) => Sink::add_times(s, times),
+            PipelineSink::Conditional(s) => Sink::add_times(s, times),
+            PipelineSink::Trace(s) => Sink::add_times(s, times),
+            PipelineSink::Lifetime(s) => Sink::add_times(s, times),
+            PipelineSink::Ptu(_) | PipelineSink::Raw(_) => (),
+            PipelineSink::Custom(s) => s.add_times(times),
+        }
+    }
+
+    fn end_measurement(&mut self, duration: u64) {
+        match self {
+            PipelineSink::Correlator(s) => Sink::end_measurement(s, duration),
+            PipelineSink::MultiTau(s) => Sink::end_measurement(s, duration),
+            PipelineSink::Coincidences(s) => Sink::end_measurement(s, duration),
+            PipelineSink::Heralded(s) => Sink::end_measurement(s, duration),
+            PipelineSink::Conditional(s) => Sink::end_measurement(s, duration),
+            PipelineSink::Trace(s) => Sink::end_measurement(s, duration),
+            PipelineSink::Lifetime(s) => Sink::end_measurement(s, duration),
+            PipelineSink::Ptu(_) | PipelineSink::Raw(_) => (),
+            PipelineSink::Custom(s) => s.end_measurement(duration),
+        }
+    }
+}
+
+/// Decodes the records with software `delays`, passes them through each of `stages` in turn,
+/// then gives the result to all of `sinks`
+#[pyclass]
+pub struct Pipeline {
+    /// Software delay of each channel in ps, see `Measurement::with_delays`
+    pub delays: Vec<i64>,
+    pub stages: Vec<PipelineStage>,
+    pub sinks: Vec<PipelineSink>,
+}
+
+impl Pipeline {
+    pub fn new(delays: Vec<i64>) -> Pipeline {
+        Pipeline {
+            delays,
+            stages: Vec::new(),
+            sinks: Vec::new(),
+        }
+    }
+
+    /// Add a stage after the existing ones
+    pub fn add_stage(&mut self, stage: PipelineStage) {
+        self.stages.push(stage);
+    }
+
+    /// Add a sink, returning its index in `sinks`
+    pub fn add_sink(&mut self, sink: PipelineSink) -> usize {
+        self.sinks.push(sink);
+        self.sinks.len() - 1
+    }
+
+    /// Pass a sorted chunk of decoded events through the stages to the sinks.
+    /// Each chunk should follow on from the last
+    pub fn process(&mut self, times: Vec<(u8, u64)>) {
+        let times = self
+            .stages
+            .iter_mut()
+            .fold(times, |times, stage| stage.process(times));
+        for sink in self.sinks.iter_mut() {
+            sink.add_times(&times);
+        }
+    }
+
+    /// Tell the stages and sinks that a measurement of `duration` ps has ended
+    pub fn end_measurement(&mut self, duration: u64) {
+        for stage in self.stages.iter_mut() {
+            stage.end_measurement();
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.end_measurement(duration);
+        }
+    }
+
+    /// Returns `InvalidMode` if a recorder is for T3 records, as only T2 records are decoded
+    fn check_mode(&self) -> Result<(), HydraHarpError> {
+        let t3 = self
+            .sinks
+            .iter()
+            .filter_map(|s| s.record_type())
+            .any(|r| r.mode() != MeasurementMode::T2);
+        if t3 {
+            return Err(HydraHarpError::InvalidMode);
+        }
+        Ok(())
+    }
+
+    /// Give a chunk of fifo records to the recorders, then decode them with `measurement` and pass
+    /// them through the stages to the sinks
+    fn add_records(
+        &mut self,
+        records: &[u32],
+        measurement: &mut Measurement,
+    ) -> Result<(), HydraHarpError> {
+        for sink in self.sinks.iter_mut() {
+            sink.add_records(records)?;
+        }
+        self.process(measurement.convert_sorted_T2(records));
+        Ok(())
+    }
+
+    /// End the measurement in the recorders, with the error it stopped with if any
+    fn end_recordings(
+        &mut self,
+        flags: i32,
+        warnings: i32,
+        error: Option<HydraHarpError>,
+    ) -> Result<(), HydraHarpError> {
+        for sink in self.sinks.iter_mut() {
+            sink.end_recording(flags, warnings, error)?;
+        }
+        Ok(())
+    }
+
+    /// Run a T2 measurement on `d` for `acquisition_time` ms, feeding the records through the
+    /// pipeline. Returns `InvalidMode` if a recorder is for T3 records
+    pub fn run<M: Measureable>(
+        &mut self,
+        d: &mut M,
+        acquisition_time: i32,
+    ) -> Result<(), HydraHarpError> {
+        const BUFFER_LENGTH: usize = 131072;
+        self.check_mode()?;
+        let mut buffer = vec![0u32; BUFFER_LENGTH];
+        let mut measurement = Measurement::with_delays(&self.delays);
+        for sink in self.sinks.iter_mut() {
+            sink.start_recording(acquisition_time);
+        }
+        let mut flags = 0;
+        let mut read = || -> Result<(), HydraHarpError> {
+            d.start_measurement(acquisition_time)?;
+            loop {
+                flags |= d.get_flags()?;
+                let num_read = d.read_fifo(&mut buffer, BUFFER_LENGTH as i32)? as usize;
+                if num_read > 0 {
+                    self.add_records(&buffer[..num_read], &mut measurement)?;
+                } else if d.get_CTC_status()? == CTCStatus::Ended {
+                    return Ok(());
+                }
+            }
+        };
+        let result = read();
+        self.end_recordings(flags, d.get_warnings().unwrap_or(0), result.err())?;
+        result?;
+        self.process(measurement.flush_T2());
+        self.end_measurement(acquisition_time as u64 * 1_000_000_000);
+        Ok(())
+    }
+
+    /// Feed recorded T2 fifo records covering `duration` ps through the pipeline.
+    /// Returns `InvalidMode` if a recorder is for T3 records, or the error from writing to a recorder
+    pub fn run_records(&mut self, records: &[u32], duration: u64) -> Result<(), HydraHarpError> {
+        self.check_mode()?;
+        let mut measurement = Measurement::with_delays(&self.delays);
+        for sink in self.sinks.iter_mut() {
+            sink.start_recording((duration / 1_000_000_000) as i32);
+        }
+        let result = self.add_records(records, &mut measurement);
+        self.end_recordings(0, 0, result.err())?;
+        result?;
+        self.process(measurement.flush_T2());
+        self.end_measurement(duration);
+        Ok(())
+    }
+
+    /// Finish the PTU files and save the raw recordings of the recorders
+    pub fn finish(&mut self) -> Result<(), HydraHarpError> {
+        for sink in self.sinks.iter_mut() {
+            match sink {
+                PipelineSink::Ptu(w) => w.finish(StopReason::TimeOver)?,
+                PipelineSink::Raw(r) => r.save()?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pipeline, PipelineSink, PipelineStage};
+    use crate::coincidence::CoincidenceCounter;
+    use crate::config::DeviceConfig;
+    use crate::logic::ConditionalCounter;
+    use crate::measurement::Measurement;
+    use crate::ptu::RecordType;
+    use crate::raw::{sidecar_path, RawFile, RawMetadata, RawRecorder};
+    use crate::simulator::PhotonSimulator;
+    use crate::types::HydraHarpError;
+
+    #[test]
+    fn stages_feed_every_sink() {
+        let mut p = Pipeline::new(Vec::new());
+        p.add_stage(PipelineStage::Channels(vec![1, 2, 3]));
+        p.add_stage(PipelineStage::Merge {
+            from: vec![3],
+            into: 2,
+        });
+        let counter = p.add_sink(PipelineSink::Coincidences(CoincidenceCounter::new(
+            3, 100, None,
+        )));
+        let conditional = p.add_sink(PipelineSink::Conditional(ConditionalCounter::new(
+            1,
+            "2[-100,100]".parse().unwrap(),
+        )));
+        p.process(vec![(0, 0), (1, 1000), (3, 1050), (1, 5000), (4, 5010)]);
+        p.end_measurement(1_000_000);
+        match &p.sinks[counter] {
+            PipelineSink::Coincidences(c) => {
+                assert_eq!(c.singles, vec![0, 2, 1]);
+                assert_eq!(c.coincidences[1][2], 1);
+            }
+            _ => panic!(),
+        }
+        match &p.sinks[conditional] {
+            PipelineSink::Conditional(c) => assert_eq!((c.triggers, c.counts), (2, 1)),
+            _ => panic!(),
+        }
+    }
+    #[test]
+    fn recorder_gets_the_records_of_the_analysis() {
+        let path = std::env::temp_dir().join(format!(
+            "hhlib_sys_pipeline_test_{}.bin",
+            std::process::id()
+        ));
+        let metadata = |record_type| RawMetadata {
+            record_type,
+            serial: "1234567".to_string(),
+            model: String::new(),
+            part_number: String::new(),
+            hardware_version: String::new(),
+            resolution: 1.0,
+            sync_period: None,
+            config: DeviceConfig::new(2),
+            measurements: Vec::new(),
+        };
+        let mut simulator = PhotonSimulator::new(3);
+        simulator.singles.push((1, 1e5));
+        simulator.singles.push((2, 1e5));
+        let mut p = Pipeline::new(Vec::new());
+        let counter = p.add_sink(PipelineSink::Coincidences(CoincidenceCounter::new(
+            3, 100, None,
+        )));
+        let recorder = RawRecorder::create(&path, metadata(RecordType::HydraHarp2T2)).unwrap();
+        p.add_sink(PipelineSink::Raw(recorder));
+        p.run(&mut simulator, 10).unwrap();
+        p.finish().unwrap();
+
+        let file = RawFile::open(&path).unwrap();
+        assert_eq!(file.metadata.measurements.len(), 1);
+        assert_eq!(file.records(), file.metadata.records());
+        let mut singles = vec![0; 3];
+        file.read_T2(Measurement::new(0), |t| {
+            for &(c, _) in t.iter() {
+                singles[c as usize] += 1;
+            }
+        })
+        .unwrap();
+        match &p.sinks[counter] {
+            PipelineSink::Coincidences(c) => assert_eq!(c.singles, singles),
+            _ => panic!(),
+        }
+
+        let mut p = Pipeline::new(Vec::new());
+        let recorder = RawRecorder::create(&path, metadata(RecordType::HydraHarp2T3)).unwrap();
+        p.add_sink(PipelineSink::Raw(recorder));
+        assert_eq!(p.run(&mut simulator, 10), Err(HydraHarpError::InvalidMode));
+        drop(p);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(sidecar_path(&path)).unwrap();
+    }
+}
@@ -0,0 +1,533 @@
+//! Writing raw T2/T3 fifo records to PicoQuant PTU files, which can be opened by SymPhoTime,
+//! QuCoa and the community readers. The header is a list of tags, each a 32 byte name, an index,
+//! a type and an 8 byte value, with strings following their tag. The records follow the header
+//! unchanged, so a file can be streamed straight from the fifo.
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{DeviceConfig, InputConfig};
+use crate::device::Device;
+use crate::measurement::Measureable;
+use crate::types::{CTCStatus, HydraHarpError, MeasurementMode};
+
+pub(crate) const MAGIC: &[u8; 8] = b"PQTTTR\0\0";
+const FORMAT_VERSION: &[u8; 8] = b"1.0.00\0\0";
+
+pub(crate) const TAG_EMPTY: u32 = 0xFFFF_0008;
+pub(crate) const TAG_BOOL: u32 = 0x0000_0008;
+pub(crate) const TAG_INT: u32 = 0x1000_0008;
+pub(crate) const TAG_BIT_SET: u32 = 0x1100_0008;
+pub(crate) const TAG_COLOUR: u32 = 0x1200_0008;
+pub(crate) const TAG_FLOAT: u32 = 0x2000_0008;
+pub(crate) const TAG_DATE_TIME: u32 = 0x2100_0008;
+pub(crate) const TAG_FLOAT_ARRAY: u32 = 0x2001_FFFF;
+pub(crate) const TAG_ANSI_STRING: u32 = 0x4001_FFFF;
+pub(crate) const TAG_WIDE_STRING: u32 = 0x4002_FFFF;
+pub(crate) const TAG_BINARY_BLOB: u32 = 0xFFFF_FFFF;
+
+/// The value of a header tag
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagValue {
+    Empty,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    BitSet(u64),
+    Colour(u64),
+    /// Days since 1899-12-30, as a Delphi `TDateTime`
+    DateTime(f64),
+    FloatArray(Vec<f64>),
+    AnsiString(String),
+    /// A UTF-16 string
+    WideString(String),
+    BinaryBlob(Vec<u8>),
+}
+
+impl TagValue {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            TagValue::Int(i) => Some(*i),
+            TagValue::Bool(b) => Some(*b as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            TagValue::Float(f) | TagValue::DateTime(f) => Some(*f),
+            TagValue::Int(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            TagValue::AnsiString(s) | TagValue::WideString(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// A header tag. `index` is the channel for per-channel tags, and -1 otherwise
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub index: i32,
+    pub value: TagValue,
+}
+
+impl Tag {
+    pub fn new(name: &str, value: TagValue) -> Tag {
+        Tag::indexed(name, -1, value)
+    }
+
+    pub fn indexed(name: &str, index: i32, value: TagValue) -> Tag {
+        Tag {
+            name: name.to_string(),
+            index,
+            value,
+        }
+    }
+}
+
+/// The format of the records in a file
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RecordType {
+    HydraHarpT2,
+    HydraHarpT3,
+    /// The records of firmware version 2, with 25 bit T2 times and T2 overflows counting several overflows
+    HydraHarp2T2,
+    HydraHarp2T3,
+}
+
+impl RecordType {
+    pub fn code(self) -> i64 {
+        match self {
+            RecordType::HydraHarpT2 => 0x0001_0204,
+            RecordType::HydraHarpT3 => 0x0001_0304,
+            RecordType::HydraHarp2T2 => 0x0101_0204,
+            RecordType::HydraHarp2T3 => 0x0101_0304,
+        }
+    }
+
+    pub fn from_code(code: i64) -> Option<RecordType> {
+        [
+            RecordType::HydraHarpT2,
+            RecordType::HydraHarpT3,
+            RecordType::HydraHarp2T2,
+            RecordType::HydraHarp2T3,
+        ]
+        .iter()
+        .cloned()
+        .find(|r| r.code() == code)
+    }
+
+    pub fn mode(self) -> MeasurementMode {
+        match self {
+            RecordType::HydraHarpT2 | RecordType::HydraHarp2T2 => MeasurementMode::T2,
+            RecordType::HydraHarpT3 | RecordType::HydraHarp2T3 => MeasurementMode::T3,
+        }
+    }
+
+    /// Whether the records are from firmware version 1, where each overflow record is a single overflow
+    pub fn version_1_records(self) -> bool {
+        match self {
+            RecordType::HydraHarpT2 | RecordType::HydraHarpT3 => true,
+            RecordType::HydraHarp2T2 | RecordType::HydraHarp2T3 => false,
+        }
+    }
+}
+
+/// Why the acquisition in a file stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    TimeOver = 0,
+    Manual = 1,
+    Overflow = 2,
+    Error = 3,
+}
+
+/// The information written to the header of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct PtuHeader {
+    pub record_type: RecordType,
+    pub serial: String,
+    /// The model, part number and version of the hardware from `Device::get_hardware_info`.
+    /// Any which are empty aren't written
+    pub model: String,
+    pub part_number: String,
+    pub hardware_version: String,
+    /// Resolution of the times in the records in ps
+    pub resolution: f64,
+    /// Sync period in ps, the resolution of the sync numbers of T3 records
+    pub sync_period: Option<f64>,
+    /// The settings the device was measuring with
+    pub config: DeviceConfig,
+    /// Acquisition time in ms
+    pub acquisition_time: i32,
+    pub start_time: SystemTime,
+    /// Any other tags to write
+    pub extra_tags: Vec<Tag>,
+}
+
+impl PtuHeader {
+    /// The header for a measurement starting now with `d` using `config`, with the hardware
+    /// information, resolution and sync period read from the device.
+    /// Returns `InvalidArgument` if the record type doesn't match the mode of `config`
+    pub fn from_device(
+        d: &Device,
+        config: &DeviceConfig,
+        record_type: RecordType,
+        acquisition_time: i32,
+    ) -> Result<PtuHeader, HydraHarpError> {
+        if record_type.mode() != config.mode {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let sync_period = match record_type.mode() {
+            MeasurementMode::T3 => match d.get_sync_rate()? {
+                0 => None,
+                rate => Some(1e12 / rate as f64),
+            },
+            _ => None,
+        };
+        let (model, part_number, hardware_version) = d.get_hardware_info()?;
+        Ok(PtuHeader {
+            record_type,
+            serial: d.serial_number(),
+            model,
+            part_number,
+            hardware_version,
+            resolution: d.get_resolution()?,
+            sync_period,
+            config: config.clone(),
+            acquisition_time,
+            start_time: SystemTime::now(),
+            extra_tags: Vec::new(),
+        })
+    }
+
+    /// The tags of the header, except for the record count and stop reason which are written when
+    /// the file is finished
+    pub fn tags(&self) -> Vec<Tag> {
+        use TagValue::*;
+        let mode = match self.record_type.mode() {
+            MeasurementMode::T2 => 2,
+            _ => 3,
+        };
+        let seconds = self
+            .start_time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9)
+            .unwrap_or(0.0);
+        let guid = {
+            let mut rng = rand::thread_rng();
+            format!(
+                "{{{:08X}-{:04X}-{:04X}-{:04X}-{:012X}}}",
+                rng.gen::<u32>(),
+                rng.gen::<u16>(),
+                rng.gen::<u16>(),
+                rng.gen::<u16>(),
+                rng.gen::<u64>() & 0xFFFF_FFFF_FFFF
+            )
+        };
+
+        let mut tags = vec![
+            Tag::new("File_GUID", AnsiString(guid)),
+            Tag::new("File_CreatingTime", DateTime(seconds / 86400.0 + 25569.0)),
+            Tag::new("CreatorSW_Name", AnsiString("hhlib-sys".to_string())),
+            Tag::new(
+                "CreatorSW_Version",
+                AnsiString(env!("CARGO_PKG_VERSION").to_string()),
+            ),
+            Tag::new("Measurement_Mode", Int(mode)),
+            Tag::new("Measurement_SubMode", Int(0)),
+            Tag::new(
+                "MeasDesc_AcquisitionTime",
+                Int(self.acquisition_time as i64),
+            ),
+            Tag::new("MeasDesc_Resolution", Float(self.resolution * 1e-12)),
+            Tag::new(
+                "MeasDesc_GlobalResolution",
+                Float(self.sync_period.unwrap_or(self.resolution) * 1e-12),
+            ),
+            Tag::new("TTResultFormat_TTTRRecType", Int(self.record_type.code())),
+            Tag::new("TTResultFormat_BitsPerRecord", Int(32)),
+            Tag::new("HW_SerialNo", AnsiString(self.serial.clone())),
+            Tag::new("HW_InpChannels", Int(self.config.inputs.len() as i64 + 1)),
+            Tag::new(
+                "HW_ExternalRefClock",
+                Bool(self.config.reference_source == crate::types::ReferenceSource::External),
+            ),
+            Tag::new("HWSync_Divider", Int(self.config.sync_divider as i64)),
+            Tag::new("HWSync_CFDLevel", Int(self.config.sync_cfd_level as i64)),
+            Tag::new(
+                "HWSync_CFDZeroCross",
+                Int(self.config.sync_cfd_zero_cross as i64),
+            ),
+            Tag::new("HWSync_Offset", Int(self.config.sync_offset as i64)),
+            Tag::new(
+                "HWInpChan_NumberOfInputs",
+                Int(self.config.inputs.len() as i64),
+            ),
+        ];
+        let hardware = [
+            ("HW_Type", &self.model),
+            ("HW_PartNo", &self.part_number),
+            ("HW_Version", &self.hardware_version),
+        ];
+        for (name, value) in hardware.iter().filter(|(_, v)| !v.is_empty()) {
+            tags.push(Tag::new(name, AnsiString(value.to_string())));
+        }
+        let input_tags = |(i, input): (usize, &InputConfig)| {
+            let i = i as i32;
+            vec![
+                Tag::indexed("HWInpChan_Enabled", i, Bool(input.enabled)),
+                Tag::indexed("HWInpChan_CFDLevel", i, Int(input.cfd_level as i64)),
+                Tag::indexed(
+                    "HWInpChan_CFDZeroCross",
+                    i,
+                    Int(input.cfd_zero_cross as i64),
+                ),
+                Tag::indexed("HWInpChan_Offset", i, Int(input.offset as i64)),
+            ]
+        };
+        tags.extend(self.config.inputs.iter().enumerate().flat_map(input_tags));
+        tags.extend(self.extra_tags.iter().cloned());
+        tags
+    }
+}
+
+pub(crate) fn write_tag<W: Write>(w: &mut W, tag: &Tag) -> std::io::Result<()> {
+    let mut name = [0u8; 32];
+    let length = tag.name.len().min(31);
+    name[..length].copy_from_slice(&tag.name.as_bytes()[..length]);
+    w.write_all(&name)?;
+    w.write_all(&tag.index.to_le_bytes())?;
+    // Strings are null terminated, and strings and arrays follow the tag with their length as the value
+    let (kind, value, data) = match &tag.value {
+        TagValue::Empty => (TAG_EMPTY, 0, Vec::new()),
+        TagValue::Bool(b) => (TAG_BOOL, if *b { -1 } else { 0 }, Vec::new()),
+        TagValue::Int(i) => (TAG_INT, *i, Vec::new()),
+        TagValue::BitSet(b) => (TAG_BIT_SET, *b as i64, Vec::new()),
+        TagValue::Colour(c) => (TAG_COLOUR, *c as i64, Vec::new()),
+        TagValue::Float(f) => (TAG_FLOAT, f.to_bits() as i64, Vec::new()),
+        TagValue::DateTime(f) => (TAG_DATE_TIME, f.to_bits() as i64, Vec::new()),
+        TagValue::FloatArray(v) => {
+            let data = v
+                .iter()
+                .flat_map(|f| f.to_bits().to_le_bytes().to_vec())
+                .collect::<Vec<_>>();
+            (TAG_FLOAT_ARRAY, data.len() as i64, data)
+        }
+        TagValue::AnsiString(s) => {
+            let mut data = s.as_bytes().to_vec();
+            data.resize((data.len() / 8 + 1) * 8, 0);
+            (TAG_ANSI_STRING, data.len() as i64, data)
+        }
+        TagValue::WideString(s) => {
+            let mut data = s
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes().to_vec())
+                .collect::<Vec<_>>();
+            data.resize((data.len() / 8 + 1) * 8, 0);
+            (TAG_WIDE_STRING, data.len() as i64, data)
+        }
+        TagValue::BinaryBlob(b) => (TAG_BINARY_BLOB, b.len() as i64, b.clone()),
+    };
+    w.write_all(&kind.to_le_bytes())?;
+    w.write_all(&value.to_le_bytes())?;
+    w.write_all(&data)
+}
+
+pub(crate) fn file_error(_: std::io::Error) -> HydraHarpError {
+    HydraHarpError::FileError
+}
+
+/// Streams records to a PTU file. The record count in the header is written when the file is
+/// finished, which happens when it is dropped if `finish` wasn't called, so the file is still
+/// readable if the acquisition is interrupted
+pub struct PtuWriter<W: Write + Seek> {
+    /// The type of the records, from the header
+    pub record_type: RecordType,
+    writer: W,
+    records: u64,
+    /// Positions of the values of the record count and stop reason tags
+    records_position: u64,
+    stop_reason_position: u64,
+    finished: bool,
+}
+
+impl PtuWriter<BufWriter<File>> {
+    /// Create a file at `path` and write the header to it
+    pub fn create<P: AsRef<std::path::Path>>(
+        path: P,
+        header: &PtuHeader,
+    ) -> Result<PtuWriter<BufWriter<File>>, HydraHarpError> {
+        let file = File::create(path).map_err(file_error)?;
+        PtuWriter::new(BufWriter::new(file), header)
+    }
+}
+
+impl<W: Write + Seek> PtuWriter<W> {
+    /// Write the header to `writer`, ready for the records
+    pub fn new(mut writer: W, header: &PtuHeader) -> Result<PtuWriter<W>, HydraHarpError> {
+        let mut write = || -> std::io::Result<(u64, u64)> {
+            writer.write_all(MAGIC)?;
+            writer.write_all(FORMAT_VERSION)?;
+            for tag in header.tags().iter() {
+                write_tag(&mut writer, tag)?;
+            }
+            // The values start after the 32 byte name, index and type
+            let records_position = writer.seek(SeekFrom::Current(0))? + 40;
+            write_tag(
+                &mut writer,
+                &Tag::new("TTResult_NumberOfRecords", TagValue::Int(0)),
+            )?;
+            let stop_reason_position = writer.seek(SeekFrom::Current(0))? + 40;
+            write_tag(
+                &mut writer,
+                &Tag::new(
+                    "TTResult_StopReason",
+                    TagValue::Int(StopReason::Manual as i64),
+                ),
+            )?;
+            write_tag(&mut writer, &Tag::new("Header_End", TagValue::Empty))?;
+            Ok((records_position, stop_reason_position))
+        };
+        let (records_position, stop_reason_position) = write().map_err(file_error)?;
+        Ok(PtuWriter {
+            record_type: header.record_type,
+            writer,
+            records: 0,
+            records_position,
+            stop_reason_position,
+            finished: false,
+        })
+    }
+
+    /// Append raw fifo records
+    pub fn write_records(&mut self, records: &[u32]) -> Result<(), HydraHarpError> {
+        for r in records.iter() {
+            self.writer
+                .write_all(&r.to_le_bytes())
+                .map_err(file_error)?;
+            self.records += 1;
+        }
+        Ok(())
+    }
+
+    /// The number of records written so far
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Write the record count and stop reason into the header and flush the file.
+    /// Further calls do nothing
+    pub fn finish(&mut self, stop_reason: StopReason) -> Result<(), HydraHarpError> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let (records, records_position, stop_reason_position) = (
+            self.records,
+            self.records_position,
+            self.stop_reason_position,
+        );
+        let w = &mut self.writer;
+        let mut write = || -> std::io::Result<()> {
+            let end = w.seek(SeekFrom::Current(0))?;
+            w.seek(SeekFrom::Start(records_position))?;
+            w.write_all(&(records as i64).to_le_bytes())?;
+            w.seek(SeekFrom::Start(stop_reason_position))?;
+            w.write_all(&(stop_reason as i64).to_le_bytes())?;
+            w.seek(SeekFrom::Start(end))?;
+            w.flush()
+        };
+        write().map_err(file_error)
+    }
+}
+
+impl<W: Write + Seek> Drop for PtuWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish(StopReason::Manual);
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, writing the fifo records to `writer` unchanged.
+/// The file is finished with the stop reason `Error` if reading from the device fails
+pub fn record_measurement<M, W>(
+    d: &mut M,
+    acquisition_time: i32,
+    writer: &mut PtuWriter<W>,
+) -> Result<(), HydraHarpError>
+where
+    M: Measureable,
+    W: Write + Seek,
+{
+    const BUFFER_LENGTH: usize = 131072;
+    let mut buffer = vec![0u32; BUFFER_LENGTH];
+
+    let mut read = || -> Result<(), HydraHarpError> {
+        d.start_measurement(acquisition_time)?;
+        loop {
+            let num_read = d.read_fifo(&mut buffer, BUFFER_LENGTH as i32)? as usize;
+            if num_read > 0 {
+                writer.write_records(&buffer[..num_read])?;
+            } else if d.get_CTC_status()? == CTCStatus::Ended {
+                return Ok(());
+            }
+        }
+    };
+    match read() {
+        Ok(()) => writer.finish(StopReason::TimeOver),
+        Err(e) => {
+            let _ = writer.finish(StopReason::Error);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PtuHeader, PtuWriter, RecordType, StopReason};
+    use crate::config::DeviceConfig;
+    use std::io::Cursor;
+    use std::time::SystemTime;
+
+    #[test]
+    fn finished_file_has_record_count() {
+        let header = PtuHeader {
+            record_type: RecordType::HydraHarp2T2,
+            serial: "1234567".to_string(),
+            model: String::new(),
+            part_number: String::new(),
+            hardware_version: String::new(),
+            resolution: 1.0,
+            sync_period: None,
+            config: DeviceConfig::new(2),
+            acquisition_time: 1000,
+            start_time: SystemTime::now(),
+            extra_tags: Vec::new(),
+        };
+        let mut file = Cursor::new(Vec::new());
+        let mut w = PtuWriter::new(&mut file, &header).unwrap();
+        w.write_records(&[1, 2, 3]).unwrap();
+        w.finish(StopReason::TimeOver).unwrap();
+        drop(w);
+        let bytes = file.into_inner();
+        assert_eq!(&bytes[..8], b"PQTTTR\0\0");
+        assert_eq!(
+            &bytes[bytes.len() - 12..],
+            &[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0]
+        );
+        let tag = bytes
+            .windows(24)
+            .position(|w| w == b"TTResult_NumberOfRecords")
+            .unwrap();
+        assert_eq!(bytes[tag + 40], 3);
+    }
+}
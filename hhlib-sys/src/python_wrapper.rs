@@ -1,10 +1,47 @@
+use crate::archive::{archive_raw_file, archive_ttr_file, ArchiveFile};
+use crate::calibration::{measure_delays, DelaySearch};
+#[cfg(feature = "catalogue")]
+use crate::catalogue::{Catalogue, Run, RunQuery};
+use crate::config::{DeviceConfig, InputConfig};
+use crate::chsh::{ChshChannels, ChshResult, SettingCounts};
+use crate::coincidence::{measure_coincidences, CoincidenceCounter, PairStatistics};
+use crate::correlation::{measure_correlation, CrossCorrelator};
 use crate::device::Device;
+use crate::export::Table;
+use crate::faults::{FaultInjector, FaultScript, FaultySimulator};
+use crate::fitting::DecayFit;
+use crate::gating::{measure_gated_coincidences, measure_gated_coincidences_T3, Gate, GateFilter};
+use crate::heralded::{measure_heralded, HeraldWindow, HeraldedCounter};
+use crate::hom::{DipFit, DipShape, HomScan};
+use crate::lifetime::{measure_lifetime, measure_lifetime_T3, LifetimeHistogram};
+use crate::logic::{measure_conditional, Condition, ConditionalCounter};
 use crate::measurement::{Measureable, Measurement};
-use crate::types::convert_hydra_harp_result;
+use crate::mub::{mub_witness, CoincidenceMatrix, MubWitness};
+use crate::multi_tau::{measure_multi_tau, MultiTauCorrelator};
+use crate::pipeline::{Pipeline, PipelineSink, PipelineStage};
+use crate::ptu::{record_measurement, PtuHeader, PtuWriter, RecordType, Tag, TagValue};
+use crate::raw::{record_raw, sidecar_path, RawFile, RawMetadata, RawRecorder};
+use crate::reader::{read_header_tags, PhuFile, TtrFile};
+use crate::replay::{FileReplay, Pacing};
+use crate::segment::{measure_segmented, MarkerSegmenter};
+use crate::simulator::{Detector, PairSource, PhotonSimulator};
+use crate::tomography::{Reconstruction, StateMetrics, Tomography};
+use crate::trace::{measure_trace, TraceBinner};
+use crate::types::{
+    convert_hydra_harp_result, CTCStatus, EdgeSelection, HydraHarpError, MeasurementControl,
+    MeasurementMode, ReferenceSource,
+};
+#[cfg(feature = "numpy")]
+use numpy::{IntoPyArray, PyArray1, PyArray2};
+use num::complex::Complex64;
+use pyo3::class::basic::PyObjectProtocol;
 use pyo3::exceptions;
 use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyBytes};
 use pyo3::wrap_pyfunction;
+use pyo3::IntoPyPointer;
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicPtr, Ordering};
 use std::thread::sleep;
 use std::time::Duration;
 
@@ -18,6 +55,313 @@ where
     }
 }
 
+/// The `CTCStatus` class made by `add_enums`, kept so that `to_python_enum` doesn't have to look
+/// it up in the module. It holds a reference for the life of the interpreter
+static CTC_STATUS_CLASS: AtomicPtr<pyo3::ffi::PyObject> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Add the enums of `types` to the module as Python `IntEnum`s. They are ints, so they can be
+/// passed to any function which takes the number of a mode, reference source, edge or control
+fn add_enums(py: Python, m: &PyModule) -> PyResult<()> {
+    let enums: [(&str, Vec<(&str, i32)>); 5] = [
+        (
+            "MeasurementMode",
+            vec![
+                ("Histogramming", MeasurementMode::Histogramming as i32),
+                ("T2", MeasurementMode::T2 as i32),
+                ("T3", MeasurementMode::T3 as i32),
+                ("Continuous", MeasurementMode::Continuous as i32),
+            ],
+        ),
+        (
+            "ReferenceSource",
+            vec![
+                ("Internal", ReferenceSource::Internal as i32),
+                ("External", ReferenceSource::External as i32),
+            ],
+        ),
+        (
+            "EdgeSelection",
+            vec![
+                ("Falling", EdgeSelection::Falling as i32),
+                ("Rising", EdgeSelection::Rising as i32),
+            ],
+        ),
+        (
+            "MeasurementControl",
+            vec![
+                ("SingleShotCTC", MeasurementControl::SingleShotCTC as i32),
+                ("C1Gated", MeasurementControl::C1Gated as i32),
+                ("C1StartCTCStop", MeasurementControl::C1StartCTCStop as i32),
+                ("C1StartC2Stop", MeasurementControl::C1StartC2Stop as i32),
+                ("ContC1Gated", MeasurementControl::ContC1Gated as i32),
+                ("ContC1StartCTCStop", MeasurementControl::ContC1StartCTCStop as i32),
+                ("ContCTCRestart", MeasurementControl::ContCTCRestart as i32),
+            ],
+        ),
+        (
+            "CTCStatus",
+            vec![
+                ("Running", CTCStatus::Running as i32),
+                ("Ended", CTCStatus::Ended as i32),
+            ],
+        ),
+    ];
+    let int_enum = py.import("enum")?.get("IntEnum")?;
+    for (name, members) in enums.iter() {
+        let e = int_enum.call1((*name, members.clone()))?;
+        e.setattr("__module__", "hhlib_sys")?;
+        m.add(*name, e.to_object(py))?;
+        if *name == "CTCStatus" {
+            CTC_STATUS_CLASS.store(e.to_object(py).into_ptr(), Ordering::Release);
+        }
+    }
+    Ok(())
+}
+
+/// The member of the Python enum `class`, one of the classes kept by `add_enums`, with the value of `value`
+fn to_python_enum<T: num::ToPrimitive>(
+    py: Python,
+    class: &AtomicPtr<pyo3::ffi::PyObject>,
+    value: T,
+) -> PyResult<PyObject> {
+    let class = class.load(Ordering::Acquire);
+    if class.is_null() {
+        return Err(exceptions::RuntimeError::py_err("the enums haven't been added to the module"));
+    }
+    let value = value.to_i32().ok_or_else(|| PyErr::from(exceptions::ValueError))?;
+    unsafe { PyObject::from_borrowed_ptr(py, class) }.call1(py, (value,))
+}
+
+/// The device as a Python class. The settings are grouped into fewer methods than the functions
+/// of the module, and the methods are named so that they don't shadow the methods of `Device` in Rust.
+/// The device is closed when a `with` block using it ends:
+///
+/// ```python
+/// with hhlib_sys.Device(0) as d:
+///     d.set_mode(hhlib_sys.MeasurementMode.T2, hhlib_sys.ReferenceSource.Internal)
+///     d.run_calibration()
+///     d.set_sync(1, 50, 10, -5000)
+///     print(d.resolution, d.number_of_input_channels)
+/// ```
+#[pymethods]
+impl Device {
+    /// Open the device with id `id`, from 0 to 7
+    #[new]
+    fn __new__(obj: &PyRawObject, id: i32) -> PyResult<()> {
+        obj.init(convert_hydra_harp_result(Device::open_device(id))?);
+        Ok(())
+    }
+
+    fn __enter__(slf: PyRef<Self>) -> PyResult<Py<Device>> {
+        Ok(slf.into())
+    }
+
+    /// Close the device, letting any exception carry on
+    fn __exit__(
+        &mut self,
+        _exc_type: &PyAny,
+        _exc_value: &PyAny,
+        _traceback: &PyAny,
+    ) -> PyResult<bool> {
+        convert_hydra_harp_result(self.close_device())?;
+        Ok(false)
+    }
+
+    /// Close the device. Does nothing if it's already closed, and the other methods raise
+    /// `DeviceNotOpen` once it is
+    fn close(&mut self) -> PyResult<()> {
+        convert_hydra_harp_result(self.close_device())
+    }
+
+    /// Whether the device is still open
+    #[getter]
+    fn open(&self) -> PyResult<bool> {
+        Ok(self.is_open())
+    }
+
+    /// Initialise the device in `mode` (a `MeasurementMode`) with the clock from `reference_source`
+    /// (a `ReferenceSource`). Should be done before the other settings
+    fn set_mode(&mut self, mode: i32, reference_source: i32) -> PyResult<()> {
+        convert_hydra_harp_result(self.initialise(
+            unwrap_or_value_error(mode)?,
+            unwrap_or_value_error(reference_source)?,
+        ))
+    }
+
+    /// Calibrate the device, which should be done after it's initialised
+    fn run_calibration(&mut self) -> PyResult<()> {
+        convert_hydra_harp_result(self.calibrate())
+    }
+
+    /// Set the sync divider, the CFD discriminator and zero cross levels in mV and the timing offset in ps
+    fn set_sync(
+        &mut self,
+        divider: i32,
+        cfd_level: i32,
+        cfd_zero_cross: i32,
+        offset: i32,
+    ) -> PyResult<()> {
+        convert_hydra_harp_result(self.set_sync_divider(divider))?;
+        convert_hydra_harp_result(self.set_sync_CFD(cfd_level, cfd_zero_cross))?;
+        convert_hydra_harp_result(self.set_sync_channel_offset(offset))
+    }
+
+    /// Enable or disable input `channel` and set its CFD discriminator and zero cross levels in mV
+    /// and its timing offset in ps
+    fn set_input(
+        &mut self,
+        channel: i32,
+        enabled: bool,
+        cfd_level: i32,
+        cfd_zero_cross: i32,
+        offset: i32,
+    ) -> PyResult<()> {
+        convert_hydra_harp_result(self.set_input_channel_enabled(channel, enabled))?;
+        convert_hydra_harp_result(self.set_input_CFD(channel, cfd_level, cfd_zero_cross))?;
+        convert_hydra_harp_result(self.set_input_channel_offset(channel, offset))
+    }
+
+    /// Set the binning, offset and length of the histograms in histogramming mode, returning the
+    /// actual length of the histograms
+    fn set_histograms(&mut self, binning: i32, offset: i32, length: i32) -> PyResult<i32> {
+        convert_hydra_harp_result(self.set_binning(binning))?;
+        convert_hydra_harp_result(self.set_offset(offset))?;
+        convert_hydra_harp_result(self.set_histogram_length(length))
+    }
+
+    /// Stop the measurement when a histogram bin reaches `stop_count`, if `stop` is true
+    fn set_overflow_stop(&mut self, stop: bool, stop_count: u32) -> PyResult<()> {
+        convert_hydra_harp_result(self.set_stop_overflow(stop, stop_count))
+    }
+
+    /// Set how measurements are started and stopped, with a `MeasurementControl` and the
+    /// `EdgeSelection`s of the start and stop signals
+    fn set_control(&mut self, control: i32, start_edge: i32, stop_edge: i32) -> PyResult<()> {
+        convert_hydra_harp_result(self.set_measurement_control(
+            unwrap_or_value_error(control)?,
+            unwrap_or_value_error(start_edge)?,
+            unwrap_or_value_error(stop_edge)?,
+        ))
+    }
+
+    /// Set the `EdgeSelection` of each of the 4 markers, which of them are enabled and the marker
+    /// holdoff time in ns
+    fn set_markers(
+        &mut self,
+        edges: (i32, i32, i32, i32),
+        enabled: (bool, bool, bool, bool),
+        holdoff_time: i32,
+    ) -> PyResult<()> {
+        convert_hydra_harp_result(self.set_marker_edges(
+            unwrap_or_value_error(edges.0)?,
+            unwrap_or_value_error(edges.1)?,
+            unwrap_or_value_error(edges.2)?,
+            unwrap_or_value_error(edges.3)?,
+        ))?;
+        convert_hydra_harp_result(
+            self.enable_marker_edges(enabled.0, enabled.1, enabled.2, enabled.3),
+        )?;
+        convert_hydra_harp_result(self.set_marker_holdoff_time(holdoff_time))
+    }
+
+    /// Clear the histograms in the device's memory
+    fn clear_histograms(&mut self) -> PyResult<()> {
+        convert_hydra_harp_result(self.clear_histogram_memory())
+    }
+
+    /// Start a measurement of `acquisition_time` ms
+    fn start(&mut self, acquisition_time: i32) -> PyResult<()> {
+        convert_hydra_harp_result(Measureable::start_measurement(self, acquisition_time))
+    }
+
+    /// Stop the measurement
+    fn stop(&mut self) -> PyResult<()> {
+        convert_hydra_harp_result(self.stop_measurement())
+    }
+
+    /// The histogram of input `channel`, clearing it in the device if `clear` is true
+    fn histogram(&mut self, channel: i32, clear: bool) -> PyResult<Vec<u32>> {
+        convert_hydra_harp_result(self.get_histogram(channel, clear))
+    }
+
+    /// The count rate of input `channel` per second
+    fn count_rate(&self, channel: i32) -> PyResult<i32> {
+        convert_hydra_harp_result(self.get_count_rate(channel))
+    }
+
+    #[getter]
+    fn id(&self) -> PyResult<i32> {
+        Ok(self.id)
+    }
+
+    /// The serial number of the device
+    #[getter]
+    fn serial(&self) -> PyResult<String> {
+        Ok(self.serial_number())
+    }
+
+    /// Resolution of the times in ps
+    #[getter]
+    fn resolution(&self) -> PyResult<f64> {
+        convert_hydra_harp_result(self.get_resolution())
+    }
+
+    /// The base resolution in ps and the number of binning steps
+    #[getter]
+    fn base_resolution(&self) -> PyResult<(f64, i32)> {
+        convert_hydra_harp_result(self.get_base_resolution())
+    }
+
+    #[getter]
+    fn number_of_input_channels(&self) -> PyResult<i32> {
+        convert_hydra_harp_result(self.get_number_of_input_channels())
+    }
+
+    /// The length of the histograms, `None` until it has been set
+    #[getter]
+    fn histogram_length(&self) -> PyResult<Option<usize>> {
+        Ok(self.histogram_length)
+    }
+
+    /// The sync rate per second
+    #[getter]
+    fn sync_rate(&self) -> PyResult<i32> {
+        convert_hydra_harp_result(self.get_sync_rate())
+    }
+
+    /// The `FLAG_*` flags
+    #[getter]
+    fn flags(&self) -> PyResult<i32> {
+        convert_hydra_harp_result(self.get_flags())
+    }
+
+    /// The warnings, encoded bitwise
+    #[getter]
+    fn warnings(&self) -> PyResult<i32> {
+        convert_hydra_harp_result(self.get_warnings())
+    }
+
+    /// The time since the measurement started in ms
+    #[getter]
+    fn elapsed_measurement_time(&self) -> PyResult<f64> {
+        convert_hydra_harp_result(self.get_elapsed_measurement_time())
+    }
+
+    /// Whether the measurement is running, as a `CTCStatus`
+    #[getter]
+    fn ctc_status(&self, py: Python) -> PyResult<PyObject> {
+        let status = convert_hydra_harp_result(self.get_CTC_status())?;
+        to_python_enum(py, &CTC_STATUS_CLASS, status)
+    }
+}
+
+#[pyproto]
+impl<'p> PyObjectProtocol<'p> for Device {
+    fn __repr__(&'p self) -> PyResult<String> {
+        Ok(format!("Device(id={}, serial='{}')", self.id, self.serial_number()))
+    }
+}
+
 #[pyfunction]
 pub fn open_device(id: i32) -> PyResult<Device> {
     convert_hydra_harp_result(Device::open_device(id))
@@ -29,7 +373,8 @@ pub fn close_device(d: &mut Device) -> PyResult<()> {
 }
 
 #[pyfunction]
-/// Initialise the device. Should be done before other functions are run
+/// Initialise the device with a `MeasurementMode` and a `ReferenceSource`. Should be done before
+/// other functions are run
 pub fn initialise(d: &mut Device, mode: i32, ref_source: i32) -> PyResult<()> {
     convert_hydra_harp_result(d.initialise(
         unwrap_or_value_error(mode)?,
@@ -380,8 +725,8 @@ fn generate_histogram_times(
 /// Make a measurement for `acquisition_time` ms and return a tuple containing
 /// `([singles], [coincidences], [histograms])`
 /// where [histograms] is a vector containing the histogrammed times
-pub fn measure_and_get_counts(
-    d: &mut Device,
+pub fn measure_and_get_counts<M: Measureable>(
+    d: &mut M,
     acquisition_time: i32,
     coincidence_window: u64,
     histogram_bins: usize,
@@ -457,51 +802,1656 @@ pub fn measure_and_get_counts(
     Ok((singles.to_vec(), coincidences.to_vec(), histograms))
 }
 
-#[pymodule]
-fn hhlib_sys(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
-    m.add_wrapped(wrap_pyfunction!(open_device))?;
-    m.add_wrapped(wrap_pyfunction!(close_device))?;
-    m.add_wrapped(wrap_pyfunction!(initialise))?;
-    m.add_wrapped(wrap_pyfunction!(get_base_resolution))?;
-    m.add_wrapped(wrap_pyfunction!(get_number_of_input_channels))?;
-    m.add_wrapped(wrap_pyfunction!(calibrate))?;
-    m.add_wrapped(wrap_pyfunction!(set_sync_divider))?;
-    m.add_wrapped(wrap_pyfunction!(set_sync_channel_offset))?;
-    m.add_wrapped(wrap_pyfunction!(set_sync_CFD))?;
-    m.add_wrapped(wrap_pyfunction!(set_input_CFD))?;
-    m.add_wrapped(wrap_pyfunction!(set_input_channel_offset))?;
-    m.add_wrapped(wrap_pyfunction!(set_input_channel_enabled))?;
-    m.add_wrapped(wrap_pyfunction!(set_stop_overflow))?;
-    m.add_wrapped(wrap_pyfunction!(set_binning))?;
-    m.add_wrapped(wrap_pyfunction!(set_offset))?;
-    m.add_wrapped(wrap_pyfunction!(clear_histogram_memory))?;
-    m.add_wrapped(wrap_pyfunction!(start_measurement))?;
-    m.add_wrapped(wrap_pyfunction!(stop_measurement))?;
-    m.add_wrapped(wrap_pyfunction!(get_CTC_status))?;
-    m.add_wrapped(wrap_pyfunction!(get_resolution))?;
-    m.add_wrapped(wrap_pyfunction!(get_sync_rate))?;
-    m.add_wrapped(wrap_pyfunction!(get_count_rate))?;
-    m.add_wrapped(wrap_pyfunction!(get_elapsed_measurement_time))?;
-    //m.add_wrapped(wrap_pyfunction!(measure_and_get_counts))?;
-    m.add_wrapped(wrap_pyfunction!(coincidence_channels_to_index))?;
-    m.add_wrapped(wrap_pyfunction!(index_to_coincidence_channels))?;
-    #[pyfn(m, "measure_and_get_counts")]
-    fn measure_and_get_counts_py(
-        py: Python,
-        d: &mut Device,
-        acquisition_time: i32,
-        coincidence_window: u64,
-        histogram_bins: usize,
-        sync_channel: u8,
-    ) -> PyResult<(Vec<usize>, Vec<usize>, Vec<Vec<usize>>)> {
-        py.allow_threads(move || {
-            measure_and_get_counts(
-                d,
-                acquisition_time,
-                coincidence_window,
-                histogram_bins,
-                sync_channel,
-            )
+/// Make a correlator for the delays `t_b - t_a` between `tau_min` and `tau_max` ps,
+/// in bins of `bin_width` ps
+#[pyfunction]
+pub fn new_correlator(
+    channel_a: u8,
+    channel_b: u8,
+    bin_width: u64,
+    tau_min: i64,
+    tau_max: i64,
+) -> PyResult<CrossCorrelator> {
+    convert_hydra_harp_result(CrossCorrelator::new(
+        channel_a, channel_b, bin_width, tau_min, tau_max,
+    ))
+}
+
+/// Get `(tau, g2, histogram)` from a correlator, with tau in ps
+#[pyfunction]
+pub fn correlator_g2(c: &CrossCorrelator) -> PyResult<(Vec<f64>, Vec<f64>, Vec<u64>)> {
+    Ok((c.tau(), c.g2(), c.histogram.clone()))
+}
+
+/// Add the counts from `other` into `c`
+#[pyfunction]
+pub fn merge_correlators(c: &mut CrossCorrelator, other: &CrossCorrelator) -> PyResult<()> {
+    convert_hydra_harp_result(c.merge(other))
+}
+
+/// Make a multi-tau correlator with the shortest lag `base_lag` ps, split into segments of
+/// `segment_length` ps for the error estimates
+#[pyfunction]
+pub fn new_multi_tau_correlator(
+    channel_a: u8,
+    channel_b: u8,
+    base_lag: u64,
+    points_per_level: usize,
+    levels: usize,
+    segment_length: u64,
+) -> PyResult<MultiTauCorrelator> {
+    convert_hydra_harp_result(MultiTauCorrelator::new(
+        channel_a,
+        channel_b,
+        base_lag,
+        points_per_level,
+        levels,
+        segment_length,
+    ))
+}
+
+/// Get `(tau, G, error)` from a multi-tau correlator, with tau in ps
+#[pyfunction]
+pub fn multi_tau_curve(c: &MultiTauCorrelator) -> PyResult<(Vec<f64>, Vec<f64>, Vec<f64>)> {
+    let (g, error) = c.curve();
+    Ok((c.tau(), g, error))
+}
+
+/// The number of segments of a multi-tau correlator without any photons on one of its channels,
+/// which aren't in the curve
+#[pyfunction]
+pub fn multi_tau_empty_segments(c: &MultiTauCorrelator) -> PyResult<u64> {
+    Ok(c.empty_segments)
+}
+
+/// Make a counter for the coincidences between all of the first `channels` channels,
+/// within `window` ps either side of zero delay.
+/// If `side_window_offset` is given, the accidentals are also counted in windows at that delay
+#[pyfunction]
+pub fn new_coincidence_counter(
+    channels: usize,
+    window: u64,
+    side_window_offset: Option<u64>,
+) -> PyResult<CoincidenceCounter> {
+    Ok(CoincidenceCounter::new(channels, window, side_window_offset))
+}
+
+/// Get `(singles, [pair statistics])` from a coincidence counter
+#[pyfunction]
+pub fn coincidence_results(c: &CoincidenceCounter) -> PyResult<(Vec<u64>, Vec<PairStatistics>)> {
+    Ok((c.singles.clone(), c.results()))
+}
+
+/// Set the window between channels `a < b` of a coincidence counter to `early < t_b - t_a < late` ps
+#[pyfunction]
+pub fn set_pair_window(
+    c: &mut CoincidenceCounter,
+    a: u8,
+    b: u8,
+    early: i64,
+    late: i64,
+) -> PyResult<()> {
+    convert_hydra_harp_result(c.set_pair_window(a, b, early, late))
+}
+
+/// Set the software delays in ps added to each channel of a coincidence counter when measuring
+#[pyfunction]
+pub fn set_software_delays(c: &mut CoincidenceCounter, delays: Vec<i64>) -> PyResult<()> {
+    c.delays = delays;
+    Ok(())
+}
+
+/// Add recorded T2 fifo records covering `duration` ps to a coincidence counter
+#[pyfunction]
+pub fn add_coincidence_records(
+    c: &mut CoincidenceCounter,
+    records: Vec<u32>,
+    duration: u64,
+) -> PyResult<()> {
+    crate::coincidence::add_coincidence_records(&records, duration, c);
+    Ok(())
+}
+
+/// Make a filter which gates the events of the first `channels` channels by their delay after the sync
+#[pyfunction]
+pub fn new_gate_filter(channels: usize) -> PyResult<GateFilter> {
+    Ok(GateFilter::new(channels))
+}
+
+/// Set the gates of `channel` as a list of `(start, end)` delays after the sync in ps
+#[pyfunction]
+pub fn set_gates(f: &mut GateFilter, channel: u8, gates: Vec<(u64, u64)>) -> PyResult<()> {
+    let gates = gates
+        .into_iter()
+        .map(|(start, end)| Gate { start, end })
+        .collect::<Vec<_>>();
+    convert_hydra_harp_result(f.set_gates(channel, &gates))
+}
+
+/// Get `(kept, dropped, rejected)` from a gate filter, where `rejected[channel][gate]` is the number
+/// of events of the channel outside that gate
+#[pyfunction]
+pub fn gate_counts(f: &GateFilter) -> PyResult<(Vec<u64>, Vec<u64>, Vec<Vec<u64>>)> {
+    Ok((f.kept.clone(), f.dropped.clone(), f.rejected.clone()))
+}
+
+/// Make a counter for the heralded g2(0), with detectors `channel_a` and `channel_b` behind a beamsplitter.
+/// The windows are `(start, end)` delays from the herald in ps
+#[pyfunction]
+pub fn new_heralded_counter(
+    herald: u8,
+    channel_a: u8,
+    channel_b: u8,
+    window_a: (i64, i64),
+    window_b: (i64, i64),
+) -> PyResult<HeraldedCounter> {
+    convert_hydra_harp_result(HeraldedCounter::new(
+        herald,
+        channel_a,
+        channel_b,
+        HeraldWindow {
+            start: window_a.0,
+            end: window_a.1,
+        },
+        HeraldWindow {
+            start: window_b.0,
+            end: window_b.1,
+        },
+    ))
+}
+
+/// Get `((heralds, herald_a, herald_b, herald_ab), (g2, error))` from a heralded counter.
+/// The g2 is `None` until there has been a herald with each of the detectors
+#[pyfunction]
+pub fn heralded_g2(c: &HeraldedCounter) -> PyResult<((u64, u64, u64, u64), Option<(f64, f64)>)> {
+    Ok((
+        (c.heralds, c.herald_a, c.herald_b, c.herald_ab),
+        c.g2(),
+    ))
+}
+
+/// Add recorded T2 fifo records to a heralded counter
+#[pyfunction]
+pub fn add_heralded_records(c: &mut HeraldedCounter, records: Vec<u32>) -> PyResult<()> {
+    crate::heralded::add_heralded_records(&records, c);
+    Ok(())
+}
+
+/// Make a counter for the events on `trigger` for which `condition` holds.
+/// The condition is written like `1[-500,500] & !2[-5000,5000]`, where `c[start,end]` is an event
+/// on channel `c` between `start` and `end` ps after the trigger, combined with `&`, `|`, `!` and brackets
+#[pyfunction]
+pub fn new_conditional_counter(trigger: u8, condition: &str) -> PyResult<ConditionalCounter> {
+    let condition = convert_hydra_harp_result(condition.parse::<Condition>())?;
+    Ok(ConditionalCounter::new(trigger, condition))
+}
+
+/// Get `(triggers, counts)` from a conditional counter
+#[pyfunction]
+pub fn conditional_counts(c: &ConditionalCounter) -> PyResult<(u64, u64)> {
+    Ok((c.triggers, c.counts))
+}
+
+/// Make a binner for count rate traces of the first `channels` channels in bins of `bin_width` ps,
+/// counting coincidences within `window` ps between each of `pairs` of channels
+#[pyfunction]
+pub fn new_trace_binner(
+    channels: usize,
+    bin_width: u64,
+    window: u64,
+    pairs: Vec<(u8, u8)>,
+) -> PyResult<TraceBinner> {
+    convert_hydra_harp_result(TraceBinner::new(channels, bin_width, window, pairs))
+}
+
+/// Remove the finished bins from a trace binner and return them as NumPy arrays
+/// `(bin_start_times, singles, coincidences)`, with the times in s from the start of the trace,
+/// `singles[bin, channel]` and `coincidences[bin, pair]`
+#[cfg(feature = "numpy")]
+#[pyfunction]
+pub fn trace_take_bins(
+    py: Python,
+    b: &mut TraceBinner,
+) -> PyResult<(Py<PyArray1<f64>>, Py<PyArray2<u64>>, Py<PyArray2<u64>>)> {
+    let (first, singles, coincidences) = b.take_complete();
+    let times = (0..singles.len() as u64)
+        .map(|i| ((first + i) * b.bin_width) as f64 * 1e-12)
+        .collect::<Vec<_>>();
+    let singles = PyArray2::from_vec2(py, &singles)
+        .map_err(|_| exceptions::ValueError::py_err("bins have different lengths"))?;
+    let coincidences = PyArray2::from_vec2(py, &coincidences)
+        .map_err(|_| exceptions::ValueError::py_err("bins have different lengths"))?;
+    Ok((
+        times.into_pyarray(py).to_owned(),
+        singles.to_owned(),
+        coincidences.to_owned(),
+    ))
+}
+
+/// Make lifetime histograms for the first `channels` channels with `bins` bins of `bin_width` ps
+#[pyfunction]
+pub fn new_lifetime_histogram(
+    channels: usize,
+    bin_width: u64,
+    bins: usize,
+) -> PyResult<LifetimeHistogram> {
+    convert_hydra_harp_result(LifetimeHistogram::new(channels, bin_width, bins))
+}
+
+/// Get `(tau, histograms)` from a lifetime histogram, where `tau` is the start of each bin in ps
+/// and `histograms[channel][bin]`
+#[pyfunction]
+pub fn lifetime_histograms(h: &LifetimeHistogram) -> PyResult<(Vec<f64>, Vec<Vec<u64>>)> {
+    Ok((h.tau(), h.histograms.clone()))
+}
+
+/// Fit a sum of exponential decays and a background to a lifetime histogram with bins of `bin_width` ps,
+/// starting from the guesses of `lifetimes` in ps. If `irf` is given, the decays are reconvolved with it.
+/// `fit_range` is the `(start, end)` bins to fit, defaulting to the whole histogram
+#[pyfunction]
+pub fn fit_decay(
+    histogram: Vec<u64>,
+    bin_width: f64,
+    lifetimes: Vec<f64>,
+    irf: Option<Vec<f64>>,
+    fit_range: Option<(usize, usize)>,
+) -> PyResult<DecayFit> {
+    let range = fit_range.unwrap_or((0, histogram.len()));
+    convert_hydra_harp_result(crate::fitting::fit_decay(
+        &histogram,
+        bin_width,
+        &lifetimes,
+        irf.as_ref().map(|i| i.as_slice()),
+        range,
+    ))
+}
+
+/// Make an empty HOM scan of the coincidences between `channel_a` and `channel_b`
+#[pyfunction]
+pub fn new_hom_scan(channel_a: u8, channel_b: u8) -> PyResult<HomScan> {
+    Ok(HomScan::new(channel_a, channel_b))
+}
+
+/// Add the coincidences from a counter measured at `position` to a HOM scan
+#[pyfunction]
+pub fn hom_add_counter(
+    scan: &mut HomScan,
+    position: f64,
+    counter: &CoincidenceCounter,
+) -> PyResult<()> {
+    convert_hydra_harp_result(scan.add_counter(position, counter))
+}
+
+/// Fit a `"gaussian"` or `"sinc"` dip to a HOM scan, optionally subtracting the accidentals first
+#[pyfunction]
+pub fn fit_hom_dip(scan: &HomScan, shape: &str, subtract_accidentals: bool) -> PyResult<DipFit> {
+    let shape = match shape {
+        "gaussian" => DipShape::Gaussian,
+        "sinc" => DipShape::Sinc,
+        _ => return Err(exceptions::ValueError.into()),
+    };
+    convert_hydra_harp_result(scan.fit(shape, subtract_accidentals))
+}
+
+fn to_complex(v: &[(f64, f64)]) -> Vec<Complex64> {
+    v.iter().map(|&(re, im)| Complex64::new(re, im)).collect()
+}
+
+/// The joint state `a ⊗ b` of two states, each given as a list of `(re, im)` amplitudes
+#[pyfunction]
+pub fn product_state(a: Vec<(f64, f64)>, b: Vec<(f64, f64)>) -> PyResult<Vec<(f64, f64)>> {
+    Ok(
+        crate::tomography::product_state(&to_complex(&a), &to_complex(&b))
+            .into_iter()
+            .map(|x| (x.re, x.im))
+            .collect(),
+    )
+}
+
+/// Reconstruct a density matrix from the `counts` measured with each of `projectors`, the states
+/// projected onto as lists of `(re, im)` amplitudes. `method` is `"linear"` or `"mle"`.
+/// Returns `(rho, metrics, (fidelity_error, purity_error, concurrence_error, entropy_error))`, with
+/// `rho[row][column]` as `(re, im)` and the errors from `samples` Poisson resamplings of the counts
+#[pyfunction]
+pub fn reconstruct_state(
+    projectors: Vec<Vec<(f64, f64)>>,
+    counts: Vec<f64>,
+    method: &str,
+    target: Option<Vec<(f64, f64)>>,
+    samples: usize,
+    seed: u64,
+) -> PyResult<(
+    Vec<Vec<(f64, f64)>>,
+    StateMetrics,
+    (Option<f64>, Option<f64>, Option<f64>, Option<f64>),
+)> {
+    let method = match method {
+        "linear" => Reconstruction::LinearInversion,
+        "mle" => Reconstruction::MaximumLikelihood,
+        _ => return Err(exceptions::ValueError.into()),
+    };
+    let projectors = projectors.iter().map(|p| to_complex(p)).collect();
+    let tomography = convert_hydra_harp_result(Tomography::new(projectors))?;
+    let target = target.map(|t| to_complex(&t));
+    let result = convert_hydra_harp_result(tomography.analyse(
+        &counts,
+        method,
+        target.as_ref().map(|t| t.as_slice()),
+        samples,
+        seed,
+    ))?;
+    Ok((
+        result
+            .density_matrix
+            .iter()
+            .map(|row| row.iter().map(|x| (x.re, x.im)).collect())
+            .collect(),
+        result.metrics,
+        (
+            result.fidelity_error,
+            result.purity_error,
+            result.concurrence_error,
+            result.entropy_error,
+        ),
+    ))
+}
+
+/// Make a segmenter which starts a new copy of `counter` at every marker of a run
+#[pyfunction]
+pub fn new_marker_segmenter(counter: &CoincidenceCounter) -> PyResult<MarkerSegmenter> {
+    Ok(MarkerSegmenter::new(counter.clone()))
+}
+
+/// Get the `(marker, counter)` of each finished segment
+#[pyfunction]
+pub fn segment_counters(s: &MarkerSegmenter) -> PyResult<Vec<(u8, CoincidenceCounter)>> {
+    Ok(s.segments.clone())
+}
+
+fn chsh_channels(channels: (u8, u8, u8, u8)) -> ChshChannels {
+    ChshChannels {
+        a_plus: channels.0,
+        a_minus: channels.1,
+        b_plus: channels.2,
+        b_minus: channels.3,
+    }
+}
+
+/// Evaluate the CHSH inequality from the `(N++, N+-, N-+, N--)` counts of the settings
+/// `(a, b)`, `(a, b')`, `(a', b)`, `(a', b')`
+#[pyfunction]
+pub fn chsh_from_counts(counts: Vec<(u64, u64, u64, u64)>) -> PyResult<ChshResult> {
+    if counts.len() != 4 {
+        return Err(exceptions::ValueError.into());
+    }
+    let setting = |i: usize| {
+        let (pp, pm, mp, mm) = counts[i];
+        SettingCounts::new(pp, pm, mp, mm)
+    };
+    convert_hydra_harp_result(crate::chsh::chsh(&[
+        setting(0),
+        setting(1),
+        setting(2),
+        setting(3),
+    ]))
+}
+
+/// Evaluate the CHSH inequality from the coincidence counters of the settings `(a, b)`, `(a, b')`,
+/// `(a', b)`, `(a', b')`. `channels` are the `(a+, a-, b+, b-)` detectors
+#[pyfunction]
+pub fn chsh_from_counters(
+    ab: &CoincidenceCounter,
+    ab_prime: &CoincidenceCounter,
+    a_prime_b: &CoincidenceCounter,
+    a_prime_b_prime: &CoincidenceCounter,
+    channels: (u8, u8, u8, u8),
+    subtract_accidentals: bool,
+) -> PyResult<ChshResult> {
+    let channels = chsh_channels(channels);
+    let setting = |c: &CoincidenceCounter| {
+        convert_hydra_harp_result(SettingCounts::from_counter(c, channels, subtract_accidentals))
+    };
+    convert_hydra_harp_result(crate::chsh::chsh(&[
+        setting(ab)?,
+        setting(ab_prime)?,
+        setting(a_prime_b)?,
+        setting(a_prime_b_prime)?,
+    ]))
+}
+
+/// Evaluate the CHSH inequality from the segments of a single run, where `markers` are the markers
+/// which start the settings `(a, b)`, `(a, b')`, `(a', b)`, `(a', b')`.
+/// `channels` are the `(a+, a-, b+, b-)` detectors
+#[pyfunction]
+pub fn chsh_from_segments(
+    s: &MarkerSegmenter,
+    markers: (u8, u8, u8, u8),
+    channels: (u8, u8, u8, u8),
+    subtract_accidentals: bool,
+) -> PyResult<ChshResult> {
+    convert_hydra_harp_result(crate::chsh::chsh_from_segments(
+        &s.segments,
+        [markers.0, markers.1, markers.2, markers.3],
+        chsh_channels(channels),
+        subtract_accidentals,
+    ))
+}
+
+/// Make a coincidence matrix of one basis from Poissonian `counts[a][b]`
+#[pyfunction]
+pub fn coincidence_matrix(counts: Vec<Vec<u64>>) -> PyResult<CoincidenceMatrix> {
+    convert_hydra_harp_result(CoincidenceMatrix::from_counts(&counts))
+}
+
+/// Make an empty `dimension` x `dimension` coincidence matrix, to be filled with `matrix_set_pair`
+#[pyfunction]
+pub fn new_coincidence_matrix(dimension: usize) -> PyResult<CoincidenceMatrix> {
+    Ok(CoincidenceMatrix::new(dimension))
+}
+
+/// Set the coincidences of outcomes `a` and `b` of a coincidence matrix, optionally subtracting
+/// the accidentals
+#[pyfunction]
+pub fn matrix_set_pair(
+    matrix: &mut CoincidenceMatrix,
+    a: usize,
+    b: usize,
+    statistics: &PairStatistics,
+    subtract_accidentals: bool,
+) -> PyResult<()> {
+    convert_hydra_harp_result(matrix.set_pair(a, b, statistics, subtract_accidentals))
+}
+
+/// Make a coincidence matrix of one basis from a counter, where `a_channels[i]` and `b_channels[i]`
+/// are the detectors on outcome `i` of each side
+#[pyfunction]
+pub fn coincidence_matrix_from_counter(
+    counter: &CoincidenceCounter,
+    a_channels: Vec<u8>,
+    b_channels: Vec<u8>,
+    subtract_accidentals: bool,
+) -> PyResult<CoincidenceMatrix> {
+    convert_hydra_harp_result(CoincidenceMatrix::from_counter(
+        counter,
+        &a_channels,
+        &b_channels,
+        subtract_accidentals,
+    ))
+}
+
+/// Get `(counts, variances)` of a coincidence matrix
+#[pyfunction]
+pub fn matrix_counts(matrix: &CoincidenceMatrix) -> PyResult<(Vec<Vec<f64>>, Vec<Vec<f64>>)> {
+    Ok((matrix.counts.clone(), matrix.variances.clone()))
+}
+
+/// Evaluate the entanglement witness from the coincidence matrices of two or more mutually unbiased bases
+#[pyfunction]
+pub fn evaluate_mub_witness(py: Python, bases: Vec<PyObject>) -> PyResult<MubWitness> {
+    let bases = bases
+        .iter()
+        .map(|b| b.extract::<&CoincidenceMatrix>(py).map(|b| b.clone()))
+        .collect::<PyResult<Vec<_>>>()?;
+    convert_hydra_harp_result(mub_witness(&bases))
+}
+
+fn parse_record_type(name: &str) -> PyResult<RecordType> {
+    match name {
+        "t2" => Ok(RecordType::HydraHarpT2),
+        "t3" => Ok(RecordType::HydraHarpT3),
+        "t2v2" => Ok(RecordType::HydraHarp2T2),
+        "t3v2" => Ok(RecordType::HydraHarp2T3),
+        _ => Err(exceptions::ValueError.into()),
+    }
+}
+
+/// The settings to write to a file, from `sync = (divider, cfd_level, cfd_zero_cross, offset)` and
+/// `(enabled, cfd_level, cfd_zero_cross, offset)` of each input
+fn device_config(
+    record_type: RecordType,
+    external_reference: bool,
+    sync: (i32, i32, i32, i32),
+    inputs: Vec<(bool, i32, i32, i32)>,
+) -> DeviceConfig {
+    DeviceConfig {
+        mode: record_type.mode(),
+        reference_source: if external_reference {
+            ReferenceSource::External
+        } else {
+            ReferenceSource::Internal
+        },
+        sync_divider: sync.0,
+        sync_cfd_level: sync.1,
+        sync_cfd_zero_cross: sync.2,
+        sync_offset: sync.3,
+        inputs: inputs
+            .into_iter()
+            .map(|(enabled, cfd_level, cfd_zero_cross, offset)| InputConfig {
+                enabled,
+                cfd_level,
+                cfd_zero_cross,
+                offset,
+            })
+            .collect(),
+    }
+}
+
+fn tag_to_python(py: Python, tag: &Tag) -> (String, i32, PyObject) {
+    let value = match &tag.value {
+        TagValue::Empty => py.None(),
+        TagValue::Bool(b) => b.to_object(py),
+        TagValue::Int(i) => i.to_object(py),
+        TagValue::BitSet(b) | TagValue::Colour(b) => b.to_object(py),
+        TagValue::Float(f) | TagValue::DateTime(f) => f.to_object(py),
+        TagValue::FloatArray(v) => v.to_object(py),
+        TagValue::AnsiString(s) | TagValue::WideString(s) => s.to_object(py),
+        TagValue::BinaryBlob(b) => PyBytes::new(py, b).to_object(py),
+    };
+    (tag.name.clone(), tag.index, value)
+}
+
+/// Read the header tags of a PTU, HT2, HT3 or PHU file as `(name, index, value)`, where the index
+/// is the channel of per-channel tags and -1 otherwise
+#[pyfunction]
+pub fn read_file_tags(py: Python, path: String) -> PyResult<Vec<(String, i32, PyObject)>> {
+    let tags = convert_hydra_harp_result(read_header_tags(&path))?;
+    Ok(tags.iter().map(|t| tag_to_python(py, t)).collect())
+}
+
+/// Read the `(resolution, counts)` of each histogram in a PHU file, with the resolution in ps
+#[pyfunction]
+pub fn read_phu(path: String) -> PyResult<Vec<(f64, Vec<u32>)>> {
+    let file = convert_hydra_harp_result(PhuFile::open(&path))?;
+    Ok(file
+        .curves
+        .into_iter()
+        .map(|c| (c.resolution, c.counts))
+        .collect())
+}
+
+/// Read the `(channel, time)` values of a T2 file, decoded as the live data would be
+#[pyfunction]
+pub fn read_T2_file(path: String) -> PyResult<Vec<(u8, u64)>> {
+    let file = convert_hydra_harp_result(TtrFile::open(&path))?;
+    let mut times = Vec::new();
+    convert_hydra_harp_result(
+        file.read_T2(file.measurement(&[]), |t| times.extend_from_slice(t)),
+    )?;
+    Ok(times)
+}
+
+/// Read the `(channel, sync number, start-stop time)` values of a T3 file, decoded as the live data would be
+#[pyfunction]
+pub fn read_T3_file(path: String) -> PyResult<Vec<(u8, u64, u32)>> {
+    let file = convert_hydra_harp_result(TtrFile::open(&path))?;
+    let mut events = Vec::new();
+    convert_hydra_harp_result(file.read_T3(|e| events.extend_from_slice(e)))?;
+    Ok(events)
+}
+
+/// Feed a recorded T2 file through a pipeline. Its recorders aren't given the records
+#[pyfunction]
+pub fn pipeline_add_file(p: &mut Pipeline, path: String) -> PyResult<()> {
+    let file = convert_hydra_harp_result(TtrFile::open(&path))?;
+    let measurement = file.measurement(&p.delays);
+    convert_hydra_harp_result(file.read_T2(measurement, |t| p.process(t.to_vec())))?;
+    p.end_measurement(file.duration().unwrap_or(0));
+    Ok(())
+}
+
+/// Read the sidecar of a raw recording as JSON
+#[pyfunction]
+pub fn read_raw_metadata(path: String) -> PyResult<String> {
+    let metadata = convert_hydra_harp_result(RawMetadata::load(&path))?;
+    serde_json::to_string(&metadata).map_err(|_| exceptions::ValueError.into())
+}
+
+/// Read the `(channel, time)` values of a T2 raw recording, with the times of each measurement
+/// carrying on from the end of the one before
+#[pyfunction]
+pub fn read_raw_T2_file(path: String) -> PyResult<Vec<(u8, u64)>> {
+    let file = convert_hydra_harp_result(RawFile::open(&path))?;
+    let mut times = Vec::new();
+    convert_hydra_harp_result(
+        file.read_T2(file.measurement(&[]), |t| times.extend_from_slice(t)),
+    )?;
+    Ok(times)
+}
+
+/// Read the `(channel, sync number, start-stop time)` values of measurement `index` of a T3 raw recording
+#[pyfunction]
+pub fn read_raw_T3_file(path: String, index: usize) -> PyResult<Vec<(u8, u64, u32)>> {
+    let file = convert_hydra_harp_result(RawFile::open(&path))?;
+    let mut events = Vec::new();
+    convert_hydra_harp_result(file.read_T3(index, |e| events.extend_from_slice(e)))?;
+    Ok(events)
+}
+
+/// Feed a T2 raw recording through a pipeline. Its recorders aren't given the records
+#[pyfunction]
+pub fn pipeline_add_raw_file(p: &mut Pipeline, path: String) -> PyResult<()> {
+    let file = convert_hydra_harp_result(RawFile::open(&path))?;
+    let measurement = file.measurement(&p.delays);
+    convert_hydra_harp_result(file.read_T2(measurement, |t| p.process(t.to_vec())))?;
+    p.end_measurement(file.duration());
+    Ok(())
+}
+
+/// Archive the T2 records of a PTU, HT2 or raw recording at `path` as compressed time tags at
+/// `destination`, keeping the markers. Returns the number of events archived
+#[pyfunction]
+pub fn archive_file(path: String, destination: String) -> PyResult<u64> {
+    if sidecar_path(std::path::Path::new(&path)).exists() {
+        convert_hydra_harp_result(archive_raw_file(&path, &destination))
+    } else {
+        convert_hydra_harp_result(archive_ttr_file(&path, &destination))
+    }
+}
+
+/// Read the `(channel, time)` values of an archive with times from `start` up to `end` ps
+#[pyfunction]
+pub fn read_archive(path: String, start: u64, end: u64) -> PyResult<Vec<(u8, u64)>> {
+    let archive = convert_hydra_harp_result(ArchiveFile::open(&path))?;
+    let mut times = Vec::new();
+    convert_hydra_harp_result(archive.read_range(start..end, |t| times.extend_from_slice(t)))?;
+    Ok(times)
+}
+
+/// Write the time tags of an archive back out as a PTU file
+#[pyfunction]
+pub fn archive_to_ptu(path: String, destination: String) -> PyResult<()> {
+    let archive = convert_hydra_harp_result(ArchiveFile::open(&path))?;
+    convert_hydra_harp_result(archive.to_ptu(&destination))
+}
+
+/// Write the time tags of an archive back out as a raw recording
+#[pyfunction]
+pub fn archive_to_raw(path: String, destination: String) -> PyResult<()> {
+    let archive = convert_hydra_harp_result(ArchiveFile::open(&path))?;
+    convert_hydra_harp_result(archive.to_raw(&destination))
+}
+
+/// Write `(channel, time)` values to `path` as `.npy`, `.npz`, Arrow (`.arrow`, `.feather`) or
+/// `.parquet`, depending on its extension, with the resolution of the times in ps
+#[pyfunction]
+pub fn export_time_tags(times: Vec<(u8, u64)>, path: String, resolution: f64) -> PyResult<()> {
+    let mut table = Table::time_tags(&times);
+    table.set_measurement(resolution, None);
+    convert_hydra_harp_result(table.write(&path))
+}
+
+/// Write the `(channel, time)` values of a T2 PTU, HT2 or raw recording to `destination`, in the
+/// format of its extension as for `export_time_tags`
+#[pyfunction]
+pub fn export_file(path: String, destination: String) -> PyResult<()> {
+    let table = convert_hydra_harp_result(Table::time_tags_of_file(&path))?;
+    convert_hydra_harp_result(table.write(&destination))
+}
+
+/// Write the bins of a trace to `path`, in the format of its extension as for `export_time_tags`
+#[pyfunction]
+pub fn export_trace(trace: &TraceBinner, path: String) -> PyResult<()> {
+    convert_hydra_harp_result(Table::trace(trace).write(&path))
+}
+
+/// Write the singles and coincidences of each pair of channels to `path`, in the format of its
+/// extension as for `export_time_tags`
+#[pyfunction]
+pub fn export_coincidences(counter: &CoincidenceCounter, path: String) -> PyResult<()> {
+    convert_hydra_harp_result(Table::coincidences(counter).write(&path))
+}
+
+/// Write a cross-correlation histogram to `path`, in the format of its extension as for `export_time_tags`
+#[pyfunction]
+pub fn export_correlation(correlator: &CrossCorrelator, path: String) -> PyResult<()> {
+    convert_hydra_harp_result(Table::correlation(correlator).write(&path))
+}
+
+/// Write lifetime histograms to `path`, in the format of its extension as for `export_time_tags`
+#[pyfunction]
+pub fn export_lifetime(histogram: &LifetimeHistogram, path: String) -> PyResult<()> {
+    convert_hydra_harp_result(Table::lifetime(histogram).write(&path))
+}
+
+/// Write a coincidence matrix to `path`, in the format of its extension as for `export_time_tags`
+#[pyfunction]
+pub fn export_coincidence_matrix(matrix: &CoincidenceMatrix, path: String) -> PyResult<()> {
+    convert_hydra_harp_result(Table::coincidence_matrix(matrix).write(&path))
+}
+
+/// Make a run starting now, with nothing measured yet
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn new_run(note: String, tags: Vec<String>) -> PyResult<Run> {
+    let mut run = Run::new(&note);
+    run.tags = tags;
+    Ok(run)
+}
+
+/// Make a run describing a raw recording, with the singles counted from its records
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn run_from_recording(path: String, note: String, tags: Vec<String>) -> PyResult<Run> {
+    let mut run = convert_hydra_harp_result(Run::from_recording(&path, &note))?;
+    run.tags = tags;
+    Ok(run)
+}
+
+/// Take the singles, coincidences and duration of a run from a coincidence counter
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn run_set_coincidences(run: &mut Run, counter: &CoincidenceCounter) -> PyResult<()> {
+    run.set_coincidences(counter);
+    Ok(())
+}
+
+/// The settings a run was measured with as JSON, if they're known
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn run_config(run: &Run) -> PyResult<Option<String>> {
+    Ok(run
+        .config
+        .as_ref()
+        .and_then(|c| serde_json::to_string(c).ok()))
+}
+
+/// Replay measurement `index` of the raw recording of a run in place of a device, to analyse it again
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn run_replay(run: &Run, index: usize, real_time: bool) -> PyResult<FileReplay> {
+    convert_hydra_harp_result(run.replay(index, pacing(real_time)))
+}
+
+/// Add a run to the catalogue at `database`, creating it if it doesn't exist, returning the id of the run
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn catalogue_add(database: String, run: &Run) -> PyResult<i64> {
+    convert_hydra_harp_result(Catalogue::open(&database).and_then(|mut c| c.add(run)))
+}
+
+/// Get the run with id `id` from the catalogue at `database`
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn catalogue_get(database: String, id: i64) -> PyResult<Run> {
+    convert_hydra_harp_result(Catalogue::open(&database).and_then(|c| c.get(id)))
+}
+
+/// Find the runs in the catalogue at `database` which started between `since` and `until` (in
+/// seconds since the unix epoch), have the tag, have the text in their note and were measured by
+/// the device with the serial number, oldest first. Any of these can be `None`. `parameters` is a
+/// JSON object of settings to match, eg. `{"sync_divider": 2, "inputs[0].offset": -300}`
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn catalogue_search(
+    database: String,
+    since: Option<f64>,
+    until: Option<f64>,
+    tag: Option<String>,
+    text: Option<String>,
+    serial: Option<String>,
+    parameters: Option<String>,
+) -> PyResult<Vec<Run>> {
+    let parameters = match parameters {
+        Some(p) => match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&p) {
+            Ok(p) => p.into_iter().collect(),
+            Err(_) => return Err(exceptions::ValueError.into()),
+        },
+        None => Vec::new(),
+    };
+    let query = RunQuery {
+        since,
+        until,
+        tag,
+        text,
+        serial,
+        parameters,
+    };
+    convert_hydra_harp_result(Catalogue::open(&database).and_then(|c| c.search(&query)))
+}
+
+/// Tag the run with id `id` in the catalogue at `database`
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn catalogue_tag(database: String, id: i64, tag: String) -> PyResult<()> {
+    convert_hydra_harp_result(Catalogue::open(&database).and_then(|c| c.tag(id, &tag)))
+}
+
+/// Remove a tag from the run with id `id` in the catalogue at `database`
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn catalogue_untag(database: String, id: i64, tag: String) -> PyResult<()> {
+    convert_hydra_harp_result(Catalogue::open(&database).and_then(|c| c.untag(id, &tag)))
+}
+
+/// Replace the note of the run with id `id` in the catalogue at `database`
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn catalogue_set_note(database: String, id: i64, note: String) -> PyResult<()> {
+    convert_hydra_harp_result(Catalogue::open(&database).and_then(|c| c.set_note(id, &note)))
+}
+
+/// Remove the run with id `id` from the catalogue at `database`, leaving its raw recording alone
+#[cfg(feature = "catalogue")]
+#[pyfunction]
+pub fn catalogue_remove(database: String, id: i64) -> PyResult<()> {
+    convert_hydra_harp_result(Catalogue::open(&database).and_then(|mut c| c.remove(id)))
+}
+
+fn pacing(real_time: bool) -> Pacing {
+    if real_time {
+        Pacing::RealTime
+    } else {
+        Pacing::AsFastAsPossible
+    }
+}
+
+/// Open a PTU, HT2 or HT3 file to replay in place of a device, either in real time or as fast as possible
+#[pyfunction]
+pub fn open_replay(path: String, real_time: bool) -> PyResult<FileReplay> {
+    convert_hydra_harp_result(FileReplay::open(&path, pacing(real_time)))
+}
+
+/// Open a file of raw fifo records recorded in `mode` (`"t2"` or `"t3"`) to replay in place of a
+/// device. `sync_period` in ps is needed to replay T3 records in real time, and `duration` in ps is
+/// the length of the recording if it's known
+#[pyfunction]
+pub fn open_raw_replay(
+    path: String,
+    mode: &str,
+    sync_period: f64,
+    duration: Option<u64>,
+    real_time: bool,
+) -> PyResult<FileReplay> {
+    let mode = match mode {
+        "t2" => MeasurementMode::T2,
+        "t3" => MeasurementMode::T3,
+        _ => return Err(exceptions::ValueError.into()),
+    };
+    convert_hydra_harp_result(FileReplay::open_raw(
+        &path,
+        mode,
+        sync_period,
+        duration,
+        pacing(real_time),
+    ))
+}
+
+/// Open measurement `index` of a raw recording to replay in place of a device, either in real
+/// time or as fast as possible
+#[pyfunction]
+pub fn open_recording_replay(path: String, index: usize, real_time: bool) -> PyResult<FileReplay> {
+    convert_hydra_harp_result(FileReplay::open_recording(&path, index, pacing(real_time)))
+}
+
+/// Make a simulated device with no light and no sync, with its random numbers seeded by `seed`
+#[pyfunction]
+pub fn new_simulator(seed: u64) -> PyResult<PhotonSimulator> {
+    Ok(PhotonSimulator::new(seed))
+}
+
+/// Set the rate of the simulated sync pulses per second, recording every `divider`th on the sync channel
+#[pyfunction]
+pub fn simulator_set_sync(s: &mut PhotonSimulator, rate: f64, divider: u32) -> PyResult<()> {
+    if rate < 0.0 || divider == 0 {
+        return Err(exceptions::ValueError.into());
+    }
+    s.sync_rate = rate;
+    s.sync_divider = divider;
+    Ok(())
+}
+
+/// Add uncorrelated light on `channel` at `rate` per second
+#[pyfunction]
+pub fn simulator_add_singles(s: &mut PhotonSimulator, channel: u8, rate: f64) -> PyResult<()> {
+    s.singles.push((channel, rate));
+    Ok(())
+}
+
+/// Add a source of photon pairs on `channels`, generating `rate` pairs per second with each photon
+/// detected with `efficiencies`. The second photon arrives `delay` ps after the first, with a
+/// standard deviation of `jitter` ps. Pulsed sources are pumped by the sync pulses
+#[pyfunction]
+pub fn simulator_add_pair_source(
+    s: &mut PhotonSimulator,
+    channels: (u8, u8),
+    rate: f64,
+    efficiencies: (f64, f64),
+    delay: i64,
+    jitter: f64,
+    pulsed: bool,
+) -> PyResult<()> {
+    s.pairs.push(PairSource {
+        channels,
+        rate,
+        efficiencies,
+        delay,
+        jitter,
+        pulsed,
+    });
+    Ok(())
+}
+
+/// Set the dark count rate per second, dead time in ps, jitter in ps, afterpulse probability and
+/// mean afterpulse delay in ps of the detector on `channel`
+#[pyfunction]
+pub fn simulator_set_detector(
+    s: &mut PhotonSimulator,
+    channel: u8,
+    dark_count_rate: f64,
+    dead_time: u64,
+    jitter: f64,
+    afterpulse_probability: f64,
+    afterpulse_delay: f64,
+) -> PyResult<()> {
+    convert_hydra_harp_result(s.set_detector(
+        channel,
+        Detector {
+            dark_count_rate,
+            dead_time,
+            jitter,
+            afterpulse_probability,
+            afterpulse_delay,
+        },
+    ))
+}
+
+/// Add `marker` (1 to 15) at `time` ps into each simulated measurement
+#[pyfunction]
+pub fn simulator_add_marker(s: &mut PhotonSimulator, time: u64, marker: u8) -> PyResult<()> {
+    if marker == 0 || marker > 15 {
+        return Err(exceptions::ValueError.into());
+    }
+    s.markers.push((time, marker));
+    Ok(())
+}
+
+/// Get the number of pairs generated in the last simulated measurement, including those which weren't detected
+#[pyfunction]
+pub fn simulator_generated_pairs(s: &PhotonSimulator) -> PyResult<u64> {
+    Ok(s.generated_pairs)
+}
+
+/// Wrap a copy of a simulated device, injecting the faults scripted in the JSON file at `path`
+#[pyfunction]
+pub fn new_faulty_simulator(s: &PhotonSimulator, path: &str) -> PyResult<FaultySimulator> {
+    let script = convert_hydra_harp_result(FaultScript::load(path))?;
+    Ok(FaultySimulator {
+        injector: FaultInjector::new(s.clone(), script),
+    })
+}
+
+/// Get the flags of a faulty simulated device, including any injected FIFO full flag
+#[pyfunction]
+pub fn faulty_get_flags(f: &FaultySimulator) -> PyResult<i32> {
+    convert_hydra_harp_result(f.injector.get_flags())
+}
+
+/// Get the warnings of a faulty simulated device, including any injected warnings
+#[pyfunction]
+pub fn faulty_get_warnings(f: &FaultySimulator) -> PyResult<i32> {
+    convert_hydra_harp_result(f.injector.get_warnings())
+}
+
+/// Make an empty pipeline, which decodes the records with `delays[channel]` ps of software delay
+#[pyfunction]
+pub fn new_pipeline(delays: Vec<i64>) -> PyResult<Pipeline> {
+    Ok(Pipeline::new(delays))
+}
+
+/// Add a stage to a pipeline which keeps only the events passed by a copy of `gate`
+#[pyfunction]
+pub fn pipeline_add_gate(p: &mut Pipeline, gate: &GateFilter) -> PyResult<()> {
+    p.add_stage(PipelineStage::Gate(gate.clone()));
+    Ok(())
+}
+
+/// Add a stage to a pipeline which keeps only the events on `channels`
+#[pyfunction]
+pub fn pipeline_add_channel_filter(p: &mut Pipeline, channels: Vec<u8>) -> PyResult<()> {
+    p.add_stage(PipelineStage::Channels(channels));
+    Ok(())
+}
+
+/// Add a stage to a pipeline which moves the events on each of `from` onto channel `into`
+#[pyfunction]
+pub fn pipeline_add_merge(p: &mut Pipeline, from: Vec<u8>, into: u8) -> PyResult<()> {
+    p.add_stage(PipelineStage::Merge { from, into });
+    Ok(())
+}
+
+/// Add a copy of a correlator or counter to a pipeline as a sink, returning its index.
+/// Get it back with the results using `pipeline_sink`
+#[pyfunction]
+pub fn pipeline_add_sink(py: Python, p: &mut Pipeline, sink: PyObject) -> PyResult<usize> {
+    let sink = if let Ok(s) = sink.extract::<&CrossCorrelator>(py) {
+        PipelineSink::Correlator(s.clone())
+    } else if let Ok(s) = sink.extract::<&MultiTauCorrelator>(py) {
+        PipelineSink::MultiTau(s.clone())
+    } else if let Ok(s) = sink.extract::<&CoincidenceCounter>(py) {
+        PipelineSink::Coincidences(s.clone())
+    } else if let Ok(s) = sink.extract::<&HeraldedCounter>(py) {
+        PipelineSink::Heralded(s.clone())
+    } else if let Ok(s) = sink.extract::<&ConditionalCounter>(py) {
+        PipelineSink::Conditional(s.clone())
+    } else if let Ok(s) = sink.extract::<&TraceBinner>(py) {
+        PipelineSink::Trace(s.clone())
+    } else if let Ok(s) = sink.extract::<&LifetimeHistogram>(py) {
+        PipelineSink::Lifetime(s.clone())
+    } else {
+        return Err(exceptions::TypeError.into());
+    };
+    Ok(p.add_sink(sink))
+}
+
+/// Get a copy of the sink at `index` in a pipeline, or the number of records written by a recorder
+#[pyfunction]
+pub fn pipeline_sink(py: Python, p: &Pipeline, index: usize) -> PyResult<PyObject> {
+    match p.sinks.get(index) {
+        Some(PipelineSink::Correlator(s)) => Ok(s.clone().into_object(py)),
+        Some(PipelineSink::MultiTau(s)) => Ok(s.clone().into_object(py)),
+        Some(PipelineSink::Coincidences(s)) => Ok(s.clone().into_object(py)),
+        Some(PipelineSink::Heralded(s)) => Ok(s.clone().into_object(py)),
+        Some(PipelineSink::Conditional(s)) => Ok(s.clone().into_object(py)),
+        Some(PipelineSink::Trace(s)) => Ok(s.clone().into_object(py)),
+        Some(PipelineSink::Lifetime(s)) => Ok(s.clone().into_object(py)),
+        Some(PipelineSink::Ptu(w)) => Ok(w.records().into_object(py)),
+        Some(PipelineSink::Raw(r)) => Ok(r.metadata.records().into_object(py)),
+        _ => Err(exceptions::IndexError.into()),
+    }
+}
+
+/// Feed recorded T2 fifo records covering `duration` ps through a pipeline
+#[pyfunction]
+pub fn pipeline_add_records(p: &mut Pipeline, records: Vec<u32>, duration: u64) -> PyResult<()> {
+    convert_hydra_harp_result(p.run_records(&records, duration))
+}
+
+/// Add a recorder to a pipeline which writes the T2 records to a new PTU file at `path`, returning
+/// its index. The arguments are as for `record_ptu`. The file is finished by `pipeline_finish`
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub fn pipeline_add_ptu_recorder(
+    p: &mut Pipeline,
+    d: &Device,
+    acquisition_time: i32,
+    path: String,
+    record_type: &str,
+    external_reference: bool,
+    sync: (i32, i32, i32, i32),
+    inputs: Vec<(bool, i32, i32, i32)>,
+) -> PyResult<usize> {
+    let record_type = parse_record_type(record_type)?;
+    let config = device_config(record_type, external_reference, sync, inputs);
+    let header = convert_hydra_harp_result(PtuHeader::from_device(
+        d,
+        &config,
+        record_type,
+        acquisition_time,
+    ))?;
+    let writer = convert_hydra_harp_result(PtuWriter::create(&path, &header))?;
+    Ok(p.add_sink(PipelineSink::Ptu(writer)))
+}
+
+/// Add a recorder to a pipeline which appends the T2 records to the raw recording at `path`,
+/// returning its index. The arguments are as for `record_raw`
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub fn pipeline_add_raw_recorder(
+    p: &mut Pipeline,
+    d: &Device,
+    path: String,
+    append: bool,
+    record_type: &str,
+    external_reference: bool,
+    sync: (i32, i32, i32, i32),
+    inputs: Vec<(bool, i32, i32, i32)>,
+) -> PyResult<usize> {
+    let recorder = if append && std::path::Path::new(&path).exists() {
+        convert_hydra_harp_result(RawRecorder::append(&path))?
+    } else {
+        let record_type = parse_record_type(record_type)?;
+        let config = device_config(record_type, external_reference, sync, inputs);
+        let metadata =
+            convert_hydra_harp_result(RawMetadata::from_device(d, &config, record_type))?;
+        convert_hydra_harp_result(RawRecorder::create(&path, metadata))?
+    };
+    Ok(p.add_sink(PipelineSink::Raw(recorder)))
+}
+
+/// Finish the PTU files and save the raw recordings of a pipeline's recorders
+#[pyfunction]
+pub fn pipeline_finish(p: &mut Pipeline) -> PyResult<()> {
+    convert_hydra_harp_result(p.finish())
+}
+
+#[pymodule]
+fn hhlib_sys(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    add_enums(py, m)?;
+    m.add_class::<Device>()?;
+    m.add_wrapped(wrap_pyfunction!(open_device))?;
+    m.add_wrapped(wrap_pyfunction!(close_device))?;
+    m.add_wrapped(wrap_pyfunction!(initialise))?;
+    m.add_wrapped(wrap_pyfunction!(get_base_resolution))?;
+    m.add_wrapped(wrap_pyfunction!(get_number_of_input_channels))?;
+    m.add_wrapped(wrap_pyfunction!(calibrate))?;
+    m.add_wrapped(wrap_pyfunction!(set_sync_divider))?;
+    m.add_wrapped(wrap_pyfunction!(set_sync_channel_offset))?;
+    m.add_wrapped(wrap_pyfunction!(set_sync_CFD))?;
+    m.add_wrapped(wrap_pyfunction!(set_input_CFD))?;
+    m.add_wrapped(wrap_pyfunction!(set_input_channel_offset))?;
+    m.add_wrapped(wrap_pyfunction!(set_input_channel_enabled))?;
+    m.add_wrapped(wrap_pyfunction!(set_stop_overflow))?;
+    m.add_wrapped(wrap_pyfunction!(set_binning))?;
+    m.add_wrapped(wrap_pyfunction!(set_offset))?;
+    m.add_wrapped(wrap_pyfunction!(clear_histogram_memory))?;
+    m.add_wrapped(wrap_pyfunction!(start_measurement))?;
+    m.add_wrapped(wrap_pyfunction!(stop_measurement))?;
+    m.add_wrapped(wrap_pyfunction!(get_CTC_status))?;
+    m.add_wrapped(wrap_pyfunction!(get_resolution))?;
+    m.add_wrapped(wrap_pyfunction!(get_sync_rate))?;
+    m.add_wrapped(wrap_pyfunction!(get_count_rate))?;
+    m.add_wrapped(wrap_pyfunction!(get_elapsed_measurement_time))?;
+    //m.add_wrapped(wrap_pyfunction!(measure_and_get_counts))?;
+    m.add_wrapped(wrap_pyfunction!(coincidence_channels_to_index))?;
+    m.add_wrapped(wrap_pyfunction!(index_to_coincidence_channels))?;
+    m.add_wrapped(wrap_pyfunction!(new_correlator))?;
+    m.add_wrapped(wrap_pyfunction!(correlator_g2))?;
+    m.add_wrapped(wrap_pyfunction!(merge_correlators))?;
+    m.add_wrapped(wrap_pyfunction!(new_multi_tau_correlator))?;
+    m.add_wrapped(wrap_pyfunction!(new_coincidence_counter))?;
+    m.add_wrapped(wrap_pyfunction!(coincidence_results))?;
+    m.add_wrapped(wrap_pyfunction!(set_pair_window))?;
+    m.add_wrapped(wrap_pyfunction!(set_software_delays))?;
+    m.add_wrapped(wrap_pyfunction!(add_coincidence_records))?;
+    m.add_wrapped(wrap_pyfunction!(new_gate_filter))?;
+    m.add_wrapped(wrap_pyfunction!(set_gates))?;
+    m.add_wrapped(wrap_pyfunction!(gate_counts))?;
+    m.add_wrapped(wrap_pyfunction!(new_heralded_counter))?;
+    m.add_wrapped(wrap_pyfunction!(new_conditional_counter))?;
+    m.add_wrapped(wrap_pyfunction!(conditional_counts))?;
+    m.add_wrapped(wrap_pyfunction!(heralded_g2))?;
+    m.add_wrapped(wrap_pyfunction!(add_heralded_records))?;
+    m.add_wrapped(wrap_pyfunction!(multi_tau_curve))?;
+    m.add_wrapped(wrap_pyfunction!(multi_tau_empty_segments))?;
+    m.add_wrapped(wrap_pyfunction!(new_trace_binner))?;
+    #[cfg(feature = "numpy")]
+    m.add_wrapped(wrap_pyfunction!(trace_take_bins))?;
+    m.add_wrapped(wrap_pyfunction!(new_lifetime_histogram))?;
+    m.add_wrapped(wrap_pyfunction!(lifetime_histograms))?;
+    m.add_wrapped(wrap_pyfunction!(fit_decay))?;
+    m.add_wrapped(wrap_pyfunction!(new_hom_scan))?;
+    m.add_wrapped(wrap_pyfunction!(hom_add_counter))?;
+    m.add_wrapped(wrap_pyfunction!(fit_hom_dip))?;
+    m.add_wrapped(wrap_pyfunction!(product_state))?;
+    m.add_wrapped(wrap_pyfunction!(reconstruct_state))?;
+    m.add_wrapped(wrap_pyfunction!(new_marker_segmenter))?;
+    m.add_wrapped(wrap_pyfunction!(segment_counters))?;
+    m.add_wrapped(wrap_pyfunction!(chsh_from_counts))?;
+    m.add_wrapped(wrap_pyfunction!(chsh_from_counters))?;
+    m.add_wrapped(wrap_pyfunction!(chsh_from_segments))?;
+    m.add_wrapped(wrap_pyfunction!(coincidence_matrix))?;
+    m.add_wrapped(wrap_pyfunction!(new_coincidence_matrix))?;
+    m.add_wrapped(wrap_pyfunction!(matrix_set_pair))?;
+    m.add_wrapped(wrap_pyfunction!(coincidence_matrix_from_counter))?;
+    m.add_wrapped(wrap_pyfunction!(matrix_counts))?;
+    m.add_wrapped(wrap_pyfunction!(evaluate_mub_witness))?;
+    m.add_wrapped(wrap_pyfunction!(read_file_tags))?;
+    m.add_wrapped(wrap_pyfunction!(read_phu))?;
+    m.add_wrapped(wrap_pyfunction!(read_T2_file))?;
+    m.add_wrapped(wrap_pyfunction!(read_T3_file))?;
+    m.add_wrapped(wrap_pyfunction!(open_replay))?;
+    m.add_wrapped(wrap_pyfunction!(open_raw_replay))?;
+    m.add_wrapped(wrap_pyfunction!(read_raw_metadata))?;
+    m.add_wrapped(wrap_pyfunction!(read_raw_T2_file))?;
+    m.add_wrapped(wrap_pyfunction!(read_raw_T3_file))?;
+    m.add_wrapped(wrap_pyfunction!(open_recording_replay))?;
+    m.add_wrapped(wrap_pyfunction!(archive_file))?;
+    m.add_wrapped(wrap_pyfunction!(read_archive))?;
+    m.add_wrapped(wrap_pyfunction!(archive_to_ptu))?;
+    m.add_wrapped(wrap_pyfunction!(archive_to_raw))?;
+    m.add_wrapped(wrap_pyfunction!(export_time_tags))?;
+    m.add_wrapped(wrap_pyfunction!(export_file))?;
+    m.add_wrapped(wrap_pyfunction!(export_trace))?;
+    m.add_wrapped(wrap_pyfunction!(export_coincidences))?;
+    m.add_wrapped(wrap_pyfunction!(export_correlation))?;
+    m.add_wrapped(wrap_pyfunction!(export_lifetime))?;
+    m.add_wrapped(wrap_pyfunction!(export_coincidence_matrix))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(new_run))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(run_from_recording))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(run_set_coincidences))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(run_config))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(run_replay))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(catalogue_add))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(catalogue_get))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(catalogue_search))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(catalogue_tag))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(catalogue_untag))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(catalogue_set_note))?;
+    #[cfg(feature = "catalogue")]
+    m.add_wrapped(wrap_pyfunction!(catalogue_remove))?;
+    m.add_wrapped(wrap_pyfunction!(new_simulator))?;
+    m.add_wrapped(wrap_pyfunction!(simulator_set_sync))?;
+    m.add_wrapped(wrap_pyfunction!(simulator_add_singles))?;
+    m.add_wrapped(wrap_pyfunction!(simulator_add_pair_source))?;
+    m.add_wrapped(wrap_pyfunction!(simulator_set_detector))?;
+    m.add_wrapped(wrap_pyfunction!(simulator_add_marker))?;
+    m.add_wrapped(wrap_pyfunction!(simulator_generated_pairs))?;
+    m.add_wrapped(wrap_pyfunction!(new_faulty_simulator))?;
+    m.add_wrapped(wrap_pyfunction!(faulty_get_flags))?;
+    m.add_wrapped(wrap_pyfunction!(faulty_get_warnings))?;
+    m.add_wrapped(wrap_pyfunction!(new_pipeline))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_gate))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_channel_filter))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_merge))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_sink))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_sink))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_records))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_ptu_recorder))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_raw_recorder))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_finish))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_file))?;
+    m.add_wrapped(wrap_pyfunction!(pipeline_add_raw_file))?;
+    #[pyfn(m, "measure_and_get_counts")]
+    fn measure_and_get_counts_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        coincidence_window: u64,
+        histogram_bins: usize,
+        sync_channel: u8,
+    ) -> PyResult<(Vec<usize>, Vec<usize>, Vec<Vec<usize>>)> {
+        py.allow_threads(move || {
+            measure_and_get_counts(
+                d,
+                acquisition_time,
+                coincidence_window,
+                histogram_bins,
+                sync_channel,
+            )
+        })
+    };
+    #[pyfn(m, "replay_and_get_counts")]
+    /// Like `measure_and_get_counts`, but replaying a file
+    fn replay_and_get_counts_py(
+        py: Python,
+        replay: &mut FileReplay,
+        acquisition_time: i32,
+        coincidence_window: u64,
+        histogram_bins: usize,
+        sync_channel: u8,
+    ) -> PyResult<(Vec<usize>, Vec<usize>, Vec<Vec<usize>>)> {
+        py.allow_threads(move || {
+            measure_and_get_counts(
+                replay,
+                acquisition_time,
+                coincidence_window,
+                histogram_bins,
+                sync_channel,
+            )
+        })
+    };
+    #[pyfn(m, "replay_coincidences")]
+    /// Replay a file for `acquisition_time` ms, adding the singles and coincidences to the counter
+    fn replay_coincidences_py(
+        py: Python,
+        replay: &mut FileReplay,
+        acquisition_time: i32,
+        counter: &mut CoincidenceCounter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_coincidences(replay, acquisition_time, counter))
+        })
+    };
+    #[pyfn(m, "replay_pipeline")]
+    /// Replay a T2 file for `acquisition_time` ms, feeding the events through the pipeline.
+    /// Raises `InvalidMode` for a T3 file, as pipelines only decode T2 records
+    fn replay_pipeline_py(
+        py: Python,
+        replay: &mut FileReplay,
+        acquisition_time: i32,
+        pipeline: &mut Pipeline,
+    ) -> PyResult<()> {
+        if replay.mode() != MeasurementMode::T2 {
+            return convert_hydra_harp_result(Err(HydraHarpError::InvalidMode));
+        }
+        py.allow_threads(move || convert_hydra_harp_result(pipeline.run(replay, acquisition_time)))
+    };
+    #[pyfn(m, "simulate_and_get_counts")]
+    /// Like `measure_and_get_counts`, but measuring with a simulated device
+    fn simulate_and_get_counts_py(
+        py: Python,
+        s: &mut PhotonSimulator,
+        acquisition_time: i32,
+        coincidence_window: u64,
+        histogram_bins: usize,
+        sync_channel: u8,
+    ) -> PyResult<(Vec<usize>, Vec<usize>, Vec<Vec<usize>>)> {
+        py.allow_threads(move || {
+            measure_and_get_counts(
+                s,
+                acquisition_time,
+                coincidence_window,
+                histogram_bins,
+                sync_channel,
+            )
+        })
+    };
+    #[pyfn(m, "simulate_coincidences")]
+    /// Measure with a simulated device for `acquisition_time` ms, adding the singles and coincidences to the counter
+    fn simulate_coincidences_py(
+        py: Python,
+        s: &mut PhotonSimulator,
+        acquisition_time: i32,
+        counter: &mut CoincidenceCounter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_coincidences(s, acquisition_time, counter))
+        })
+    };
+    #[pyfn(m, "simulate_pipeline")]
+    /// Measure with a simulated device for `acquisition_time` ms, feeding the events through the pipeline
+    fn simulate_pipeline_py(
+        py: Python,
+        s: &mut PhotonSimulator,
+        acquisition_time: i32,
+        pipeline: &mut Pipeline,
+    ) -> PyResult<()> {
+        py.allow_threads(move || convert_hydra_harp_result(pipeline.run(s, acquisition_time)))
+    };
+    #[pyfn(m, "faulty_coincidences")]
+    /// Measure with a faulty simulated device for `acquisition_time` ms, adding the singles and coincidences to the counter
+    fn faulty_coincidences_py(
+        py: Python,
+        f: &mut FaultySimulator,
+        acquisition_time: i32,
+        counter: &mut CoincidenceCounter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_coincidences(
+                &mut f.injector,
+                acquisition_time,
+                counter,
+            ))
+        })
+    };
+    #[pyfn(m, "faulty_pipeline")]
+    /// Measure with a faulty simulated device for `acquisition_time` ms, feeding the events through the pipeline
+    fn faulty_pipeline_py(
+        py: Python,
+        f: &mut FaultySimulator,
+        acquisition_time: i32,
+        pipeline: &mut Pipeline,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(pipeline.run(&mut f.injector, acquisition_time))
+        })
+    };
+    #[pyfn(m, "measure_correlation")]
+    /// Measure for `acquisition_time` ms, adding the delays to the correlator
+    fn measure_correlation_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        correlator: &mut CrossCorrelator,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_correlation(d, acquisition_time, correlator))
+        })
+    };
+    #[pyfn(m, "measure_coincidences")]
+    /// Measure for `acquisition_time` ms, adding the singles and coincidences to the counter
+    fn measure_coincidences_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        counter: &mut CoincidenceCounter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_coincidences(d, acquisition_time, counter))
+        })
+    };
+    #[pyfn(m, "measure_gated_coincidences")]
+    /// Measure for `acquisition_time` ms, adding the events which pass the gate filter to the counter
+    fn measure_gated_coincidences_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        gate: &mut GateFilter,
+        counter: &mut CoincidenceCounter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_gated_coincidences(
+                d,
+                acquisition_time,
+                gate,
+                counter,
+            ))
+        })
+    };
+    #[pyfn(m, "measure_gated_coincidences_T3")]
+    /// Measure in T3 mode for `acquisition_time` ms, gating the events on their start-stop time
+    /// before adding them to the counter. `resolution` is the T3 resolution of the device and
+    /// `sync_period` the time between syncs, both in ps
+    fn measure_gated_coincidences_T3_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        resolution: u64,
+        sync_period: u64,
+        gate: &mut GateFilter,
+        counter: &mut CoincidenceCounter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_gated_coincidences_T3(
+                d,
+                acquisition_time,
+                resolution,
+                sync_period,
+                gate,
+                counter,
+            ))
+        })
+    };
+    #[pyfn(m, "run_pipeline")]
+    /// Measure for `acquisition_time` ms, feeding the events through the pipeline
+    fn run_pipeline_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        pipeline: &mut Pipeline,
+    ) -> PyResult<()> {
+        py.allow_threads(move || convert_hydra_harp_result(pipeline.run(d, acquisition_time)))
+    };
+    #[pyfn(m, "measure_trace")]
+    /// Measure for `acquisition_time` ms, adding the counts to the trace binner
+    fn measure_trace_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        binner: &mut TraceBinner,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_trace(d, acquisition_time, binner))
+        })
+    };
+    #[pyfn(m, "measure_lifetime")]
+    /// Measure in T2 mode for `acquisition_time` ms, adding the delays after the sync to the histogram
+    fn measure_lifetime_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        histogram: &mut LifetimeHistogram,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_lifetime(d, acquisition_time, histogram))
+        })
+    };
+    #[pyfn(m, "measure_lifetime_T3")]
+    /// Measure in T3 mode for `acquisition_time` ms, adding the start-stop times to the histogram.
+    /// `resolution` is the T3 resolution of the device in ps
+    fn measure_lifetime_T3_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        resolution: u64,
+        histogram: &mut LifetimeHistogram,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_lifetime_T3(
+                d,
+                acquisition_time,
+                resolution,
+                histogram,
+            ))
+        })
+    };
+    #[pyfn(m, "measure_segmented")]
+    /// Measure for `acquisition_time` ms, counting the coincidences between each pair of markers separately
+    fn measure_segmented_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        segmenter: &mut MarkerSegmenter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_segmented(d, acquisition_time, segmenter))
+        })
+    };
+    #[pyfn(m, "record_ptu")]
+    /// Measure for `acquisition_time` ms, writing the raw records to a PTU file at `path`.
+    /// `record_type` is `"t2"`, `"t3"`, `"t2v2"` or `"t3v2"` for the firmware version 1 or 2 formats.
+    /// The settings the device is measuring with are written to the header, given as
+    /// `sync = (divider, cfd_level, cfd_zero_cross, offset)` and `(enabled, cfd_level, cfd_zero_cross, offset)`
+    /// of each input. Returns the number of records written
+    fn record_ptu_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        path: String,
+        record_type: &str,
+        external_reference: bool,
+        sync: (i32, i32, i32, i32),
+        inputs: Vec<(bool, i32, i32, i32)>,
+    ) -> PyResult<u64> {
+        let record_type = parse_record_type(record_type)?;
+        let config = device_config(record_type, external_reference, sync, inputs);
+        let header = convert_hydra_harp_result(PtuHeader::from_device(
+            d,
+            &config,
+            record_type,
+            acquisition_time,
+        ))?;
+        py.allow_threads(move || {
+            convert_hydra_harp_result(PtuWriter::create(&path, &header).and_then(|mut writer| {
+                record_measurement(d, acquisition_time, &mut writer)?;
+                Ok(writer.records())
+            }))
+        })
+    };
+    #[pyfn(m, "record_raw")]
+    /// Measure for `acquisition_time` ms, appending the raw records to the recording at `path` and
+    /// the measurement to its JSON sidecar. A new recording is started unless `append` is true and
+    /// there's already one at `path`. `record_type`, `sync` and `inputs` are as for `record_ptu`, and
+    /// are only used when starting a new recording. Returns the number of records written
+    fn record_raw_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        path: String,
+        append: bool,
+        record_type: &str,
+        external_reference: bool,
+        sync: (i32, i32, i32, i32),
+        inputs: Vec<(bool, i32, i32, i32)>,
+    ) -> PyResult<u64> {
+        let mut recorder = if append && std::path::Path::new(&path).exists() {
+            convert_hydra_harp_result(RawRecorder::append(&path))?
+        } else {
+            let record_type = parse_record_type(record_type)?;
+            let config = device_config(record_type, external_reference, sync, inputs);
+            let metadata =
+                convert_hydra_harp_result(RawMetadata::from_device(d, &config, record_type))?;
+            convert_hydra_harp_result(RawRecorder::create(&path, metadata))?
+        };
+        py.allow_threads(move || {
+            convert_hydra_harp_result(record_raw(d, acquisition_time, &mut recorder))?;
+            Ok(recorder.metadata.measurements.last().map_or(0, |m| m.records))
+        })
+    };
+    #[pyfn(m, "calibrate_delays")]
+    /// Measure for `acquisition_time` ms and find the delay of each of `channels` relative to
+    /// `reference`, searching between `-tau` and `tau` ps in bins of `bin_width` ps.
+    /// Returns a list of `(channel, delay, jitter, peak_counts)`, with the delay and the jitter
+    /// (FWHM of the peak) in ps. Subtract the delay from a channel's offset to line it up
+    fn calibrate_delays_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        reference: u8,
+        channels: Vec<u8>,
+        bin_width: u64,
+        tau: i64,
+    ) -> PyResult<Vec<(u8, f64, f64, u64)>> {
+        let delays = py.allow_threads(move || {
+            let search = DelaySearch {
+                reference,
+                channels,
+                bin_width,
+                tau,
+            };
+            convert_hydra_harp_result(measure_delays(d, acquisition_time, &search))
+        })?;
+        Ok(delays
+            .into_iter()
+            .map(|c| (c.channel, c.delay, c.jitter, c.peak_counts))
+            .collect())
+    };
+    #[pyfn(m, "measure_heralded")]
+    /// Measure for `acquisition_time` ms, adding the herald counts to the counter
+    fn measure_heralded_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        counter: &mut HeraldedCounter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_heralded(d, acquisition_time, counter))
+        })
+    };
+    #[pyfn(m, "measure_conditional")]
+    /// Measure for `acquisition_time` ms, adding the triggers and counts to the counter
+    fn measure_conditional_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        counter: &mut ConditionalCounter,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_conditional(d, acquisition_time, counter))
+        })
+    };
+    #[pyfn(m, "measure_multi_tau")]
+    /// Measure for `acquisition_time` ms, adding the completed segments to the correlator.
+    /// Call repeatedly and plot `multi_tau_curve` to get a live curve
+    fn measure_multi_tau_py(
+        py: Python,
+        d: &mut Device,
+        acquisition_time: i32,
+        correlator: &mut MultiTauCorrelator,
+    ) -> PyResult<()> {
+        py.allow_threads(move || {
+            convert_hydra_harp_result(measure_multi_tau(d, acquisition_time, correlator))
         })
     };
     Ok(())
@@ -0,0 +1,415 @@
+//! Raw recordings: the fifo records appended unchanged to a `.bin` file, with a JSON sidecar of
+//! the same name describing the device, its settings and each measurement. Recording costs no more
+//! than writing the records to disk, and the reader maps the file into memory so that recordings
+//! larger than RAM can be analysed again with any of the live analyses.
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use memmap::Mmap;
+use serde::{Deserialize, Serialize};
+
+use crate::config::DeviceConfig;
+use crate::device::Device;
+use crate::measurement::{Measureable, Measurement};
+use crate::ptu::{file_error, PtuHeader, RecordType};
+use crate::types::{CTCStatus, HydraHarpError, MeasurementMode};
+
+/// One measurement of a recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawMeasurement {
+    /// The index of the first record of the measurement in the file
+    pub first_record: u64,
+    pub records: u64,
+    /// Acquisition time in ms
+    pub acquisition_time: i32,
+    /// Wall clock time the measurement started and stopped at, in seconds since the unix epoch
+    pub start_time: f64,
+    pub stop_time: f64,
+    /// The `FLAG_*` flags seen at any point during the measurement
+    pub flags: i32,
+    /// The warnings at the end of the measurement
+    pub warnings: i32,
+    /// The error the measurement stopped with, if any
+    pub error: Option<HydraHarpError>,
+}
+
+/// The contents of the sidecar of a recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RawMetadata {
+    pub record_type: RecordType,
+    pub serial: String,
+    /// The model, part number and version of the hardware, empty if unknown
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub part_number: String,
+    #[serde(default)]
+    pub hardware_version: String,
+    /// Resolution of the times in the records in ps
+    pub resolution: f64,
+    /// Sync period in ps, the resolution of the sync numbers of T3 records
+    pub sync_period: Option<f64>,
+    /// The settings the device was measuring with
+    pub config: DeviceConfig,
+    pub measurements: Vec<RawMeasurement>,
+}
+
+impl RawMetadata {
+    /// The metadata of a recording with no measurements yet, with the hardware information,
+    /// resolution and sync period read from the device.
+    /// Returns `InvalidArgument` if the record type doesn't match the mode of `config`
+    pub fn from_device(
+        d: &Device,
+        config: &DeviceConfig,
+        record_type: RecordType,
+    ) -> Result<RawMetadata, HydraHarpError> {
+        let header = PtuHeader::from_device(d, config, record_type, 0)?;
+        Ok(RawMetadata {
+            record_type,
+            serial: header.serial,
+            model: header.model,
+            part_number: header.part_number,
+            hardware_version: header.hardware_version,
+            resolution: header.resolution,
+            sync_period: header.sync_period,
+            config: header.config,
+            measurements: Vec::new(),
+        })
+    }
+
+    /// The number of records in all of the measurements
+    pub fn records(&self) -> u64 {
+        self.measurements.iter().map(|m| m.records).sum()
+    }
+
+    /// Read the sidecar of the recording at `path`.
+    /// Returns `FileError` if it can't be read and `InvalidArgument` if it isn't valid
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RawMetadata, HydraHarpError> {
+        let json = std::fs::read_to_string(sidecar_path(path.as_ref())).map_err(file_error)?;
+        serde_json::from_str(&json).map_err(|_| HydraHarpError::InvalidArgument)
+    }
+
+    /// Write the sidecar of the recording at `path`, replacing it in one step so that a reader
+    /// never sees it half written
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), HydraHarpError> {
+        let sidecar = sidecar_path(path.as_ref());
+        let partial = sidecar.with_extension("json.partial");
+        let json = serde_json::to_string_pretty(self).map_err(|_| HydraHarpError::FileError)?;
+        std::fs::write(&partial, json).map_err(file_error)?;
+        std::fs::rename(&partial, &sidecar).map_err(file_error)
+    }
+}
+
+/// The path of the sidecar of the recording at `path`, with the extension replaced by `.json`
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("json")
+}
+
+pub(crate) fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// Appends records to a recording, keeping its sidecar up to date after each measurement
+pub struct RawRecorder {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    pub metadata: RawMetadata,
+}
+
+impl RawRecorder {
+    /// Start a new recording at `path`, replacing any recording there.
+    /// Returns `FileError` if it can't be written
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        metadata: RawMetadata,
+    ) -> Result<RawRecorder, HydraHarpError> {
+        let file = File::create(path.as_ref()).map_err(file_error)?;
+        let mut metadata = metadata;
+        metadata.measurements.clear();
+        metadata.save(path.as_ref())?;
+        Ok(RawRecorder {
+            path: path.as_ref().to_path_buf(),
+            writer: BufWriter::new(file),
+            metadata,
+        })
+    }
+
+    /// Carry on the recording at `path`, appending to its records. Anything after the records of
+    /// the last measurement in the sidecar, eg. from a recording which was interrupted, is dropped.
+    /// Returns `FileError` if it can't be read or written, and `InvalidArgument` if the sidecar isn't valid
+    pub fn append<P: AsRef<Path>>(path: P) -> Result<RawRecorder, HydraHarpError> {
+        let metadata = RawMetadata::load(path.as_ref())?;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path.as_ref())
+            .map_err(file_error)?;
+        file.set_len(metadata.records() * 4).map_err(file_error)?;
+        let mut writer = BufWriter::new(file);
+        writer.seek(SeekFrom::End(0)).map_err(file_error)?;
+        Ok(RawRecorder {
+            path: path.as_ref().to_path_buf(),
+            writer,
+            metadata,
+        })
+    }
+
+    pub fn write_records(&mut self, records: &[u32]) -> Result<(), HydraHarpError> {
+        for r in records.iter() {
+            self.writer
+                .write_all(&r.to_le_bytes())
+                .map_err(file_error)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the records to disk and save the sidecar
+    pub fn save(&mut self) -> Result<(), HydraHarpError> {
+        self.writer.flush().map_err(file_error)?;
+        self.metadata.save(&self.path)
+    }
+}
+
+impl Drop for RawRecorder {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, appending the fifo records to `recorder` unchanged
+/// and adding the measurement to its sidecar, along with any error it stopped with
+pub fn record_raw<M>(
+    d: &mut M,
+    acquisition_time: i32,
+    recorder: &mut RawRecorder,
+) -> Result<(), HydraHarpError>
+where
+    M: Measureable,
+{
+    const BUFFER_LENGTH: usize = 131072;
+    let mut buffer = vec![0u32; BUFFER_LENGTH];
+    let mut measurement = RawMeasurement {
+        first_record: recorder.metadata.records(),
+        records: 0,
+        acquisition_time,
+        start_time: unix_time(),
+        stop_time: 0.0,
+        flags: 0,
+        warnings: 0,
+        error: None,
+    };
+
+    let mut read = || -> Result<(), HydraHarpError> {
+        d.start_measurement(acquisition_time)?;
+        loop {
+            measurement.flags |= d.get_flags()?;
+            let num_read = d.read_fifo(&mut buffer, BUFFER_LENGTH as i32)? as usize;
+            if num_read > 0 {
+                recorder.write_records(&buffer[..num_read])?;
+                measurement.records += num_read as u64;
+            } else if d.get_CTC_status()? == CTCStatus::Ended {
+                return Ok(());
+            }
+        }
+    };
+    let result = read();
+    measurement.stop_time = unix_time();
+    measurement.warnings = d.get_warnings().unwrap_or(0);
+    measurement.error = result.err();
+    recorder.metadata.measurements.push(measurement);
+    recorder.save()?;
+    result
+}
+
+/// A recording mapped into memory, so that only the parts being read are loaded
+pub struct RawFile {
+    pub metadata: RawMetadata,
+    /// `None` for a recording with no records, which can't be mapped
+    map: Option<Mmap>,
+}
+
+impl RawFile {
+    /// Open the recording at `path` and its sidecar.
+    /// Returns `FileError` if either can't be read and `InvalidArgument` if the sidecar isn't valid
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RawFile, HydraHarpError> {
+        let metadata = RawMetadata::load(path.as_ref())?;
+        let file = File::open(path.as_ref()).map_err(file_error)?;
+        let length = file.metadata().map_err(file_error)?.len();
+        // The file mustn't be changed while it's mapped, which the recorder only does by appending
+        let map = match length {
+            0 => None,
+            _ => Some(unsafe { Mmap::map(&file) }.map_err(file_error)?),
+        };
+        Ok(RawFile { metadata, map })
+    }
+
+    /// The number of records in the file, which can include records after the last measurement in
+    /// the sidecar if the recording was interrupted
+    pub fn records(&self) -> u64 {
+        self.map.as_ref().map_or(0, |m| m.len() as u64 / 4)
+    }
+
+    /// The total length of the measurements in ps
+    pub fn duration(&self) -> u64 {
+        self.metadata
+            .measurements
+            .iter()
+            .map(|m| m.acquisition_time.max(0) as u64 * 1_000_000_000)
+            .sum()
+    }
+
+    /// A measurement which decodes the records as they were recorded, delaying each channel by
+    /// `delays[channel]` ps in software
+    pub fn measurement(&self, delays: &[i64]) -> Measurement {
+        let mut measurement = Measurement::with_delays(delays);
+        measurement.version_1_records = self.metadata.record_type.version_1_records();
+        measurement
+    }
+
+    /// Pass each chunk of the raw records in `range` to `process`, stopping at the end of the file
+    pub fn read_range<F>(&self, range: Range<u64>, mut process: F)
+    where
+        F: FnMut(&[u32]),
+    {
+        const BUFFER_LENGTH: u64 = 131072;
+        let map = match self.map.as_ref() {
+            Some(map) => map,
+            None => return,
+        };
+        let end = range.end.min(self.records());
+        let mut records = Vec::with_capacity(BUFFER_LENGTH as usize);
+        let mut start = range.start;
+        while start < end {
+            let stop = (start + BUFFER_LENGTH).min(end);
+            records.clear();
+            records.extend(
+                map[start as usize * 4..stop as usize * 4]
+                    .chunks(4)
+                    .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])),
+            );
+            process(&records);
+            start = stop;
+        }
+    }
+
+    /// Pass each chunk of raw records to `process`
+    pub fn read_records<F>(&self, process: F)
+    where
+        F: FnMut(&[u32]),
+    {
+        self.read_range(0..self.records(), process)
+    }
+
+    /// Decode the records of a T2 recording with `measurement`, passing each chunk of sorted
+    /// `(channel, time)` values to `process` as `run_measurement_T2` does for live data. The device
+    /// restarts its clock with each measurement, so the times of each measurement carry on from
+    /// the end of the acquisition time of the one before.
+    /// Returns `InvalidMode` if the recording isn't T2
+    pub fn read_T2<F>(
+        &self,
+        mut measurement: Measurement,
+        mut process: F,
+    ) -> Result<(), HydraHarpError>
+    where
+        F: FnMut(&[(u8, u64)]),
+    {
+        if self.metadata.record_type.mode() != MeasurementMode::T2 {
+            return Err(HydraHarpError::InvalidMode);
+        }
+        let mut start_time = 0;
+        for m in self.metadata.measurements.iter() {
+            measurement.time_overflow = start_time;
+            self.read_range(m.first_record..m.first_record + m.records, |records| {
+                process(&measurement.convert_sorted_T2(records))
+            });
+            process(&measurement.flush_T2());
+            start_time += m.acquisition_time.max(0) as u64 * 1_000_000_000;
+        }
+        Ok(())
+    }
+
+    /// Decode the records of one measurement of a T3 recording, passing each chunk of `(channel,
+    /// sync number, start-stop time)` values to `process` as `run_measurement_T3` does for live data.
+    /// Returns `InvalidMode` if the recording isn't T3 and `InvalidArgument` if there's no such measurement
+    pub fn read_T3<F>(&self, index: usize, process: F) -> Result<(), HydraHarpError>
+    where
+        F: FnMut(&[(u8, u64, u32)]),
+    {
+        let mut process = process;
+        if self.metadata.record_type.mode() != MeasurementMode::T3 {
+            return Err(HydraHarpError::InvalidMode);
+        }
+        let m = self
+            .metadata
+            .measurements
+            .get(index)
+            .ok_or(HydraHarpError::InvalidArgument)?;
+        let mut measurement = self.measurement(&[]);
+        self.read_range(m.first_record..m.first_record + m.records, |records| {
+            process(&measurement.convert_values_T3(records))
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_raw, sidecar_path, RawFile, RawMetadata, RawRecorder};
+    use crate::config::DeviceConfig;
+    use crate::measurement::{run_measurement_T2, Measurement};
+    use crate::ptu::RecordType;
+    use crate::simulator::PhotonSimulator;
+
+    #[test]
+    fn recording_reads_back_like_live_data() {
+        let path =
+            std::env::temp_dir().join(format!("hhlib_sys_raw_test_{}.bin", std::process::id()));
+        let metadata = RawMetadata {
+            record_type: RecordType::HydraHarp2T2,
+            serial: "1234567".to_string(),
+            model: "HydraHarp 400".to_string(),
+            part_number: "930002".to_string(),
+            hardware_version: "2.0".to_string(),
+            resolution: 1.0,
+            sync_period: None,
+            config: DeviceConfig::new(2),
+            measurements: Vec::new(),
+        };
+        let mut simulator = PhotonSimulator::new(3);
+        simulator.singles.push((1, 1e5));
+        simulator.singles.push((2, 1e5));
+        let mut recorder = RawRecorder::create(&path, metadata).unwrap();
+        record_raw(&mut simulator, 10, &mut recorder).unwrap();
+        drop(recorder);
+        let mut recorder = RawRecorder::append(&path).unwrap();
+        record_raw(&mut simulator, 10, &mut recorder).unwrap();
+        drop(recorder);
+
+        let mut live = PhotonSimulator::new(3);
+        live.singles = simulator.singles.clone();
+        let mut expected = Vec::new();
+        for offset in [0, 10_000_000_000].iter() {
+            run_measurement_T2(&mut live, 10, |t| {
+                expected.extend(t.iter().map(|&(c, time)| (c, time + offset)))
+            })
+            .unwrap();
+        }
+
+        let file = RawFile::open(&path).unwrap();
+        assert_eq!(file.metadata.model, "HydraHarp 400");
+        assert_eq!(file.metadata.measurements.len(), 2);
+        assert_eq!(file.records(), file.metadata.records());
+        assert_eq!(file.duration(), 20_000_000_000);
+        let mut times = Vec::new();
+        file.read_T2(Measurement::new(0), |t| times.extend_from_slice(t))
+            .unwrap();
+        assert_eq!(times, expected);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(sidecar_path(&path)).unwrap();
+    }
+}
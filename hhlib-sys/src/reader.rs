@@ -0,0 +1,621 @@
+//! Reading files recorded by PicoQuant's software or `ptu::PtuWriter`: PTU tagged T2/T3 files,
+//! the older HT2/HT3 files and PHU histogram files. The headers of the older files are converted
+//! into the tags a PTU file would have, so they can be looked at in the same way, and the records
+//! are decoded with the same `Measurement` as live data.
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::measurement::Measurement;
+use crate::ptu::{
+    file_error, RecordType, Tag, TagValue, MAGIC, TAG_ANSI_STRING, TAG_BINARY_BLOB, TAG_BIT_SET,
+    TAG_BOOL, TAG_COLOUR, TAG_DATE_TIME, TAG_EMPTY, TAG_FLOAT, TAG_FLOAT_ARRAY, TAG_INT,
+    TAG_WIDE_STRING,
+};
+use crate::types::HydraHarpError;
+
+const HISTOGRAM_MAGIC: &[u8; 8] = b"PQHISTO\0";
+const LEGACY_IDENT: &[u8] = b"HydraHarp";
+/// The name, index, type and value of a tag, before any data of variable length
+const TAG_LENGTH: u64 = 32 + 4 + 4 + 8;
+
+fn read_bytes<R: Read>(r: &mut R, length: usize) -> Result<Vec<u8>, HydraHarpError> {
+    let mut bytes = vec![0; length];
+    r.read_exact(&mut bytes).map_err(file_error)?;
+    Ok(bytes)
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32, HydraHarpError> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes).map_err(file_error)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_i32<R: Read>(r: &mut R) -> Result<i32, HydraHarpError> {
+    read_u32(r).map(|v| v as i32)
+}
+
+fn read_i64<R: Read>(r: &mut R) -> Result<i64, HydraHarpError> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes).map_err(file_error)?;
+    Ok(i64::from_le_bytes(bytes))
+}
+
+fn read_f64<R: Read>(r: &mut R) -> Result<f64, HydraHarpError> {
+    read_i64(r).map(|v| f64::from_bits(v as u64))
+}
+
+/// A fixed length, null padded string
+fn read_string<R: Read>(r: &mut R, length: usize) -> Result<String, HydraHarpError> {
+    let bytes = read_bytes(r, length)?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(length);
+    Ok(String::from_utf8_lossy(&bytes[..end]).trim().to_string())
+}
+
+/// The length of the data following a tag, checked against the `remaining` bytes of the file,
+/// which are reduced by it
+fn data_length(value: i64, remaining: &mut u64) -> Result<usize, HydraHarpError> {
+    if value < 0 || value as u64 > *remaining {
+        return Err(HydraHarpError::FileError);
+    }
+    *remaining -= value as u64;
+    Ok(value as usize)
+}
+
+/// Read a tag with `remaining` bytes left in the file, which are reduced by the bytes read
+fn read_tag<R: Read>(r: &mut R, remaining: &mut u64) -> Result<Tag, HydraHarpError> {
+    *remaining = remaining
+        .checked_sub(TAG_LENGTH)
+        .ok_or(HydraHarpError::FileError)?;
+    let name = read_string(r, 32)?;
+    let index = read_i32(r)?;
+    let kind = read_u32(r)?;
+    let value = read_i64(r)?;
+    let value = match kind {
+        TAG_EMPTY => TagValue::Empty,
+        TAG_BOOL => TagValue::Bool(value != 0),
+        TAG_INT => TagValue::Int(value),
+        TAG_BIT_SET => TagValue::BitSet(value as u64),
+        TAG_COLOUR => TagValue::Colour(value as u64),
+        TAG_FLOAT => TagValue::Float(f64::from_bits(value as u64)),
+        TAG_DATE_TIME => TagValue::DateTime(f64::from_bits(value as u64)),
+        TAG_FLOAT_ARRAY => TagValue::FloatArray(
+            read_bytes(r, data_length(value, remaining)?)?
+                .chunks_exact(8)
+                .map(|c| {
+                    let mut bytes = [0; 8];
+                    bytes.copy_from_slice(c);
+                    f64::from_le_bytes(bytes)
+                })
+                .collect(),
+        ),
+        TAG_ANSI_STRING => TagValue::AnsiString(read_string(r, data_length(value, remaining)?)?),
+        TAG_WIDE_STRING => {
+            let units = read_bytes(r, data_length(value, remaining)?)?
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0)
+                .collect::<Vec<_>>();
+            TagValue::WideString(String::from_utf16_lossy(&units))
+        }
+        TAG_BINARY_BLOB => TagValue::BinaryBlob(read_bytes(r, data_length(value, remaining)?)?),
+        _ => return Err(HydraHarpError::FileError),
+    };
+    Ok(Tag { name, index, value })
+}
+
+/// Read the tags after the magic and version, up to and not including `Header_End`.
+/// `remaining` is the number of bytes left in the file, which the tags can't run past
+fn read_tags<R: Read>(r: &mut R, mut remaining: u64) -> Result<Vec<Tag>, HydraHarpError> {
+    let mut tags = Vec::new();
+    loop {
+        let tag = read_tag(r, &mut remaining)?;
+        if tag.name == "Header_End" {
+            return Ok(tags);
+        }
+        tags.push(tag);
+    }
+}
+
+fn find_tag<'a>(tags: &'a [Tag], name: &str, index: i32) -> Option<&'a TagValue> {
+    tags.iter()
+        .find(|t| t.name == name && t.index == index)
+        .map(|t| &t.value)
+}
+
+/// The header of an HT2 or HT3 file of `length` bytes written by the HydraHarp software, converted
+/// into PTU tags. Returns the tags and the record type
+fn read_legacy_header<R: Read + Seek>(
+    r: &mut R,
+    length: u64,
+) -> Result<(Vec<Tag>, RecordType), HydraHarpError> {
+    use TagValue::*;
+    let mut tags = Vec::new();
+    let mut string = |r: &mut R, name: &str, length: usize| -> Result<String, HydraHarpError> {
+        let s = read_string(r, length)?;
+        tags.push(Tag::new(name, AnsiString(s.clone())));
+        Ok(s)
+    };
+    string(r, "File_Ident", 16)?;
+    let version = string(r, "File_FormatVersion", 6)?;
+    string(r, "CreatorSW_Name", 18)?;
+    string(r, "CreatorSW_Version", 12)?;
+    string(r, "File_CreatingTime", 18)?;
+    read_bytes(r, 2)?;
+    string(r, "File_Comment", 256)?;
+
+    let mut int = |r: &mut R, name: &str| -> Result<i32, HydraHarpError> {
+        let i = read_i32(r)?;
+        if !name.is_empty() {
+            tags.push(Tag::new(name, Int(i as i64)));
+        }
+        Ok(i)
+    };
+    int(r, "")?; // Curves
+    int(r, "TTResultFormat_BitsPerRecord")?;
+    int(r, "")?; // Active curve
+    let mode = int(r, "Measurement_Mode")?;
+    int(r, "Measurement_SubMode")?;
+    int(r, "MeasDesc_BinningFactor")?;
+    let resolution = read_f64(r)?;
+    int(r, "MeasDesc_Offset")?;
+    int(r, "MeasDesc_AcquisitionTime")?;
+    int(r, "")?; // Stop at
+    int(r, "")?; // Stop on overflow
+    int(r, "")?; // Restart
+
+    // The display settings, curve mappings, parameters, repeat settings and script name
+    read_bytes(r, 5 * 4 + 8 * 8 + 3 * 12 + 4 * 4 + 20)?;
+
+    let hardware = read_string(r, 16)?;
+    let part = read_string(r, 8)?;
+    let serial = read_i32(r)?;
+    let modules = read_i32(r)?;
+    read_bytes(r, 10 * 8)?; // Module info
+    let base_resolution = read_f64(r)?;
+    let inputs_enabled = read_i64(r)? as u64;
+    let inputs = int(r, "HW_InpChannels")?;
+    let reference = read_i32(r)?;
+    int(r, "HW_ExternalDevices")?;
+    int(r, "HW_MarkerSettings")?;
+    int(r, "HWSync_Divider")?;
+    int(r, "HWSync_CFDLevel")?;
+    int(r, "HWSync_CFDZeroCross")?;
+    int(r, "HWSync_Offset")?;
+    // The enabled inputs are a bit set of 64 bits
+    if inputs < 1 || inputs > 64 {
+        return Err(HydraHarpError::FileError);
+    }
+    let mut channel_tags = Vec::new();
+    for i in 0..inputs {
+        let names = [
+            "HWInpChan_ModuleIdx",
+            "HWInpChan_CFDLevel",
+            "HWInpChan_CFDZeroCross",
+            "HWInpChan_Offset",
+        ];
+        for name in names.iter() {
+            channel_tags.push(Tag::indexed(name, i, Int(read_i32(r)? as i64)));
+        }
+        let enabled = inputs_enabled & (1 << i) != 0;
+        channel_tags.push(Tag::indexed("HWInpChan_Enabled", i, Bool(enabled)));
+    }
+    for i in 0..inputs {
+        channel_tags.push(Tag::indexed(
+            "TTResult_InputRate",
+            i,
+            Int(read_i32(r)? as i64),
+        ));
+    }
+    let sync_rate = int(r, "TTResult_SyncRate")?;
+    int(r, "TTResult_StopAfter")?;
+    int(r, "TTResult_StopReason")?;
+    let image_header = read_i32(r)?;
+    let records = read_i64(r)?;
+    let position = r.seek(SeekFrom::Current(0)).map_err(file_error)?;
+    let mut remaining = length.saturating_sub(position);
+    read_bytes(r, data_length(image_header as i64 * 4, &mut remaining)?)?;
+
+    let record_type = match (mode, version.starts_with("1.")) {
+        (2, true) => RecordType::HydraHarpT2,
+        (2, false) => RecordType::HydraHarp2T2,
+        (3, true) => RecordType::HydraHarpT3,
+        (3, false) => RecordType::HydraHarp2T3,
+        _ => return Err(HydraHarpError::FileError),
+    };
+    let global_resolution = match (mode, sync_rate) {
+        (3, rate) if rate > 0 => 1.0 / rate as f64,
+        _ => resolution * 1e-12,
+    };
+    tags.extend(channel_tags);
+    tags.extend(vec![
+        Tag::new("HW_Type", AnsiString(hardware)),
+        Tag::new("HW_PartNo", AnsiString(part)),
+        Tag::new("HW_SerialNo", AnsiString(serial.to_string())),
+        Tag::new("HW_Modules", Int(modules as i64)),
+        Tag::new("HW_BaseResolution", Float(base_resolution * 1e-12)),
+        Tag::new("HW_ExternalRefClock", Bool(reference != 0)),
+        Tag::new("MeasDesc_Resolution", Float(resolution * 1e-12)),
+        Tag::new("MeasDesc_GlobalResolution", Float(global_resolution)),
+        Tag::new("TTResultFormat_TTTRRecType", Int(record_type.code())),
+        Tag::new("TTResult_NumberOfRecords", Int(records)),
+    ]);
+    Ok((tags, record_type))
+}
+
+/// A file of T2 or T3 records, either PTU or HT2/HT3
+#[derive(Debug, Clone, PartialEq)]
+pub struct TtrFile {
+    pub path: PathBuf,
+    /// The tags of the header. The headers of HT2/HT3 files are converted into the PTU tags
+    pub tags: Vec<Tag>,
+    pub record_type: RecordType,
+    /// The number of records in the header
+    pub records: u64,
+    /// Where the records start in the file
+    pub(crate) data_offset: u64,
+}
+
+impl TtrFile {
+    /// Read the header of the file at `path`.
+    /// Returns `FileError` if it can't be read, or isn't a HydraHarp T2/T3 file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<TtrFile, HydraHarpError> {
+        let file = File::open(path.as_ref()).map_err(file_error)?;
+        let length = file.metadata().map_err(file_error)?.len();
+        let mut r = BufReader::new(file);
+        let start = read_bytes(&mut r, 8)?;
+        let (tags, record_type) = if start.as_slice() == MAGIC {
+            read_bytes(&mut r, 8)?;
+            let tags = read_tags(&mut r, length.saturating_sub(16))?;
+            let record_type = find_tag(&tags, "TTResultFormat_TTTRRecType", -1)
+                .and_then(|v| v.as_int())
+                .and_then(RecordType::from_code)
+                .ok_or(HydraHarpError::FileError)?;
+            (tags, record_type)
+        } else if start.starts_with(&LEGACY_IDENT[..8]) {
+            r.seek(SeekFrom::Start(0)).map_err(file_error)?;
+            read_legacy_header(&mut r, length)?
+        } else {
+            return Err(HydraHarpError::FileError);
+        };
+        let records = find_tag(&tags, "TTResult_NumberOfRecords", -1)
+            .and_then(|v| v.as_int())
+            .unwrap_or(0) as u64;
+        let data_offset = r.seek(SeekFrom::Current(0)).map_err(file_error)?;
+        Ok(TtrFile {
+            path: path.as_ref().to_path_buf(),
+            tags,
+            record_type,
+            records,
+            data_offset,
+        })
+    }
+
+    /// The value of a tag without an index
+    pub fn tag(&self, name: &str) -> Option<&TagValue> {
+        find_tag(&self.tags, name, -1)
+    }
+
+    /// The value of a per-channel tag
+    pub fn tag_indexed(&self, name: &str, index: i32) -> Option<&TagValue> {
+        find_tag(&self.tags, name, index)
+    }
+
+    /// The resolution of the times in the records in ps
+    pub fn resolution(&self) -> Option<f64> {
+        self.tag("MeasDesc_Resolution")
+            .and_then(|v| v.as_float())
+            .map(|r| r * 1e12)
+    }
+
+    /// The sync period in ps, the resolution of the sync numbers of T3 records
+    pub fn sync_period(&self) -> Option<f64> {
+        self.tag("MeasDesc_GlobalResolution")
+            .and_then(|v| v.as_float())
+            .map(|r| r * 1e12)
+    }
+
+    /// The length of the measurement in ps, from the time it stopped after or else the acquisition time
+    pub fn duration(&self) -> Option<u64> {
+        self.tag("TTResult_StopAfter")
+            .or_else(|| self.tag("MeasDesc_AcquisitionTime"))
+            .and_then(|v| v.as_int())
+            .map(|ms| ms as u64 * 1_000_000_000)
+    }
+
+    /// A measurement to decode the records with, adding `delays` to each channel like `Measurement::with_delays`
+    pub fn measurement(&self, delays: &[i64]) -> Measurement {
+        let mut measurement = Measurement::with_delays(delays);
+        measurement.version_1_records = self.record_type.version_1_records();
+        measurement
+    }
+
+    /// Pass each chunk of raw records to `process`. A file which is shorter than the number of
+    /// records in the header, eg. if the recording was interrupted, is read up to its end
+    pub fn read_records<F>(&self, mut process: F) -> Result<(), HydraHarpError>
+    where
+        F: FnMut(&[u32]),
+    {
+        const BUFFER_LENGTH: usize = 131072;
+        let mut r = BufReader::new(File::open(&self.path).map_err(file_error)?);
+        r.seek(SeekFrom::Start(self.data_offset))
+            .map_err(file_error)?;
+        let mut remaining = self.records;
+        let mut bytes = vec![0u8; BUFFER_LENGTH * 4];
+        while remaining > 0 {
+            let length = (remaining as usize).min(BUFFER_LENGTH) * 4;
+            let mut read = 0;
+            while read < length {
+                match r.read(&mut bytes[read..length]).map_err(file_error)? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            let records = bytes[..read - read % 4]
+                .chunks(4)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect::<Vec<_>>();
+            if records.is_empty() {
+                break;
+            }
+            process(&records);
+            remaining -= records.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Decode the records of a T2 file with `measurement`, passing each chunk of sorted
+    /// `(channel, time)` values to `process` as `run_measurement_T2` does for live data.
+    /// Returns `InvalidMode` if the file isn't T2
+    pub fn read_T2<F>(
+        &self,
+        mut measurement: Measurement,
+        mut process: F,
+    ) -> Result<(), HydraHarpError>
+    where
+        F: FnMut(&[(u8, u64)]),
+    {
+        if self.record_type.mode() != crate::types::MeasurementMode::T2 {
+            return Err(HydraHarpError::InvalidMode);
+        }
+        self.read_records(|records| process(&measurement.convert_sorted_T2(records)))?;
+        process(&measurement.flush_T2());
+        Ok(())
+    }
+
+    /// Decode the records of a T3 file, passing each chunk of `(channel, sync number, start-stop time)`
+    /// values to `process` as `run_measurement_T3` does for live data.
+    /// Returns `InvalidMode` if the file isn't T3
+    pub fn read_T3<F>(&self, mut process: F) -> Result<(), HydraHarpError>
+    where
+        F: FnMut(&[(u8, u64, u32)]),
+    {
+        if self.record_type.mode() != crate::types::MeasurementMode::T3 {
+            return Err(HydraHarpError::InvalidMode);
+        }
+        let mut measurement = self.measurement(&[]);
+        self.read_records(|records| process(&measurement.convert_values_T3(records)))
+    }
+}
+
+/// One histogram of a PHU file
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramCurve {
+    /// Width of each bin in ps
+    pub resolution: f64,
+    pub counts: Vec<u32>,
+}
+
+/// A PHU file of histograms
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhuFile {
+    pub tags: Vec<Tag>,
+    pub curves: Vec<HistogramCurve>,
+}
+
+impl PhuFile {
+    /// Read the header and histograms of the file at `path`.
+    /// Returns `FileError` if it can't be read, or isn't a PHU file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PhuFile, HydraHarpError> {
+        let file = File::open(path).map_err(file_error)?;
+        let length = file.metadata().map_err(file_error)?.len();
+        let mut r = BufReader::new(file);
+        if read_bytes(&mut r, 8)?.as_slice() != HISTOGRAM_MAGIC {
+            return Err(HydraHarpError::FileError);
+        }
+        read_bytes(&mut r, 8)?;
+        let tags = read_tags(&mut r, length.saturating_sub(16))?;
+        let int = |name: &str, index: i32| {
+            find_tag(&tags, name, index)
+                .and_then(|v| v.as_int())
+                .ok_or(HydraHarpError::FileError)
+        };
+        let number = int("HistoResult_NumberOfCurves", -1)?;
+        // Each curve has its own tags, so there can't be more curves than tags
+        if number < 0 || number as usize > tags.len() {
+            return Err(HydraHarpError::FileError);
+        }
+        let mut curves = Vec::with_capacity(number as usize);
+        for i in 0..number as i32 {
+            let bins = int("HistResDscr_HistogramBins", i)?;
+            let offset = int("HistResDscr_DataOffset", i)?;
+            let resolution = find_tag(&tags, "HistResDscr_MDescResolution", i)
+                .and_then(|v| v.as_float())
+                .ok_or(HydraHarpError::FileError)?;
+            r.seek(SeekFrom::Start(offset as u64)).map_err(file_error)?;
+            let counts = (0..bins)
+                .map(|_| read_u32(&mut r))
+                .collect::<Result<Vec<_>, _>>()?;
+            curves.push(HistogramCurve {
+                resolution: resolution * 1e12,
+                counts,
+            });
+        }
+        Ok(PhuFile { tags, curves })
+    }
+
+    /// The value of a tag without an index
+    pub fn tag(&self, name: &str) -> Option<&TagValue> {
+        find_tag(&self.tags, name, -1)
+    }
+}
+
+/// Read the header tags of a PTU, HT2, HT3 or PHU file, choosing the reader from the start of the file.
+/// Returns `FileError` if it can't be read
+pub fn read_header_tags<P: AsRef<Path>>(path: P) -> Result<Vec<Tag>, HydraHarpError> {
+    let mut start = [0; 8];
+    File::open(path.as_ref())
+        .and_then(|mut f| f.read_exact(&mut start))
+        .map_err(file_error)?;
+    if &start == HISTOGRAM_MAGIC {
+        Ok(PhuFile::open(path)?.tags)
+    } else {
+        Ok(TtrFile::open(path)?.tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PhuFile, TtrFile, HISTOGRAM_MAGIC};
+    use crate::config::DeviceConfig;
+    use crate::ptu::{
+        PtuHeader, PtuWriter, RecordType, StopReason, Tag, TagValue, MAGIC, TAG_BINARY_BLOB,
+        TAG_EMPTY, TAG_INT,
+    };
+    use std::time::SystemTime;
+
+    fn tag_bytes(name: &str, index: i32, kind: u32, value: i64) -> Vec<u8> {
+        let mut bytes = vec![0; 32];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&index.to_le_bytes());
+        bytes.extend_from_slice(&kind.to_le_bytes());
+        bytes.extend_from_slice(&value.to_le_bytes());
+        bytes
+    }
+
+    /// An HT2 header with `inputs` input channels and `image_header` words of image header
+    fn legacy_header(inputs: i32, image_header: i32) -> Vec<u8> {
+        let mut bytes = b"HydraHarp".to_vec();
+        bytes.resize(16, 0);
+        bytes.extend_from_slice(b"2.0\0\0\0");
+        // The software, time and comment, then the curves, bits per record and active curve
+        bytes.resize(bytes.len() + 18 + 12 + 18 + 2 + 256 + 3 * 4, 0);
+        // The mode, T2
+        bytes.extend_from_slice(&2i32.to_le_bytes());
+        // Up to the number of inputs
+        bytes.resize(
+            bytes.len() + 2 * 4 + 8 + 5 * 4 + 156 + 16 + 8 + 2 * 4 + 80 + 8 + 8,
+            0,
+        );
+        bytes.extend_from_slice(&inputs.to_le_bytes());
+        // The reference, sync settings, inputs and rates, then up to the image header
+        let channels = inputs.max(0).min(64) as usize * 5 * 4;
+        bytes.resize(bytes.len() + 7 * 4 + channels + 3 * 4, 0);
+        bytes.extend_from_slice(&image_header.to_le_bytes());
+        bytes.extend_from_slice(&0i64.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let path =
+            std::env::temp_dir().join(format!("hhlib_sys_reader_malformed_{}", std::process::id()));
+        std::fs::write(&path, legacy_header(64, 0)).unwrap();
+        assert_eq!(
+            TtrFile::open(&path).unwrap().record_type,
+            RecordType::HydraHarp2T2
+        );
+        for &(inputs, image_header) in [(0, 0), (65, 0), (2, -1), (2, 1 << 30)].iter() {
+            std::fs::write(&path, legacy_header(inputs, image_header)).unwrap();
+            assert!(TtrFile::open(&path).is_err());
+        }
+        for &curves in [-1, 1 << 40].iter() {
+            let mut bytes = HISTOGRAM_MAGIC.to_vec();
+            bytes.extend_from_slice(b"1.0.00\0\0");
+            bytes.extend(tag_bytes("HistoResult_NumberOfCurves", -1, TAG_INT, curves));
+            bytes.extend(tag_bytes("Header_End", -1, TAG_EMPTY, 0));
+            std::fs::write(&path, &bytes).unwrap();
+            assert!(PhuFile::open(&path).is_err());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_tag_longer_than_file() {
+        let path = std::env::temp_dir().join(format!(
+            "hhlib_sys_reader_long_tag_{}.ptu",
+            std::process::id()
+        ));
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(b"1.0.00\0\0");
+        let mut name = [0; 32];
+        name[..7].copy_from_slice(b"UsrBlob");
+        bytes.extend_from_slice(&name);
+        bytes.extend_from_slice(&(-1i32).to_le_bytes());
+        bytes.extend_from_slice(&TAG_BINARY_BLOB.to_le_bytes());
+        bytes.extend_from_slice(&(1i64 << 40).to_le_bytes());
+        bytes.extend_from_slice(&[0; 64]);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(TtrFile::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_written_file() {
+        let path =
+            std::env::temp_dir().join(format!("hhlib_sys_reader_test_{}.ptu", std::process::id()));
+        let mut header = PtuHeader {
+            record_type: RecordType::HydraHarp2T2,
+            serial: "1234567".to_string(),
+            model: "HydraHarp 400".to_string(),
+            part_number: "930021".to_string(),
+            hardware_version: "2.0".to_string(),
+            resolution: 1.0,
+            sync_period: None,
+            config: DeviceConfig::new(2),
+            acquisition_time: 1000,
+            start_time: SystemTime::now(),
+            extra_tags: Vec::new(),
+        };
+        header.extra_tags.push(Tag::new(
+            "UsrComment",
+            TagValue::WideString("ψ test".to_string()),
+        ));
+        header
+            .extra_tags
+            .push(Tag::new("UsrBlob", TagValue::BinaryBlob(vec![1, 2, 3])));
+        {
+            let mut w = PtuWriter::create(&path, &header).unwrap();
+            // A sync at 10ps, an overflow, then channel 1 at 20ps
+            w.write_records(&[(1 << 31) | 10, (1 << 31) | (63 << 25) | 1, (1 << 25) | 20])
+                .unwrap();
+            w.finish(StopReason::TimeOver).unwrap();
+        }
+        let file = TtrFile::open(&path).unwrap();
+        assert_eq!(file.record_type, RecordType::HydraHarp2T2);
+        assert_eq!(file.records, 3);
+        assert_eq!(file.resolution(), Some(1.0));
+        assert_eq!(
+            file.tag("HW_Type").and_then(|v| v.as_str()),
+            Some("HydraHarp 400")
+        );
+        assert_eq!(
+            file.tag("HW_PartNo").and_then(|v| v.as_str()),
+            Some("930021")
+        );
+        assert_eq!(file.tag("HW_Version").and_then(|v| v.as_str()), Some("2.0"));
+        assert_eq!(
+            file.tag("UsrComment").and_then(|v| v.as_str()),
+            Some("ψ test")
+        );
+        assert_eq!(
+            file.tag("UsrBlob"),
+            Some(&TagValue::BinaryBlob(vec![1, 2, 3]))
+        );
+        let mut times = Vec::new();
+        file.read_T2(file.measurement(&[]), |t| times.extend_from_slice(t))
+            .unwrap();
+        assert_eq!(times, vec![(0, 10), (2, 33554432 + 20)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+//! Replaying a recorded file as if it were a device, so that every measurement can be run on real
+//! data without the hardware attached.
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use pyo3::prelude::*;
+
+use crate::measurement::{Measureable, Measurement};
+use crate::ptu::file_error;
+use crate::raw::RawMetadata;
+use crate::reader::TtrFile;
+use crate::types::{CTCStatus, HydraHarpError, MeasurementMode};
+
+/// How quickly the records are served
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// Serve the records as quickly as they're read
+    AsFastAsPossible,
+    /// Serve each record once the time it was recorded at has passed since the start of the measurement
+    RealTime,
+}
+
+/// The most records served at once when pacing in real time, so that the records arrive smoothly
+const REAL_TIME_CHUNK: usize = 4096;
+
+/// Serves the records of a PTU, HT2, HT3 or raw file from `read_fifo`. Each measurement replays the
+/// file from the start, ending after the acquisition time or when the records run out
+#[pyclass]
+pub struct FileReplay {
+    path: PathBuf,
+    data_offset: u64,
+    records: u64,
+    mode: MeasurementMode,
+    version_1_records: bool,
+    /// Sync period in ps, for the times of T3 records
+    sync_period: f64,
+    /// Length of the recording in ps, if known
+    duration: Option<u64>,
+    pub pacing: Pacing,
+    reader: Option<BufReader<File>>,
+    /// The bytes of the records read from the file
+    bytes: Vec<u8>,
+    remaining: u64,
+    /// Decodes the records to find the time they were recorded at
+    clock: Measurement,
+    start: Instant,
+    /// When the current measurement ends in ps
+    end_time: u64,
+    finished: bool,
+}
+
+impl FileReplay {
+    fn new(
+        path: PathBuf,
+        data_offset: u64,
+        records: u64,
+        mode: MeasurementMode,
+        pacing: Pacing,
+    ) -> FileReplay {
+        FileReplay {
+            path,
+            data_offset,
+            records,
+            mode,
+            version_1_records: false,
+            sync_period: 0.0,
+            duration: None,
+            pacing,
+            reader: None,
+            bytes: Vec::new(),
+            remaining: 0,
+            clock: Measurement::new(0),
+            start: Instant::now(),
+            end_time: 0,
+            finished: true,
+        }
+    }
+
+    /// Replay a PTU, HT2 or HT3 file.
+    /// Returns `FileError` if it can't be read, or isn't a HydraHarp T2/T3 file
+    pub fn open<P: AsRef<Path>>(path: P, pacing: Pacing) -> Result<FileReplay, HydraHarpError> {
+        let file = TtrFile::open(path.as_ref())?;
+        let mut replay = FileReplay::new(
+            file.path.clone(),
+            file.data_offset,
+            file.records,
+            file.record_type.mode(),
+            pacing,
+        );
+        replay.version_1_records = file.measurement(&[]).version_1_records;
+        replay.sync_period = file.sync_period().unwrap_or(0.0);
+        replay.duration = file.duration();
+        Ok(replay)
+    }
+
+    /// Replay a file of raw version 2 fifo records with no header, recorded in `mode`.
+    /// `sync_period` in ps is needed to pace T3 records in real time, and `duration` in ps is the
+    /// length of the recording if it's known.
+    /// Returns `FileError` if it can't be read
+    pub fn open_raw<P: AsRef<Path>>(
+        path: P,
+        mode: MeasurementMode,
+        sync_period: f64,
+        duration: Option<u64>,
+        pacing: Pacing,
+    ) -> Result<FileReplay, HydraHarpError> {
+        let length = std::fs::metadata(path.as_ref()).map_err(file_error)?.len();
+        let mut replay = FileReplay::new(path.as_ref().to_path_buf(), 0, length / 4, mode, pacing);
+        replay.sync_period = sync_period;
+        replay.duration = duration;
+        Ok(replay)
+    }
+
+    /// Replay measurement `index` of a raw recording, using its sidecar.
+    /// Returns `FileError` if either can't be read, and `InvalidArgument` if the sidecar isn't valid
+    /// or there's no such measurement
+    pub fn open_recording<P: AsRef<Path>>(
+        path: P,
+        index: usize,
+        pacing: Pacing,
+    ) -> Result<FileReplay, HydraHarpError> {
+        let metadata = RawMetadata::load(path.as_ref())?;
+        let m = metadata
+            .measurements
+            .get(index)
+            .ok_or(HydraHarpError::InvalidArgument)?;
+        let mut replay = FileReplay::new(
+            path.as_ref().to_path_buf(),
+            m.first_record * 4,
+            m.records,
+            metadata.record_type.mode(),
+            pacing,
+        );
+        replay.version_1_records = metadata.record_type.version_1_records();
+        replay.sync_period = metadata.sync_period.unwrap_or(0.0);
+        replay.duration = Some(m.acquisition_time.max(0) as u64 * 1_000_000_000);
+        Ok(replay)
+    }
+
+    /// The length of the recording in ps, if known
+    pub fn duration(&self) -> Option<u64> {
+        self.duration
+    }
+
+    /// Whether the recording is of T2 or T3 records
+    pub fn mode(&self) -> MeasurementMode {
+        self.mode
+    }
+
+    /// The time in ps of a record decoded by the clock, if it has one
+    fn record_time(&mut self, record: &u32) -> Option<u64> {
+        match self.mode {
+            MeasurementMode::T3 => self
+                .clock
+                .convert_value_T3(record)
+                .map(|(_, nsync, _)| (nsync as f64 * self.sync_period) as u64),
+            _ => self.clock.convert_value_T2(record).map(|(_, t)| t),
+        }
+    }
+}
+
+impl Measureable for FileReplay {
+    fn start_measurement(&mut self, acquisition_time: i32) -> Result<(), HydraHarpError> {
+        if acquisition_time <= 0 {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let mut reader = BufReader::new(File::open(&self.path).map_err(file_error)?);
+        reader
+            .seek(SeekFrom::Start(self.data_offset))
+            .map_err(file_error)?;
+        self.reader = Some(reader);
+        self.remaining = self.records;
+        self.clock = Measurement::new(0);
+        self.clock.keep_markers = true;
+        self.clock.version_1_records = self.version_1_records;
+        self.start = Instant::now();
+        self.end_time = acquisition_time as u64 * 1_000_000_000;
+        self.finished = false;
+        Ok(())
+    }
+
+    fn read_fifo(
+        &mut self,
+        buffer: &mut [u32],
+        records_to_fetch: i32,
+    ) -> Result<i32, HydraHarpError> {
+        if self.finished {
+            return Ok(0);
+        }
+        let mut length = (records_to_fetch.max(0) as usize)
+            .min(buffer.len())
+            .min(self.remaining as usize);
+        if self.pacing == Pacing::RealTime {
+            length = length.min(REAL_TIME_CHUNK);
+        }
+        self.bytes.resize(length * 4, 0);
+        let reader = self.reader.as_mut().ok_or(HydraHarpError::NotInitialized)?;
+        let mut read = 0;
+        while read < self.bytes.len() {
+            match reader.read(&mut self.bytes[read..]).map_err(file_error)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        let read = read / 4;
+        for (record, chunk) in buffer.iter_mut().zip(self.bytes[..read * 4].chunks(4)) {
+            *record = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        self.remaining -= read as u64;
+        if read == 0 || self.remaining == 0 {
+            self.finished = true;
+        }
+
+        // Only serve the records from before the end of the measurement
+        let mut served = read;
+        let mut latest = None;
+        for (i, record) in buffer[..read].iter().enumerate() {
+            if let Some(time) = self.record_time(record) {
+                if time >= self.end_time {
+                    served = i;
+                    latest = Some(self.end_time);
+                    self.finished = true;
+                    break;
+                }
+                latest = Some(time);
+            }
+        }
+        if let (Some(time), Pacing::RealTime) = (latest, self.pacing) {
+            let due = self.start + Duration::from_nanos(time / 1000);
+            let now = Instant::now();
+            if due > now {
+                sleep(due - now);
+            }
+        }
+        Ok(served as i32)
+    }
+
+    /// Ended once the records have run out or passed the acquisition time. When pacing in real
+    /// time, the measurement also lasts until the acquisition time or the end of the recording
+    fn get_CTC_status(&self) -> Result<CTCStatus, HydraHarpError> {
+        if !self.finished {
+            return Ok(CTCStatus::Running);
+        }
+        let end = match self.duration {
+            Some(duration) => duration.min(self.end_time),
+            None => self.end_time,
+        };
+        let elapsed = self.start.elapsed();
+        let elapsed = elapsed.as_secs() * 1_000_000_000_000 + elapsed.subsec_nanos() as u64 * 1000;
+        if self.pacing == Pacing::AsFastAsPossible || elapsed >= end {
+            Ok(CTCStatus::Ended)
+        } else {
+            Ok(CTCStatus::Running)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileReplay, Pacing};
+    use crate::measurement::run_measurement_T2;
+    use crate::types::MeasurementMode;
+    use std::io::Write;
+
+    fn write_records(name: &str, records: &[u32]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_{}.bin", name, std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        for r in records.iter() {
+            file.write_all(&r.to_le_bytes()).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn stops_at_the_acquisition_time() {
+        // A sync, an overflow to past 1ms, then channel 1 and a sync which aren't served
+        let path = write_records(
+            "hhlib_sys_replay_end_test",
+            &[
+                (1 << 31) | 10,
+                (1 << 31) | (63 << 25) | 30,
+                (1 << 25) | 5,
+                (1 << 31) | 20,
+            ],
+        );
+        let mut replay = FileReplay::open_raw(
+            &path,
+            MeasurementMode::T2,
+            0.0,
+            None,
+            Pacing::AsFastAsPossible,
+        )
+        .unwrap();
+        let mut times = Vec::new();
+        run_measurement_T2(&mut replay, 1, |t| times.extend_from_slice(t)).unwrap();
+        assert_eq!(times, vec![(0, 10)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replays_raw_records() {
+        let path = write_records(
+            "hhlib_sys_replay_test",
+            &[
+                (1 << 31) | 10,
+                (1 << 25) | 20,
+                (1 << 31) | (63 << 25) | 2,
+                30,
+            ],
+        );
+
+        let mut replay = FileReplay::open_raw(
+            &path,
+            MeasurementMode::T2,
+            0.0,
+            None,
+            Pacing::AsFastAsPossible,
+        )
+        .unwrap();
+        for _ in 0..2 {
+            let mut times = Vec::new();
+            run_measurement_T2(&mut replay, 1, |t| times.extend_from_slice(t)).unwrap();
+            assert_eq!(times, vec![(0, 10), (2, 20), (1, 2 * 33554432 + 30)]);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+//! Splitting a single run into segments at the markers, so that a setting can be changed during
+//! the run (with a marker sent to the device at each change) and the coincidences of each setting
+//! counted separately.
+use pyo3::prelude::*;
+
+use crate::coincidence::CoincidenceCounter;
+use crate::measurement::{run_measurement_with_T2, Measureable, Measurement, MARKER_CHANNEL};
+use crate::types::HydraHarpError;
+
+/// Counts the coincidences of each segment of a run separately, starting a new segment at every marker.
+/// Each segment is labelled with the marker which started it. The events before the first marker are
+/// not counted, as the setting they were measured with isn't known
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarkerSegmenter {
+    /// The empty counter copied at the start of each segment
+    pub template: CoincidenceCounter,
+    /// `(marker, counter)` of each finished segment, in order
+    pub segments: Vec<(u8, CoincidenceCounter)>,
+    /// The marker, counter and start time of the current segment
+    current: Option<(u8, CoincidenceCounter, u64)>,
+}
+
+impl MarkerSegmenter {
+    pub fn new(template: CoincidenceCounter) -> MarkerSegmenter {
+        MarkerSegmenter {
+            template,
+            segments: Vec::new(),
+            current: None,
+        }
+    }
+
+    fn end_segment(&mut self, time: u64) {
+        if let Some((marker, mut counter, start)) = self.current.take() {
+            counter.end_measurement(time.saturating_sub(start));
+            self.segments.push((marker, counter));
+        }
+    }
+
+    /// Add a chunk of channels and times, with the markers kept as channel `MARKER_CHANNEL + marker`.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last
+    pub fn add_times(&mut self, times: &[(u8, u64)]) {
+        let mut rest = times;
+        while !rest.is_empty() {
+            let next_marker = rest.iter().position(|&(c, _)| c >= MARKER_CHANNEL);
+            let (events, after) = rest.split_at(next_marker.unwrap_or_else(|| rest.len()));
+            if let Some((_, counter, _)) = self.current.as_mut() {
+                counter.add_times(events);
+            }
+            if let Some(&(marker, time)) = after.first() {
+                self.end_segment(time);
+                self.current = Some((marker - MARKER_CHANNEL, self.template.clone(), time));
+                rest = &after[1..];
+            } else {
+                rest = after;
+            }
+        }
+    }
+
+    /// Finish the current segment at `duration` ps, the length of the measurement.
+    /// Call this at the end of each measurement, as the times restart from zero in the next one
+    pub fn end_measurement(&mut self, duration: u64) {
+        self.end_segment(duration);
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, splitting it into segments at the markers
+pub fn measure_segmented<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    segmenter: &mut MarkerSegmenter,
+) -> Result<(), HydraHarpError> {
+    let mut measurement = Measurement::with_delays(&segmenter.template.delays);
+    measurement.keep_markers = true;
+    run_measurement_with_T2(d, acquisition_time, measurement, |times| {
+        segmenter.add_times(times)
+    })?;
+    segmenter.end_measurement(acquisition_time as u64 * 1_000_000_000);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MarkerSegmenter;
+    use crate::coincidence::CoincidenceCounter;
+    use crate::measurement::MARKER_CHANNEL;
+
+    #[test]
+    fn splits_at_markers() {
+        let mut s = MarkerSegmenter::new(CoincidenceCounter::new(3, 100, None));
+        s.add_times(&[(1, 0), (2, 10), (MARKER_CHANNEL + 1, 1000), (1, 1100)]);
+        s.add_times(&[(2, 1150), (MARKER_CHANNEL + 2, 2000), (1, 2500)]);
+        s.end_measurement(3000);
+        assert_eq!(s.segments.len(), 2);
+        assert_eq!(s.segments[0].0, 1);
+        assert_eq!(s.segments[0].1.coincidences[1][2], 1);
+        assert_eq!(s.segments[0].1.duration, 1000);
+        assert_eq!(s.segments[1].1.singles, vec![0, 1, 0]);
+    }
+}
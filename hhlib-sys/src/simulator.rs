@@ -0,0 +1,441 @@
+//! A simulated HydraHarp, which emits T2 fifo records of a configurable light source so that the
+//! real decoding path and every analysis can be tested against known values without the hardware.
+//! The channels are numbered as after decoding, with the sync as channel 0 and input `i` as channel `i + 1`.
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+
+use pyo3::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Exp1, StandardNormal};
+
+use crate::measurement::{Measureable, T2Encoder, MARKER_CHANNEL};
+use crate::types::{CTCStatus, HydraHarpError};
+
+/// Length of the time simulated in one go in ps
+const SLICE: u64 = 1_000_000_000;
+
+/// A source of photon pairs, eg. from SPDC, with one photon of each pair going to each channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairSource {
+    pub channels: (u8, u8),
+    /// Pairs generated per second
+    pub rate: f64,
+    /// The probability that each photon of a pair is detected, the heralding efficiencies
+    pub efficiencies: (f64, f64),
+    /// Delay of the second photon after the first in ps
+    pub delay: i64,
+    /// Standard deviation of the time between the photons in ps
+    pub jitter: f64,
+    /// If true, the pairs are generated by the sync pulses, with a Poissonian number of pairs per pulse
+    pub pulsed: bool,
+}
+
+/// The imperfections of the detector on one channel
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Detector {
+    /// Dark counts per second
+    pub dark_count_rate: f64,
+    /// Time after a detection in ps during which photons aren't detected
+    pub dead_time: u64,
+    /// Standard deviation of the timing jitter in ps
+    pub jitter: f64,
+    /// The probability of an afterpulse after each detection
+    pub afterpulse_probability: f64,
+    /// Mean delay of an afterpulse after the end of the dead time in ps
+    pub afterpulse_delay: f64,
+}
+
+/// The Poisson processes which generate the photons, each with the time of its next event
+#[derive(Debug, Clone, PartialEq)]
+enum Process {
+    /// Uncorrelated light on a channel at a rate per second
+    Singles(u8, f64),
+    /// Dark counts of a detector
+    Dark(u8, f64),
+    /// One of the pair sources, continuous wave
+    Pairs(usize),
+    /// One of the pair sources, pumped by the sync pulses. The next time is the index of the next
+    /// pulse with at least one pair
+    PulsedPairs(usize),
+}
+
+/// A simulated device. Set up the source, then measure with it like a device. The random numbers
+/// carry on from one measurement to the next, so a simulator with the same seed and settings
+/// always gives the same measurements
+#[pyclass]
+#[derive(Clone)]
+pub struct PhotonSimulator {
+    /// Rate of the sync pulses per second, or 0 for no sync
+    pub sync_rate: f64,
+    /// Only every `sync_divider`th pulse is recorded on the sync channel
+    pub sync_divider: u32,
+    /// `(channel, rate per second)` of uncorrelated light
+    pub singles: Vec<(u8, f64)>,
+    pub pairs: Vec<PairSource>,
+    /// The detector of channel `i + 1`. Channels without a detector given have perfect detectors
+    pub detectors: Vec<Detector>,
+    /// `(time in ps, marker)` of the markers in each measurement, with markers 1 to 15
+    pub markers: Vec<(u64, u8)>,
+    /// The number of pairs generated in the last measurement, including those which weren't detected
+    pub generated_pairs: u64,
+    rng: StdRng,
+    processes: Vec<(Process, f64)>,
+    /// Observed events, waiting until no earlier events could still be generated
+    pending: BinaryHeap<Reverse<(u64, u8)>>,
+    last_detection: Vec<Option<u64>>,
+    next_pulse: u64,
+    /// The time simulated up to in ps
+    time: u64,
+    end_time: u64,
+    encoder: T2Encoder,
+    output: VecDeque<u32>,
+    done: bool,
+}
+
+impl PhotonSimulator {
+    /// A simulator with no light and no sync
+    pub fn new(seed: u64) -> PhotonSimulator {
+        PhotonSimulator {
+            sync_rate: 0.0,
+            sync_divider: 1,
+            singles: Vec::new(),
+            pairs: Vec::new(),
+            detectors: Vec::new(),
+            markers: Vec::new(),
+            generated_pairs: 0,
+            rng: StdRng::seed_from_u64(seed),
+            processes: Vec::new(),
+            pending: BinaryHeap::new(),
+            last_detection: Vec::new(),
+            next_pulse: 0,
+            time: 0,
+            end_time: 0,
+            encoder: T2Encoder::new(),
+            output: VecDeque::new(),
+            done: true,
+        }
+    }
+
+    /// Set the detector of a channel, adding perfect detectors to the channels before it.
+    /// Returns `InvalidArgument` for the sync channel
+    pub fn set_detector(&mut self, channel: u8, detector: Detector) -> Result<(), HydraHarpError> {
+        if channel == 0 || channel >= MARKER_CHANNEL {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let i = channel as usize - 1;
+        if self.detectors.len() <= i {
+            self.detectors.resize(i + 1, Detector::default());
+        }
+        self.detectors[i] = detector;
+        Ok(())
+    }
+
+    fn detector(&self, channel: u8) -> Detector {
+        self.detectors
+            .get(channel as usize - 1)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn sync_period(&self) -> Option<f64> {
+        if self.sync_rate > 0.0 {
+            Some(1e12 / self.sync_rate)
+        } else {
+            None
+        }
+    }
+
+    fn exponential(&mut self, mean: f64) -> f64 {
+        let e: f64 = self.rng.sample(Exp1);
+        e * mean
+    }
+
+    fn normal(&mut self, standard_deviation: f64) -> f64 {
+        let n: f64 = self.rng.sample(StandardNormal);
+        n * standard_deviation
+    }
+
+    /// The number of pulses until the next one with at least one pair, when each has `mean` pairs
+    fn pulses_to_next_pair(&mut self, mean: f64) -> u64 {
+        let p = 1.0 - (-mean).exp();
+        if p >= 1.0 {
+            return 1;
+        }
+        let u: f64 = 1.0 - self.rng.gen::<f64>();
+        1 + (u.ln() / (1.0 - p).ln()).floor() as u64
+    }
+
+    /// A Poissonian number of pairs with `mean`, given that there's at least one
+    fn pairs_in_pulse(&mut self, mean: f64) -> u64 {
+        let target = self.rng.gen::<f64>() * (1.0 - (-mean).exp());
+        let mut n = 1;
+        let mut term = mean * (-mean).exp();
+        let mut sum = term;
+        while sum < target && n < 1000 {
+            n += 1;
+            term *= mean / n as f64;
+            sum += term;
+        }
+        n
+    }
+
+    /// Add a photon arriving at the detector at `time`, with the detector's jitter
+    fn add_photon(&mut self, channel: u8, time: f64) {
+        let time = time + self.normal(self.detector(channel).jitter);
+        if time >= 0.0 {
+            self.pending.push(Reverse((time as u64, channel)));
+        }
+    }
+
+    fn add_pair(&mut self, source: usize, time: f64) {
+        let s = self.pairs[source].clone();
+        self.generated_pairs += 1;
+        if self.rng.gen::<f64>() < s.efficiencies.0 {
+            self.add_photon(s.channels.0, time);
+        }
+        if self.rng.gen::<f64>() < s.efficiencies.1 {
+            let offset = s.delay as f64 + self.normal(s.jitter);
+            self.add_photon(s.channels.1, time + offset);
+        }
+    }
+
+    /// The longest time an event can be observed before it was generated, plus a margin for the jitter
+    fn guard(&self) -> u64 {
+        let jitter = self
+            .detectors
+            .iter()
+            .map(|d| d.jitter)
+            .chain(self.pairs.iter().map(|p| p.jitter))
+            .fold(0.0, f64::max);
+        let delay = self.pairs.iter().map(|p| p.delay.abs()).max().unwrap_or(0);
+        delay as u64 + (12.0 * jitter) as u64 + 1
+    }
+
+    fn start_processes(&mut self) {
+        let mut processes = Vec::new();
+        for &(channel, rate) in self.singles.iter() {
+            processes.push(Process::Singles(channel, rate));
+        }
+        for (i, d) in self.detectors.iter().enumerate() {
+            processes.push(Process::Dark(i as u8 + 1, d.dark_count_rate));
+        }
+        for (i, p) in self.pairs.iter().enumerate() {
+            if p.pulsed && self.sync_rate > 0.0 {
+                processes.push(Process::PulsedPairs(i));
+            } else {
+                processes.push(Process::Pairs(i));
+            }
+        }
+        let pair_rates = self.pairs.iter().map(|p| p.rate).collect::<Vec<_>>();
+        let rate = |p: &Process| match *p {
+            Process::Singles(_, rate) | Process::Dark(_, rate) => rate,
+            Process::Pairs(i) | Process::PulsedPairs(i) => pair_rates[i],
+        };
+        processes.retain(|p| rate(p) > 0.0);
+        self.processes = Vec::new();
+        for process in processes.into_iter() {
+            let next = match process {
+                Process::Singles(_, rate) | Process::Dark(_, rate) => self.exponential(1e12 / rate),
+                Process::Pairs(i) => self.exponential(1e12 / self.pairs[i].rate),
+                Process::PulsedPairs(i) => {
+                    let mean = self.pairs[i].rate / self.sync_rate;
+                    (self.pulses_to_next_pair(mean) - 1) as f64
+                }
+            };
+            self.processes.push((process, next));
+        }
+    }
+
+    /// Generate the events of each process up to `end` ps
+    fn generate(&mut self, end: u64) {
+        let end = end as f64;
+        if let Some(period) = self.sync_period() {
+            while (self.next_pulse as f64 * period) < end {
+                if self.next_pulse % self.sync_divider.max(1) as u64 == 0 {
+                    let time = (self.next_pulse as f64 * period) as u64;
+                    self.pending.push(Reverse((time, 0)));
+                }
+                self.next_pulse += 1;
+            }
+        }
+        for i in 0..self.processes.len() {
+            let (process, mut next) = self.processes[i].clone();
+            match process {
+                Process::Singles(channel, rate) | Process::Dark(channel, rate) => {
+                    while next < end {
+                        self.add_photon(channel, next);
+                        next += self.exponential(1e12 / rate);
+                    }
+                }
+                Process::Pairs(source) => {
+                    while next < end {
+                        self.add_pair(source, next);
+                        next += self.exponential(1e12 / self.pairs[source].rate);
+                    }
+                }
+                Process::PulsedPairs(source) => {
+                    let period = 1e12 / self.sync_rate;
+                    let mean = self.pairs[source].rate / self.sync_rate;
+                    while next * period < end {
+                        for _ in 0..self.pairs_in_pulse(mean) {
+                            self.add_pair(source, next * period);
+                        }
+                        next += self.pulses_to_next_pair(mean) as f64;
+                    }
+                }
+            }
+            self.processes[i].1 = next;
+        }
+    }
+
+    fn encode(&mut self, channel: u8, time: u64) {
+        let output = &mut self.output;
+        self.encoder.encode(channel, time, |r| output.push_back(r));
+    }
+
+    /// Pass the events before `horizon` through the detectors and encode them
+    fn detect(&mut self, horizon: u64) {
+        while let Some(&Reverse((time, channel))) = self.pending.peek() {
+            if time >= horizon {
+                break;
+            }
+            self.pending.pop();
+            if channel == 0 || channel >= MARKER_CHANNEL {
+                self.encode(channel, time);
+                continue;
+            }
+            let detector = self.detector(channel);
+            let i = channel as usize;
+            if self.last_detection.len() <= i {
+                self.last_detection.resize(i + 1, None);
+            }
+            if let Some(last) = self.last_detection[i] {
+                if time < last + detector.dead_time {
+                    continue;
+                }
+            }
+            self.last_detection[i] = Some(time);
+            self.encode(channel, time);
+            if self.rng.gen::<f64>() < detector.afterpulse_probability {
+                let delay = self.exponential(detector.afterpulse_delay);
+                let afterpulse = time + detector.dead_time + delay as u64;
+                self.pending.push(Reverse((afterpulse, channel)));
+            }
+        }
+    }
+
+    /// Simulate the next slice of the measurement
+    fn simulate_slice(&mut self) {
+        let end = (self.time + SLICE).min(self.end_time);
+        self.generate(end);
+        if end >= self.end_time {
+            self.detect(self.end_time);
+            self.pending.clear();
+            self.done = true;
+        } else {
+            self.detect(end.saturating_sub(self.guard()));
+        }
+        self.time = end;
+    }
+}
+
+impl Measureable for PhotonSimulator {
+    fn start_measurement(&mut self, acquisition_time: i32) -> Result<(), HydraHarpError> {
+        if acquisition_time <= 0 {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        if self.markers.iter().any(|&(_, m)| m == 0 || m > 15) {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        self.generated_pairs = 0;
+        self.pending.clear();
+        for &(time, marker) in self.markers.iter() {
+            self.pending.push(Reverse((time, MARKER_CHANNEL + marker)));
+        }
+        self.last_detection.clear();
+        self.next_pulse = 0;
+        self.time = 0;
+        self.end_time = acquisition_time as u64 * 1_000_000_000;
+        self.encoder = T2Encoder::new();
+        self.output.clear();
+        self.done = false;
+        self.start_processes();
+        Ok(())
+    }
+
+    fn read_fifo(
+        &mut self,
+        buffer: &mut [u32],
+        records_to_fetch: i32,
+    ) -> Result<i32, HydraHarpError> {
+        let wanted = (records_to_fetch.max(0) as usize).min(buffer.len());
+        while self.output.len() < wanted && !self.done {
+            self.simulate_slice();
+        }
+        let n = wanted.min(self.output.len());
+        for (b, record) in buffer.iter_mut().zip(self.output.drain(..n)) {
+            *b = record;
+        }
+        Ok(n as i32)
+    }
+
+    fn get_CTC_status(&self) -> Result<CTCStatus, HydraHarpError> {
+        if self.done && self.output.is_empty() {
+            Ok(CTCStatus::Ended)
+        } else {
+            Ok(CTCStatus::Running)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Detector, PairSource, PhotonSimulator};
+    use crate::measurement::{run_measurement_with_T2, Measurement, MARKER_CHANNEL};
+
+    #[test]
+    fn pairs_arrive_with_delay() {
+        let mut s = PhotonSimulator::new(1);
+        s.sync_rate = 1e6;
+        s.sync_divider = 4;
+        s.pairs.push(PairSource {
+            channels: (1, 2),
+            rate: 2e5,
+            efficiencies: (1.0, 1.0),
+            delay: 500,
+            jitter: 0.0,
+            pulsed: false,
+        });
+        s.singles.push((3, 1e6));
+        s.set_detector(
+            3,
+            Detector {
+                dead_time: 50_000,
+                ..Detector::default()
+            },
+        )
+        .unwrap();
+        s.markers.push((5_000_000_000, 2));
+
+        let mut measurement = Measurement::new(0);
+        measurement.keep_markers = true;
+        let mut times = Vec::new();
+        run_measurement_with_T2(&mut s, 10, measurement, |t| times.extend_from_slice(t)).unwrap();
+        let channel = |c: u8| {
+            times
+                .iter()
+                .filter(|(ch, _)| *ch == c)
+                .map(|&(_, t)| t)
+                .collect::<Vec<_>>()
+        };
+        let (a, b) = (channel(1), channel(2));
+        assert_eq!(a.len() as u64, s.generated_pairs);
+        assert!(a.iter().zip(b.iter()).all(|(a, b)| b - a == 500));
+        assert_eq!(channel(0).len(), 2500);
+        assert!(channel(3).windows(2).all(|w| w[1] - w[0] >= 50_000));
+        assert_eq!(channel(MARKER_CHANNEL + 2), vec![5_000_000_000]);
+        assert!(times.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert!(times.last().unwrap().1 > 1 << 25);
+    }
+}
@@ -0,0 +1,606 @@
+//! Quantum state tomography from coincidence counts.
+//! Each measurement setting is a projector `|psi><psi|` onto a (joint) state, with the number of
+//! coincidences counted for it. The density matrix is reconstructed by linear inversion or by
+//! maximum likelihood, and the figures of merit get error bars by resampling the counts.
+use num::complex::Complex64;
+use pyo3::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Poisson};
+use std::cmp::Ordering;
+
+use crate::fitting::{levenberg_marquardt, solve};
+use crate::types::HydraHarpError;
+
+/// A square complex matrix, `m[row][column]`
+pub type Matrix = Vec<Vec<Complex64>>;
+
+/// The joint state `a ⊗ b` of two systems, eg. for the settings of a two-fold coincidence measurement
+pub fn product_state(a: &[Complex64], b: &[Complex64]) -> Vec<Complex64> {
+    a.iter()
+        .flat_map(|x| b.iter().map(move |y| x * y))
+        .collect()
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let n = a.len();
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| (0..n).map(|k| a[i][k] * b[k][j]).sum())
+                .collect()
+        })
+        .collect()
+}
+
+fn trace(m: &Matrix) -> Complex64 {
+    (0..m.len()).map(|i| m[i][i]).sum()
+}
+
+/// `<psi| m |psi>`
+fn expectation(m: &Matrix, psi: &[Complex64]) -> f64 {
+    let n = psi.len();
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| psi[i].conj() * m[i][j] * psi[j])
+                .sum::<Complex64>()
+        })
+        .sum::<Complex64>()
+        .re
+}
+
+/// The eigenvalues (ascending) and eigenvectors (as columns) of a real symmetric matrix, by Jacobi rotations
+fn symmetric_eigen(mut a: Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = a.len();
+    let mut v = (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| if i == j { 1.0 } else { 0.0 })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    for _ in 0..100 {
+        let off: f64 = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i][j] * a[i][j])
+            .sum();
+        if off < 1e-22 {
+            break;
+        }
+        for p in 0..n {
+            for q in (p + 1)..n {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let t = if theta == 0.0 { 1.0 } else { t };
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[k][p], a[k][q]);
+                    a[k][p] = c * akp - s * akq;
+                    a[k][q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p][k], a[q][k]);
+                    a[p][k] = c * apk - s * aqk;
+                    a[q][k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k][p], v[k][q]);
+                    v[k][p] = c * vkp - s * vkq;
+                    v[k][q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    let mut order = (0..n).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| a[i][i].partial_cmp(&a[j][j]).unwrap_or(Ordering::Equal));
+    let values = order.iter().map(|&i| a[i][i]).collect();
+    let vectors = (0..n)
+        .map(|k| order.iter().map(|&i| v[k][i]).collect())
+        .collect();
+    (values, vectors)
+}
+
+/// A hermitian matrix `A + iB` as the real symmetric matrix `[[A, -B], [B, A]]`, which has the
+/// same eigenvalues, each twice
+fn embed(m: &Matrix) -> Vec<Vec<f64>> {
+    let n = m.len();
+    (0..2 * n)
+        .map(|i| {
+            (0..2 * n)
+                .map(|j| {
+                    let x = m[i % n][j % n];
+                    match (i < n, j < n) {
+                        (true, true) | (false, false) => x.re,
+                        (true, false) => -x.im,
+                        (false, true) => x.im,
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The eigenvalues of a hermitian matrix, ascending
+pub fn eigenvalues(m: &Matrix) -> Vec<f64> {
+    let (values, _) = symmetric_eigen(embed(m));
+    values.into_iter().step_by(2).collect()
+}
+
+/// Apply `f` to the eigenvalues of a hermitian matrix
+fn hermitian_function<F: Fn(f64) -> f64>(m: &Matrix, f: F) -> Matrix {
+    let n = m.len();
+    let (values, vectors) = symmetric_eigen(embed(m));
+    let entry = |i: usize, j: usize| -> f64 {
+        (0..2 * n)
+            .map(|k| vectors[i][k] * f(values[k]) * vectors[j][k])
+            .sum()
+    };
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .map(|j| Complex64::new(entry(i, j), entry(i + n, j)))
+                .collect()
+        })
+        .collect()
+}
+
+/// The figures of merit of a density matrix
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateMetrics {
+    /// `<psi| rho |psi>` for the pure target state `psi`, if one was given
+    #[prop(get)]
+    pub fidelity: Option<f64>,
+    /// `tr(rho^2)`
+    #[prop(get)]
+    pub purity: f64,
+    /// The Wootters concurrence, only for two qubits
+    #[prop(get)]
+    pub concurrence: Option<f64>,
+    /// The von Neumann entropy in bits
+    #[prop(get)]
+    pub entropy: f64,
+}
+
+/// Work out the figures of merit of `rho`, with the fidelity to `target` if given
+pub fn state_metrics(rho: &Matrix, target: Option<&[Complex64]>) -> StateMetrics {
+    let fidelity = target.map(|psi| {
+        let norm: f64 = psi.iter().map(|x| x.norm_sqr()).sum();
+        expectation(rho, psi) / norm
+    });
+    let purity = trace(&multiply(rho, rho)).re;
+    let entropy = eigenvalues(rho)
+        .into_iter()
+        .filter(|&l| l > 1e-15)
+        .map(|l| -l * l.log2())
+        .sum();
+    let concurrence = if rho.len() == 4 {
+        // rho~ = (sy ⊗ sy) rho* (sy ⊗ sy), and sy ⊗ sy just reverses the basis with some signs
+        let sign = [1.0, -1.0, -1.0, 1.0];
+        let flipped = (0..4)
+            .map(|i| {
+                (0..4)
+                    .map(|j| rho[3 - i][3 - j].conj() * sign[i] * sign[j])
+                    .collect()
+            })
+            .collect();
+        let root = hermitian_function(rho, |l| l.max(0.0).sqrt());
+        let m = multiply(&multiply(&root, &flipped), &root);
+        let mut l = eigenvalues(&m)
+            .into_iter()
+            .map(|l| l.max(0.0).sqrt())
+            .collect::<Vec<_>>();
+        l.reverse();
+        Some((l[0] - l[1] - l[2] - l[3]).max(0.0))
+    } else {
+        None
+    };
+    StateMetrics {
+        fidelity,
+        purity,
+        concurrence,
+        entropy,
+    }
+}
+
+/// How the density matrix is reconstructed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reconstruction {
+    /// Least squares inversion of the counts. Fast, but the result can have negative eigenvalues
+    LinearInversion,
+    /// The physical state which maximises the Poisson likelihood of the counts, starting from the
+    /// linear inversion
+    MaximumLikelihood,
+}
+
+/// A set of tomography measurement settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tomography {
+    /// The state each setting projects onto
+    pub projectors: Vec<Vec<Complex64>>,
+    pub dimension: usize,
+}
+
+impl Tomography {
+    /// Returns `InvalidArgument` if the states have different dimensions, or there are fewer settings
+    /// than the `dimension^2` needed to reconstruct the state
+    pub fn new(projectors: Vec<Vec<Complex64>>) -> Result<Tomography, HydraHarpError> {
+        let dimension = projectors.first().map_or(0, |p| p.len());
+        if dimension == 0
+            || projectors.iter().any(|p| p.len() != dimension)
+            || projectors.len() < dimension * dimension
+        {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        Ok(Tomography {
+            projectors,
+            dimension,
+        })
+    }
+
+    /// An orthonormal basis of hermitian matrices, in which to expand the density matrix
+    fn hermitian_basis(&self) -> Vec<Matrix> {
+        let d = self.dimension;
+        let zero = vec![vec![Complex64::new(0.0, 0.0); d]; d];
+        let r = std::f64::consts::FRAC_1_SQRT_2;
+        let mut basis = Vec::with_capacity(d * d);
+        for j in 0..d {
+            let mut m = zero.clone();
+            m[j][j] = Complex64::new(1.0, 0.0);
+            basis.push(m);
+            for k in (j + 1)..d {
+                let mut x = zero.clone();
+                x[j][k] = Complex64::new(r, 0.0);
+                x[k][j] = Complex64::new(r, 0.0);
+                basis.push(x);
+                let mut y = zero.clone();
+                y[j][k] = Complex64::new(0.0, -r);
+                y[k][j] = Complex64::new(0.0, r);
+                basis.push(y);
+            }
+        }
+        basis
+    }
+
+    /// Reconstruct the density matrix from the `counts` of each setting by linear inversion.
+    /// Returns `InvalidArgument` if the counts aren't finite or the settings don't determine the state
+    pub fn linear_inversion(&self, counts: &[f64]) -> Result<Matrix, HydraHarpError> {
+        if counts.len() != self.projectors.len() || counts.iter().any(|n| !n.is_finite()) {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        let basis = self.hermitian_basis();
+        let a = self
+            .projectors
+            .iter()
+            .map(|psi| {
+                basis
+                    .iter()
+                    .map(|g| expectation(g, psi))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let k = basis.len();
+        let ata = (0..k)
+            .map(|i| {
+                (0..k)
+                    .map(|j| a.iter().map(|row| row[i] * row[j]).sum())
+                    .collect()
+            })
+            .collect();
+        let atn = (0..k)
+            .map(|i| a.iter().zip(counts.iter()).map(|(row, n)| row[i] * n).sum())
+            .collect();
+        let x = solve(ata, atn).ok_or(HydraHarpError::InvalidArgument)?;
+        let d = self.dimension;
+        let mut rho = vec![vec![Complex64::new(0.0, 0.0); d]; d];
+        for (g, x) in basis.iter().zip(x.iter()) {
+            for i in 0..d {
+                for j in 0..d {
+                    rho[i][j] += g[i][j] * x;
+                }
+            }
+        }
+        let t = trace(&rho).re;
+        if t <= 0.0 {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        Ok(rho
+            .into_iter()
+            .map(|row| row.into_iter().map(|x| x / t).collect())
+            .collect())
+    }
+
+    /// Reconstruct the physical density matrix most likely to give the `counts` of each setting,
+    /// taking each count to be Poissonian with a mean of `<psi| rho |psi>` times the total rate.
+    /// The state is written as `rho = L L^dagger` with `L` lower triangular, so it is always positive,
+    /// and `L` is found by minimising the Poisson deviance, `2 sum(m - n + n ln(n / m))` for expected
+    /// counts `m`, starting from the linear inversion
+    pub fn maximum_likelihood(&self, counts: &[f64]) -> Result<Matrix, HydraHarpError> {
+        let d = self.dimension;
+        let linear = self.linear_inversion(counts)?;
+        // Make the starting point positive and scale it to the counts
+        let positive = hermitian_function(&linear, |l| l.max(1e-3));
+        let expected: f64 = self
+            .projectors
+            .iter()
+            .map(|p| expectation(&positive, p))
+            .sum();
+        let scale = counts.iter().sum::<f64>() / expected;
+        let cholesky = cholesky(&positive).ok_or(HydraHarpError::InvalidArgument)?;
+
+        // The parameters are the real diagonal of L, then the real and imaginary parts below it
+        let mut initial = (0..d)
+            .map(|i| cholesky[i][i].re * scale.sqrt())
+            .collect::<Vec<_>>();
+        for i in 0..d {
+            for j in 0..i {
+                initial.push(cholesky[i][j].re * scale.sqrt());
+                initial.push(cholesky[i][j].im * scale.sqrt());
+            }
+        }
+        let lower = |p: &[f64]| {
+            let mut l = vec![vec![Complex64::new(0.0, 0.0); d]; d];
+            let mut next = d;
+            for i in 0..d {
+                l[i][i] = Complex64::new(p[i], 0.0);
+                for j in 0..i {
+                    l[i][j] = Complex64::new(p[next], p[next + 1]);
+                    next += 2;
+                }
+            }
+            l
+        };
+        // <psi| L L^dagger |psi> = |L^dagger psi|^2
+        let model = |p: &[f64]| {
+            let l = lower(p);
+            self.projectors
+                .iter()
+                .map(|psi| {
+                    (0..d)
+                        .map(|k| {
+                            (0..d)
+                                .map(|j| l[j][k].conj() * psi[j])
+                                .sum::<Complex64>()
+                                .norm_sqr()
+                        })
+                        .sum()
+                })
+                .collect::<Vec<f64>>()
+        };
+        // The signed square root of each count's part of the deviance, so that fitting them to
+        // zero minimises the deviance and maximises the likelihood
+        let deviance_residuals = |p: &[f64]| {
+            model(p)
+                .into_iter()
+                .zip(counts.iter())
+                .map(|(m, &n)| {
+                    let m = m.max(1e-300);
+                    let deviance = if n > 0.0 {
+                        2.0 * (m - n + n * (n / m).ln())
+                    } else {
+                        2.0 * m
+                    };
+                    (m - n).signum() * deviance.max(0.0).sqrt()
+                })
+                .collect::<Vec<f64>>()
+        };
+        let zeros = vec![0.0; counts.len()];
+        let ones = vec![1.0; counts.len()];
+        let fit = levenberg_marquardt(deviance_residuals, &zeros, &ones, &initial)?;
+        let l = lower(&fit.params);
+        let rho = (0..d)
+            .map(|i| {
+                (0..d)
+                    .map(|j| (0..d).map(|k| l[i][k] * l[j][k].conj()).sum())
+                    .collect()
+            })
+            .collect::<Matrix>();
+        let t = trace(&rho).re;
+        Ok(rho
+            .into_iter()
+            .map(|row| row.into_iter().map(|x| x / t).collect())
+            .collect())
+    }
+
+    pub fn reconstruct(
+        &self,
+        counts: &[f64],
+        method: Reconstruction,
+    ) -> Result<Matrix, HydraHarpError> {
+        match method {
+            Reconstruction::LinearInversion => self.linear_inversion(counts),
+            Reconstruction::MaximumLikelihood => self.maximum_likelihood(counts),
+        }
+    }
+
+    /// Reconstruct the state and work out its figures of merit, with errors from the spread over
+    /// `samples` reconstructions of Poisson resampled counts
+    pub fn analyse(
+        &self,
+        counts: &[f64],
+        method: Reconstruction,
+        target: Option<&[Complex64]>,
+        samples: usize,
+        seed: u64,
+    ) -> Result<TomographyResult, HydraHarpError> {
+        let density_matrix = self.reconstruct(counts, method)?;
+        let metrics = state_metrics(&density_matrix, target);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut resampled = Vec::with_capacity(samples);
+        for _ in 0..samples {
+            let counts = counts
+                .iter()
+                .map(|&n| {
+                    Poisson::new(n)
+                        .map(|p| Distribution::<u64>::sample(&p, &mut rng) as f64)
+                        .unwrap_or(0.0)
+                })
+                .collect::<Vec<_>>();
+            if let Ok(rho) = self.reconstruct(&counts, method) {
+                resampled.push(state_metrics(&rho, target));
+            }
+        }
+        let spread = |f: &dyn Fn(&StateMetrics) -> Option<f64>| -> Option<f64> {
+            let values = resampled.iter().filter_map(|m| f(m)).collect::<Vec<_>>();
+            if values.len() < 2 {
+                return None;
+            }
+            let mean = values.iter().sum::<f64>() / values.len() as f64;
+            let variance =
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (values.len() - 1) as f64;
+            Some(variance.sqrt())
+        };
+        Ok(TomographyResult {
+            fidelity_error: spread(&|m| m.fidelity),
+            purity_error: spread(&|m| Some(m.purity)),
+            concurrence_error: spread(&|m| m.concurrence),
+            entropy_error: spread(&|m| Some(m.entropy)),
+            density_matrix,
+            metrics,
+        })
+    }
+}
+
+/// `m = L L^dagger` for a positive definite hermitian matrix, returning `None` if it isn't
+fn cholesky(m: &Matrix) -> Option<Matrix> {
+    let n = m.len();
+    let mut l = vec![vec![Complex64::new(0.0, 0.0); n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let sum: Complex64 = (0..j).map(|k| l[i][k] * l[j][k].conj()).sum();
+            if i == j {
+                let diagonal = (m[i][i] - sum).re;
+                if diagonal <= 0.0 {
+                    return None;
+                }
+                l[i][i] = Complex64::new(diagonal.sqrt(), 0.0);
+            } else {
+                l[i][j] = (m[i][j] - sum) / l[j][j].re;
+            }
+        }
+    }
+    Some(l)
+}
+
+/// A reconstructed state, its figures of merit and their Monte-Carlo errors.
+/// The errors are `None` if too few of the resampled reconstructions worked
+#[derive(Debug, Clone, PartialEq)]
+pub struct TomographyResult {
+    pub density_matrix: Matrix,
+    pub metrics: StateMetrics,
+    pub fidelity_error: Option<f64>,
+    pub purity_error: Option<f64>,
+    pub concurrence_error: Option<f64>,
+    pub entropy_error: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expectation, product_state, state_metrics, Matrix, Reconstruction, Tomography};
+    use num::complex::Complex64;
+
+    #[test]
+    fn reconstructs_bell_state() {
+        let c = |re: f64, im: f64| Complex64::new(re, im);
+        let r = std::f64::consts::FRAC_1_SQRT_2;
+        let local = vec![
+            vec![c(1.0, 0.0), c(0.0, 0.0)],
+            vec![c(0.0, 0.0), c(1.0, 0.0)],
+            vec![c(r, 0.0), c(r, 0.0)],
+            vec![c(r, 0.0), c(-r, 0.0)],
+            vec![c(r, 0.0), c(0.0, r)],
+            vec![c(r, 0.0), c(0.0, -r)],
+        ];
+        let projectors = local
+            .iter()
+            .flat_map(|a| local.iter().map(move |b| product_state(a, b)))
+            .collect::<Vec<_>>();
+        let bell = vec![c(r, 0.0), c(0.0, 0.0), c(0.0, 0.0), c(r, 0.0)];
+        let counts = projectors
+            .iter()
+            .map(|p| {
+                let overlap: Complex64 = p.iter().zip(bell.iter()).map(|(a, b)| a.conj() * b).sum();
+                1000.0 * overlap.norm_sqr()
+            })
+            .collect::<Vec<_>>();
+        let t = Tomography::new(projectors).unwrap();
+        let mut bad = counts.clone();
+        bad[0] = std::f64::NAN;
+        assert!(t.linear_inversion(&bad).is_err());
+        for &method in [
+            Reconstruction::LinearInversion,
+            Reconstruction::MaximumLikelihood,
+        ]
+        .iter()
+        {
+            let rho = t.reconstruct(&counts, method).unwrap();
+            let m = state_metrics(&rho, Some(&bell));
+            assert!((m.fidelity.unwrap() - 1.0).abs() < 1e-3);
+            assert!((m.purity - 1.0).abs() < 1e-3);
+            assert!((m.concurrence.unwrap() - 1.0).abs() < 1e-2);
+            assert!(m.entropy < 0.05);
+        }
+    }
+
+    #[test]
+    fn maximum_likelihood_maximises_the_poisson_likelihood() {
+        let c = |re: f64, im: f64| Complex64::new(re, im);
+        let r = std::f64::consts::FRAC_1_SQRT_2;
+        let t = Tomography::new(vec![
+            vec![c(1.0, 0.0), c(0.0, 0.0)],
+            vec![c(0.0, 0.0), c(1.0, 0.0)],
+            vec![c(r, 0.0), c(r, 0.0)],
+            vec![c(r, 0.0), c(-r, 0.0)],
+            vec![c(r, 0.0), c(0.0, r)],
+        ])
+        .unwrap();
+        // The two bases have different totals, so no state fits the counts exactly, and a fit
+        // weighted by sqrt(n) finds a different state
+        let counts = [6.0, 4.0, 8.0, 6.0, 6.0];
+        let rho = t
+            .reconstruct(&counts, Reconstruction::MaximumLikelihood)
+            .unwrap();
+        // The log-likelihood with the best total rate for the state
+        let log_likelihood = |rho: &Matrix| {
+            let p = t
+                .projectors
+                .iter()
+                .map(|psi| expectation(rho, psi))
+                .collect::<Vec<_>>();
+            let total = p.iter().sum::<f64>();
+            counts
+                .iter()
+                .zip(p.iter())
+                .map(|(n, p)| n * (p / total).ln())
+                .sum::<f64>()
+        };
+        let best = log_likelihood(&rho);
+        let pauli = [
+            vec![
+                vec![c(0.0, 0.0), c(1.0, 0.0)],
+                vec![c(1.0, 0.0), c(0.0, 0.0)],
+            ],
+            vec![
+                vec![c(0.0, 0.0), c(0.0, -1.0)],
+                vec![c(0.0, 1.0), c(0.0, 0.0)],
+            ],
+            vec![
+                vec![c(1.0, 0.0), c(0.0, 0.0)],
+                vec![c(0.0, 0.0), c(-1.0, 0.0)],
+            ],
+        ];
+        for sigma in pauli.iter() {
+            for &step in [-1e-3, 1e-3].iter() {
+                let moved = (0..2)
+                    .map(|i| (0..2).map(|j| rho[i][j] + sigma[i][j] * step).collect())
+                    .collect::<Matrix>();
+                assert!(log_likelihood(&moved) <= best + 1e-9);
+            }
+        }
+    }
+}
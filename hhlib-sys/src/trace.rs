@@ -0,0 +1,179 @@
+//! Count rate traces, the singles and coincidences binned in time over a long acquisition.
+//! Used to look at blinking, drift and the stability of an alignment.
+use pyo3::prelude::*;
+use std::collections::VecDeque;
+
+use crate::measurement::{run_measurement_T2, Measureable};
+use crate::types::HydraHarpError;
+
+/// Bins the singles of each channel, and the coincidences of chosen pairs of channels, in time.
+/// The bins carry on across measurements, so the trace covers the total acquisition time.
+/// A coincidence goes in the bin of its later event
+#[pyclass]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceBinner {
+    /// The number of channels, including the sync at channel 0
+    pub channels: usize,
+    /// Width of each bin in ps
+    pub bin_width: u64,
+    /// Half width of the coincidence window in ps
+    pub window: u64,
+    /// The pairs of channels to count coincidences between
+    pub pairs: Vec<(u8, u8)>,
+    /// The index of the first bin in `singles` and `coincidences`, counting from the start of the trace
+    pub first_bin: u64,
+    /// `singles[bin][channel]`
+    pub singles: VecDeque<Vec<u64>>,
+    /// `coincidences[bin][pair]`, in the order of `pairs`
+    pub coincidences: VecDeque<Vec<u64>>,
+    /// The number of times which fell in bins already removed by `take_complete`, which aren't
+    /// counted. These come from measurements which run on past their nominal duration
+    pub late: u64,
+    /// The start of the current measurement from the start of the trace in ps
+    offset: u64,
+    /// The latest time seen from the start of the trace in ps
+    latest: u64,
+    buffer: VecDeque<(u8, u64)>,
+}
+
+impl TraceBinner {
+    /// Returns `InvalidArgument` if `bin_width` is zero
+    pub fn new(
+        channels: usize,
+        bin_width: u64,
+        window: u64,
+        pairs: Vec<(u8, u8)>,
+    ) -> Result<TraceBinner, HydraHarpError> {
+        if bin_width == 0 {
+            return Err(HydraHarpError::InvalidArgument);
+        }
+        Ok(TraceBinner {
+            channels,
+            bin_width,
+            window,
+            pairs,
+            first_bin: 0,
+            singles: VecDeque::new(),
+            coincidences: VecDeque::new(),
+            late: 0,
+            offset: 0,
+            latest: 0,
+            buffer: VecDeque::new(),
+        })
+    }
+
+    /// The index of `bin` in the stored bins, adding empty bins up to it if needed.
+    /// `bin` mustn't be before `first_bin`
+    fn bin_index(&mut self, bin: u64) -> usize {
+        while self.first_bin + (self.singles.len() as u64) <= bin {
+            self.singles.push_back(vec![0; self.channels]);
+            self.coincidences.push_back(vec![0; self.pairs.len()]);
+        }
+        (bin - self.first_bin) as usize
+    }
+
+    /// Add a chunk of channels and times.
+    /// THE SLICE INPUT SHOULD BE SORTED, and each chunk should follow on from the last
+    pub fn add_times(&mut self, times: &[(u8, u64)]) {
+        for &(channel, time) in times.iter() {
+            if channel as usize >= self.channels {
+                continue;
+            }
+            let time = time + self.offset;
+            if time / self.bin_width < self.first_bin {
+                self.late += 1;
+                continue;
+            }
+            self.latest = time;
+            let bin = self.bin_index(time / self.bin_width);
+            self.singles[bin][channel as usize] += 1;
+
+            let window = self.window;
+            while self
+                .buffer
+                .front()
+                .map_or(false, |&(_, t)| t + window <= time)
+            {
+                self.buffer.pop_front();
+            }
+            for &(other, _) in self.buffer.iter() {
+                for (i, &(a, b)) in self.pairs.iter().enumerate() {
+                    if (a == other && b == channel) || (a == channel && b == other) {
+                        self.coincidences[bin][i] += 1;
+                    }
+                }
+            }
+            if self
+                .pairs
+                .iter()
+                .any(|&(a, b)| a == channel || b == channel)
+            {
+                self.buffer.push_back((channel, time));
+            }
+        }
+    }
+
+    /// Move the trace on by the `duration` ps of the measurement and forget the buffered events.
+    /// Call this at the end of each measurement, as the times restart from zero in the next one
+    pub fn end_measurement(&mut self, duration: u64) {
+        self.offset += duration;
+        self.latest = self.offset;
+        self.buffer.clear();
+    }
+
+    /// Remove and return the bins which can't get any more counts, as `(first_bin, singles, coincidences)`
+    pub fn take_complete(&mut self) -> (u64, Vec<Vec<u64>>, Vec<Vec<u64>>) {
+        let first = self.first_bin;
+        // Fill in the empty bins up to the current one
+        let complete = self.bin_index((self.latest / self.bin_width).max(self.first_bin));
+        self.first_bin += complete as u64;
+        (
+            first,
+            self.singles.drain(..complete).collect(),
+            self.coincidences.drain(..complete).collect(),
+        )
+    }
+}
+
+/// Run a measurement for `acquisition_time` ms, adding the counts to `binner`
+pub fn measure_trace<M: Measureable>(
+    d: &mut M,
+    acquisition_time: i32,
+    binner: &mut TraceBinner,
+) -> Result<(), HydraHarpError> {
+    run_measurement_T2(d, acquisition_time, |times| binner.add_times(times))?;
+    binner.end_measurement(acquisition_time as u64 * 1_000_000_000);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceBinner;
+
+    #[test]
+    fn bins_carry_on_across_measurements() {
+        let mut b = TraceBinner::new(3, 1000, 50, vec![(1, 2)]).unwrap();
+        b.add_times(&[(1, 100), (2, 120), (1, 1500)]);
+        b.end_measurement(2500);
+        b.add_times(&[(2, 0), (2, 900)]);
+        let (first, singles, coincidences) = b.take_complete();
+        assert_eq!(first, 0);
+        assert_eq!(singles, vec![vec![0, 1, 1], vec![0, 1, 0], vec![0, 0, 1]]);
+        assert_eq!(coincidences, vec![vec![1], vec![0], vec![0]]);
+        assert_eq!(b.singles.len(), 1);
+    }
+
+    #[test]
+    fn late_times_are_counted_not_binned() {
+        assert!(TraceBinner::new(3, 0, 50, vec![]).is_err());
+        let mut b = TraceBinner::new(3, 1000, 50, vec![(1, 2)]).unwrap();
+        // The first measurement runs on past its duration of 1000ps
+        b.add_times(&[(1, 100), (1, 2500)]);
+        b.take_complete();
+        b.end_measurement(1000);
+        b.add_times(&[(1, 100), (2, 1200)]);
+        assert_eq!(b.late, 1);
+        assert_eq!(b.first_bin, 2);
+        assert_eq!(b.singles, vec![vec![0, 1, 1]]);
+    }
+}
@@ -2,8 +2,9 @@
 use crate::bindings::*;
 use pyo3::exceptions;
 use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(FromPrimitive, ToPrimitive, Debug, PartialEq)]
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum HydraHarpError {
     DeviceFailedToOpen = HH_ERROR_DEVICE_OPEN_FAIL as isize,
     DeviceBusy = HH_ERROR_DEVICE_BUSY as isize,
@@ -68,6 +69,8 @@ pub enum HydraHarpError {
     EEPROMF11 = HH_ERROR_EEPROM_F11 as isize,
     UnknownError = HH_ERROR_EEPROM_F11 as isize - 1,
     HistogramLengthNotKnown = HH_ERROR_EEPROM_F11 as isize - 2,
+    /// Reading or writing a file failed
+    FileError = HH_ERROR_EEPROM_F11 as isize - 3,
 }
 
 pub mod py_hydra_harp_error{
@@ -136,6 +139,7 @@ pub mod py_hydra_harp_error{
     create_exception!(hhlib_sys, EEPROMF11, exceptions::Exception);
     create_exception!(hhlib_sys, UnknownError, exceptions::Exception);
     create_exception!(hhlib_sys, HistogramLengthNotKnown, exceptions::Exception);
+    create_exception!(hhlib_sys, FileError, exceptions::Exception);
 }
 
 /// Convert a function returning a `Result<T, HydraHarpError>` into a PyResult
@@ -207,11 +211,12 @@ pub fn convert_hydra_harp_result<T>(r: Result<T, HydraHarpError>) -> PyResult<T>
             HydraHarpError::EEPROMF11 => EEPROMF11.into(),
             HydraHarpError::UnknownError => UnknownError.into(),
             HydraHarpError::HistogramLengthNotKnown => HistogramLengthNotKnown.into(),
+            HydraHarpError::FileError => FileError.into(),
         }),
     }
 }
 
-#[derive(FromPrimitive, Debug)]
+#[derive(FromPrimitive, Debug, Clone, Copy, PartialEq, Deserialize)]
 pub enum Warning {
     SyncRateZero = WARNING_SYNC_RATE_ZERO as isize,
     SyncRateTooLow = WARNING_SYNC_RATE_TOO_LOW as isize,
@@ -224,7 +229,7 @@ pub enum Warning {
     OffsetUnnecessary = WARNING_OFFSET_UNNECESSARY as isize,
 }
 
-#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy)]
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum MeasurementMode {
     Histogramming = MODE_HIST as isize,
     T2 = MODE_T2 as isize,
@@ -232,7 +237,7 @@ pub enum MeasurementMode {
     Continuous = MODE_CONT as isize,
 }
 
-#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy)]
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy, PartialEq)]
 pub enum MeasurementControl {
     SingleShotCTC = MEASCTRL_SINGLESHOT_CTC as isize,
     C1Gated = MEASCTRL_C1_GATED as isize,
@@ -243,7 +248,7 @@ pub enum MeasurementControl {
     ContCTCRestart = MEASCTRL_CONT_CTC_RESTART as isize,
 }
 
-#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy, PartialEq)]
+#[derive(FromPrimitive, ToPrimitive, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ReferenceSource {
     Internal = 0,
     External = 1,
@@ -1,24 +1,74 @@
 extern crate hhlib_sys;
 
+use hhlib_sys::calibration::{calibrate_delays, DelaySearch};
+#[cfg(feature = "catalogue")]
+use hhlib_sys::catalogue::{Catalogue, Run};
+use hhlib_sys::coincidence::{measure_coincidences, CoincidenceCounter};
+use hhlib_sys::config::DeviceConfig;
 use hhlib_sys::device::Device;
 use hhlib_sys::measurement::{Measurement, Measureable};
+use hhlib_sys::ptu::RecordType;
+use hhlib_sys::raw::{record_raw, RawMetadata, RawRecorder};
+use hhlib_sys::replay::{FileReplay, Pacing};
 use hhlib_sys::types::{CTCStatus, HydraHarpError, MeasurementMode, ReferenceSource};
 use std::thread::sleep_ms;
 
 fn main() -> Result<(), HydraHarpError> {
+    if let Some(path) = std::env::args().skip_while(|a| a != "--replay").nth(1) {
+        return replay(&path);
+    }
     let mut dev = Device::open_device(0)?;
     dev.initialise(MeasurementMode::T2, ReferenceSource::Internal)?;
-    dev.calibrate()?;
-    dev.set_sync_divider(1)?;
-    dev.set_sync_CFD(50, 10)?;
-    dev.set_sync_channel_offset(-5000)?;
     let num_channels = dev.get_number_of_input_channels()?;
-    for i in (0..num_channels) {
-        dev.set_input_CFD(i, 50, 10)?;
-        dev.set_input_channel_offset(i, 0)?;
-    }
+    // Start from the configuration saved by the last calibration, if there is one
+    let config_path = std::env::args()
+        .skip_while(|a| a != "--config")
+        .nth(1)
+        .unwrap_or_else(|| "hydraharp_config.json".to_string());
+    let mut config = if std::path::Path::new(&config_path).exists() {
+        DeviceConfig::load(&config_path)?
+    } else {
+        let mut config = DeviceConfig::new(num_channels as usize);
+        config.sync_offset = -5000;
+        config
+    };
+    config.apply(&mut dev)?;
     let sleep_time: u32 = 4000;
     sleep_ms(200);
+    if std::env::args().any(|a| a == "--calibrate") {
+        // Line every input up with the sync, searching +-100ns in 100ps bins
+        let search = DelaySearch {
+            reference: 0,
+            channels: (1..=num_channels as u8).collect(),
+            bin_width: 100,
+            tau: 100_000,
+        };
+        let delays = calibrate_delays(&mut dev, &mut config, 1000, &search, true)?;
+        for d in delays.iter() {
+            println!(
+                "Channel {}: delay {:.1}ps, jitter {:.1}ps FWHM, new offset {:?}ps",
+                d.channel,
+                d.delay,
+                d.jitter,
+                config.channel_offset(d.channel)
+            );
+        }
+        config.save(&config_path)?;
+        println!("Saved the offsets to {}", config_path);
+    }
+    if let Some(path) = std::env::args().skip_while(|a| a != "--record").nth(1) {
+        // Append each measurement to a raw recording instead of throwing the records away
+        let metadata = RawMetadata::from_device(&dev, &config, RecordType::HydraHarp2T2)?;
+        let mut recorder = RawRecorder::create(&path, metadata)?;
+        for _ in 0..1000 {
+            record_raw(&mut dev, sleep_time as i32, &mut recorder)?;
+            println!("Recorded {} records", recorder.metadata.records());
+        }
+        recorder.save()?;
+        #[cfg(feature = "catalogue")]
+        add_to_catalogue(&path)?;
+        return Ok(());
+    }
     for i in (0..1000) {
         let results = run_measurement_and_wait_till_finished(sleep_time, &mut dev)?;
         println!("Measurement length: {}", results.len());
@@ -33,6 +83,37 @@ fn main() -> Result<(), HydraHarpError> {
     Ok(())
 }
 
+/// Keep a record of the run recorded at `path` in the catalogue given after --catalogue, if any,
+/// with the note given after --note
+#[cfg(feature = "catalogue")]
+fn add_to_catalogue(path: &str) -> Result<(), HydraHarpError> {
+    if let Some(database) = std::env::args().skip_while(|a| a != "--catalogue").nth(1) {
+        let note = std::env::args().skip_while(|a| a != "--note").nth(1);
+        let run = Run::from_recording(path, note.as_ref().map_or("", |n| n.as_str()))?;
+        let id = Catalogue::open(&database)?.add(&run)?;
+        println!("Added run {} to {}", id, database);
+    }
+    Ok(())
+}
+
+/// Count the coincidences in a recorded file in real time, for trying things out without the device.
+/// Raw `.bin` recordings replay their first measurement
+fn replay(path: &str) -> Result<(), HydraHarpError> {
+    let mut replay = if path.ends_with(".bin") {
+        FileReplay::open_recording(path, 0, Pacing::RealTime)?
+    } else {
+        FileReplay::open(path, Pacing::RealTime)?
+    };
+    let acquisition_time = replay
+        .duration()
+        .map_or(1000, |d| (d / 1_000_000_000).max(1) as i32);
+    let mut counter = CoincidenceCounter::new(9, 1000, None);
+    measure_coincidences(&mut replay, acquisition_time, &mut counter)?;
+    println!("Singles: {:?}", counter.singles);
+    println!("Coincidences: {:?}", counter.coincidences);
+    Ok(())
+}
+
 fn run_measurement_and_wait_till_finished(
     time: u32,
     dev: &mut Device,